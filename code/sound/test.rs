@@ -1,189 +1,11617 @@
-#![feature(arc_counts)]
+// the "library" portions of this file expose more than the demo in main uses
+#![allow(dead_code)]
+
+#[cfg(feature = "crossbeam")]
+extern crate crossbeam_channel;
+
+#[cfg(feature = "egui")]
+extern crate eframe;
+
+#[cfg(feature = "egui")]
+use eframe::egui;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+use std::arch::asm;
 
 use std::thread;
+use std::cell::{Cell, UnsafeCell};
+use std::collections::VecDeque;
 use std::f32;
-use std::sync::{Arc, Mutex, mpsc};
+use std::fmt;
+use std::future::Future;
+use std::env;
+use std::io::{self, BufRead, Read, Write};
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::ptr::{self, NonNull};
+use std::sync::{Arc, Condvar, Mutex, Once, Weak, mpsc};
+use std::sync::atomic::{self, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(PartialEq)]
 enum CallbackStatus {
     Continue,
+    /// carry on, and everything this callback put out was silence. Hosts can use this to skip
+    /// work of their own (mixing, waking up the device, ...)
+    KeepQuiet,
     Shutdown,
+    /// something went wrong which the realtime thread can't recover from. The engine stops and
+    /// `run_threads` hands the error back
+    Error(EngineError),
+}
+
+/// Why the engine stopped without being asked to
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EngineError {
+    /// the host handed the callback an output buffer which doesn't hold the number of frames it
+    /// said it did
+    BadBuffer(usize),
+    /// the audio device went away
+    DeviceLost,
+    /// the user's processor (see `RealtimeThread::set_processor`) failed
+    Processor(&'static str),
+    /// the user's processor panicked. The callback's output was silenced
+    Panicked,
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EngineError::BadBuffer(len) => write!(f, "output buffer of {} samples doesn't match the frame count", len),
+            EngineError::DeviceLost     => write!(f, "audio device lost"),
+            EngineError::Processor(why) => write!(f, "processor failed: {}", why),
+            EngineError::Panicked       => write!(f, "processor panicked"),
+        }
+    }
 }
 
 // "library" code starts here
-type Samples = [f32; 64];
+// number of frames (one sample per channel) in a block of samples, unless picked otherwise
+const FRAMES: usize = 64;
+
+// number of output channels. 2 is stereo
+const CHANNELS: usize = 2;
+
+// frames per second the engine runs at, unless picked otherwise
+const SAMPLE_RATE: u32 = 48_000;
+
+/// A block of `N` frames, each holding a sample per channel. Frames sit next to each other, so
+/// in memory this is interleaved: channel `c` of frame `i` is sample `i * CHANNELS + c`.
+/// Pick a different `N` to use a different period size, everything built on `Samples` follows.
+/// `S` is the sample format, see `Sample`
+type Samples<const N: usize = FRAMES, S = f32> = [[S; CHANNELS]; N];
+
+/// A block of silence
+fn silence<const N: usize, S: Sample>() -> Samples<N, S> {
+    [[S::SILENCE; CHANNELS]; N]
+}
+
+/// A format the engine can carry samples in. The engine itself only moves samples around, so
+/// anything which converts to and from f32 works; the few places which do math (mixing,
+/// metering, synthesis) go through f32.
+/// f32 is the default, f64 is there for high quality offline renders and i16 for fixed point
+/// hardware
+trait Sample: Copy + Send + Sync + 'static {
+    const SILENCE: Self;
+
+    /// Convert to f32, full scale is -1.0 to 1.0
+    fn to_f32(self) -> f32;
+
+    /// Convert from f32, full scale is -1.0 to 1.0. Integer formats clip anything outside
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Sample for f32 {
+    const SILENCE: f32 = 0.0;
+    fn to_f32(self) -> f32 { self }
+    fn from_f32(value: f32) -> f32 { value }
+}
+
+impl Sample for f64 {
+    const SILENCE: f64 = 0.0;
+    fn to_f32(self) -> f32 { self as f32 }
+    fn from_f32(value: f32) -> f64 { value as f64 }
+}
+
+impl Sample for i16 {
+    const SILENCE: i16 = 0;
+    fn to_f32(self) -> f32 { self as f32 / i16::MAX as f32 }
+    fn from_f32(value: f32) -> i16 { (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16 }
+}
+
+/// Convert a buffer of samples from one format to another, for the edges of the engine where
+/// the device (or file) wants a different format than the engine runs in.
+/// Converts as many samples as fit in both
+fn convert_samples<A: Sample, B: Sample>(from: &[A], to: &mut [B]) {
+    for (to, from) in to.iter_mut().zip(from) {
+        *to = B::from_f32(from.to_f32());
+    }
+}
+
+/// true if every sample is silence
+fn is_silent<S: Sample>(samples: &[S]) -> bool {
+    samples.iter().all(|sample| sample.to_f32() == 0.0)
+}
+
+/// Convert a whole block from one format to another
+fn convert_block<const N: usize, A: Sample, B: Sample>(from: &Samples<N, A>) -> Samples<N, B> {
+    let mut to = silence();
+    convert_samples(from.as_flattened(), to.as_flattened_mut());
+    to
+}
+
+/// Makes the CPU treat denormal floats as zero on the current thread for as long as it is
+/// alive, putting the old behaviour back when dropped. Recursive filters decay towards zero
+/// through denormals, and on most CPUs those are slow enough to blow the callback's deadline.
+/// Only x86 (FTZ and DAZ) and aarch64 (FZ) are covered, elsewhere this does nothing and
+/// `flush_denormals` has to do the job
+struct DenormalGuard {
+    saved:   usize,
+    // the flags belong to the thread which set them, so the guard can't leave it
+    _thread: PhantomData<*const ()>,
+}
+
+impl DenormalGuard {
+    fn new() -> Self {
+        let saved = fp_flags();
+        set_fp_flags(saved | FLUSH_DENORMALS);
+        DenormalGuard { saved, _thread: PhantomData }
+    }
+}
+
+impl Drop for DenormalGuard {
+    fn drop(&mut self) {
+        set_fp_flags(self.saved);
+    }
+}
+
+// MXCSR flush to zero (bit 15) and denormals are zero (bit 6)
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const FLUSH_DENORMALS: usize = 0x8040;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn fp_flags() -> usize {
+    let mut csr: u32 = 0;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut csr, options(nostack)) };
+    csr as usize
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn set_fp_flags(flags: usize) {
+    let csr = flags as u32;
+    unsafe { asm!("ldmxcsr [{}]", in(reg) &csr, options(nostack)) };
+}
+
+// FPCR flush to zero (bit 24), which covers inputs as well as outputs
+#[cfg(target_arch = "aarch64")]
+const FLUSH_DENORMALS: usize = 1 << 24;
+
+#[cfg(target_arch = "aarch64")]
+fn fp_flags() -> usize {
+    let fpcr: u64;
+    unsafe { asm!("mrs {}, fpcr", out(reg) fpcr, options(nomem, nostack)) };
+    fpcr as usize
+}
+
+#[cfg(target_arch = "aarch64")]
+fn set_fp_flags(flags: usize) {
+    unsafe { asm!("msr fpcr, {}", in(reg) flags as u64, options(nomem, nostack)) };
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+const FLUSH_DENORMALS: usize = 0;
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn fp_flags() -> usize { 0 }
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn set_fp_flags(_flags: usize) { }
+
+/// Flush a denormal to zero, for filter state on CPUs `DenormalGuard` can't help with
+#[inline]
+fn flush_denormal(value: f32) -> f32 {
+    if value.abs() < f32::MIN_POSITIVE { 0.0 } else { value }
+}
+
+/// Flush every denormal in `samples` to zero, see `flush_denormal`
+fn flush_denormals(samples: &mut [f32]) {
+    for sample in samples.iter_mut() {
+        *sample = flush_denormal(*sample);
+    }
+}
+
+/// How the thread running the realtime loop should be scheduled, see
+/// `RealtimeThread::set_scheduling`. By default it is left alone
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct RtScheduling {
+    /// run with realtime priority: SCHED_FIFO at this priority (1 to 99) on Linux, time critical
+    /// on Windows
+    priority: Option<i32>,
+    /// only run on this core
+    core:     Option<usize>,
+}
+
+impl RtScheduling {
+    /// Apply to the calling thread. Raising the priority usually needs permission (CAP_SYS_NICE
+    /// or an rtprio limit on Linux). Anything which can't be done is skipped with a warning, and
+    /// the loop runs with whatever it has
+    fn apply(&self) {
+        if let Some(priority) = self.priority {
+            if let Err(e) = set_realtime_priority(priority) {
+                println!("[realtime] couldn't raise priority, running without it: {}", e);
+            }
+        }
+
+        if let Some(core) = self.core {
+            if let Err(e) = pin_to_core(core) {
+                println!("[realtime] couldn't pin to core {}, running anywhere: {}", core, e);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SchedParam {
+    sched_priority: i32,
+}
+
+#[cfg(target_os = "linux")]
+const SCHED_FIFO: i32 = 1;
+
+// size of glibc's cpu_set_t, in bytes. Enough for 1024 cores
+#[cfg(target_os = "linux")]
+const CPU_SET_SIZE: usize = 128;
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn pthread_self() -> usize;
+    fn pthread_setschedparam(thread: usize, policy: i32, param: *const SchedParam) -> i32;
+    fn sched_setaffinity(pid: i32, size: usize, mask: *const u8) -> i32;
+}
+
+#[cfg(target_os = "linux")]
+fn set_realtime_priority(priority: i32) -> io::Result<()> {
+    let param = SchedParam { sched_priority: priority };
+    match unsafe { pthread_setschedparam(pthread_self(), SCHED_FIFO, &param) } {
+        0     => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_core(core: usize) -> io::Result<()> {
+    if core >= CPU_SET_SIZE * 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no such core"));
+    }
+
+    let mut mask = [0u8; CPU_SET_SIZE];
+    mask[core / 8] |= 1 << (core % 8);
+
+    // pid 0 is the calling thread
+    match unsafe { sched_setaffinity(0, CPU_SET_SIZE, mask.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(windows)]
+const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCurrentThread() -> isize;
+    fn SetThreadPriority(thread: isize, priority: i32) -> i32;
+    fn SetThreadAffinityMask(thread: isize, mask: usize) -> usize;
+}
+
+// Windows only has the one realtime level, so the priority itself is ignored
+#[cfg(windows)]
+fn set_realtime_priority(_priority: i32) -> io::Result<()> {
+    match unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL) } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(windows)]
+fn pin_to_core(core: usize) -> io::Result<()> {
+    if core >= mem::size_of::<usize>() * 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no such core"));
+    }
+
+    match unsafe { SetThreadAffinityMask(GetCurrentThread(), 1 << core) } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn set_realtime_priority(_priority: i32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn pin_to_core(_core: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"))
+}
+
+/// Audio processing which runs on the realtime thread, once per callback.
+/// `RealtimeThread` implements this itself, and also runs a user supplied one after it has
+/// filled in the output (see `RealtimeThread::set_processor`), so custom DSP gets the message
+/// handling, shutdown and buffer swapping for free. Must not block or allocate.
+/// `input` holds whatever the input device captured for this callback (silence if there isn't
+/// one), so effects and monitoring can work on live input.
+/// `info` says where this callback sits in time, so there is no need to ask the OS clock
+trait RealtimeCallback<S: Sample = f32>: Send {
+    fn process(&mut self, info: CallbackInfo, input: &[S], out: &mut [S]) -> CallbackStatus;
+}
+
+/// Timing for a single callback, filled in by the host
+#[derive(Clone, Copy, Debug)]
+struct CallbackInfo {
+    /// number of frames in this callback's buffers
+    frame_count:   usize,
+    /// the frame the first sample of this callback will be played at, counting from when the
+    /// host started
+    sample_time:   u64,
+    /// when the output needs to be ready by. None if the host doesn't know
+    wall_deadline: Option<Instant>,
+}
+
+/// Anything the UI thread can send to the realtime thread.
+/// Applications can define their own message type to add commands (SetGain, SetFrequency, ...)
+/// without touching the engine. `Message` is the one the engine comes with.
+/// Messages are cloned when they are broadcast to several realtime threads, so keep them cheap to
+/// clone
+trait RtMessage<const N: usize = FRAMES, S: Sample = f32>: Clone + Send + 'static {
+    /// The message which tells the realtime thread to shut down
+    fn shutdown() -> Self;
+
+    /// The message which plays `table` at `frequency` Hz and `gain`, see
+    /// `RealtimeThread::play_wavetable`. The UI thread plays pitched waves this way, so they sound
+    /// at exactly the frequency asked for. None (the default) means it sends samples instead
+    fn play_wavetable(_table: Arc<Wavetable>, _frequency: f32, _gain: f32) -> Option<Self> { None }
+
+    /// The message which stops the wavetable, see `RealtimeThread::stop_wavetable`
+    fn stop_wavetable() -> Option<Self> { None }
+
+    /// Does this message tell the realtime thread to shut down?
+    fn is_shutdown(&self) -> bool;
+
+    /// Samples carried by this message, if any. These are tracked by the collector before the
+    /// message is sent, so the realtime thread never frees them
+    fn samples(&self) -> Option<&Arc<Samples<N, S>>> { None }
+
+    /// A wavetable carried by this message, if any. Like samples, these are tracked by the
+    /// collector before the message is sent
+    fn wavetable(&self) -> Option<&Arc<Wavetable>> { None }
+
+    /// The position (in frames, see `Feedback::Position`) at which this message should take
+    /// effect. None means as soon as possible
+    fn due(&self) -> Option<u64> { None }
+
+    /// Messages with the same key set the same parameter, so only the most recent one matters.
+    /// If the realtime thread isn't keeping up, `UIThread::send` holds on to keyed messages and
+    /// replaces them as newer ones come in, instead of queueing up every one. None (the default)
+    /// means the message is never coalesced
+    fn key(&self) -> Option<usize> { None }
+
+    /// Apply this message on the realtime thread. Called from the realtime callback, so this must
+    /// not block or allocate
+    fn apply(self, rt: &mut RealtimeThread<Self, N, S>) where Self: Sized;
+}
+
+// number of log records which can be waiting to be printed before the realtime thread drops them
+const LOG_CAPACITY: usize = 256;
+
+// number of callbacks run against scratch buffers before the realtime loop starts, see
+// `RealtimeThread::warm_up`
+const WARM_UP_CALLBACKS: usize = 8;
+
+// number of times the processor can panic before run_threads gives up on it and shuts down
+const MAX_PANICS: usize = 3;
+
+/// What happened during a call to `run_threads`
+#[derive(Clone, Copy, Debug)]
+struct RunSummary {
+    /// did the realtime thread acknowledge the shutdown before the UI thread gave up on it?
+    clean_shutdown: bool,
+    /// total number of frames the realtime thread produced
+    position:       u64,
+    /// glitches counted by the realtime thread
+    xruns:          XrunCounters,
+    /// the collector's stats after everything was shut down
+    gc:             GcStats,
+}
+
+/// Run the engine with a queue between the UI and realtime threads which can hold `capacity`
+/// messages. A small queue keeps latency down, a bigger one lets the UI thread get further ahead
+/// of the realtime thread before it has to wait. `sample_rate` is the rate the host runs at, in
+/// frames per second.
+/// Fails if the realtime thread stopped because of an error, rather than being shut down
+fn run_threads<M, const N: usize, S: Sample>(capacity: usize, sample_rate: u32) -> Result<RunSummary, EngineError>
+    where M: RtMessage<N, S> + From<Arc<Samples<N, S>>>
+{
+    let (tx, rx) = ring_buffer(capacity);
+    run_threads_with(RealtimeThread::<M, N, S>::new(rx), UIThread::new(tx), sample_rate)
+}
+
+/// Run the engine with threads the caller has already set up, see `run_threads`
+fn run_threads_with<M, const N: usize, S: Sample>(mut rt: RealtimeThread<M, N, S>, mut ui: UIThread<M, N, S>, sample_rate: u32)
+    -> Result<RunSummary, EngineError>
+    where M: RtMessage<N, S> + From<Arc<Samples<N, S>>>
+{
+    rt.set_sample_rate(sample_rate);
+    ui.set_sample_rate(sample_rate);
+
+    // the collector lives exactly as long as the engine does
+    let mut scope = GcScope::<N, S>::new();
+    let reports = scope.collector.reports();
+    ui.set_collector(scope.handle(), reports);
+
+    // lets the realtime thread tell the UI thread what it is up to
+    let (feedback_tx, feedback_rx) = ring_buffer(FEEDBACK_CAPACITY);
+    rt.set_feedback(feedback_tx);
+    ui.set_feedback(feedback_rx);
+
+    // lets shutdown skip past anything still sitting in the queue
+    let shutdown = Arc::new(AtomicBool::new(false));
+    rt.set_shutdown_flag(shutdown.clone());
+    ui.set_shutdown_flag(shutdown.clone());
+
+    // the fast path for replacing the whole buffer at once
+    let sample_slot = Arc::new(SampleSlot::new());
+    rt.set_sample_slot(sample_slot.clone());
+    ui.set_sample_slot(sample_slot);
+
+    // keeps println out of the realtime callback
+    let (logger, log_thread) = rt_log(LOG_CAPACITY);
+    rt.set_logger(logger);
+
+    // somewhere to put the realtime thread's state when the UI thread asks for it
+    let (state_writer, state_reader) = triple_buffer(StateDump::default());
+    rt.set_state_dump(state_writer);
+    ui.set_state_dump(state_reader);
+
+    // allocate every sample buffer the UI thread will need up front
+    ui.set_sample_pool(SamplePool::new(SAMPLE_POOL_CAPACITY, scope.handle()));
+
+    // nothing has been sent yet, so this can't eat any messages
+    rt.warm_up(WARM_UP_CALLBACKS);
 
-fn run_threads(mut rt: RealtimeThread, mut ui: UIThread) {
     let join_handle = thread::spawn(move || {
         println!("[ui] thread started");
         ui.run();
         println!("[ui] thread shutting down");
+        ui.stopped()
     });
 
     println!("[realtime] thread started");
-    let mut output = [0.0; 64];
-    while rt.realtime_callback(&mut output) != CallbackStatus::Shutdown { }
+    rt.scheduling.apply();
+    let _denormals = DenormalGuard::new();
+    // there's no input device here, so the realtime thread just hears silence
+    let input = silence::<N, S>();
+    let mut output = silence::<N, S>();
+    let mut sample_time = 0;
+    let mut panics = 0;
+    let error = loop {
+        // no device is waiting on us either, so there's no deadline
+        let info = CallbackInfo {
+            frame_count:   N,
+            sample_time,
+            wall_deadline: None,
+        };
+
+        sample_time += N as u64;
+        match rt.realtime_callback(info, input.as_flattened(), output.as_flattened_mut()) {
+            CallbackStatus::Continue  => (),
+            CallbackStatus::KeepQuiet => (),
+            CallbackStatus::Shutdown  => break None,
+
+            // the output has already been silenced, so carry on unless it keeps happening
+            CallbackStatus::Error(EngineError::Panicked) => {
+                panics += 1;
+                if panics > MAX_PANICS {
+                    rt.fail(EngineError::Panicked);
+                    break Some(EngineError::Panicked);
+                }
+            },
+
+            CallbackStatus::Error(e) => break Some(e),
+        }
+    };
     println!("[realtime] thread shutting down");
+    let position = rt.position;
+    let xruns = rt.xruns();
+
+    // the UI thread may be waiting for room in the queue, which nobody is going to make if we
+    // stopped on an error. Tell it to stop, and let go of our end so it sees we've gone.
+    // This also lets go of the logger, so the logging thread can finish up
+    shutdown.store(true, Ordering::Release);
+    drop(rt);
+
+    let clean_shutdown = join_handle.join().unwrap();
 
-    join_handle.join().unwrap();
+    // make sure everything the realtime thread has let go of is gone before reporting
+    log_thread.join();
+    scope.collector.flush();
+    println!("[gc] collector stats: {:?}", scope.collector.stats());
+
+    match error {
+        Some(e) => Err(e),
+        None    => Ok(RunSummary {
+            clean_shutdown,
+            position,
+            xruns,
+            gc:             scope.collector.stats(),
+        }),
+    }
+
+    // collector shuts down here, when the scope goes out of scope
 }
 // end of "library" code
 
 // beginning of GC implementation
-// struct TrustMe<T> {
-//     pub data: T
-// }
+/// Anything the collector knows how to clean up
+trait Collectable: Send {
+    /// true once the collector is holding the only remaining reference
+    fn is_garbage(&self) -> bool;
 
-// unsafe impl<T> Send for TrustMe<T> {}
+    /// called on the collector thread once the item is garbage. By default, just drops it
+    fn collect(self: Box<Self>) {}
+}
 
-/// A garbage collector for Arc<T> pointers
-struct GC<T> {
-    pool: Vec<Arc<T>>,
-    thread: thread::JoinHandle<()>,
+impl<T: Send + Sync + 'static> Collectable for Arc<T> {
+    fn is_garbage(&self) -> bool {
+        Arc::strong_count(self) == 1
+    }
 }
 
-impl<T: Send + 'static> GC<T> {
-    pub fn new() -> Self {
-        let pool = Vec::new();
+// A weak reference doesn't keep the value alive, so whoever drops the last Arc drops the value.
+// The allocation itself isn't released until the last Weak goes away though, so the collector
+// still ends up being the one to call the allocator
+impl<T: Send + Sync + 'static> Collectable for Weak<T> {
+    fn is_garbage(&self) -> bool {
+        self.strong_count() == 0
+    }
+}
 
-        let gc = || {
-            loop {
-                pool.retain(|e: &Arc<_>| {
-                    if Arc::strong_count(&e) > 1 {
-                        return true
-                    } else {
-                        return false
-                    }
-                });
+// an Arc along with a closure to run when it is collected, see `GC::track_with`
+struct Hooked<T, F> {
+    arc:     Arc<T>,
+    on_drop: F,
+}
 
-                let sleep = std::time::Duration::from_millis(100);
-                thread::sleep(sleep);
-            }
-        };
+impl<T, F> Collectable for Hooked<T, F>
+    where T: Send + Sync + 'static,
+          F: FnOnce(T) + Send + 'static
+{
+    // a Weak could be upgraded between this check and `collect`, so wait until there are none
+    fn is_garbage(&self) -> bool {
+        self.arc.is_garbage() && Arc::weak_count(&self.arc) == 0
+    }
 
-        let gc_thread = thread::spawn(gc);
+    fn collect(self: Box<Self>) {
+        let this = *self;
 
-        GC {
-            pool:   pool,
-            thread: gc_thread
+        // we hold the only reference of any kind, so nobody can be cloning it out from under us
+        match Arc::try_unwrap(this.arc) {
+            Ok(t)  => (this.on_drop)(t),
+            Err(_) => unreachable!("collected an Arc which was still shared"),
         }
     }
+}
 
-    pub fn track(&mut self, t: Arc<T>) {
-        self.pool.push(t);
+// one tracked item, along with the number of scans it has survived
+struct Entry {
+    item:     Box<dyn Collectable>,
+    survived: u32,
+}
+
+impl Entry {
+    fn new(item: Box<dyn Collectable>) -> Self {
+        Entry { item, survived: 0 }
     }
 }
 
-// impl<T: Send + 'static> Drop for GC<T> {
-//     fn drop(&mut self) {
-//         println!("collector going down!");
-//         self.notify.send(true).unwrap();
+// an Arc which goes back to a Recycler when it becomes garbage
+struct Recycled<T> {
+    arc:   Arc<T>,
+    reset: Arc<dyn Fn(&mut T) + Send + Sync>,
+    home:  mpsc::Sender<Arc<T>>,
+}
+
+impl<T: Send + Sync + 'static> Collectable for Recycled<T> {
+    fn is_garbage(&self) -> bool {
+        self.arc.is_garbage()
+    }
 
-//         let t = self.thread.take();
-//         match t {
-//             Some(t) => t.join().unwrap(),
-//             None    => ()
-//         }
-//     }
-// }
-// end of GC implementation
+    fn collect(self: Box<Self>) {
+        let mut this = *self;
 
-enum Message {
-    NewSamples(Arc<Samples>),
-    Shutdown,
+        // we hold the only strong reference, so unless someone still has a Weak to it we are free
+        // to scribble on it. Otherwise it can't be handed out again, so just let go of it
+        match Arc::get_mut(&mut this.arc) {
+            Some(t) => (this.reset)(t),
+            None    => return,
+        }
+
+        // if the recycler is gone, the buffer is just freed
+        let _ = this.home.send(this.arc);
+    }
 }
 
-/// A struct containing the realtime callback and all data owned by the realtime thread
-struct RealtimeThread {
-    current_samples: Option<Arc<Samples>>,
-    incoming:        mpsc::Receiver<Message>,
+/// A free-list of buffers which the collector hands back instead of freeing.
+/// Once a buffer tracked through the recycler becomes garbage, the collector thread runs `reset`
+/// on it and returns it here, where `get` can hand it out again. In steady state, no new buffers
+/// need to be allocated at all
+struct Recycler<T: Send + Sync + 'static> {
+    returned: mpsc::Receiver<Arc<T>>,
+    home:     mpsc::Sender<Arc<T>>,
+    reset:    Arc<dyn Fn(&mut T) + Send + Sync>,
 }
 
-impl RealtimeThread {
-    fn new(incoming: mpsc::Receiver<Message>) -> Self {
-        RealtimeThread {
-            current_samples: None,
-            incoming:        incoming,
+impl<T: Send + Sync + 'static> Recycler<T> {
+    pub fn new<R>(reset: R) -> Self
+        where R: Fn(&mut T) + Send + Sync + 'static
+    {
+        let (home, returned) = mpsc::channel();
+        Recycler { returned, home, reset: Arc::new(reset) }
+    }
+
+    /// Reuse a buffer the collector has returned, or make a new one with `make` if there aren't
+    /// any. The returned Arc is not shared, so `Arc::get_mut` can be used to fill it in
+    pub fn get<F: FnOnce() -> T>(&self, make: F) -> Arc<T> {
+        match self.returned.try_recv() {
+            Ok(t)  => t,
+            Err(_) => Arc::new(make()),
         }
     }
 
-    /// realtime callback, called to get the list of samples
-    fn realtime_callback(&mut self, output_samples: &mut Samples) -> CallbackStatus {
-        match self.incoming.try_recv() {
-            // we've received a messaged
-            Ok(message) => match message {
-                Message::NewSamples(samples) => {
-                    println!("[realtime] received new samples. Second sample: {}", samples[1]);
-                    self.current_samples = Some(samples)
-                },
+    // private. wraps up an Arc so the collector sends it back here
+    fn wrap(&self, t: Arc<T>) -> Box<dyn Collectable> {
+        Box::new(Recycled { arc: t, reset: self.reset.clone(), home: self.home.clone() })
+    }
 
-                Message::Shutdown => return CallbackStatus::Shutdown
-            },
+    // private. undoes `wrap` for an Arc which never made it to the collector
+    fn unwrap(&self, t: Box<dyn Collectable>) -> Arc<T> {
+        // the only thing wrap ever produces is a Recycled<T>
+        let raw = Box::into_raw(t) as *mut Recycled<T>;
+        unsafe { Box::from_raw(raw) }.arc
+    }
+}
+
+// the pool is type erased so that one collector thread can own garbage of any type
+type ArcPool = Vec<Entry>;
+
+/// Marker type for a GC which tracks Arcs of many different types, see `AnyGC`
+enum Erased {}
+
+/// A garbage collector which can track `Arc<T>` for any `T: Send + Sync`
+type AnyGC = GC<Erased>;
+
+/// A snapshot of the collector's counters, see `GC::stats`
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct GcStats {
+    /// total number of Arcs ever handed to `track`
+    pub tracked:    usize,
+    /// total number of Arcs the collector has dropped
+    pub collected:  usize,
+    /// number of Arcs currently sitting in the pool
+    pub pool_size:  usize,
+    /// largest the pool has ever been
+    pub high_water: usize,
+}
+
+// shared between the collector thread and the owner of the GC
+struct GcCounters {
+    tracked:    AtomicUsize,
+    collected:  AtomicUsize,
+    pool_size:  AtomicUsize,
+    high_water: AtomicUsize,
+}
 
-            // if we failed to receive anything, just keep sending samples
-            Err(_) => ()
+impl GcCounters {
+    fn new() -> Self {
+        GcCounters {
+            tracked:    AtomicUsize::new(0),
+            collected:  AtomicUsize::new(0),
+            pool_size:  AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
         }
+    }
 
-        // copy our current samples into the output buffer
-        self.current_samples.as_ref().map(|samples| {
-            // samples: &Arc<[f32; 64>
-            output_samples.copy_from_slice(samples.as_ref())
-        });
+    // record the current size of the pool, bumping the high-water mark if needed
+    fn set_pool_size(&self, size: usize) {
+        self.pool_size.store(size, Ordering::Relaxed);
+        self.high_water.fetch_max(size, Ordering::Relaxed);
+    }
+}
 
-        CallbackStatus::Continue
+/// A summary of one collection pass, see `GC::reports`
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct GcReport {
+    /// number of items left in the pool (both generations) after the pass
+    pub pool_size:     usize,
+    /// number of items freed during the pass
+    pub freed:         usize,
+    /// how long the pass took
+    pub pass_duration: Duration,
+}
+
+// number of reports which can pile up before the collector starts throwing new ones away
+const REPORT_BACKLOG: usize = 16;
+
+// lets the owner of a GC wait for the collector to finish a complete pass, see `GC::flush`
+struct FlushSignal {
+    requested: AtomicUsize,
+    completed: Mutex<usize>,
+    cond:      Condvar,
+}
+
+impl FlushSignal {
+    fn new() -> Self {
+        FlushSignal {
+            requested: AtomicUsize::new(0),
+            completed: Mutex::new(0),
+            cond:      Condvar::new(),
+        }
+    }
+
+    // owner side. returns a ticket to wait on
+    fn request(&self) -> usize {
+        self.requested.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    // owner side. blocks until the collector has finished a full pass on behalf of `ticket`
+    fn wait_for(&self, ticket: usize) {
+        let mut completed = self.completed.lock().unwrap();
+        while *completed < ticket {
+            completed = self.cond.wait(completed).unwrap();
+        }
+    }
+
+    // collector side. the highest ticket the collector is about to serve
+    fn pending(&self) -> Option<usize> {
+        let requested = self.requested.load(Ordering::Acquire);
+        if requested > *self.completed.lock().unwrap() {
+            Some(requested)
+        } else {
+            None
+        }
+    }
+
+    // collector side. wake up everyone waiting on `ticket` or earlier
+    fn complete(&self, ticket: usize) {
+        *self.completed.lock().unwrap() = ticket;
+        self.cond.notify_all();
     }
 }
 
-/// A struct which runs the UI thread and contains all of the data owned by the UI thread
-struct UIThread {
-    outgoing: mpsc::SyncSender<Message>,
+// number of slots in the lock-free intake queue, must be a power of two
+const INTAKE_CAPACITY: usize = 1024;
+
+// one slot in the intake ring. `seq` says whose turn it is to use the slot
+struct IntakeSlot<T> {
+    seq:   AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+// A preallocated, bounded, lock-free multi-producer single-consumer queue (Vyukov's bounded queue).
+// Producers never lock or allocate, so this is safe to push to from the realtime thread.
+// There is only ever one consumer: the collector thread, or the realtime thread for submissions
+struct Intake<T> {
+    slots:   Box<[IntakeSlot<T>]>,
+    mask:    usize,
+    head:    AtomicUsize, // next position to push to
+    tail:    AtomicUsize, // next position to pop from
+    handles: AtomicUsize, // number of live producers (GcHandles or Submitters)
 }
 
-impl UIThread {
-    fn new(outgoing: mpsc::SyncSender<Message>) -> Self {
-        UIThread { outgoing: outgoing }
+unsafe impl<T: Send> Send for Intake<T> {}
+unsafe impl<T: Send> Sync for Intake<T> {}
+
+impl<T> Intake<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity >= 2 && capacity.is_power_of_two());
+
+        let slots: Vec<IntakeSlot<T>> = (0..capacity).map(|i| {
+            IntakeSlot { seq: AtomicUsize::new(i), value: UnsafeCell::new(None) }
+        }).collect();
+
+        Intake {
+            slots:   slots.into_boxed_slice(),
+            mask:    capacity - 1,
+            head:    AtomicUsize::new(0),
+            tail:    AtomicUsize::new(0),
+            handles: AtomicUsize::new(0),
+        }
     }
 
-    /// computes the samples needed for on cycle of a sine wave
-    /// the volume parameter sets the audible volume of sound produced
-    fn compute_samples(&self, volume: f32) -> Samples {
-        assert!(volume >= 0.0);
-        assert!(volume <= 1.0);
+    // any thread. hands the value back if the queue is full
+    fn push(&self, t: T) -> Result<(), T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq = slot.seq.load(Ordering::Acquire);
+
+            if seq == pos {
+                // the slot is free, try to claim it
+                match self.head.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { *slot.value.get() = Some(t) };
+                        slot.seq.store(pos + 1, Ordering::Release);
+                        return Ok(());
+                    },
+                    Err(current) => pos = current,
+                }
+            } else if seq < pos {
+                // the consumer hasn't emptied this slot since we last went around the ring
+                return Err(t);
+            } else {
+                // another producer beat us to this slot
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    // consumer only
+    fn pop(&self) -> Option<T> {
+        let pos = self.tail.load(Ordering::Relaxed);
+        let slot = &self.slots[pos & self.mask];
 
-        // we need to populate 64 samples with 1 cycle of a sine wave (arbitrary choice)
-        let constant_factor = (1.0/64.0) * 2.0 * f32::consts::PI;
-        let mut samples = [0.0; 64];
-        for i in 0..64 {
-            samples[i] = (constant_factor * i as f32).sin() * volume;
+        if slot.seq.load(Ordering::Acquire) != pos + 1 {
+            return None;
         }
 
-        samples
+        let t = unsafe { (*slot.value.get()).take() };
+        slot.seq.store(pos + self.mask + 1, Ordering::Release);
+        self.tail.store(pos + 1, Ordering::Relaxed);
+        t
     }
+}
 
-    /// All of the UI thread code
-    fn run(&mut self) {
-        // start the garbage collector
-        // let mut gc = GC::new();
+// a Vec<U> with the U forgotten, so that it can sit in the intake without being boxed
+struct ErasedVec {
+    ptr:  *mut u8,
+    len:  usize,
+    cap:  usize,
+    drop: unsafe fn(*mut u8, usize, usize),
+}
 
-        // create 10 "ui events"
-        for i in 0..5 {
-            let volume = i as f32 / 10.0;
-            let samples = Arc::new(self.compute_samples(volume));
-            // gc.track(samples.clone());
+unsafe impl Send for ErasedVec {}
+
+unsafe fn drop_vec<U>(ptr: *mut u8, len: usize, cap: usize) {
+    drop(Vec::from_raw_parts(ptr as *mut U, len, cap));
+}
 
-            // send the samples to the other thread
-            println!("[ui] sending new samples. Second sample: {}", samples[1]);
-            self.outgoing.send(Message::NewSamples(samples)).unwrap();
+impl ErasedVec {
+    fn new<U: Send + 'static>(v: Vec<U>) -> Self {
+        let mut v = ManuallyDrop::new(v);
+        ErasedVec {
+            ptr:  v.as_mut_ptr() as *mut u8,
+            len:  v.len(),
+            cap:  v.capacity(),
+            drop: drop_vec::<U>,
         }
+    }
+}
 
-        // tell the other thread to shutdown
-        self.outgoing.send(Message::Shutdown).unwrap();
+impl Drop for ErasedVec {
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.ptr, self.len, self.cap) }
     }
 }
 
-fn main() {
-    let (tx, rx) = mpsc::sync_channel(0);
-    let rt = RealtimeThread::new(rx);
-    let ui = UIThread::new(tx);
-    run_threads(rt, ui);
+// everything which can come in through a GcHandle
+enum Handoff<T> {
+    // an Arc to watch, just like `GC::track`
+    Track(Arc<T>),
+    // something already wrapped up for the pool, such as a recycled buffer
+    Boxed(Box<dyn Collectable>),
+    // owned data to drop as soon as the collector sees it
+    Defer(Box<dyn Send>),
+    DeferVec(ErasedVec),
+}
+
+/// A cheap, cloneable handle which can pass Arcs to a collector from any thread, including the
+/// realtime thread. See `GC::handle`
+struct GcHandle<T: Send + Sync + 'static> {
+    intake: Arc<Intake<Handoff<T>>>,
+}
+
+impl<T: Send + Sync + 'static> GcHandle<T> {
+    /// Give the collector an Arc without locking or allocating.
+    /// If the intake queue is full, the Arc is handed back
+    pub fn try_track(&self, t: Arc<T>) -> Result<(), Arc<T>> {
+        match self.intake.push(Handoff::Track(t)) {
+            Ok(())                 => Ok(()),
+            Err(Handoff::Track(t)) => Err(t),
+            Err(_)                 => unreachable!(),
+        }
+    }
+
+    /// Like `try_track`, for an Arc of any type. This allocates, so don't use it from the realtime
+    /// thread
+    pub fn try_track_any<U: Send + Sync + 'static>(&self, t: Arc<U>) -> Result<(), Arc<U>> {
+        match self.intake.push(Handoff::Boxed(Box::new(t))) {
+            Ok(())                 => Ok(()),
+            // the only thing in the box is the Arc<U> we just put there
+            Err(Handoff::Boxed(t)) => Err(*unsafe { Box::from_raw(Box::into_raw(t) as *mut Arc<U>) }),
+            Err(_)                 => unreachable!(),
+        }
+    }
+
+    /// Like `try_track`, but once the Arc is garbage it is reset and handed back to `recycler`
+    /// instead of being freed. This allocates, so don't use it from the realtime thread
+    pub fn try_track_recycled(&self, t: Arc<T>, recycler: &Recycler<T>) -> Result<(), Arc<T>> {
+        match self.intake.push(Handoff::Boxed(recycler.wrap(t))) {
+            Ok(())                 => Ok(()),
+            Err(Handoff::Boxed(t)) => Err(recycler.unwrap(t)),
+            Err(_)                 => unreachable!(),
+        }
+    }
+
+    /// Have the collector free some owned data, instead of freeing it on this thread.
+    /// Moving an existing Box into the intake doesn't allocate. If the intake is full, the Box is
+    /// handed back
+    pub fn defer(&self, garbage: Box<dyn Send>) -> Result<(), Box<dyn Send>> {
+        match self.intake.push(Handoff::Defer(garbage)) {
+            Ok(())                       => Ok(()),
+            Err(Handoff::Defer(garbage)) => Err(garbage),
+            Err(_)                       => unreachable!(),
+        }
+    }
+
+    /// Like `defer`, for a Vec. The Vec is not boxed, so this doesn't allocate either
+    pub fn defer_vec<U: Send + 'static>(&self, garbage: Vec<U>) -> Result<(), Vec<U>> {
+        let len = garbage.len();
+        match self.intake.push(Handoff::DeferVec(ErasedVec::new(garbage))) {
+            Ok(())                         => Ok(()),
+            Err(Handoff::DeferVec(erased)) => {
+                // put the Vec back together for the caller
+                let erased = ManuallyDrop::new(erased);
+                Err(unsafe { Vec::from_raw_parts(erased.ptr as *mut U, len, erased.cap) })
+            },
+            Err(_)                         => unreachable!(),
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> Clone for GcHandle<T> {
+    fn clone(&self) -> Self {
+        self.intake.handles.fetch_add(1, Ordering::Relaxed);
+        GcHandle { intake: self.intake.clone() }
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for GcHandle<T> {
+    fn drop(&mut self) {
+        self.intake.handles.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// intrusive link shared by every RtArc allocation, along with a way to free it without knowing T
+struct FreeNode {
+    next: AtomicPtr<FreeNode>,
+    free: unsafe fn(*mut FreeNode),
+}
+
+// Vyukov's intrusive multi-producer single-consumer queue of allocations waiting to be freed.
+// Pushing is a single atomic swap (wait-free) and needs no memory beyond the node itself, which
+// lives inside the allocation being released. The collector thread is the only consumer
+struct Freelist {
+    head:    AtomicPtr<FreeNode>,
+    tail:    UnsafeCell<*mut FreeNode>,
+    stub:    Box<FreeNode>,
+    live:    AtomicUsize, // RtArc allocations which have not been freed yet
+    closed:  AtomicBool,  // set once the collector is gone
+    pushing: AtomicUsize, // releases which are part way through handing an allocation over
+}
+
+unsafe impl Send for Freelist {}
+unsafe impl Sync for Freelist {}
+
+unsafe fn free_nothing(_: *mut FreeNode) {}
+
+impl Freelist {
+    fn new() -> Self {
+        let mut stub = Box::new(FreeNode { next: AtomicPtr::new(ptr::null_mut()), free: free_nothing });
+        let stub_ptr: *mut FreeNode = &mut *stub;
+
+        Freelist {
+            head:    AtomicPtr::new(stub_ptr),
+            tail:    UnsafeCell::new(stub_ptr),
+            stub,
+            live:    AtomicUsize::new(0),
+            closed:  AtomicBool::new(false),
+            pushing: AtomicUsize::new(0),
+        }
+    }
+
+    fn stub_ptr(&self) -> *mut FreeNode {
+        &*self.stub as *const FreeNode as *mut FreeNode
+    }
+
+    // any thread. wait-free
+    unsafe fn push(&self, node: *mut FreeNode) {
+        (*node).next.store(ptr::null_mut(), Ordering::Relaxed);
+        let prev = self.head.swap(node, Ordering::AcqRel);
+        (*prev).next.store(node, Ordering::Release);
+    }
+
+    // collector thread only. may spuriously return None while a push is half finished
+    unsafe fn pop(&self) -> Option<*mut FreeNode> {
+        let tail = &mut *self.tail.get();
+        let mut next = (**tail).next.load(Ordering::Acquire);
+
+        if *tail == self.stub_ptr() {
+            if next.is_null() {
+                return None;
+            }
+            *tail = next;
+            next = (*next).next.load(Ordering::Acquire);
+        }
+
+        if !next.is_null() {
+            let node = *tail;
+            *tail = next;
+            return Some(node);
+        }
+
+        // a producer is between the swap and the store, try again next time
+        if *tail != self.head.load(Ordering::Acquire) {
+            return None;
+        }
+
+        // tail is the last real node, put the stub behind it so it can be handed out
+        self.push(self.stub_ptr());
+        next = (**tail).next.load(Ordering::Acquire);
+        if !next.is_null() {
+            let node = *tail;
+            *tail = next;
+            return Some(node);
+        }
+
+        None
+    }
+
+    // any thread. hands an allocation to the collector, or frees it here if the collector is gone
+    unsafe fn release(&self, node: *mut FreeNode) {
+        // announce ourselves before looking at `closed`, so a `close` we miss waits for us
+        self.pushing.fetch_add(1, Ordering::SeqCst);
+        if self.closed.load(Ordering::SeqCst) {
+            self.pushing.fetch_sub(1, Ordering::Release);
+            self.live.fetch_sub(1, Ordering::Relaxed);
+            ((*node).free)(node);
+        } else {
+            self.push(node);
+            self.pushing.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    // collector thread only. from now on whoever releases an allocation frees it. Waits for any
+    // release already under way, then frees everything handed over, returning how many were freed
+    fn close(&self) -> usize {
+        self.closed.store(true, Ordering::SeqCst);
+        while self.pushing.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+
+        self.drain()
+    }
+
+    // collector thread only. frees everything released so far, returning how many were freed
+    fn drain(&self) -> usize {
+        let mut freed = 0;
+        while let Some(node) = unsafe { self.pop() } {
+            unsafe { ((*node).free)(node) };
+            self.live.fetch_sub(1, Ordering::Relaxed);
+            freed += 1;
+        }
+        freed
+    }
+}
+
+#[repr(C)]
+struct RtArcInner<T> {
+    node:     FreeNode, // must be first, so a *mut FreeNode is also a *mut RtArcInner<T>
+    count:    AtomicUsize,
+    freelist: Arc<Freelist>,
+    value:    T,
+}
+
+unsafe fn free_rt_arc<T>(node: *mut FreeNode) {
+    drop(Box::from_raw(node as *mut RtArcInner<T>));
+}
+
+/// An atomically reference counted pointer which never frees memory on the thread that drops it.
+/// When the last RtArc is dropped, the allocation is pushed onto its collector's freelist (a single
+/// atomic swap, without any allocation) and the collector thread frees it.
+/// Create these with `GC::rt_arc`. Unlike tracked Arcs, the collector doesn't need to poll them
+struct RtArc<T: Send + 'static> {
+    ptr: NonNull<RtArcInner<T>>,
+}
+
+unsafe impl<T: Send + Sync + 'static> Send for RtArc<T> {}
+unsafe impl<T: Send + Sync + 'static> Sync for RtArc<T> {}
+
+impl<T: Send + 'static> RtArc<T> {
+    fn new(t: T, freelist: Arc<Freelist>) -> Self {
+        freelist.live.fetch_add(1, Ordering::Relaxed);
+
+        let inner = Box::new(RtArcInner {
+            node:     FreeNode { next: AtomicPtr::new(ptr::null_mut()), free: free_rt_arc::<T> },
+            count:    AtomicUsize::new(1),
+            freelist,
+            value:    t,
+        });
+
+        RtArc { ptr: unsafe { NonNull::new_unchecked(Box::into_raw(inner)) } }
+    }
+
+    fn inner(&self) -> &RtArcInner<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Number of RtArcs pointing at this value
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().count.load(Ordering::Acquire)
+    }
+}
+
+impl<T: Send + 'static> Clone for RtArc<T> {
+    fn clone(&self) -> Self {
+        self.inner().count.fetch_add(1, Ordering::Relaxed);
+        RtArc { ptr: self.ptr }
+    }
+}
+
+impl<T: Send + 'static> Deref for RtArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().value
+    }
+}
+
+impl<T: Send + 'static> Drop for RtArc<T> {
+    fn drop(&mut self) {
+        if self.inner().count.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        // synchronize with every other release before anyone touches the value
+        atomic::fence(Ordering::Acquire);
+
+        // if the collector is gone, there's nobody else to do it and this frees it here. The
+        // allocation holds a reference to the freelist, so keep one of our own until we're done
+        let freelist = self.inner().freelist.clone();
+        let node = self.ptr.as_ptr() as *mut FreeNode;
+        unsafe { freelist.release(node) };
+    }
+}
+
+/// Reasons `GC::try_track` can refuse to track an Arc
+enum TrackError<T> {
+    /// the pool is at capacity. The Arc is handed back to the caller
+    PoolFull(Arc<T>),
+}
+
+impl<T> fmt::Debug for TrackError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrackError::PoolFull(_) => write!(f, "PoolFull"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrackError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrackError::PoolFull(_) => write!(f, "garbage collector pool is full"),
+        }
+    }
+}
+
+/// A garbage collector for Arc<T> pointers
+struct GC<T: Send + Sync + 'static> {
+    pool:     Arc<Mutex<ArcPool>>,
+    counters: Arc<GcCounters>,
+    intake:   Arc<Intake<Handoff<T>>>,
+    freelist: Arc<Freelist>,
+    running:  Arc<AtomicBool>,
+    flush:    Arc<FlushSignal>,
+    paused:   Arc<AtomicBool>,
+    reports:  Option<mpsc::Receiver<GcReport>>,
+    thread:   Option<thread::JoinHandle<()>>,
+    capacity: Option<usize>,
+    _type:    PhantomData<T>,
+}
+
+// check the clock every this many items, so the budget check doesn't dominate the scan
+const BUDGET_CHECK_STRIDE: usize = 32;
+
+// items which survive this many scans of the hot generation are moved to the cold generation
+const PROMOTE_AFTER: u32 = 8;
+
+// the cold generation is only scanned once every this many passes
+const COLD_SCAN_EVERY: usize = 10;
+
+// private. moves any dead pointers out of a pool and into `garbage`, see `collect_garbage`.
+// Scanning starts at `cursor` and stops early if `budget` runs out. The cursor is left where the
+// scan stopped so the next pass can pick up from there
+fn cleanup(pool: &mut ArcPool, counters: &GcCounters, cursor: &mut usize, budget: Option<Duration>, garbage: &mut Vec<Box<dyn Collectable>>) {
+    let start = Instant::now();
+    let before = pool.len();
+
+    for scanned in 0..before {
+        if pool.is_empty() {
+            break
+        }
+
+        if *cursor >= pool.len() {
+            *cursor = 0;
+        }
+
+        if pool[*cursor].item.is_garbage() {
+            // the last element takes this one's place, so don't advance the cursor
+            garbage.push(pool.swap_remove(*cursor).item);
+        } else {
+            pool[*cursor].survived += 1;
+            *cursor += 1;
+        }
+
+        if let Some(budget) = budget {
+            if scanned % BUDGET_CHECK_STRIDE == 0 && start.elapsed() >= budget {
+                break
+            }
+        }
+    }
+
+    counters.collected.fetch_add(before - pool.len(), Ordering::Relaxed);
+}
+
+// private. frees (or runs the hooks of) everything `cleanup` found.
+// Never call this with the pool locked: a hook is free to call `GC::track`
+fn collect_garbage(garbage: &mut Vec<Box<dyn Collectable>>) {
+    for item in garbage.drain(..) {
+        item.collect();
+    }
+}
+
+// private. moves long lived items out of the hot generation and into the cold one
+fn promote(hot: &mut ArcPool, cold: &mut ArcPool) {
+    let mut i = 0;
+    while i < hot.len() {
+        if hot[i].survived >= PROMOTE_AFTER {
+            cold.push(hot.swap_remove(i));
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Settings for the collector thread, see `GC::with_config`
+#[derive(Clone, Debug)]
+struct GcConfig {
+    /// name given to the collector thread, so it is easy to spot in profilers and debuggers
+    pub name:     String,
+    /// how often to scan for garbage
+    pub interval: Duration,
+    /// roughly the longest a single scan may take, see `GC::with_budget`
+    pub budget:   Option<Duration>,
+    /// niceness to run the collector thread at (Linux only). Positive values keep the collector
+    /// out of the way of everything else, including the realtime thread
+    pub nice:     Option<i32>,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            name:     "gc-collector".to_string(),
+            interval: Duration::from_millis(100),
+            budget:   None,
+            nice:     None,
+        }
+    }
+}
+
+// private. sets the niceness of the calling thread
+#[cfg(target_os = "linux")]
+fn set_thread_nice(nice: i32) -> Result<(), String> {
+    extern "C" {
+        fn setpriority(which: i32, who: u32, prio: i32) -> i32;
+    }
+
+    // on Linux, PRIO_PROCESS with `who == 0` only applies to the calling thread
+    const PRIO_PROCESS: i32 = 0;
+    if unsafe { setpriority(PRIO_PROCESS, 0, nice) } == 0 {
+        Ok(())
+    } else {
+        Err(format!("{}", std::io::Error::last_os_error()))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_thread_nice(_nice: i32) -> Result<(), String> {
+    Err("thread niceness is not supported on this platform".to_string())
+}
+
+impl<T: Send + Sync + 'static> GC<T> {
+    /// Construct a new garbage collector which scans for garbage every 100 milliseconds
+    pub fn new() -> Self {
+        Self::with_config(GcConfig::default())
+    }
+
+    /// Construct a new garbage collector which scans for garbage every `interval`
+    pub fn with_interval(interval: Duration) -> Self {
+        Self::with_config(GcConfig { interval, ..GcConfig::default() })
+    }
+
+    /// Construct a new garbage collector which scans for garbage every `interval`, but never
+    /// spends more than (roughly) `budget` on a single scan.
+    /// Large pools are collected incrementally, with each scan resuming where the last one stopped
+    pub fn with_budget(interval: Duration, budget: Duration) -> Self {
+        Self::with_config(GcConfig { interval, budget: Some(budget), ..GcConfig::default() })
+    }
+
+    /// Construct a new garbage collector, configuring the collector thread with `config`
+    pub fn with_config(config: GcConfig) -> Self {
+        let interval = config.interval;
+        let budget = config.budget;
+        let nice = config.nice;
+
+        let pool: Arc<Mutex<ArcPool>> = Arc::new(Mutex::new(Vec::new()));
+        let counters = Arc::new(GcCounters::new());
+        let intake = Arc::new(Intake::with_capacity(INTAKE_CAPACITY));
+        let freelist = Arc::new(Freelist::new());
+
+        // cleared by Drop when the GC goes out of scope
+        let running = Arc::new(AtomicBool::new(true));
+        let flush = Arc::new(FlushSignal::new());
+        let paused = Arc::new(AtomicBool::new(false));
+
+        // the collector never blocks on these, if nobody is listening reports are dropped
+        let (report_tx, reports) = mpsc::sync_channel(REPORT_BACKLOG);
+
+        // create a copy of the pool. The GC thread will own this clone
+        let thread_pool = pool.clone();
+        let thread_counters = counters.clone();
+        let thread_intake: Arc<Intake<Handoff<T>>> = intake.clone();
+        let thread_freelist = freelist.clone();
+        let thread_running = running.clone();
+        let thread_flush = flush.clone();
+        let thread_paused = paused.clone();
+
+        let gc = move || {
+            // failing to lower our priority isn't fatal, we just compete a bit more
+            if let Some(nice) = nice {
+                if let Err(e) = set_thread_nice(nice) {
+                    println!("[gc] unable to set collector niceness: {}", e);
+                }
+            }
+
+            // the pool shared with the owner is the hot generation, which is scanned every pass.
+            // Long lived items are moved to the cold generation, which only this thread touches
+            let mut cold = Vec::new();
+
+            // where the last (possibly interrupted) scan of each generation left off
+            let mut cursor = 0;
+            let mut cold_cursor = 0;
+            let mut passes = 0;
+
+            // whatever each pass finds, collected once the lock is let go of
+            let mut garbage = Vec::new();
+
+            while thread_running.load(Ordering::Acquire) {
+                // a flush needs a complete pass, so ignore the budget this time around.
+                // Start from the top too: a scan which wraps around after removing items can
+                // skip over the ones swapped in behind it
+                let flushing = thread_flush.pending();
+                let budget = if flushing.is_some() { None } else { budget };
+                if flushing.is_some() {
+                    cursor = 0;
+                    cold_cursor = 0;
+                }
+
+                // while paused, leave everything alone until resume (or flush) wakes us up
+                if flushing.is_none() && thread_paused.load(Ordering::Acquire) {
+                    thread::park();
+                    continue;
+                }
+
+                let pass_start = Instant::now();
+                let collected_before = thread_counters.collected.load(Ordering::Relaxed);
+
+                // lock the mutex, then let go of it so that track doesn't block
+                let idle = {
+                    let mut pool = thread_pool.lock().unwrap();
+
+                    // move anything handed over through a GcHandle into the pool.
+                    // Deferred data is already garbage, so free it straight away
+                    while let Some(handoff) = thread_intake.pop() {
+                        thread_counters.tracked.fetch_add(1, Ordering::Relaxed);
+                        match handoff {
+                            Handoff::Track(t) => pool.push(Entry::new(Box::new(t))),
+                            Handoff::Boxed(t) => pool.push(Entry::new(t)),
+                            _                 => {
+                                drop(handoff);
+                                thread_counters.collected.fetch_add(1, Ordering::Relaxed);
+                            },
+                        }
+                    }
+                    thread_counters.set_pool_size(pool.len() + cold.len());
+
+                    // RtArcs tell us when they are garbage, no need to scan for them
+                    let freed = thread_freelist.drain();
+                    thread_counters.collected.fetch_add(freed, Ordering::Relaxed);
+
+                    cleanup(&mut pool, &thread_counters, &mut cursor, budget, &mut garbage);
+                    promote(&mut pool, &mut cold);
+
+                    pool.is_empty()
+                };
+
+                // the cold generation isn't shared, so scan it without holding the lock
+                if flushing.is_some() || passes % COLD_SCAN_EVERY == 0 {
+                    cleanup(&mut cold, &thread_counters, &mut cold_cursor, budget, &mut garbage);
+                }
+                collect_garbage(&mut garbage);
+                passes += 1;
+
+                let pool_size = thread_pool.lock().unwrap().len() + cold.len();
+                thread_counters.pool_size.store(pool_size, Ordering::Relaxed);
+
+                let _ = report_tx.try_send(GcReport {
+                    pool_size,
+                    freed:         thread_counters.collected.load(Ordering::Relaxed) - collected_before,
+                    pass_duration: pass_start.elapsed(),
+                });
+
+                // handles can't wake us up, so keep checking on the intake while any exist
+                let idle = idle && cold.is_empty()
+                    && thread_intake.handles.load(Ordering::Relaxed) == 0
+                    && thread_freelist.live.load(Ordering::Relaxed) == 0;
+
+                if let Some(ticket) = flushing {
+                    thread_flush.complete(ticket);
+                }
+
+                // track and Drop unpark us. If there's nothing in the pool, there's nothing to
+                // do until one of those happens. Otherwise, something we are holding could become
+                // garbage at any moment (nobody tells us when), so check again after a bit
+                if idle {
+                    thread::park();
+                } else {
+                    thread::park_timeout(interval);
+                }
+            }
+
+            // free what we can from the cold generation on the way out.
+            // Whatever is left just loses our reference
+            cleanup(&mut cold, &thread_counters, &mut 0, None, &mut garbage);
+            collect_garbage(&mut garbage);
+
+            // anything released from now on is freed by whoever releases it
+            let freed = thread_freelist.close();
+            thread_counters.collected.fetch_add(freed, Ordering::Relaxed);
+        };
+
+        let gc_thread = thread::Builder::new()
+            .name(config.name)
+            .spawn(gc)
+            .unwrap();
+
+        GC {
+            pool,
+            counters,
+            intake,
+            freelist,
+            running,
+            flush,
+            paused,
+            reports:  Some(reports),
+            thread:   Some(gc_thread),
+            capacity: None,
+            _type:    PhantomData,
+        }
+    }
+
+    /// Limit the number of Arcs the pool may hold before `try_track` starts refusing new ones.
+    /// `track` ignores the capacity
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = Some(capacity);
+    }
+
+    pub fn track(&mut self, t: Arc<T>) {
+        self.track_erased(Box::new(t));
+    }
+
+    /// Allocate `t` behind an `RtArc` whose memory will be freed by this collector, no matter
+    /// which thread drops the last reference
+    pub fn rt_arc(&self, t: T) -> RtArc<T> {
+        self.counters.tracked.fetch_add(1, Ordering::Relaxed);
+        RtArc::new(t, self.freelist.clone())
+    }
+
+    /// Like `track`, but once the Arc is garbage it is reset and handed back to `recycler` for
+    /// reuse instead of being freed
+    pub fn track_recycled(&mut self, t: Arc<T>, recycler: &Recycler<T>) {
+        self.track_erased(recycler.wrap(t));
+    }
+
+    /// Watch an Arc without holding a strong reference to it, so tracking never extends the value's
+    /// lifetime. When the last owner lets go, the value is dropped on that owner's thread, but the
+    /// memory itself is released later by the collector.
+    /// That's fine for plain data such as `Samples`. If dropping `T` does real work, the realtime
+    /// thread should hand its reference to the graveyard (a `GcHandle`) instead of dropping it
+    pub fn track_weak(&mut self, t: &Arc<T>) {
+        self.track_erased(Box::new(Arc::downgrade(t)));
+    }
+
+    /// Create a handle which can hand Arcs to this collector from other threads (such as the
+    /// realtime thread) without locking or allocating.
+    /// Whoever uses the handle should give up their reference, not a clone of one which is also
+    /// being tracked, otherwise the two references in the pool keep each other alive forever
+    pub fn handle(&self) -> GcHandle<T> {
+        self.intake.handles.fetch_add(1, Ordering::Relaxed);
+        GcHandle { intake: self.intake.clone() }
+    }
+
+    /// Like `track`, but `on_drop` is handed the value when the collector frees it.
+    /// The closure runs on the collector thread (or on whichever thread drops the GC, if the value
+    /// becomes garbage during shutdown), so it is free to lock, allocate, log, track more values,
+    /// etc. A value with a `Weak` still pointing at it isn't garbage yet.
+    /// If the value is still shared when the GC is dropped, the closure never runs
+    pub fn track_with<F>(&mut self, t: Arc<T>, on_drop: F)
+        where F: FnOnce(T) + Send + 'static
+    {
+        self.track_erased(Box::new(Hooked { arc: t, on_drop }));
+    }
+
+    /// Like `track`, but hands the Arc back if the pool is already at capacity, so the caller can
+    /// throttle allocation until the collector catches up
+    pub fn try_track(&mut self, t: Arc<T>) -> Result<(), TrackError<T>> {
+        if !self.has_room() {
+            return Err(TrackError::PoolFull(t));
+        }
+
+        self.track(t);
+        Ok(())
+    }
+
+    // private. true if the pool (both generations) is under capacity.
+    // The collector only publishes the size once per pass, so this is approximate
+    fn has_room(&self) -> bool {
+        match self.capacity {
+            Some(capacity) => self.counters.pool_size.load(Ordering::Relaxed) < capacity,
+            None           => true,
+        }
+    }
+
+    // private. hands anything collectable to the collector thread
+    fn track_erased(&mut self, t: Box<dyn Collectable>) {
+        let mut pool = self.pool.lock().unwrap();
+        pool.push(Entry::new(t));
+
+        self.counters.tracked.fetch_add(1, Ordering::Relaxed);
+        self.counters.pool_size.fetch_add(1, Ordering::Relaxed);
+        self.counters.high_water.fetch_max(self.counters.pool_size.load(Ordering::Relaxed),
+                                           Ordering::Relaxed);
+
+        // let go of the lock before waking the collector, it's going to want it
+        drop(pool);
+        self.wake();
+    }
+
+    /// Take the stream of per-pass reports the collector sends. The collector sends one report
+    /// after every pass, but drops reports if the receiver falls too far behind.
+    /// Only the first call returns anything
+    pub fn reports(&mut self) -> Option<mpsc::Receiver<GcReport>> {
+        self.reports.take()
+    }
+
+    /// Stop collecting until `resume` is called, so that nothing is freed during a latency
+    /// sensitive section of the application. `track` keeps working while paused
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Start collecting again, freeing everything that piled up while paused
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+        self.wake();
+    }
+
+    /// Block until the collector has made a complete pass over the pool, dropping everything which
+    /// had already become garbage when `flush` was called.
+    /// An explicit flush is honored even while the collector is paused
+    pub fn flush(&self) {
+        let ticket = self.flush.request();
+        self.wake();
+        self.flush.wait_for(ticket);
+    }
+
+    // private. wakes up the collector thread if it is parked
+    fn wake(&self) {
+        if let Some(ref t) = self.thread {
+            t.thread().unpark();
+        }
+    }
+
+    /// Read the collector's counters.
+    /// Each counter is read individually, so they may be very slightly out of sync with each other
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            tracked:    self.counters.tracked.load(Ordering::Relaxed),
+            collected:  self.counters.collected.load(Ordering::Relaxed),
+            pool_size:  self.counters.pool_size.load(Ordering::Relaxed),
+            high_water: self.counters.high_water.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl GC<Erased> {
+    /// Instruct the collector to monitor this Arc<U>, whatever `U` happens to be
+    pub fn track_any<U: Send + Sync + 'static>(&mut self, t: Arc<U>) {
+        self.track_erased(Box::new(t));
+    }
+
+    /// Like `rt_arc`, for a value of any type
+    pub fn rt_arc_any<U: Send + Sync + 'static>(&self, t: U) -> RtArc<U> {
+        self.counters.tracked.fetch_add(1, Ordering::Relaxed);
+        RtArc::new(t, self.freelist.clone())
+    }
+
+    /// Like `track_any`, but respects the capacity set with `set_capacity`
+    pub fn try_track_any<U: Send + Sync + 'static>(&mut self, t: Arc<U>) -> Result<(), TrackError<U>> {
+        if !self.has_room() {
+            return Err(TrackError::PoolFull(t));
+        }
+
+        self.track_any(t);
+        Ok(())
+    }
+}
+
+/// Several independent collectors behind one facade.
+/// Tracked Arcs are spread across the shards by address, so each collector thread only has to scan
+/// a fraction of the garbage. Useful when thousands of short lived buffers are created per second
+struct ShardedGC<T: Send + Sync + 'static> {
+    shards: Vec<GC<T>>,
+}
+
+// private. picks a shard for an Arc, based on where it lives
+fn shard_for<T>(t: &Arc<T>, shards: usize) -> usize {
+    // allocations are aligned, so the low bits don't tell us much
+    let addr = &**t as *const T as usize;
+    (addr >> 4) % shards
+}
+
+impl<T: Send + Sync + 'static> ShardedGC<T> {
+    /// Start `shards` collectors with the default configuration
+    pub fn new(shards: usize) -> Self {
+        Self::with_config(GcConfig::default(), shards)
+    }
+
+    /// Start `shards` collectors, each configured with `config`.
+    /// Collector threads are named after `config.name`, with the shard number appended
+    pub fn with_config(config: GcConfig, shards: usize) -> Self {
+        assert!(shards > 0);
+
+        let shards = (0..shards).map(|i| {
+            GC::with_config(GcConfig { name: format!("{}-{}", config.name, i), ..config.clone() })
+        }).collect();
+
+        ShardedGC { shards }
+    }
+
+    pub fn track(&mut self, t: Arc<T>) {
+        let shard = shard_for(&t, self.shards.len());
+        self.shards[shard].track(t);
+    }
+
+    /// See `GC::try_track`. Capacity is per shard
+    pub fn try_track(&mut self, t: Arc<T>) -> Result<(), TrackError<T>> {
+        let shard = shard_for(&t, self.shards.len());
+        self.shards[shard].try_track(t)
+    }
+
+    /// See `GC::set_capacity`. Each shard gets an equal share of `capacity`
+    pub fn set_capacity(&mut self, capacity: usize) {
+        let per_shard = capacity.div_ceil(self.shards.len());
+        for shard in self.shards.iter_mut() {
+            shard.set_capacity(per_shard);
+        }
+    }
+
+    /// A handle which spreads Arcs across the shards' intakes, see `GC::handle`
+    pub fn handle(&self) -> ShardedGcHandle<T> {
+        ShardedGcHandle { handles: self.shards.iter().map(|s| s.handle()).collect() }
+    }
+
+    /// Counters summed over every shard. `high_water` is the sum of each shard's high-water mark,
+    /// so it may be higher than the combined pool ever actually was
+    pub fn stats(&self) -> GcStats {
+        self.shards.iter().map(|s| s.stats()).fold(
+            GcStats { tracked: 0, collected: 0, pool_size: 0, high_water: 0 },
+            |acc, s| GcStats {
+                tracked:    acc.tracked + s.tracked,
+                collected:  acc.collected + s.collected,
+                pool_size:  acc.pool_size + s.pool_size,
+                high_water: acc.high_water + s.high_water,
+            })
+    }
+
+    pub fn pause(&self) {
+        for shard in self.shards.iter() {
+            shard.pause();
+        }
+    }
+
+    pub fn resume(&self) {
+        for shard in self.shards.iter() {
+            shard.resume();
+        }
+    }
+
+    /// Block until every shard has finished a complete pass, see `GC::flush`
+    pub fn flush(&self) {
+        for shard in self.shards.iter() {
+            shard.flush();
+        }
+    }
+}
+
+/// A `GcHandle` for a `ShardedGC`
+struct ShardedGcHandle<T: Send + Sync + 'static> {
+    handles: Vec<GcHandle<T>>,
+}
+
+impl<T: Send + Sync + 'static> ShardedGcHandle<T> {
+    /// See `GcHandle::try_track`. Only the chosen shard's intake is touched
+    pub fn try_track(&self, t: Arc<T>) -> Result<(), Arc<T>> {
+        let shard = shard_for(&t, self.handles.len());
+        self.handles[shard].try_track(t)
+    }
+}
+
+impl<T: Send + Sync + 'static> Clone for ShardedGcHandle<T> {
+    fn clone(&self) -> Self {
+        ShardedGcHandle { handles: self.handles.clone() }
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for GC<T> {
+    fn drop(&mut self) {
+        println!("[gc] collector going down!");
+
+        self.running.store(false, Ordering::Release);
+        self.wake();
+
+        if let Some(t) = self.thread.take() {
+            t.join().unwrap();
+        }
+
+        // anything which still can't be freed just loses our reference here
+        let mut garbage = Vec::new();
+        cleanup(&mut self.pool.lock().unwrap(), &self.counters, &mut 0, None, &mut garbage);
+        collect_garbage(&mut garbage);
+    }
+}
+// end of GC implementation
+
+// beginning of ring buffer implementation
+// A preallocated single-producer single-consumer queue.
+// Each slot carries a sequence number (like the GC's intake) saying whether it is ready to be
+// written or read. Pushing is wait-free. Popping is wait-free too, unless the producer is evicting
+// old items with `force_push`, in which case the consumer may have to retry once or twice.
+// Nothing here ever locks or allocates
+struct RingBuffer<T> {
+    slots:      Box<[IntakeSlot<T>]>,
+    mask:       usize,
+    head:       AtomicUsize, // next slot the producer writes
+    tail:       AtomicUsize, // next slot the consumer reads
+    high_water: AtomicUsize, // deepest the queue has been, only the producer writes this
+}
+
+unsafe impl<T: Send> Send for RingBuffer<T> {}
+unsafe impl<T: Send> Sync for RingBuffer<T> {}
+
+/// The queue underneath both ends of `ring_buffer`. `RingBuffer` is the one to use, the others
+/// are there to compare it against without touching anything built on top of the queue
+trait Queue<T>: Send + Sync {
+    /// Allocate everything needed to hold at least `capacity` items
+    fn with_capacity(capacity: usize) -> Self where Self: Sized;
+
+    /// Push without waiting, handing the item back if there is no room
+    fn push(&self, t: T) -> Result<(), T>;
+
+    /// Push as many items from the front of `items` as there is room for, returning how many
+    fn push_batch(&self, items: &mut Vec<T>) -> usize;
+
+    /// Pop without waiting. The producer may call this too, to evict the oldest item
+    fn pop(&self) -> Option<T>;
+
+    /// Number of items waiting, only a snapshot
+    fn len(&self) -> usize;
+
+    /// Number of items the queue can hold
+    fn capacity(&self) -> usize;
+
+    /// The deepest the queue has ever been
+    fn high_water(&self) -> usize;
+}
+
+// the queue `ring_buffer` hands out, picked by cargo feature
+#[cfg(not(any(feature = "crossbeam", feature = "mpsc")))]
+type Backend<T> = RingBuffer<T>;
+#[cfg(all(feature = "mpsc", not(feature = "crossbeam")))]
+type Backend<T> = MpscQueue<T>;
+#[cfg(feature = "crossbeam")]
+type Backend<T> = CrossbeamQueue<T>;
+
+/// The sending half of a ring buffer, see `ring_buffer`
+struct RingProducer<T, Q = Backend<T>> {
+    ring:  Arc<Q>,
+    _item: PhantomData<T>,
+}
+
+/// The receiving half of a ring buffer, see `ring_buffer`
+struct RingConsumer<T, Q = Backend<T>> {
+    ring:  Arc<Q>,
+    _item: PhantomData<T>,
+}
+
+/// Create a single-producer single-consumer queue which can hold at least `capacity` items.
+/// All of the memory the queue will ever need is allocated here. By default this is the wait-free
+/// `RingBuffer`, the `mpsc` and `crossbeam` features swap in a std or crossbeam channel instead
+fn ring_buffer<T: Send>(capacity: usize) -> (RingProducer<T>, RingConsumer<T>) {
+    queue_with(capacity)
+}
+
+/// Like `ring_buffer`, but with the queue underneath picked by the caller rather than by feature
+fn queue_with<T: Send, Q: Queue<T>>(capacity: usize) -> (RingProducer<T, Q>, RingConsumer<T, Q>) {
+    let ring = Arc::new(Q::with_capacity(capacity));
+    (RingProducer { ring: ring.clone(), _item: PhantomData }, RingConsumer { ring, _item: PhantomData })
+}
+
+impl<T: Send> Queue<T> for RingBuffer<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        // with a single slot, "full" and "empty" would have the same sequence number
+        let capacity = capacity.max(2).next_power_of_two();
+        let slots: Vec<IntakeSlot<T>> = (0..capacity).map(|i| {
+            IntakeSlot { seq: AtomicUsize::new(i), value: UnsafeCell::new(None) }
+        }).collect();
+
+        RingBuffer {
+            slots:      slots.into_boxed_slice(),
+            mask:       capacity - 1,
+            head:       AtomicUsize::new(0),
+            tail:       AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    // any thread, but only a snapshot: the other side may be pushing or popping right now
+    fn len(&self) -> usize {
+        // the tail never passes the head, so load it first
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Relaxed);
+        head.wrapping_sub(tail)
+    }
+
+    fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    // producer only
+    fn push(&self, t: T) -> Result<(), T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[head & self.mask];
+
+        // the consumer hasn't finished with this slot since we last went around the ring
+        if slot.seq.load(Ordering::Acquire) != head {
+            return Err(t);
+        }
+
+        unsafe { *slot.value.get() = Some(t) };
+        slot.seq.store(head + 1, Ordering::Release);
+        self.head.store(head + 1, Ordering::Relaxed);
+
+        let depth = self.len();
+        if depth > self.high_water.load(Ordering::Relaxed) {
+            self.high_water.store(depth, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    // producer only. pushes as many items from the front of `items` as there is room for, and
+    // returns how many. The consumer sees none of them until it can see all of them
+    fn push_batch(&self, items: &mut Vec<T>) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+
+        // a slot a whole lap ahead still has last lap's sequence number, so this stops at capacity
+        let mut count = 0;
+        while count < items.len() && self.slots[(head + count) & self.mask].seq.load(Ordering::Acquire) == head + count {
+            count += 1;
+        }
+
+        for (i, t) in items.drain(..count).enumerate() {
+            unsafe { *self.slots[(head + i) & self.mask].value.get() = Some(t) };
+        }
+
+        // the consumer stops at the first slot which isn't ready, so publish back to front
+        for i in (0..count).rev() {
+            self.slots[(head + i) & self.mask].seq.store(head + i + 1, Ordering::Release);
+        }
+        self.head.store(head + count, Ordering::Relaxed);
+
+        let depth = self.len();
+        if depth > self.high_water.load(Ordering::Relaxed) {
+            self.high_water.store(depth, Ordering::Relaxed);
+        }
+        count
+    }
+
+    // usually the consumer, but the producer uses this to evict the oldest item too
+    fn pop(&self) -> Option<T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[tail & self.mask];
+            if slot.seq.load(Ordering::Acquire) != tail + 1 {
+                return None;
+            }
+
+            match self.tail.compare_exchange_weak(tail, tail + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    let t = unsafe { (*slot.value.get()).take() };
+                    slot.seq.store(tail + self.mask + 1, Ordering::Release);
+                    return t;
+                },
+                Err(current) => tail = current,
+            }
+        }
+    }
+}
+
+// A bounded std channel standing in for `RingBuffer`. The receiver can't be shared, so it sits
+// behind a lock which the producer also takes when evicting. Build with `--features mpsc` to use it
+struct MpscQueue<T> {
+    tx:         mpsc::SyncSender<T>,
+    rx:         Mutex<mpsc::Receiver<T>>,
+    capacity:   usize,
+    depth:      AtomicUsize, // std channels can't say how full they are, so count
+    high_water: AtomicUsize,
+}
+
+impl<T: Send> Queue<T> for MpscQueue<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        // a zero capacity channel never holds anything, it only hands over
+        let capacity = capacity.max(1);
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        MpscQueue {
+            tx,
+            rx:         Mutex::new(rx),
+            capacity,
+            depth:      AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, t: T) -> Result<(), T> {
+        // count first, so a pop racing with us never takes the depth below zero
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Err(e) = self.tx.try_send(t) {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            return Err(match e {
+                mpsc::TrySendError::Full(t)         => t,
+                mpsc::TrySendError::Disconnected(t) => t,
+            });
+        }
+
+        if depth > self.high_water.load(Ordering::Relaxed) {
+            self.high_water.store(depth, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    // std has no way to publish several items at once, so they just go one at a time
+    fn push_batch(&self, items: &mut Vec<T>) -> usize {
+        let mut pushed = 0;
+        while !items.is_empty() {
+            match self.push(items.remove(0)) {
+                Ok(())   => pushed += 1,
+                Err(ret) => {
+                    items.insert(0, ret);
+                    break;
+                },
+            }
+        }
+
+        pushed
+    }
+
+    fn pop(&self) -> Option<T> {
+        let t = self.rx.lock().unwrap().try_recv().ok()?;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        Some(t)
+    }
+
+    fn len(&self) -> usize {
+        self.depth.load(Ordering::Relaxed).min(self.capacity)
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed).min(self.capacity)
+    }
+}
+
+// A bounded crossbeam channel standing in for `RingBuffer`. Build with `--features crossbeam` to
+// use it. Crossbeam channels are multi-consumer, so the producer can still pop to evict old items
+#[cfg(feature = "crossbeam")]
+struct CrossbeamQueue<T> {
+    tx:         crossbeam_channel::Sender<T>,
+    rx:         crossbeam_channel::Receiver<T>,
+    high_water: AtomicUsize,
+}
+
+#[cfg(feature = "crossbeam")]
+impl<T: Send> Queue<T> for CrossbeamQueue<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(capacity.max(1));
+        CrossbeamQueue { tx, rx, high_water: AtomicUsize::new(0) }
+    }
+
+    fn push(&self, t: T) -> Result<(), T> {
+        self.tx.try_send(t).map_err(|e| e.into_inner())?;
+        if self.len() > self.high_water.load(Ordering::Relaxed) {
+            self.high_water.store(self.len(), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    // crossbeam has no way to publish several items at once, so they just go one at a time
+    fn push_batch(&self, items: &mut Vec<T>) -> usize {
+        let mut pushed = 0;
+        while !items.is_empty() {
+            match self.push(items.remove(0)) {
+                Ok(())   => pushed += 1,
+                Err(ret) => {
+                    items.insert(0, ret);
+                    break;
+                },
+            }
+        }
+
+        pushed
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+
+    fn len(&self) -> usize {
+        self.rx.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.rx.capacity().unwrap_or(0)
+    }
+
+    fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+}
+
+/// How full a queue is. Only a snapshot, the queue may change while it is being looked at
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct QueueOccupancy {
+    /// number of items waiting to be popped
+    depth:      usize,
+    /// number of items the queue can hold
+    capacity:   usize,
+    /// the deepest the queue has ever been
+    high_water: usize,
+}
+
+// private. both ends of a queue share this
+fn occupancy<T, Q: Queue<T>>(ring: &Q) -> QueueOccupancy {
+    QueueOccupancy {
+        depth:      ring.len(),
+        capacity:   ring.capacity(),
+        high_water: ring.high_water(),
+    }
+}
+
+impl<T: Send, Q: Queue<T>> RingProducer<T, Q> {
+    /// How full the queue is, for keeping an eye on backpressure
+    pub fn occupancy(&self) -> QueueOccupancy {
+        occupancy(&*self.ring)
+    }
+
+    /// Push without waiting. If the queue is full, the item is handed back
+    pub fn try_push(&mut self, t: T) -> Result<(), T> {
+        self.ring.push(t)
+    }
+
+    /// Push without waiting. If the queue is full, the oldest item in the queue is thrown out to
+    /// make room, and handed back
+    pub fn force_push(&mut self, t: T) -> Option<T> {
+        let mut t = t;
+        let mut evicted = None;
+        loop {
+            match self.ring.push(t) {
+                Ok(())   => return evicted,
+                Err(ret) => t = ret,
+            }
+
+            // if the consumer beat us to it, there's room now anyway
+            if let Some(oldest) = self.ring.pop() {
+                evicted = Some(oldest);
+            }
+        }
+    }
+
+    /// Push as many items from the front of `items` as there is room for, without waiting.
+    /// Returns how many were pushed. The consumer sees the whole lot at once, rather than one at a
+    /// time
+    pub fn try_push_all(&mut self, items: &mut Vec<T>) -> usize {
+        self.ring.push_batch(items)
+    }
+
+    /// Push, yielding this thread until there is room.
+    /// If the consumer has gone away, the item is handed back. Never call this from the realtime
+    /// thread
+    pub fn push(&mut self, t: T) -> Result<(), T> {
+        self.push_unless(t, || false)
+    }
+
+    /// Like `push`, but also hands the item back once `stop` returns true. For when the consumer
+    /// may stop making room without going away
+    pub fn push_unless<F: Fn() -> bool>(&mut self, t: T, stop: F) -> Result<(), T> {
+        let mut t = t;
+        loop {
+            match self.try_push(t) {
+                Ok(())   => return Ok(()),
+                Err(ret) => t = ret,
+            }
+
+            if self.is_disconnected() || stop() {
+                return Err(t);
+            }
+
+            thread::yield_now();
+        }
+    }
+
+    /// true if the consumer has been dropped
+    pub fn is_disconnected(&self) -> bool {
+        Arc::strong_count(&self.ring) == 1
+    }
+}
+
+impl<T: Send, Q: Queue<T>> RingConsumer<T, Q> {
+    /// How full the queue is, for keeping an eye on backpressure
+    pub fn occupancy(&self) -> QueueOccupancy {
+        occupancy(&*self.ring)
+    }
+
+    /// Pop without waiting, returning None if the queue is empty
+    pub fn try_pop(&mut self) -> Option<T> {
+        self.ring.pop()
+    }
+
+    /// true if the producer has been dropped
+    pub fn is_disconnected(&self) -> bool {
+        Arc::strong_count(&self.ring) == 1
+    }
+}
+// end of ring buffer implementation
+
+// beginning of triple buffer implementation
+// the shared index carries this bit when it points at a buffer the reader hasn't seen yet
+const TRIPLE_DIRTY: usize = 0b100;
+
+// Three copies of a value. At any moment the writer owns one, the reader owns one, and the third
+// (the "back" buffer) is parked in `back`. Publishing and reading both swap their buffer with the
+// back buffer in a single atomic operation
+struct TripleBuffer<T> {
+    buffers: [UnsafeCell<T>; 3],
+    back:    AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for TripleBuffer<T> {}
+unsafe impl<T: Send> Sync for TripleBuffer<T> {}
+
+/// The publishing half of a triple buffer, see `triple_buffer`
+struct TripleWriter<T> {
+    shared: Arc<TripleBuffer<T>>,
+    write:  usize,
+}
+
+/// The reading half of a triple buffer, see `triple_buffer`
+struct TripleReader<T> {
+    shared: Arc<TripleBuffer<T>>,
+    read:   usize,
+}
+
+/// Create a triple buffer where every copy starts out as `initial`.
+/// The writer can publish as often as it likes, and the reader always sees the most recently
+/// published value; intermediate values are simply overwritten. Neither side ever waits
+fn triple_buffer<T: Clone + Send>(initial: T) -> (TripleWriter<T>, TripleReader<T>) {
+    let shared = Arc::new(TripleBuffer {
+        buffers: [UnsafeCell::new(initial.clone()), UnsafeCell::new(initial.clone()), UnsafeCell::new(initial)],
+        back:    AtomicUsize::new(1),
+    });
+
+    (TripleWriter { shared: shared.clone(), write: 0 }, TripleReader { shared, read: 2 })
+}
+
+impl<T: Send> TripleWriter<T> {
+    /// The buffer the writer is free to scribble on
+    pub fn buffer(&mut self) -> &mut T {
+        unsafe { &mut *self.shared.buffers[self.write].get() }
+    }
+
+    /// Make the contents of `buffer()` visible to the reader
+    pub fn publish(&mut self) {
+        let old = self.shared.back.swap(self.write | TRIPLE_DIRTY, Ordering::AcqRel);
+        self.write = old & !TRIPLE_DIRTY;
+    }
+
+    /// Copy `t` into the write buffer and publish it
+    pub fn write(&mut self, t: T) {
+        *self.buffer() = t;
+        self.publish();
+    }
+}
+
+impl<T: Send> TripleReader<T> {
+    /// Grab the most recently published value, if anything has been published since the last
+    /// call. Returns true if there was something new
+    pub fn update(&mut self) -> bool {
+        if self.shared.back.load(Ordering::Relaxed) & TRIPLE_DIRTY == 0 {
+            return false;
+        }
+
+        let old = self.shared.back.swap(self.read, Ordering::AcqRel);
+        self.read = old & !TRIPLE_DIRTY;
+        true
+    }
+
+    /// The value the reader currently holds
+    pub fn read(&self) -> &T {
+        unsafe { &*self.shared.buffers[self.read].get() }
+    }
+}
+// end of triple buffer implementation
+
+// beginning of broadcast implementation
+/// Fans every message out to any number of consumers, each with its own queue, so a single sender
+/// can drive several realtime threads. Consumers which have gone away are skipped
+struct Broadcast<T> {
+    outputs: Vec<RingProducer<T>>,
+}
+
+impl<T: Clone + Send> Broadcast<T> {
+    /// A broadcast with no consumers yet. Everything pushed is dropped until someone subscribes
+    fn new() -> Self {
+        Broadcast { outputs: Vec::new() }
+    }
+
+    /// Add a consumer, with a queue which can hold `capacity` items
+    fn subscribe(&mut self, capacity: usize) -> RingConsumer<T> {
+        let (tx, rx) = ring_buffer(capacity);
+        self.outputs.push(tx);
+        rx
+    }
+
+    /// Number of consumers which are still around
+    fn consumers(&self) -> usize {
+        self.outputs.iter().filter(|output| !output.is_disconnected()).count()
+    }
+
+    // private. hands each live output its own copy of `t`, the last one gets the original
+    fn each<F>(&mut self, t: T, mut f: F) where F: FnMut(&mut RingProducer<T>, T) {
+        let last = match self.outputs.iter().rposition(|output| !output.is_disconnected()) {
+            Some(last) => last,
+            None       => return,
+        };
+
+        for output in self.outputs[..last].iter_mut().filter(|output| !output.is_disconnected()) {
+            f(output, t.clone());
+        }
+        f(&mut self.outputs[last], t);
+    }
+
+    /// Push to every consumer without waiting. Consumers with room get the item either way, but if
+    /// any of the queues was full, a copy of the item is handed back
+    pub fn try_push(&mut self, t: T) -> Result<(), T> {
+        let mut full = None;
+        self.each(t, |output, t| {
+            if let Err(t) = output.try_push(t) {
+                full = Some(t);
+            }
+        });
+
+        match full {
+            Some(t) => Err(t),
+            None    => Ok(()),
+        }
+    }
+
+    /// Push to every consumer without waiting, throwing out the oldest item in any queue which is
+    /// full. If anything was thrown out, one of the evicted items is handed back
+    pub fn force_push(&mut self, t: T) -> Option<T> {
+        let mut evicted = None;
+        self.each(t, |output, t| {
+            if let Some(oldest) = output.force_push(t) {
+                evicted = Some(oldest);
+            }
+        });
+
+        evicted
+    }
+
+    /// Push to every consumer, yielding this thread until each one has room.
+    /// If every consumer has gone away, the item is handed back. Never call this from the realtime
+    /// thread
+    pub fn push(&mut self, t: T) -> Result<(), T> {
+        self.push_unless(t, || false)
+    }
+
+    /// Like `push`, but gives up waiting once `stop` returns true. Consumers with room still get
+    /// the item, but a copy is handed back
+    pub fn push_unless<F: Fn() -> bool>(&mut self, t: T, stop: F) -> Result<(), T> {
+        if self.is_disconnected() || stop() {
+            return Err(t);
+        }
+
+        // a consumer going away while we wait is fine, the others still get the item
+        let mut stopped = None;
+        self.each(t, |output, t| {
+            if let Err(t) = output.push_unless(t, &stop) {
+                if stop() {
+                    stopped = Some(t);
+                }
+            }
+        });
+
+        match stopped {
+            Some(t) => Err(t),
+            None    => Ok(()),
+        }
+    }
+
+    /// Push every item to every consumer, yielding this thread until each one has room. Each
+    /// consumer sees as much of the batch at once as fits in its queue.
+    /// If every consumer has gone away, the items are handed back. Never call this from the
+    /// realtime thread
+    pub fn push_all(&mut self, items: Vec<T>) -> Result<(), Vec<T>> {
+        self.push_all_unless(items, || false)
+    }
+
+    /// Like `push_all`, but gives up waiting once `stop` returns true. Consumers with room still
+    /// get what fits, and whatever the slowest consumer didn't get is handed back
+    pub fn push_all_unless<F: Fn() -> bool>(&mut self, items: Vec<T>, stop: F) -> Result<(), Vec<T>> {
+        // consumers can go away at any moment, so look for the last live one just once
+        let last = match self.outputs.iter().rposition(|output| !output.is_disconnected()) {
+            Some(last) => last,
+            None       => return Err(items),
+        };
+
+        let mut stopped: Option<Vec<T>> = None;
+        let mut keep_longest = |rest: Vec<T>| {
+            if stopped.as_ref().is_none_or(|longest| rest.len() > longest.len()) {
+                stopped = Some(rest);
+            }
+        };
+
+        for output in self.outputs[..last].iter_mut().filter(|output| !output.is_disconnected()) {
+            if let Err(rest) = Broadcast::push_all_to(output, items.clone(), &stop) {
+                keep_longest(rest);
+            }
+        }
+        if let Err(rest) = Broadcast::push_all_to(&mut self.outputs[last], items, &stop) {
+            keep_longest(rest);
+        }
+
+        match stopped {
+            Some(rest) => Err(rest),
+            None       => Ok(()),
+        }
+    }
+
+    // private. a consumer going away while we wait is fine, the others still get the items.
+    // Hands back whatever hadn't been pushed when `stop` fired
+    fn push_all_to<F: Fn() -> bool>(output: &mut RingProducer<T>, items: Vec<T>, stop: &F) -> Result<(), Vec<T>> {
+        let mut items = items;
+        loop {
+            output.try_push_all(&mut items);
+            if items.is_empty() || output.is_disconnected() {
+                return Ok(());
+            }
+
+            if stop() {
+                return Err(items);
+            }
+
+            thread::yield_now();
+        }
+    }
+
+    /// true if every consumer has been dropped
+    pub fn is_disconnected(&self) -> bool {
+        self.consumers() == 0
+    }
+
+    /// How full the consumers' queues are. Each number is the largest across every consumer which
+    /// is still around, so this describes the one falling furthest behind
+    pub fn occupancy(&self) -> QueueOccupancy {
+        let mut worst = QueueOccupancy { depth: 0, capacity: 0, high_water: 0 };
+        for output in self.outputs.iter().filter(|output| !output.is_disconnected()) {
+            let occupancy = output.occupancy();
+            worst.depth      = worst.depth.max(occupancy.depth);
+            worst.capacity   = worst.capacity.max(occupancy.capacity);
+            worst.high_water = worst.high_water.max(occupancy.high_water);
+        }
+
+        worst
+    }
+}
+
+impl<T> From<RingProducer<T>> for Broadcast<T> {
+    fn from(output: RingProducer<T>) -> Self {
+        Broadcast { outputs: vec![output] }
+    }
+}
+// end of broadcast implementation
+
+// beginning of rt-log implementation
+/// A log record small enough to push from the realtime thread without allocating.
+/// Formatting is left to the logging thread
+#[derive(Clone, Copy, Debug)]
+struct LogRecord {
+    text:  &'static str,
+    value: f32,
+}
+
+/// The realtime thread's end of the log, see `rt_log`. Never blocks or allocates
+struct RtLogger {
+    queue:   RingProducer<LogRecord>,
+    dropped: Arc<AtomicUsize>,
+}
+
+/// Prints whatever the realtime thread logs, see `rt_log`
+struct LogThread {
+    thread: thread::JoinHandle<()>,
+}
+
+// how long the logging thread sleeps when there is nothing to print
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Start a logging thread which prints records pushed through the returned `RtLogger`.
+/// Up to `capacity` records can be waiting to be printed, anything past that is dropped (and
+/// counted). The thread exits once the logger is dropped and every record has been printed
+fn rt_log(capacity: usize) -> (RtLogger, LogThread) {
+    let (tx, mut rx) = ring_buffer(capacity);
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let logger = RtLogger { queue: tx, dropped: dropped.clone() };
+
+    let thread = thread::Builder::new().name("rt-log".to_string()).spawn(move || {
+        loop {
+            // check before draining, so nothing pushed right before the logger went away is missed
+            let done = rx.is_disconnected();
+            while let Some(record) = rx.try_pop() {
+                println!("{} {}", record.text, record.value);
+            }
+
+            if done {
+                break;
+            }
+
+            thread::sleep(LOG_POLL_INTERVAL);
+        }
+
+        let dropped = dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            println!("[rt-log] {} records were dropped", dropped);
+        }
+    }).unwrap();
+
+    (logger, LogThread { thread })
+}
+
+impl RtLogger {
+    /// Log `text` followed by `value`. If the logging thread is behind, the record is dropped
+    pub fn log(&mut self, text: &'static str, value: f32) {
+        if self.queue.try_push(LogRecord { text, value }).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl LogThread {
+    /// Wait for everything logged so far to be printed. Drop the logger first, or this never
+    /// returns
+    pub fn join(self) {
+        self.thread.join().unwrap();
+    }
+}
+// end of rt-log implementation
+
+// beginning of envelope implementation
+
+/// The shape of an `Envelope`. Times are in frames, the sustain level is a gain from 0 to 1. The
+/// default does nothing: full volume from the start, cut off as soon as the note is released
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Adsr {
+    attack:  usize,
+    decay:   usize,
+    sustain: f32,
+    release: usize,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Adsr { attack: 0, decay: 0, sustain: 1.0, release: 0 }
+    }
+}
+
+impl Adsr {
+    /// An envelope with these times, for a realtime thread running at `sample_rate`
+    fn from_times(attack: Duration, decay: Duration, sustain: f32, release: Duration, sample_rate: u32) -> Self {
+        let frames = |time: Duration| (time.as_secs_f64() * sample_rate as f64).round() as usize;
+        Adsr {
+            attack:  frames(attack),
+            decay:   frames(decay),
+            sustain: sustain.clamp(0.0, 1.0),
+            release: frames(release),
+        }
+    }
+}
+
+// private. where an envelope is up to
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+/// A gain which rises when a note starts, falls to the sustain level while it is held, then
+/// falls to nothing once it is let go, all in straight lines. See `Adsr`. Evaluated once per
+/// frame in the realtime callback, so it never allocates
+#[derive(Clone, Copy, Debug)]
+struct Envelope {
+    adsr:         Adsr,
+    stage:        Stage,
+    level:        f32,
+    // how far the level falls each frame once released, so the release takes the same time
+    // wherever it starts from
+    release_step: f32,
+}
+
+impl Envelope {
+    /// An envelope at the start of its attack
+    fn new(adsr: Adsr) -> Self {
+        Envelope { adsr, stage: Stage::Attack, level: 0.0, release_step: 0.0 }
+    }
+
+    /// The gain for the next frame
+    fn next(&mut self) -> f32 {
+        match self.stage {
+            Stage::Attack => {
+                self.level += 1.0 / self.adsr.attack.max(1) as f32;
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            },
+
+            Stage::Decay => {
+                self.level -= (1.0 - self.adsr.sustain) / self.adsr.decay.max(1) as f32;
+                if self.level <= self.adsr.sustain {
+                    self.level = self.adsr.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            },
+
+            Stage::Sustain => self.level = self.adsr.sustain,
+
+            Stage::Release => {
+                self.level -= self.release_step;
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Done;
+                }
+            },
+
+            Stage::Done => self.level = 0.0,
+        }
+
+        self.level
+    }
+
+    /// Let the note go, from wherever the envelope is up to
+    fn release(&mut self) {
+        if self.adsr.release == 0 {
+            self.level = 0.0;
+            self.stage = Stage::Done;
+            return;
+        }
+
+        self.release_step = self.level / self.adsr.release as f32;
+        self.stage = Stage::Release;
+    }
+
+    /// Has the note been let go?
+    fn is_released(&self) -> bool {
+        self.stage == Stage::Release || self.stage == Stage::Done
+    }
+
+    /// Has the release finished, so there's nothing left to hear?
+    fn is_done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+}
+
+// end of envelope implementation
+
+// beginning of filter implementation
+
+// how long a biquad takes to move to new coefficients, in frames
+const BIQUAD_RAMP_FRAMES: usize = 256;
+
+/// The kinds of filter `BiquadCoefficients` can make. The shelves carry their gain, in dB
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FilterKind {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    LowShelf(f32),
+    HighShelf(f32),
+}
+
+/// The coefficients of a `Biquad`, normalized so a0 is 1. Working these out takes trig, so it's
+/// done on the UI thread and the results are sent over (see `Message::SetFilter`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BiquadCoefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl BiquadCoefficients {
+    /// A filter which leaves everything as it was
+    fn passthrough() -> Self {
+        BiquadCoefficients { b0: 1.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0 }
+    }
+
+    /// A `kind` filter at `frequency` Hz with resonance `q` (0.707 is flat), for a realtime
+    /// thread running at `sample_rate`. From the Audio EQ Cookbook
+    fn new(kind: FilterKind, frequency: f32, q: f32, sample_rate: u32) -> Self {
+        let nyquist = sample_rate as f32 / 2.0;
+        let w0 = 2.0 * f32::consts::PI * frequency.max(1.0).min(nyquist * 0.99) / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q.max(0.01));
+
+        let (b0, b1, b2, a0, a1, a2) = match kind {
+            FilterKind::Lowpass  => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterKind::Highpass => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterKind::Bandpass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+
+            FilterKind::LowShelf(gain) => {
+                let a = 10.0f32.powf(gain / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+                (a * ((a + 1.0) - (a - 1.0) * cos + k),
+                 2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                 a * ((a + 1.0) - (a - 1.0) * cos - k),
+                 (a + 1.0) + (a - 1.0) * cos + k,
+                 -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                 (a + 1.0) + (a - 1.0) * cos - k)
+            },
+
+            FilterKind::HighShelf(gain) => {
+                let a = 10.0f32.powf(gain / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+                (a * ((a + 1.0) + (a - 1.0) * cos + k),
+                 -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                 a * ((a + 1.0) + (a - 1.0) * cos - k),
+                 (a + 1.0) - (a - 1.0) * cos + k,
+                 2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                 (a + 1.0) - (a - 1.0) * cos - k)
+            },
+        };
+
+        BiquadCoefficients { b0: b0 / a0, b1: b1 / a0, b2: b2 / a0, a1: a1 / a0, a2: a2 / a0 }
+    }
+
+    // private. `t` of the way from `self` to `to`
+    fn lerp(&self, to: &BiquadCoefficients, t: f32) -> Self {
+        let mix = |from: f32, to: f32| from + (to - from) * t;
+        BiquadCoefficients {
+            b0: mix(self.b0, to.b0),
+            b1: mix(self.b1, to.b1),
+            b2: mix(self.b2, to.b2),
+            a1: mix(self.a1, to.a1),
+            a2: mix(self.a2, to.a2),
+        }
+    }
+}
+
+/// A second order filter, run over every channel in the realtime callback. New coefficients are
+/// faded in over `BIQUAD_RAMP_FRAMES` instead of jumping, so moving the filter doesn't click.
+/// Direct form I, which copes with coefficients changing under it better than the transposed
+/// forms
+#[derive(Clone, Copy)]
+struct Biquad {
+    from:      BiquadCoefficients,
+    to:        BiquadCoefficients,
+    // frames left until we reach `to`
+    remaining: usize,
+    // the last two inputs and outputs of each channel
+    inputs:    [[f32; 2]; CHANNELS],
+    outputs:   [[f32; 2]; CHANNELS],
+}
+
+impl Biquad {
+    /// A filter which passes everything through, until it gets coefficients
+    fn new() -> Self {
+        Biquad {
+            from:      BiquadCoefficients::passthrough(),
+            to:        BiquadCoefficients::passthrough(),
+            remaining: 0,
+            inputs:    [[0.0; 2]; CHANNELS],
+            outputs:   [[0.0; 2]; CHANNELS],
+        }
+    }
+
+    /// Fade over to `coefficients`, from wherever a fade already going has got to
+    fn set_coefficients(&mut self, coefficients: BiquadCoefficients) {
+        self.from = self.coefficients();
+        self.to = coefficients;
+        self.remaining = BIQUAD_RAMP_FRAMES;
+    }
+
+    /// The coefficients right now
+    fn coefficients(&self) -> BiquadCoefficients {
+        let t = 1.0 - self.remaining as f32 / BIQUAD_RAMP_FRAMES as f32;
+        self.from.lerp(&self.to, t)
+    }
+
+    /// Does the filter do anything? Once it has faded back to passing everything through, the
+    /// callback can skip it
+    fn is_active(&self) -> bool {
+        self.remaining > 0 || self.to != BiquadCoefficients::passthrough()
+    }
+
+    /// Filter interleaved frames in place
+    fn process<S: Sample>(&mut self, frames: &mut [S]) {
+        for frame in frames.chunks_mut(CHANNELS) {
+            let c = self.coefficients();
+            if self.remaining > 0 {
+                self.remaining -= 1;
+            }
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let x = sample.to_f32();
+                let [x1, x2] = self.inputs[channel];
+                let [y1, y2] = self.outputs[channel];
+                let y = c.b0 * x + c.b1 * x1 + c.b2 * x2 - c.a1 * y1 - c.a2 * y2;
+
+                self.inputs[channel] = [x, x1];
+                self.outputs[channel] = [y, y1];
+                *sample = S::from_f32(y);
+            }
+        }
+    }
+}
+
+/// Which output of an `Svf` to take
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SvfMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+}
+
+// private. how many times an `Svf` runs for every frame. The Chamberlin filter goes unstable as
+// the cutoff nears a sixth of the rate it runs at, running it twice keeps it well clear
+const SVF_OVERSAMPLING: usize = 2;
+
+/// A Chamberlin state-variable filter. Unlike a `Biquad`, the cutoff can change every frame
+/// without any coefficient math beyond one `sin`, so it can follow an LFO or envelope at audio
+/// rate. See `SvfNode`
+struct Svf {
+    mode:    SvfMode,
+    // 1/Q, how much of the band output is fed back. Lower rings more
+    damping: f32,
+    // the low and band outputs of each channel
+    low:     [f32; CHANNELS],
+    band:    [f32; CHANNELS],
+}
+
+impl Svf {
+    /// A filter with resonance `q` (0.707 is flat)
+    fn new(mode: SvfMode, q: f32) -> Self {
+        Svf {
+            mode,
+            damping: 1.0 / q.max(0.5),
+            low:     [0.0; CHANNELS],
+            band:    [0.0; CHANNELS],
+        }
+    }
+
+    /// Filter one interleaved frame in place, with the cutoff at `cutoff` Hz
+    #[inline]
+    fn process_frame<S: Sample>(&mut self, frame: &mut [S], cutoff: f32, sample_rate: u32) {
+        let rate = (sample_rate as usize * SVF_OVERSAMPLING) as f32;
+        let cutoff = cutoff.max(1.0).min(sample_rate as f32 / 4.0);
+        let f = 2.0 * (f32::consts::PI * cutoff / rate).sin();
+
+        for (channel, sample) in frame.iter_mut().enumerate() {
+            let x = sample.to_f32();
+            let mut high = 0.0;
+            for _ in 0..SVF_OVERSAMPLING {
+                self.low[channel] += f * self.band[channel];
+                high = x - self.low[channel] - self.damping * self.band[channel];
+                self.band[channel] += f * high;
+            }
+
+            let y = match self.mode {
+                SvfMode::Lowpass  => self.low[channel],
+                SvfMode::Highpass => high,
+                SvfMode::Bandpass => self.band[channel],
+                SvfMode::Notch    => high + self.low[channel],
+            };
+            *sample = S::from_f32(y);
+        }
+    }
+}
+
+/// A control signal which moves a parameter every frame, see `SvfNode`
+trait Modulator: Send {
+    /// The value for the next frame, between -1 and 1
+    fn next_value(&mut self, sample_rate: u32) -> f32;
+
+    /// Told whether there's any sound coming through at the start of each callback. Envelopes
+    /// start when the sound does and are released when it stops
+    fn gate(&mut self, _open: bool) {}
+}
+
+impl Modulator for Lfo {
+    fn next_value(&mut self, sample_rate: u32) -> f32 {
+        self.next(sample_rate)
+    }
+}
+
+impl Modulator for Envelope {
+    fn next_value(&mut self, _sample_rate: u32) -> f32 {
+        self.next()
+    }
+
+    fn gate(&mut self, open: bool) {
+        if open && self.is_released() {
+            *self = Envelope::new(self.adsr);
+        } else if !open && !self.is_released() {
+            self.release();
+        }
+    }
+}
+
+/// An `Svf` with its cutoff moved by a `Modulator`, as a processor for the realtime thread (see
+/// `RealtimeThread::set_processor`). The cutoff is `cutoff * 2^(depth * modulator)`, so `depth`
+/// is how many octaves the modulator sweeps it. Everything is set up front, so processing never
+/// allocates
+struct SvfNode<Mod: Modulator> {
+    svf:         Svf,
+    cutoff:      f32,
+    depth:       f32,
+    modulator:   Mod,
+    sample_rate: u32,
+}
+
+impl<Mod: Modulator> SvfNode<Mod> {
+    fn new(svf: Svf, cutoff: f32, depth: f32, modulator: Mod, sample_rate: u32) -> Self {
+        SvfNode { svf, cutoff, depth, modulator, sample_rate }
+    }
+}
+
+impl<Mod: Modulator, S: Sample> RealtimeCallback<S> for SvfNode<Mod> {
+    fn process(&mut self, _info: CallbackInfo, _input: &[S], out: &mut [S]) -> CallbackStatus {
+        self.modulator.gate(!is_silent(out));
+        for frame in out.chunks_mut(CHANNELS) {
+            let cutoff = self.cutoff * (self.depth * self.modulator.next_value(self.sample_rate)).exp2();
+            self.svf.process_frame(frame, cutoff, self.sample_rate);
+        }
+
+        CallbackStatus::Continue
+    }
+}
+
+// end of filter implementation
+
+// beginning of effects implementation
+
+// the longest delay `RealtimeThread::set_delay` can make, in seconds, unless picked otherwise
+// with `RealtimeThread::set_max_delay`. The buffer is allocated for the sample rate when it is
+// set, see `RealtimeThread::set_sample_rate`
+const MAX_DELAY_SECONDS: usize = 2;
+
+// how long a delay's time takes to glide to a new value, in frames. Changing it moves the read
+// position, which bends the pitch while it glides, like a tape delay
+const DELAY_TIME_RAMP_FRAMES: usize = 4800;
+
+// how long a delay's feedback and mix take to glide to new values, in frames
+const DELAY_MIX_RAMP_FRAMES: usize = 256;
+
+/// A circular buffer of the last few frames, allocated up front so pushing and reading never
+/// allocate. Reads can land between frames, see `read`
+struct DelayLine {
+    frames: Vec<[f32; CHANNELS]>,
+    // where the next frame goes
+    write:  usize,
+}
+
+impl DelayLine {
+    /// Room for `frames` frames, all silent
+    fn new(frames: usize) -> Self {
+        DelayLine { frames: vec![[0.0; CHANNELS]; frames.max(2)], write: 0 }
+    }
+
+    /// The longest delay `read` can make
+    fn max_delay(&self) -> usize {
+        self.frames.len() - 1
+    }
+
+    /// Add the newest frame, overwriting the oldest
+    #[inline]
+    fn push(&mut self, frame: [f32; CHANNELS]) {
+        self.frames[self.write] = frame;
+        self.write = (self.write + 1) % self.frames.len();
+    }
+
+    /// The frame pushed `delay` frames ago, where 1 is the newest. Fractional delays interpolate
+    /// between the frames on either side
+    #[inline]
+    fn read(&self, delay: f32) -> [f32; CHANNELS] {
+        let delay = delay.clamp(1.0, self.max_delay() as f32);
+        let whole = delay as usize;
+        let t = delay - whole as f32;
+
+        let len = self.frames.len();
+        let newer = &self.frames[(self.write + len - whole) % len];
+        let older = &self.frames[(self.write + len - whole - 1) % len];
+
+        let mut frame = [0.0; CHANNELS];
+        for (channel, out) in frame.iter_mut().enumerate() {
+            *out = newer[channel] + (older[channel] - newer[channel]) * t;
+        }
+        frame
+    }
+
+    /// Forget everything, and touch the whole buffer so the callback doesn't page fault on it
+    fn clear(&mut self) {
+        for frame in self.frames.iter_mut() {
+            *frame = [0.0; CHANNELS];
+        }
+    }
+}
+
+/// An echo: the output is the input mixed with what came in a while ago, which is fed back in
+/// so the echoes repeat. The delay time, feedback and mix all glide to new values
+struct Delay {
+    line:     DelayLine,
+    /// in frames
+    time:     SmoothedParam,
+    /// how much of the echo is fed back, from 0 to just under 1
+    feedback: SmoothedParam,
+    /// how much of the output is echo, from 0 (none) to 1 (only echo)
+    mix:      SmoothedParam,
+}
+
+impl Delay {
+    /// A delay which can reach back `max_frames` frames, with no echo to start with
+    fn new(max_frames: usize) -> Self {
+        Delay {
+            line:     DelayLine::new(max_frames + 1),
+            time:     SmoothedParam::new(1.0, Ramp::Linear(DELAY_TIME_RAMP_FRAMES)),
+            feedback: SmoothedParam::new(0.0, Ramp::Linear(DELAY_MIX_RAMP_FRAMES)),
+            mix:      SmoothedParam::new(0.0, Ramp::Linear(DELAY_MIX_RAMP_FRAMES)),
+        }
+    }
+
+    fn set(&mut self, frames: usize, feedback: f32, mix: f32) {
+        self.time.set_target(frames.max(1).min(self.line.max_delay()) as f32);
+        self.feedback.set_target(feedback.clamp(0.0, 0.99));
+        self.mix.set_target(mix.clamp(0.0, 1.0));
+    }
+
+    /// Run interleaved frames through the delay, in place. With no mix the output is the input,
+    /// but the line still fills, so there's nothing stale in it when the mix comes back up
+    fn process<S: Sample>(&mut self, frames: &mut [S]) {
+        for frame in frames.chunks_mut(CHANNELS) {
+            let delayed = self.line.read(self.time.next());
+            let feedback = self.feedback.next();
+            let mix = self.mix.next();
+
+            let mut input = [0.0; CHANNELS];
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                input[channel] = sample.to_f32();
+                *sample = S::from_f32(input[channel] * (1.0 - mix) + delayed[channel] * mix);
+                input[channel] += delayed[channel] * feedback;
+            }
+
+            self.line.push(input);
+        }
+    }
+}
+
+// the longest delay a chorus can sweep to, in milliseconds. 50ms is past the longest chorus
+const CHORUS_MAX_MILLIS: usize = 50;
+
+// how long a chorus' delay, feedback and mix take to glide to new values, in frames
+const CHORUS_RAMP_FRAMES: usize = 1024;
+
+/// How a `Chorus` sounds. Times are in frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ChorusSettings {
+    /// the delay the LFO sweeps around
+    delay:    f32,
+    /// how far the LFO sweeps the delay either way
+    depth:    f32,
+    /// how fast the LFO sweeps, in Hz
+    rate:     f32,
+    /// how much of the delayed signal is fed back, from -1 to 1 (exclusive). Flangers use a lot
+    feedback: f32,
+    /// how much of the output is delayed signal, from 0 (none) to 1 (only delayed)
+    mix:      f32,
+}
+
+impl ChorusSettings {
+    /// Settings with these times, for a realtime thread running at `sample_rate`
+    fn from_times(delay: Duration, depth: Duration, rate: f32, feedback: f32, mix: f32, sample_rate: u32) -> Self {
+        let frames = |time: Duration| (time.as_secs_f64() * sample_rate as f64) as f32;
+        ChorusSettings { delay: frames(delay), depth: frames(depth), rate, feedback, mix }
+    }
+
+    /// A gentle chorus: a long delay swept slowly, with no feedback
+    fn chorus(sample_rate: u32) -> Self {
+        ChorusSettings::from_times(Duration::from_millis(20), Duration::from_millis(5), 0.8, 0.0, 0.5, sample_rate)
+    }
+
+    /// A flanger: a short delay with lots of feedback, for the jet plane sweep
+    fn flanger(sample_rate: u32) -> Self {
+        ChorusSettings::from_times(Duration::from_micros(2500), Duration::from_micros(2000), 0.25, 0.7, 0.5, sample_rate)
+    }
+}
+
+/// A chorus or flanger: the input mixed with a copy of itself, delayed by an amount an LFO
+/// sweeps back and forth. The delay lands between frames most of the time, so reads
+/// interpolate (see `DelayLine::read`). Odd channels sweep the opposite way to even ones, which
+/// sounds wider. The buffer is allocated up front, so processing never allocates
+struct Chorus {
+    line:     DelayLine,
+    lfo:      Lfo,
+    delay:    SmoothedParam,
+    feedback: SmoothedParam,
+    mix:      SmoothedParam,
+}
+
+impl Chorus {
+    /// A chorus which can sweep out to `max_frames` frames, with no mix to start with
+    fn new(max_frames: usize) -> Self {
+        Chorus {
+            line:     DelayLine::new(max_frames + 1),
+            lfo:      Lfo::new(),
+            delay:    SmoothedParam::new(1.0, Ramp::Linear(CHORUS_RAMP_FRAMES)),
+            feedback: SmoothedParam::new(0.0, Ramp::Linear(CHORUS_RAMP_FRAMES)),
+            mix:      SmoothedParam::new(0.0, Ramp::Linear(CHORUS_RAMP_FRAMES)),
+        }
+    }
+
+    fn set(&mut self, settings: ChorusSettings) {
+        // the sweep has to stay inside the line, and never reach past the newest frame
+        let longest = self.line.max_delay() as f32;
+        let depth = settings.depth.max(0.0).min((longest - 1.0) / 2.0);
+        let delay = settings.delay.max(depth + 1.0).min(longest - depth);
+
+        self.lfo.set(settings.rate, depth);
+        self.delay.set_target(delay);
+        self.feedback.set_target(settings.feedback.clamp(-0.95, 0.95));
+        self.mix.set_target(settings.mix.clamp(0.0, 1.0));
+    }
+
+    /// Run interleaved frames through the chorus, in place. Like `Delay`, the line keeps
+    /// filling with no mix
+    fn process<S: Sample>(&mut self, frames: &mut [S], sample_rate: u32) {
+        for frame in frames.chunks_mut(CHANNELS) {
+            let sweep = self.lfo.next(sample_rate);
+            let delay = self.delay.next();
+            let feedback = self.feedback.next();
+            let mix = self.mix.next();
+
+            let mut input = [0.0; CHANNELS];
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let sweep = if channel % 2 == 0 { sweep } else { -sweep };
+                let delayed = self.line.read(delay + sweep)[channel];
+
+                input[channel] = sample.to_f32();
+                *sample = S::from_f32(input[channel] * (1.0 - mix) + delayed * mix);
+                input[channel] += delayed * feedback;
+            }
+
+            self.line.push(input);
+        }
+    }
+}
+
+// the lengths of a reverb's comb and allpass filters in frames, at REVERB_TUNING_RATE. These
+// are Freeverb's, tuned by ear, and never share a factor so their echoes don't line up
+const REVERB_COMBS: [usize; 8]     = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const REVERB_ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+
+// the sample rate the reverb lengths above were tuned at
+const REVERB_TUNING_RATE: f32 = 44_100.0;
+
+// how much longer each channel's filters are than the last one's, in frames at
+// REVERB_TUNING_RATE. Slightly different lengths make the channels sound wide
+const REVERB_SPREAD: usize = 23;
+
+// how much the input is turned down going in, so eight combs adding up don't clip
+const REVERB_INPUT_GAIN: f32 = 0.015;
+
+// how much the wet signal is turned up coming out, to make up for the input gain
+const REVERB_WET_GAIN: f32 = 3.0;
+
+// how long a reverb's room size, damping and mix take to glide to new values, in frames
+const REVERB_RAMP_FRAMES: usize = 256;
+
+// private. a feedback comb filter with a lowpass in the loop, one of a reverb's parallel echoes
+struct Comb {
+    buffer: Vec<f32>,
+    index:  usize,
+    // the lowpass' last output
+    store:  f32,
+}
+
+impl Comb {
+    fn new(frames: usize) -> Self {
+        Comb { buffer: vec![0.0; frames.max(1)], index: 0, store: 0.0 }
+    }
+
+    #[inline]
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.store = output * (1.0 - damping) + self.store * damping;
+        self.buffer[self.index] = input + self.store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+// private. a Schroeder allpass, which smears the combs' echoes out without colouring them
+struct Allpass {
+    buffer: Vec<f32>,
+    index:  usize,
+}
+
+impl Allpass {
+    fn new(frames: usize) -> Self {
+        Allpass { buffer: vec![0.0; frames.max(1)], index: 0 }
+    }
+
+    #[inline]
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// A Freeverb style reverb: for each channel, eight combs in parallel followed by four
+/// allpasses in series. Every buffer is allocated by `new`, so make it on the UI thread and move
+/// it into the realtime thread (see `RealtimeThread::set_reverb`). After that, it's adjusted
+/// with messages and never allocates
+struct Reverb {
+    combs:     Vec<Vec<Comb>>,
+    allpasses: Vec<Vec<Allpass>>,
+    /// from 0 to 1, bigger rooms ring on for longer
+    room_size: SmoothedParam,
+    /// from 0 to 1, how quickly the high end dies away
+    damping:   SmoothedParam,
+    /// how much of the output is reverb, from 0 (none) to 1 (only reverb)
+    mix:       SmoothedParam,
+}
+
+impl Reverb {
+    /// A reverb for a realtime thread running at `sample_rate`, in a middling room with no mix
+    fn new(sample_rate: u32) -> Self {
+        let scale = |frames: usize, channel: usize| {
+            ((frames + channel * REVERB_SPREAD) as f32 * sample_rate as f32 / REVERB_TUNING_RATE) as usize
+        };
+
+        Reverb {
+            combs:     (0..CHANNELS).map(|c| REVERB_COMBS.iter().map(|&frames| Comb::new(scale(frames, c))).collect()).collect(),
+            allpasses: (0..CHANNELS).map(|c| REVERB_ALLPASSES.iter().map(|&frames| Allpass::new(scale(frames, c))).collect()).collect(),
+            room_size: SmoothedParam::new(0.5, Ramp::Linear(REVERB_RAMP_FRAMES)),
+            damping:   SmoothedParam::new(0.5, Ramp::Linear(REVERB_RAMP_FRAMES)),
+            mix:       SmoothedParam::new(0.0, Ramp::Linear(REVERB_RAMP_FRAMES)),
+        }
+    }
+
+    fn set(&mut self, room_size: f32, damping: f32, mix: f32) {
+        self.room_size.set_target(room_size.clamp(0.0, 1.0));
+        self.damping.set_target(damping.clamp(0.0, 1.0));
+        self.mix.set_target(mix.clamp(0.0, 1.0));
+    }
+
+    /// Run interleaved frames through the reverb, in place. Like `Delay`, the filters keep
+    /// running with no mix, so turning the mix up never brings back an old tail
+    fn process<S: Sample>(&mut self, frames: &mut [S]) {
+        for frame in frames.chunks_mut(CHANNELS) {
+            let feedback = self.room_size.next() * 0.28 + 0.7;
+            let damping = self.damping.next() * 0.4;
+            let mix = self.mix.next();
+
+            // every channel's reverb is fed the same mono input
+            let input = frame.iter().map(|sample| sample.to_f32()).sum::<f32>() * REVERB_INPUT_GAIN;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut wet = 0.0;
+                for comb in self.combs[channel].iter_mut() {
+                    wet += comb.process(input, feedback, damping);
+                }
+
+                for allpass in self.allpasses[channel].iter_mut() {
+                    wet = allpass.process(wet);
+                }
+
+                *sample = S::from_f32(sample.to_f32() * (1.0 - mix) + wet * REVERB_WET_GAIN * mix);
+            }
+        }
+    }
+
+    /// Forget the tail, and touch every buffer so the callback doesn't page fault on them
+    fn clear(&mut self) {
+        for comb in self.combs.iter_mut().flatten() {
+            comb.buffer.iter_mut().for_each(|sample| *sample = 0.0);
+            comb.store = 0.0;
+        }
+
+        for allpass in self.allpasses.iter_mut().flatten() {
+            allpass.buffer.iter_mut().for_each(|sample| *sample = 0.0);
+        }
+    }
+}
+
+// end of effects implementation
+
+// beginning of voice manager implementation
+/// Identifies a voice. Whoever starts the voice picks it (a MIDI note number, say), so it can be
+/// stopped again later
+type VoiceId = u32;
+
+// private. a set of samples being played by the voice manager
+struct Voice<const N: usize, S: Sample> {
+    id:       VoiceId,
+    samples:  Arc<Samples<N, S>>,
+    gain:     f32,
+    frame:    usize,
+    envelope: Envelope,
+}
+
+/// Plays several sets of samples at once, each looping at its own gain, mixed together on top of
+/// whatever the realtime thread is already playing. Each voice follows an `Envelope`.
+/// Room for every voice is allocated up front, so starting and stopping voices never allocates.
+/// If every voice is busy, starting another one steals the oldest
+struct VoiceManager<const N: usize = FRAMES, S: Sample = f32> {
+    voices:     Vec<Voice<N, S>>,
+    max_voices: usize, // the Vec may well have been given more room than this
+    adsr:       Adsr,
+}
+
+impl<const N: usize, S: Sample> VoiceManager<N, S> {
+    fn new(max_voices: usize) -> Self {
+        assert!(max_voices > 0);
+        VoiceManager { voices: Vec::with_capacity(max_voices), max_voices, adsr: Adsr::default() }
+    }
+
+    /// The envelope for voices started from now on. Voices already playing keep theirs
+    fn set_envelope(&mut self, adsr: Adsr) {
+        self.adsr = adsr;
+    }
+
+    /// Start playing `samples` as voice `id`. Returns the samples which were playing before, if
+    /// `id` was already playing or the oldest voice had to be stolen
+    fn note_on(&mut self, id: VoiceId, samples: Arc<Samples<N, S>>, gain: f32) -> Option<Arc<Samples<N, S>>> {
+        let voice = Voice { id, samples, gain, frame: 0, envelope: Envelope::new(self.adsr) };
+
+        // retriggering a voice starts it over
+        if let Some(playing) = self.voices.iter_mut().find(|voice| voice.id == id) {
+            return Some(mem::replace(playing, voice).samples);
+        }
+
+        let stolen = if self.voices.len() == self.max_voices {
+            Some(self.voices.remove(0).samples)
+        } else {
+            None
+        };
+
+        self.voices.push(voice);
+        stolen
+    }
+
+    /// Let voice `id` go. It carries on through its envelope's release, see `reap`. Returns its
+    /// samples if there's no release, so the voice stopped right away. None if it wasn't playing
+    fn note_off(&mut self, id: VoiceId) -> Option<Arc<Samples<N, S>>> {
+        let index = self.voices.iter().position(|voice| voice.id == id && !voice.envelope.is_released())?;
+        self.voices[index].envelope.release();
+        if !self.voices[index].envelope.is_done() {
+            return None;
+        }
+
+        Some(self.voices.remove(index).samples)
+    }
+
+    /// Take away a voice which has finished its release, returning its samples. Call until it
+    /// returns None
+    fn reap(&mut self) -> Option<Arc<Samples<N, S>>> {
+        let index = self.voices.iter().position(|voice| voice.envelope.is_done())?;
+        Some(self.voices.remove(index).samples)
+    }
+
+    /// Stop whichever voice was started first, returning its samples. None if nothing is playing
+    fn shed_oldest(&mut self) -> Option<Arc<Samples<N, S>>> {
+        if self.voices.is_empty() {
+            return None;
+        }
+
+        Some(self.voices.remove(0).samples)
+    }
+
+    /// Number of voices playing
+    fn active(&self) -> usize {
+        self.voices.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.voices.is_empty()
+    }
+
+    /// Add every voice into frames [from, to) of `output`, moving their envelopes along. Frame 0
+    /// is the start of the callback, see `advance`
+    fn mix(&mut self, output: &mut [S], from: usize, to: usize) {
+        for voice in self.voices.iter_mut() {
+            for frame in from..to {
+                let read = (voice.frame + frame) % N;
+                let gain = voice.gain * voice.envelope.next();
+                for (out, sample) in output[frame * CHANNELS..(frame + 1) * CHANNELS].iter_mut().zip(voice.samples[read].iter()) {
+                    *out = S::from_f32(out.to_f32() + sample.to_f32() * gain);
+                }
+            }
+        }
+    }
+
+    /// Write to the room set aside for voices, so the first voices don't page fault
+    fn warm_up(&mut self) {
+        for slot in self.voices.spare_capacity_mut() {
+            *slot = MaybeUninit::zeroed();
+        }
+    }
+
+    /// Move every voice on past a callback's worth of frames
+    fn advance(&mut self, frames: usize) {
+        for voice in self.voices.iter_mut() {
+            voice.frame = (voice.frame + frames) % N;
+        }
+    }
+}
+// end of voice manager implementation
+
+// beginning of parameter smoothing implementation
+/// How a `SmoothedParam` gets to a new target
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ramp {
+    /// a straight line, reaching the target after exactly this many frames
+    Linear(usize),
+    /// closes about 63% of the remaining distance every this many frames, so it slows down as it
+    /// gets close. Sounds more natural for gains
+    Exponential(f32),
+}
+
+// an exponential ramp this close to its target has arrived
+const RAMP_EPSILON: f32 = 1e-5;
+
+/// A parameter which glides to new values instead of jumping to them, so changing it between
+/// (or in the middle of) buffers doesn't cause zipper noise. Set the target from a message, then
+/// call `next` once per frame in the callback
+#[derive(Clone, Copy, Debug)]
+struct SmoothedParam {
+    current:   f32,
+    target:    f32,
+    ramp:      Ramp,
+    // linear ramps (and `ramp`): the change per frame, and the frames left to go
+    step:      f32,
+    remaining: usize,
+    // exponential ramps only: the fraction of the distance covered per frame
+    coeff:     f32,
+}
+
+impl SmoothedParam {
+    fn new(value: f32, ramp: Ramp) -> Self {
+        let coeff = match ramp {
+            Ramp::Exponential(frames) => 1.0 - (-1.0 / frames.max(1.0)).exp(),
+            Ramp::Linear(_)           => 1.0,
+        };
+
+        SmoothedParam {
+            current:   value,
+            target:    value,
+            ramp,
+            step:      0.0,
+            remaining: 0,
+            coeff,
+        }
+    }
+
+    /// Start gliding towards `target` from wherever the parameter is now
+    fn set_target(&mut self, target: f32) {
+        self.target = target;
+        if let Ramp::Linear(frames) = self.ramp {
+            if frames == 0 {
+                self.current = target;
+                return;
+            }
+
+            self.remaining = frames;
+            self.step = (target - self.current) / frames as f32;
+        } else {
+            // cuts short anything started by `ramp`
+            self.remaining = 0;
+        }
+    }
+
+    /// Glide from `from` to `to` in a straight line over exactly `frames` frames, whatever kind of
+    /// ramp the parameter normally uses
+    fn ramp(&mut self, from: f32, to: f32, frames: usize) {
+        if frames == 0 {
+            self.set_immediate(to);
+            return;
+        }
+
+        self.current = from;
+        self.target = to;
+        self.remaining = frames;
+        self.step = (to - from) / frames as f32;
+    }
+
+    /// Jump straight to `value`, without smoothing
+    fn set_immediate(&mut self, value: f32) {
+        self.current = value;
+        self.target = value;
+        self.remaining = 0;
+    }
+
+    /// Move on a frame, returning the value for that frame
+    #[inline]
+    fn next(&mut self) -> f32 {
+        // straight lines first, `ramp` uses them whatever kind of ramp we have
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            // land exactly on the target, whatever rounding did on the way
+            self.current = if self.remaining == 0 { self.target } else { self.current + self.step };
+            return self.current;
+        }
+
+        match self.ramp {
+            Ramp::Linear(_) => (),
+
+            Ramp::Exponential(_) => if self.current != self.target {
+                self.current += (self.target - self.current) * self.coeff;
+                if (self.target - self.current).abs() < RAMP_EPSILON {
+                    self.current = self.target;
+                }
+            },
+        }
+
+        self.current
+    }
+
+    /// The value right now
+    fn value(&self) -> f32 {
+        self.current
+    }
+
+    fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// true until the parameter reaches its target
+    fn is_smoothing(&self) -> bool {
+        self.current != self.target
+    }
+}
+// end of parameter smoothing implementation
+
+// beginning of oscillator implementation
+
+/// Something which makes a wave, one sample at a time. Oscillators are told where they are in
+/// the cycle rather than keeping track themselves, so the UI thread can make samples which loop
+trait Oscillator {
+    /// The value `phase` of the way through a cycle (from 0 up to 1), between -1 and 1. The phase
+    /// moves on by `step` every frame
+    fn sample(&mut self, phase: f32, step: f32) -> f32;
+
+    /// Write `cycles` cycles of the wave, scaled by `volume`, into every channel of `samples`,
+    /// starting `phase` of the way through a cycle. Returns the phase the next block starts at
+    fn fill<const N: usize, S: Sample>(&mut self, samples: &mut Samples<N, S>, phase: f32, cycles: f32, volume: f32) -> f32 {
+        let step = cycles / N as f32;
+        for (i, frame) in samples.iter_mut().enumerate() {
+            let value = self.sample((phase + step * i as f32).fract(), step);
+            for sample in frame.iter_mut() {
+                *sample = S::from_f32(value * volume);
+            }
+        }
+
+        (phase + cycles).fract()
+    }
+}
+
+/// A sine wave, starting at 0 on its way up
+struct Sine;
+
+impl Oscillator for Sine {
+    fn sample(&mut self, phase: f32, _step: f32) -> f32 {
+        (phase * 2.0 * f32::consts::PI).sin()
+    }
+}
+
+/// A square wave, high for the first half of the cycle. The jumps alias badly at audio
+/// frequencies, see `PolyBlepSquare`
+struct Square;
+
+impl Oscillator for Square {
+    fn sample(&mut self, phase: f32, _step: f32) -> f32 {
+        if phase < 0.5 { 1.0 } else { -1.0 }
+    }
+}
+
+/// A rising sawtooth wave, crossing 0 at the start of the cycle. The jump aliases badly at audio
+/// frequencies, see `PolyBlepSaw`
+struct Saw;
+
+impl Oscillator for Saw {
+    fn sample(&mut self, phase: f32, _step: f32) -> f32 {
+        2.0 * (phase + 0.5).fract() - 1.0
+    }
+}
+
+/// A triangle wave, starting at 0 on its way up
+struct Triangle;
+
+impl Oscillator for Triangle {
+    fn sample(&mut self, phase: f32, _step: f32) -> f32 {
+        1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs()
+    }
+}
+
+// private. the polynomial band-limited step (PolyBLEP) correction for a jump of 2 at phase 0,
+// `phase` of the way through a cycle which moves on by `step` every frame. Subtracting it from a
+// naive wave which jumps down smooths the two frames on either side of the jump, which takes out
+// most of the aliasing
+fn poly_blep(phase: f32, step: f32) -> f32 {
+    // a wave doing more than a cycle every two frames can't be helped
+    let step = step.min(0.5);
+    if phase < step {
+        let t = phase / step;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - step {
+        let t = (phase - 1.0) / step;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// `Square`, band-limited with PolyBLEP so it can be played at audio frequencies
+struct PolyBlepSquare;
+
+impl Oscillator for PolyBlepSquare {
+    fn sample(&mut self, phase: f32, step: f32) -> f32 {
+        // up at the start of the cycle, down half way through
+        Square.sample(phase, step) + poly_blep(phase, step) - poly_blep((phase + 0.5).fract(), step)
+    }
+}
+
+/// `Saw`, band-limited with PolyBLEP so it can be played at audio frequencies
+struct PolyBlepSaw;
+
+impl Oscillator for PolyBlepSaw {
+    fn sample(&mut self, phase: f32, step: f32) -> f32 {
+        // the jump down is half way through the cycle
+        Saw.sample(phase, step) - poly_blep((phase + 0.5).fract(), step)
+    }
+}
+
+// where every `Noise` starts, any number but 0 will do
+const NOISE_SEED: u32 = 0x2545_f491;
+
+/// White noise, from a xorshift generator. The phase means nothing to it, so noise made to loop
+/// repeats every block
+struct Noise {
+    state: u32,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise { state: NOISE_SEED }
+    }
+}
+
+impl Oscillator for Noise {
+    fn sample(&mut self, _phase: f32, _step: f32) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+/// Any of the oscillators, picked by a `Waveform`. See `Waveform::oscillator`. Squares and saws
+/// are band-limited. Triangles have no jumps, so their aliasing is quiet enough to leave be
+enum WaveOscillator {
+    Sine(Sine),
+    Square(PolyBlepSquare),
+    Saw(PolyBlepSaw),
+    Triangle(Triangle),
+    Noise(Noise),
+}
+
+impl WaveOscillator {
+    /// The waveform this oscillator makes
+    fn waveform(&self) -> Waveform {
+        match *self {
+            WaveOscillator::Sine(_)     => Waveform::Sine,
+            WaveOscillator::Square(_)   => Waveform::Square,
+            WaveOscillator::Saw(_)      => Waveform::Saw,
+            WaveOscillator::Triangle(_) => Waveform::Triangle,
+            WaveOscillator::Noise(_)    => Waveform::Noise,
+        }
+    }
+}
+
+impl Oscillator for WaveOscillator {
+    fn sample(&mut self, phase: f32, step: f32) -> f32 {
+        match *self {
+            WaveOscillator::Sine(ref mut osc)     => osc.sample(phase, step),
+            WaveOscillator::Square(ref mut osc)   => osc.sample(phase, step),
+            WaveOscillator::Saw(ref mut osc)      => osc.sample(phase, step),
+            WaveOscillator::Triangle(ref mut osc) => osc.sample(phase, step),
+            WaveOscillator::Noise(ref mut osc)    => osc.sample(phase, step),
+        }
+    }
+}
+
+// number of samples in each of a wavetable's tables
+const WAVETABLE_SIZE: usize = 2048;
+
+// number of tables in a wavetable, one for each octave up from WAVETABLE_LOWEST
+const WAVETABLE_OCTAVES: usize = 10;
+
+// the frequency a wavetable's first table starts at, in Hz. Anything lower plays from it too
+const WAVETABLE_LOWEST: f32 = 20.0;
+
+/// A wave built up from harmonics, precomputed as one table per octave (a mip-map). Each table
+/// only has the harmonics which stay below the Nyquist frequency for every note in its octave, so
+/// playing back doesn't alias. Building one is slow, so it's done on the UI thread and the
+/// realtime thread gets an `Arc` (see `Message::PlayWavetable`). Reading one never allocates
+#[derive(Debug)]
+struct Wavetable {
+    /// the amplitude of each harmonic, fundamental first
+    harmonics:   Vec<f32>,
+    sample_rate: u32,
+    // each table has the first sample again on the end, so reads can interpolate without wrapping
+    tables:      Vec<Vec<f32>>,
+}
+
+impl Wavetable {
+    /// Build the tables for a wave made of sines at each multiple of the fundamental, with the
+    /// amplitudes in `harmonics`, to be played at `sample_rate`
+    fn from_harmonics(harmonics: Vec<f32>, sample_rate: u32) -> Self {
+        let nyquist = sample_rate as f32 / 2.0;
+        let mut tables: Vec<Vec<f32>> = (0..WAVETABLE_OCTAVES).map(|octave| {
+            let highest = WAVETABLE_LOWEST * (1 << (octave + 1)) as f32;
+            let count = ((nyquist / highest) as usize).max(1).min(harmonics.len());
+
+            let mut table = vec![0.0; WAVETABLE_SIZE + 1];
+            for (i, sample) in table.iter_mut().enumerate() {
+                let angle = i as f32 / WAVETABLE_SIZE as f32 * 2.0 * f32::consts::PI;
+                *sample = harmonics[..count].iter().enumerate()
+                    .map(|(h, amplitude)| amplitude * (angle * (h + 1) as f32).sin())
+                    .sum();
+            }
+            table
+        }).collect();
+
+        // the same scale for every table, so the level doesn't jump between octaves
+        let peak = tables.iter().flatten().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        if peak > 1.0 {
+            for sample in tables.iter_mut().flatten() {
+                *sample /= peak;
+            }
+        }
+
+        Wavetable { harmonics, sample_rate, tables }
+    }
+
+    /// A wavetable for `waveform`, with as many harmonics as the lowest octave has room for.
+    /// None for noise, which has no harmonics
+    fn for_waveform(waveform: Waveform, sample_rate: u32) -> Option<Self> {
+        let count = (sample_rate as f32 / 2.0 / WAVETABLE_LOWEST) as usize;
+        let harmonic = |n: usize| -> f32 {
+            let n = n as f32;
+            let odd = n % 2.0 == 1.0;
+            match waveform {
+                Waveform::Sine     => if n == 1.0 { 1.0 } else { 0.0 },
+                Waveform::Square   => if odd { 4.0 / (f32::consts::PI * n) } else { 0.0 },
+                // crossing zero on the way up at the start of the cycle, like `Saw`
+                Waveform::Saw      => -2.0 / (f32::consts::PI * n) * if odd { -1.0 } else { 1.0 },
+                Waveform::Triangle => match n as usize % 4 {
+                    1 => 8.0 / (f32::consts::PI * f32::consts::PI * n * n),
+                    3 => -8.0 / (f32::consts::PI * f32::consts::PI * n * n),
+                    _ => 0.0,
+                },
+                Waveform::Noise    => 0.0,
+            }
+        };
+
+        if waveform == Waveform::Noise {
+            return None;
+        }
+
+        Some(Wavetable::from_harmonics((1..=count).map(harmonic).collect(), sample_rate))
+    }
+
+    /// The value `phase` of the way through a cycle (from 0 up to 1) of the wave played at
+    /// `frequency`, interpolated between the two nearest samples of the table for its octave
+    fn read(&self, phase: f32, frequency: f32) -> f32 {
+        let octave = (frequency / WAVETABLE_LOWEST).max(1.0).log2() as usize;
+        let table = &self.tables[octave.min(WAVETABLE_OCTAVES - 1)];
+
+        let position = phase * WAVETABLE_SIZE as f32;
+        let i = (position as usize).min(WAVETABLE_SIZE - 1);
+        let t = position - i as f32;
+        table[i] + (table[i + 1] - table[i]) * t
+    }
+}
+
+impl Oscillator for Wavetable {
+    fn sample(&mut self, phase: f32, step: f32) -> f32 {
+        self.read(phase, step * self.sample_rate as f32)
+    }
+}
+
+// how long an LFO's rate and depth take to glide to new values, in frames
+const LFO_RAMP_FRAMES: usize = 1024;
+
+/// What an `Lfo` moves, see `RealtimeThread::set_lfo`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LfoTarget {
+    /// the output volume (tremolo). The depth is how much of the gain is taken away at the
+    /// bottom of each cycle, from 0 to 1
+    Volume,
+    /// the frequency of the wavetable (vibrato). The depth is how far the pitch swings either
+    /// way, in semitones
+    Frequency,
+}
+
+/// A low-frequency sine oscillator, for modulating a parameter. The rate and depth glide to new
+/// values (see `SmoothedParam`), so changing them doesn't click. With no depth, it does nothing
+#[derive(Clone, Copy, Debug)]
+struct Lfo {
+    /// in Hz
+    rate:  SmoothedParam,
+    depth: SmoothedParam,
+    phase: f32,
+}
+
+impl Lfo {
+    /// An LFO with no depth
+    fn new() -> Self {
+        Lfo {
+            rate:  SmoothedParam::new(0.0, Ramp::Linear(LFO_RAMP_FRAMES)),
+            depth: SmoothedParam::new(0.0, Ramp::Linear(LFO_RAMP_FRAMES)),
+            phase: 0.0,
+        }
+    }
+
+    fn set(&mut self, rate: f32, depth: f32) {
+        self.rate.set_target(rate.max(0.0));
+        self.depth.set_target(depth);
+    }
+
+    /// Is the LFO moving anything?
+    fn is_active(&self) -> bool {
+        self.depth.value() != 0.0 || self.depth.is_smoothing()
+    }
+
+    /// Move on a frame of a realtime thread running at `sample_rate`, returning the sine scaled
+    /// by the depth
+    #[inline]
+    fn next(&mut self, sample_rate: u32) -> f32 {
+        let rate = self.rate.next();
+        let depth = self.depth.next();
+        let value = Sine.sample(self.phase, 0.0) * depth;
+        self.phase = (self.phase + rate / sample_rate as f32).fract();
+        value
+    }
+
+    /// The gain for the next frame as a tremolo, from 1 down to 1 - depth
+    #[inline]
+    fn next_gain(&mut self, sample_rate: u32) -> f32 {
+        let depth = self.depth.value();
+        1.0 - (depth + self.next(sample_rate)) * 0.5
+    }
+
+    /// What to multiply a frequency by for the next frame as a vibrato
+    #[inline]
+    fn next_ratio(&mut self, sample_rate: u32) -> f32 {
+        (self.next(sample_rate) / 12.0).exp2()
+    }
+}
+
+// private. a wavetable the realtime thread is playing, see `RealtimeThread::play_wavetable`
+struct WavetableVoice {
+    table:     Arc<Wavetable>,
+    frequency: f32,
+    gain:      f32,
+    phase:     f32,
+}
+
+// end of oscillator implementation
+
+#[derive(Clone)]
+enum Message<const N: usize = FRAMES, S: Sample = f32> {
+    NewSamples(Arc<Samples<N, S>>),
+    /// start playing these samples exactly when the realtime thread reaches the given position
+    NewSamplesAt(Arc<Samples<N, S>>, u64),
+    /// report what the realtime thread is up to, see `Feedback::Snapshot`
+    Snapshot,
+    /// change how the samples are played through, see `PlaybackMode`
+    SetPlaybackMode(PlaybackMode),
+    /// start playing these samples as another voice, at the given gain. See `VoiceManager`
+    NoteOn(VoiceId, Arc<Samples<N, S>>, f32),
+    /// let go of a voice started by `NoteOn`, which then fades out over its release
+    NoteOff(VoiceId),
+    /// the envelope for voices started from now on, see `Envelope`
+    SetEnvelope(Adsr),
+    /// modulate a parameter at the given rate (in Hz) and depth, see `RealtimeThread::set_lfo`
+    SetLfo(LfoTarget, f32, f32),
+    /// filter the output, see `RealtimeThread::set_filter`
+    SetFilter(BiquadCoefficients),
+    /// echo the output this many frames later, with the given feedback and mix. See
+    /// `RealtimeThread::set_delay`
+    SetDelay(usize, f32, f32),
+    /// set the reverb's room size, damping and mix, see `RealtimeThread::adjust_reverb`
+    SetReverb(f32, f32, f32),
+    /// change the chorus (or flanger), see `RealtimeThread::set_chorus`
+    SetChorus(ChorusSettings),
+    /// glide the output volume to this gain, see `RealtimeThread::set_volume`
+    SetVolume(f32),
+    /// fade the output volume from the first gain to the second over this many frames, see
+    /// `RealtimeThread::ramp_volume`
+    RampVolume(f32, f32, usize),
+    /// put out silence, holding on to our place in the samples
+    Pause,
+    /// carry on from wherever `Pause` or `Stop` left off
+    Resume,
+    /// put out silence, and go back to the start of the samples
+    Stop,
+    /// play these samples in a layer at the given gain, replacing whatever the layer held. See
+    /// `RealtimeThread::set_layer`
+    SetLayer(LayerId, Arc<Samples<N, S>>, f32),
+    /// change the gain of a layer
+    SetLayerGain(LayerId, f32),
+    /// empty a layer
+    RemoveLayer(LayerId),
+    /// play a wavetable at the given frequency (in Hz) and gain, on top of everything else. See
+    /// `RealtimeThread::play_wavetable`. Send these from the UI thread, which has the collector
+    /// track the table so the realtime thread never frees it
+    PlayWavetable(Arc<Wavetable>, f32, f32),
+    /// change the frequency the wavetable is played at
+    WavetableFrequency(f32),
+    /// stop playing the wavetable
+    StopWavetable,
+    /// copy everything the realtime thread knows about itself somewhere the UI thread can look
+    /// at it, see `StateDump`
+    DumpState,
+    /// pass the input straight through (true) or go back to normal (false), see
+    /// `RealtimeThread::set_bypass`
+    Bypass(bool),
+    Shutdown,
+}
+
+impl<const N: usize, S: Sample> RtMessage<N, S> for Message<N, S> {
+    fn shutdown() -> Self {
+        Message::Shutdown
+    }
+
+    fn play_wavetable(table: Arc<Wavetable>, frequency: f32, gain: f32) -> Option<Self> {
+        Some(Message::PlayWavetable(table, frequency, gain))
+    }
+
+    fn stop_wavetable() -> Option<Self> {
+        Some(Message::StopWavetable)
+    }
+
+    fn is_shutdown(&self) -> bool {
+        matches!(*self, Message::Shutdown)
+    }
+
+    fn samples(&self) -> Option<&Arc<Samples<N, S>>> {
+        match *self {
+            Message::NewSamples(ref samples)      => Some(samples),
+            Message::NewSamplesAt(ref samples, _) => Some(samples),
+            Message::NoteOn(_, ref samples, _)    => Some(samples),
+            Message::SetLayer(_, ref samples, _)  => Some(samples),
+            Message::Snapshot                     => None,
+            Message::SetPlaybackMode(_)           => None,
+            Message::NoteOff(_)                   => None,
+            Message::SetEnvelope(_)               => None,
+            Message::SetLfo(..)                   => None,
+            Message::SetFilter(_)                 => None,
+            Message::SetDelay(..)                 => None,
+            Message::SetReverb(..)                => None,
+            Message::SetChorus(_)                 => None,
+            Message::SetVolume(_)                 => None,
+            Message::RampVolume(..)               => None,
+            Message::Pause                        => None,
+            Message::Resume                       => None,
+            Message::Stop                         => None,
+            Message::SetLayerGain(..)             => None,
+            Message::RemoveLayer(_)               => None,
+            Message::PlayWavetable(..)            => None,
+            Message::WavetableFrequency(_)        => None,
+            Message::StopWavetable                => None,
+            Message::DumpState                    => None,
+            Message::Bypass(_)                    => None,
+            Message::Shutdown                     => None,
+        }
+    }
+
+    fn wavetable(&self) -> Option<&Arc<Wavetable>> {
+        match *self {
+            Message::PlayWavetable(ref table, ..) => Some(table),
+            _                                     => None,
+        }
+    }
+
+    fn due(&self) -> Option<u64> {
+        match *self {
+            Message::NewSamplesAt(_, at) => Some(at),
+            _                            => None,
+        }
+    }
+
+    fn apply(self, rt: &mut RealtimeThread<Self, N, S>) {
+        match self {
+            Message::NewSamples(samples)             => rt.play(samples),
+            Message::NewSamplesAt(samples, _)        => rt.play(samples),
+            Message::Snapshot                        => rt.snapshot(),
+            Message::SetPlaybackMode(mode)           => rt.set_playback_mode(mode),
+            Message::NoteOn(id, samples, gain)       => rt.note_on(id, samples, gain),
+            Message::NoteOff(id)                     => rt.note_off(id),
+            Message::SetEnvelope(adsr)               => rt.set_envelope(adsr),
+            Message::SetLfo(target, rate, depth)     => rt.set_lfo(target, rate, depth),
+            Message::SetFilter(coefficients)         => rt.set_filter(coefficients),
+            Message::SetDelay(frames, feedback, mix) => rt.set_delay(frames, feedback, mix),
+            Message::SetReverb(room, damping, mix)   => rt.adjust_reverb(room, damping, mix),
+            Message::SetChorus(settings)             => rt.set_chorus(settings),
+            Message::SetVolume(volume)               => rt.set_volume(volume),
+            Message::RampVolume(from, to, frames)    => rt.ramp_volume(from, to, frames),
+            Message::Pause                           => rt.pause(),
+            Message::Resume                          => rt.resume(),
+            Message::Stop                            => rt.stop_playback(),
+            Message::SetLayer(id, samples, gain)     => rt.set_layer(id, samples, gain),
+            Message::SetLayerGain(id, gain)          => rt.set_layer_gain(id, gain),
+            Message::RemoveLayer(id)                 => rt.remove_layer(id),
+            Message::PlayWavetable(table, hz, gain)  => rt.play_wavetable(table, hz, gain),
+            Message::WavetableFrequency(hz)          => rt.set_wavetable_frequency(hz),
+            Message::StopWavetable                   => rt.stop_wavetable(),
+            Message::DumpState                       => rt.dump_state(),
+            Message::Bypass(bypass)                  => rt.set_bypass(bypass),
+            Message::Shutdown                        => (),
+        }
+    }
+}
+
+impl<const N: usize, S: Sample> From<Arc<Samples<N, S>>> for Message<N, S> {
+    fn from(samples: Arc<Samples<N, S>>) -> Self {
+        Message::NewSamples(samples)
+    }
+}
+
+/// How the realtime thread plays through its samples
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum PlaybackMode {
+    /// play the samples once from the start, then silence until new samples arrive
+    OneShot,
+    /// play the samples over and over
+    #[default]
+    Loop,
+    /// play the samples forwards, then backwards, then forwards again, ...
+    PingPong,
+}
+
+/// Whether the realtime thread is playing, see `Message::Pause`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Transport {
+    #[default]
+    Playing,
+    Paused,
+    Stopped,
+}
+
+/// What the realtime thread does when callbacks keep running over budget, see
+/// `RealtimeThread::set_overload_policy`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum OverloadPolicy {
+    /// only send `Feedback::Overloaded`
+    #[default]
+    Report,
+    /// send `Feedback::Overloaded` and stop the oldest voice, to claw some time back
+    ShedVoices,
+}
+
+/// A message stamped with its place in the stream, so the realtime thread can tell when messages
+/// have gone missing. See `Feedback::Gap`
+#[derive(Clone)]
+struct Sequenced<M> {
+    seq:     u64,
+    message: M,
+}
+
+/// What the UI thread does when the queue to the realtime thread is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OverflowPolicy {
+    /// wait for the realtime thread to make room
+    Block,
+    /// throw away the oldest queued message to make room for the new one
+    DropOldest,
+    /// throw away the new message
+    DropNewest,
+    /// hand the new message back to the caller
+    Error,
+}
+
+/// Returned by `UIThread::try_send` when a message couldn't be delivered as asked
+enum SendError<M = Message> {
+    /// the queue was full and the policy is `Error`. The message is handed back
+    Full(M),
+    /// the queue was full and the policy is `DropNewest`, so this message was thrown away
+    DroppedNewest(M),
+    /// the queue was full and the policy is `DropOldest`. The new message was sent, but the oldest
+    /// queued message (handed back here) was thrown away to make room for it
+    DroppedOldest(M),
+    /// the realtime thread has gone away. The message is handed back
+    Disconnected(M),
+}
+
+impl<M> fmt::Debug for SendError<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::Full(_)          => write!(f, "Full"),
+            SendError::DroppedNewest(_) => write!(f, "DroppedNewest"),
+            SendError::DroppedOldest(_) => write!(f, "DroppedOldest"),
+            SendError::Disconnected(_)  => write!(f, "Disconnected"),
+        }
+    }
+}
+
+impl<M> fmt::Display for SendError<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::Full(_)          => write!(f, "queue full, message not sent"),
+            SendError::DroppedNewest(_) => write!(f, "queue full, dropped newest message"),
+            SendError::DroppedOldest(_) => write!(f, "queue full, dropped oldest message"),
+            SendError::Disconnected(_)  => write!(f, "realtime thread went away"),
+        }
+    }
+}
+
+/// Events the realtime thread reports back to the UI thread
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Feedback {
+    /// samples sent by the UI thread have started playing
+    BufferApplied,
+    /// the callback had nothing to play
+    Underrun,
+    /// total number of frames produced so far
+    Position(u64),
+    /// loudest sample produced since the last peak report
+    Peak(f32),
+    /// this many messages were dropped before they reached the realtime thread
+    Gap(u64),
+    /// the realtime thread has shut down, see `UIThread::wait_for_stop`
+    Stopped,
+    /// the realtime thread has stopped because of an error
+    Failed(EngineError),
+    /// the realtime thread's state, in response to `Message::Snapshot`
+    Snapshot(EngineSnapshot),
+    /// glitches so far, sent with the position whenever there have been new ones
+    Xruns(XrunCounters),
+    /// where playback is up to, sent with the position
+    Playhead(Playhead),
+    /// the user's processor panicked, so the callback put out silence
+    Panicked,
+    /// a new `StateDump` is ready, in response to `Message::DumpState`
+    StateDumped,
+    /// fraction of the buffer's playing time spent in the callback, smoothed. Sent with the
+    /// position. Anything near 1.0 means there's no headroom left
+    Load(f32),
+    /// this many callbacks in a row have run over their budget, see
+    /// `RealtimeThread::set_overload_threshold`. Sent again every time the streak grows by
+    /// another threshold's worth
+    Overloaded(u64),
+    /// output levels since the last report, sent with the position
+    Levels(Levels),
+}
+
+/// Where the realtime thread is in the samples it is playing, as of the end of a callback
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Playhead {
+    /// total number of frames rendered, the same clock `RtMessage::due` uses
+    position: u64,
+    /// the frame of the samples which plays next. None if a one shot has finished
+    frame:    Option<usize>,
+    /// how the samples are being played through
+    mode:     PlaybackMode,
+}
+
+/// Output levels for each channel, measured by the realtime thread over every callback since the
+/// previous report
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Levels {
+    /// loudest sample, ignoring the sign
+    peak: [f32; CHANNELS],
+    /// root mean square
+    rms:  [f32; CHANNELS],
+}
+
+/// Counts of everything which could have been heard as a glitch
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct XrunCounters {
+    /// callbacks which finished after their deadline (see `CallbackInfo::wall_deadline`)
+    late:    u64,
+    /// callbacks which had nothing to play, so put out silence
+    silent:  u64,
+    /// callbacks which didn't start where the previous one left off, so the host skipped (or
+    /// repeated) some frames
+    skipped: u64,
+}
+
+impl XrunCounters {
+    fn total(&self) -> u64 {
+        self.late + self.silent + self.skipped
+    }
+}
+
+/// The state of the realtime thread at some point in time, see `Message::Snapshot`
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct EngineSnapshot {
+    /// identifies the samples being played (the address of the buffer), None if there aren't any
+    buffer_id:     Option<usize>,
+    /// true if the samples being played came through the triple buffer
+    use_published: bool,
+    /// total number of frames produced so far
+    position:      u64,
+    /// number of callbacks so far
+    callbacks:     u64,
+    /// true if the last callback had nothing to play
+    underrun:      bool,
+    /// true if any timestamped messages are waiting for their due time
+    waiting:       bool,
+    /// glitches so far
+    xruns:         XrunCounters,
+    /// whether we're playing, paused or stopped
+    transport:     Transport,
+}
+
+/// Everything the realtime thread knows about itself, for debugging sessions which are stuck or
+/// misbehaving. See `Message::DumpState`.
+/// Too big to go through the feedback queue with everything else, so the realtime thread copies
+/// it into a triple buffer set up in advance (see `RealtimeThread::set_state_dump`)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct StateDump {
+    /// identifies the samples being played (the address of the buffer), None if there aren't any
+    buffer_id:     Option<usize>,
+    /// identifies the samples being faded out of, if there are any
+    fading_id:     Option<usize>,
+    use_published: bool,
+    source_silent: bool,
+    /// the read cursor, see `PlaybackMode`
+    read_frame:    usize,
+    playback_mode: PlaybackMode,
+    transport:     Transport,
+    /// total number of frames produced so far
+    position:      u64,
+    callbacks:     u64,
+    /// the sequence number expected on the next message from the UI thread
+    next_seq:      u64,
+    /// the incoming queue, as the realtime thread sees it
+    incoming:      QueueOccupancy,
+    /// a message held back because the schedule was full
+    waiting:       bool,
+    /// number of timestamped messages waiting for their due time
+    scheduled:     usize,
+    /// number of voices playing
+    voices:        usize,
+    /// number of layers playing something
+    layers:        usize,
+    volume:        f32,
+    volume_target: f32,
+    /// frames of the current crossfade done so far, and its length
+    fade_done:     usize,
+    fade_frames:   usize,
+    underrun:      bool,
+    xruns:         XrunCounters,
+    load:          f32,
+    sample_rate:   u32,
+    bypass:        bool,
+}
+
+// number of feedback events which can be queued up before the realtime thread starts dropping them
+const FEEDBACK_CAPACITY: usize = 256;
+
+// position and peak are reported once every this many callbacks, unless picked otherwise
+const FEEDBACK_EVERY: u64 = 64;
+
+/// Owns the engine's garbage collector.
+/// `run_threads` creates one and gives the UI thread a handle to it, so every set of samples sent
+/// to the realtime thread is tracked automatically. Dropping the scope shuts the collector down
+struct GcScope<const N: usize = FRAMES, S: Sample = f32> {
+    pub collector: GC<Samples<N, S>>,
+}
+
+impl<const N: usize, S: Sample> GcScope<N, S> {
+    fn new() -> Self {
+        GcScope { collector: GC::new() }
+    }
+
+    /// A handle which senders can use to track samples before sending them
+    fn handle(&self) -> GcHandle<Samples<N, S>> {
+        self.collector.handle()
+    }
+
+    /// Track anything in the message which the realtime thread might end up holding on to
+    fn track_message<M: RtMessage<N, S>>(collector: &GcHandle<Samples<N, S>>, message: &M) {
+        GcScope::track_held(collector, GcScope::hold(message));
+    }
+
+    /// Hold on to everything in the message which `track_message` would track, so it can be
+    /// tracked with `track_held` once the message is in the queue. A message which is handed back
+    /// and sent again would otherwise be tracked twice, and the two copies in the pool would keep
+    /// each other alive forever
+    fn hold<M: RtMessage<N, S>>(message: &M) -> Held<N, S> {
+        Held { samples: message.samples().cloned(), wavetable: message.wavetable().cloned() }
+    }
+
+    /// Track what `hold` held on to
+    fn track_held(collector: &GcHandle<Samples<N, S>>, held: Held<N, S>) {
+        if let Some(ref samples) = held.samples {
+            GcScope::track_samples(collector, samples);
+        }
+
+        if let Some(mut table) = held.wavetable {
+            while let Err(t) = collector.try_track_any(table) {
+                table = t;
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Track a set of samples the realtime thread might end up holding on to
+    fn track_samples(collector: &GcHandle<Samples<N, S>>, samples: &Arc<Samples<N, S>>) {
+        // the UI thread can afford to wait for the collector to make room
+        let mut samples = samples.clone();
+        while let Err(s) = collector.try_track(samples) {
+            samples = s;
+            thread::yield_now();
+        }
+    }
+}
+
+/// Everything a message carries which needs tracking, see `GcScope::hold`
+struct Held<const N: usize = FRAMES, S: Sample = f32> {
+    samples:   Option<Arc<Samples<N, S>>>,
+    wavetable: Option<Arc<Wavetable>>,
+}
+
+// number of preallocated sample buffers `run_threads` gives the UI thread
+const SAMPLE_POOL_CAPACITY: usize = 8;
+
+/// A fixed set of preallocated sample buffers which are reused instead of allocating new ones.
+/// The pool holds a reference to every buffer, so the realtime thread letting go of one never
+/// frees it. A buffer can be reused as soon as the pool's reference is the only one left
+struct SamplePool<const N: usize = FRAMES, S: Sample = f32> {
+    slots:     Vec<Arc<Samples<N, S>>>,
+    next:      usize,
+    collector: GcHandle<Samples<N, S>>,
+}
+
+impl<const N: usize, S: Sample> SamplePool<N, S> {
+    /// Allocate `capacity` buffers up front. When the pool is dropped, its buffers are handed to
+    /// `collector`, since the realtime thread may still be playing one of them
+    fn new(capacity: usize, collector: GcHandle<Samples<N, S>>) -> Self {
+        SamplePool {
+            slots:     (0..capacity).map(|_| Arc::new(silence())).collect(),
+            next:      0,
+            collector,
+        }
+    }
+
+    /// Fill in a free buffer with `f` and return it, ready to send.
+    /// Returns None if every buffer is still in use
+    fn fill<F: FnOnce(&mut Samples<N, S>)>(&mut self, f: F) -> Option<Arc<Samples<N, S>>> {
+        // start looking where we left off, the oldest buffers are the most likely to be free
+        for i in 0..self.slots.len() {
+            let idx = (self.next + i) % self.slots.len();
+            if let Some(buffer) = Arc::get_mut(&mut self.slots[idx]) {
+                f(buffer);
+                self.next = idx + 1;
+                return Some(self.slots[idx].clone());
+            }
+        }
+
+        None
+    }
+}
+
+impl<const N: usize, S: Sample> Drop for SamplePool<N, S> {
+    fn drop(&mut self) {
+        for slot in self.slots.drain(..) {
+            let mut slot = slot;
+            while let Err(s) = self.collector.try_track(slot) {
+                slot = s;
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+/// A single slot holding the next set of samples to play, for the common "replace the whole
+/// buffer" case. The UI thread swaps a pointer in and the realtime thread swaps it out, so there is
+/// no queue and nothing is copied
+struct SampleSlot<const N: usize = FRAMES, S: Sample = f32> {
+    next: AtomicPtr<Samples<N, S>>,
+}
+
+impl<const N: usize, S: Sample> SampleSlot<N, S> {
+    fn new() -> Self {
+        SampleSlot { next: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    /// Make `samples` the next thing to play. If the realtime thread never picked up the previous
+    /// samples, they are handed back
+    fn put(&self, samples: Arc<Samples<N, S>>) -> Option<Arc<Samples<N, S>>> {
+        let new = Arc::into_raw(samples) as *mut Samples<N, S>;
+        let old = self.next.swap(new, Ordering::AcqRel);
+        if old.is_null() {
+            None
+        } else {
+            Some(unsafe { Arc::from_raw(old) })
+        }
+    }
+
+    /// Take the samples most recently put in the slot, if there are any. Never blocks
+    fn take(&self) -> Option<Arc<Samples<N, S>>> {
+        let old = self.next.swap(ptr::null_mut(), Ordering::AcqRel);
+        if old.is_null() {
+            None
+        } else {
+            Some(unsafe { Arc::from_raw(old) })
+        }
+    }
+}
+
+impl<const N: usize, S: Sample> Drop for SampleSlot<N, S> {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
+
+/// Lets worker threads (parallel synthesis jobs, ...) send messages to the realtime thread
+/// alongside the UI thread. Any number of submitters can send at once, and each one's messages
+/// arrive in the order it sent them. Clone it to hand it to another thread
+struct Submitter<M = Message, const N: usize = FRAMES, S: Sample = f32> {
+    queue:     Arc<Intake<M>>,
+    collector: Option<GcHandle<Samples<N, S>>>,
+}
+
+/// The realtime thread's end of a submission queue, see `RealtimeThread::set_submissions`
+struct Submissions<M = Message> {
+    queue: Arc<Intake<M>>,
+}
+
+/// Create a queue which worker threads can submit messages through. It can hold `capacity`
+/// messages, which is rounded up to a power of two. Anything carrying samples is tracked by
+/// `collector` once it is submitted
+fn submission_queue<M: RtMessage<N, S>, const N: usize, S: Sample>(capacity: usize, collector: Option<GcHandle<Samples<N, S>>>) -> (Submitter<M, N, S>, Submissions<M>) {
+    let queue = Arc::new(Intake::with_capacity(capacity.max(2).next_power_of_two()));
+    queue.handles.fetch_add(1, Ordering::Relaxed);
+
+    (Submitter { queue: queue.clone(), collector }, Submissions { queue })
+}
+
+impl<M: RtMessage<N, S>, const N: usize, S: Sample> Submitter<M, N, S> {
+    /// Submit without waiting. If the queue is full, the message is handed back, and nothing in it
+    /// has been tracked yet
+    fn try_submit(&self, message: M) -> Result<(), M> {
+        let held = GcScope::hold(&message);
+        self.queue.push(message)?;
+
+        if let Some(ref collector) = self.collector {
+            GcScope::track_held(collector, held);
+        }
+
+        Ok(())
+    }
+
+    /// Submit, yielding this thread until there is room.
+    /// If the realtime thread has gone away, the message is handed back
+    fn submit(&self, message: M) -> Result<(), M> {
+        let mut message = message;
+        loop {
+            match self.try_submit(message) {
+                Ok(())   => return Ok(()),
+                Err(ret) => message = ret,
+            }
+
+            if self.is_disconnected() {
+                return Err(message);
+            }
+
+            thread::yield_now();
+        }
+    }
+
+    /// true if the realtime thread's end has been dropped
+    fn is_disconnected(&self) -> bool {
+        // every other reference belongs to a submitter
+        Arc::strong_count(&self.queue) == self.queue.handles.load(Ordering::Relaxed)
+    }
+}
+
+impl<M, const N: usize, S: Sample> Clone for Submitter<M, N, S> {
+    fn clone(&self) -> Self {
+        self.queue.handles.fetch_add(1, Ordering::Relaxed);
+        Submitter { queue: self.queue.clone(), collector: self.collector.clone() }
+    }
+}
+
+impl<M, const N: usize, S: Sample> Drop for Submitter<M, N, S> {
+    fn drop(&mut self) {
+        self.queue.handles.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A struct containing the realtime callback and all data owned by the realtime thread
+struct RealtimeThread<M = Message, const N: usize = FRAMES, S: Sample = f32> {
+    current_samples: Option<Arc<Samples<N, S>>>,
+    incoming:        RingConsumer<Sequenced<M>>,
+    published:       Option<TripleReader<Samples<N, S>>>,
+    use_published:   bool,
+    feedback:        Option<RingProducer<Feedback>>,
+    callbacks:       u64,
+    position:        u64,
+    peak:            f32,
+    underrun:        bool,
+    max_messages:    usize,
+    shutdown:        Option<Arc<AtomicBool>>,
+    sample_slot:     Option<Arc<SampleSlot<N, S>>>,
+    waiting:         Option<M>,
+    events:          Vec<(u64, M)>,
+    next_seq:        u64,
+    logger:          Option<RtLogger>,
+    submissions:     Option<Submissions<M>>,
+    returns:         Option<RingProducer<Arc<Samples<N, S>>>>,
+    collector:       Option<GcHandle<Samples<N, S>>>,
+    processor:       Option<Box<dyn RealtimeCallback<S>>>,
+    monitor_gain:    f32,
+    read_frame:      usize,
+    playback_mode:   PlaybackMode,
+    feedback_every:  u64,
+    xruns:           XrunCounters,
+    reported_xruns:  u64,
+    next_time:       Option<u64>,
+    fading:          Option<Arc<Samples<N, S>>>,
+    fade_frames:     usize,
+    fade_done:       usize,
+    voices:          VoiceManager<N, S>,
+    load:            f32,
+    sample_rate:     u32,
+    volume:          SmoothedParam,
+    transport:       Transport,
+    source_silent:   bool,
+    state_dump:      Option<TripleWriter<StateDump>>,
+    bypass:          bool,
+    shutdown_fade:   usize,
+    tail:            Option<usize>,
+    scheduling:      RtScheduling,
+    overruns:        u64,
+    overload_after:  u64,
+    overload_policy: OverloadPolicy,
+    retired:         Vec<Arc<Samples<N, S>>>,
+    layers:          [Option<Layer<N, S>>; MAX_LAYERS],
+    wavetable:       Option<WavetableVoice>,
+    tremolo:         Lfo,
+    vibrato:         Lfo,
+    filter:          Biquad,
+    delay:           Delay,
+    max_delay:       Option<usize>,
+    reverb:          Option<Reverb>,
+    chorus:          Chorus,
+    meter_peak:      [f32; CHANNELS],
+    meter_squares:   [f32; CHANNELS],
+    meter_frames:    usize,
+}
+
+/// Identifies one of the realtime thread's layers, from 0 up to (but not including) `MAX_LAYERS`
+type LayerId = usize;
+
+// private. samples playing in one of the realtime thread's layers, see `RealtimeThread::set_layer`
+struct Layer<const N: usize, S: Sample> {
+    samples: Arc<Samples<N, S>>,
+    gain:    f32,
+}
+
+// default cap on the number of messages handled in one callback
+const MAX_MESSAGES_PER_CALLBACK: usize = 32;
+
+// default length of the crossfade between the old and new samples, in frames
+const CROSSFADE_FRAMES: usize = 64;
+
+// length of the fade out when shutting down, in frames
+const SHUTDOWN_FADE_FRAMES: usize = 256;
+
+// number of timestamped messages the realtime thread can hold on to until they are due
+const EVENT_CAPACITY: usize = 64;
+
+// number of voices the realtime thread can play at once, see `VoiceManager`
+const MAX_VOICES: usize = 16;
+
+// number of layers the realtime thread has, see `RealtimeThread::set_layer`
+const MAX_LAYERS: usize = 4;
+
+// how long the output volume takes to glide to a new value, in frames
+const VOLUME_RAMP_FRAMES: usize = 256;
+
+// how much each callback moves the smoothed load figure, between 0 and 1. Bigger reacts faster
+const LOAD_SMOOTHING: f32 = 0.05;
+
+// number of retired buffers the realtime thread can hold on to while the return queue and the
+// collector are both full, see `RealtimeThread::retire`
+const RETIRED_CAPACITY: usize = 16;
+
+// default number of callbacks in a row which can run over budget before the realtime thread
+// counts itself as overloaded
+const OVERLOAD_CALLBACKS: u64 = 8;
+
+impl<M: RtMessage<N, S>, const N: usize, S: Sample> RealtimeThread<M, N, S> {
+    fn new(incoming: RingConsumer<Sequenced<M>>) -> Self {
+        // the log reads the second frame of every buffer, and playback wraps around N
+        const { assert!(N >= 2, "buffers need at least two frames") };
+
+        RealtimeThread {
+            current_samples: None,
+            incoming,
+            published:       None,
+            use_published:   false,
+            feedback:        None,
+            callbacks:       0,
+            position:        0,
+            peak:            0.0,
+            underrun:        false,
+            max_messages:    MAX_MESSAGES_PER_CALLBACK,
+            shutdown:        None,
+            sample_slot:     None,
+            waiting:         None,
+            events:          Vec::with_capacity(EVENT_CAPACITY),
+            next_seq:        0,
+            logger:          None,
+            submissions:     None,
+            returns:         None,
+            collector:       None,
+            processor:       None,
+            monitor_gain:    0.0,
+            read_frame:      0,
+            playback_mode:   PlaybackMode::Loop,
+            feedback_every:  FEEDBACK_EVERY,
+            xruns:           XrunCounters::default(),
+            reported_xruns:  0,
+            next_time:       None,
+            fading:          None,
+            fade_frames:     CROSSFADE_FRAMES,
+            fade_done:       usize::MAX, // not fading
+            voices:          VoiceManager::new(MAX_VOICES),
+            load:            0.0,
+            sample_rate:     SAMPLE_RATE,
+            volume:          SmoothedParam::new(1.0, Ramp::Linear(VOLUME_RAMP_FRAMES)),
+            transport:       Transport::Playing,
+            source_silent:   false,
+            state_dump:      None,
+            bypass:          false,
+            shutdown_fade:   SHUTDOWN_FADE_FRAMES,
+            tail:            None,
+            scheduling:      RtScheduling::default(),
+            overruns:        0,
+            overload_after:  OVERLOAD_CALLBACKS,
+            overload_policy: OverloadPolicy::Report,
+            retired:         Vec::with_capacity(RETIRED_CAPACITY),
+            layers:          Default::default(),
+            wavetable:       None,
+            tremolo:         Lfo::new(),
+            vibrato:         Lfo::new(),
+            filter:          Biquad::new(),
+            delay:           Delay::new(MAX_DELAY_SECONDS * SAMPLE_RATE as usize),
+            max_delay:       None,
+            reverb:          None,
+            chorus:          Chorus::new(CHORUS_MAX_MILLIS * SAMPLE_RATE as usize / 1000),
+            meter_peak:      [0.0; CHANNELS],
+            meter_squares:   [0.0; CHANNELS],
+            meter_frames:    0,
+        }
+    }
+
+    /// Report what the realtime thread is doing to whoever holds the other end of `feedback`
+    fn set_feedback(&mut self, feedback: RingProducer<Feedback>) {
+        self.feedback = Some(feedback);
+    }
+
+    /// Hand samples back through `returns` once something else starts playing, so the UI thread
+    /// can reuse them without involving the collector. See `UIThread::set_returns`.
+    /// If the return queue is ever full, retired samples go to `collector` instead, so make it at
+    /// least as big as the number of buffers the UI thread keeps in flight
+    fn set_returns(&mut self, returns: RingProducer<Arc<Samples<N, S>>>, collector: Option<GcHandle<Samples<N, S>>>) {
+        self.returns = Some(returns);
+        self.collector = collector;
+    }
+
+    /// Run `processor` on the output of every callback, after the engine has filled it in with
+    /// whatever it is playing. It can transform the output or replace it completely. If it
+    /// panics, the panic is reported with `Feedback::Panicked` instead of being printed
+    fn set_processor(&mut self, processor: Box<dyn RealtimeCallback<S>>) {
+        install_quiet_panic_hook();
+        self.processor = Some(processor);
+    }
+
+    /// Mix the input into the output at `gain`, so whoever is playing can hear themselves.
+    /// 0 (the default) turns monitoring off
+    fn set_monitor_gain(&mut self, gain: f32) {
+        self.monitor_gain = gain;
+    }
+
+    /// Also take messages from worker threads, see `submission_queue`
+    fn set_submissions(&mut self, submissions: Submissions<M>) {
+        self.submissions = Some(submissions);
+    }
+
+    /// Log through `logger` instead of printing from the callback, see `rt_log`.
+    /// Without a logger, the realtime thread doesn't log anything
+    fn set_logger(&mut self, logger: RtLogger) {
+        self.logger = Some(logger);
+    }
+
+    // private. never blocks. If the logging thread isn't keeping up, the record is dropped
+    fn log(&mut self, text: &'static str, value: f32) {
+        if let Some(ref mut logger) = self.logger {
+            logger.log(text, value);
+        }
+    }
+
+    // private. never blocks. If the UI thread isn't keeping up, the event is dropped
+    fn report(&mut self, event: Feedback) {
+        if let Some(ref mut feedback) = self.feedback {
+            let _ = feedback.try_push(event);
+        }
+    }
+
+    /// Also accept samples published through a triple buffer, see `UIThread::set_publisher`.
+    /// Whichever of the two paths delivered samples most recently is the one that gets played
+    fn set_published(&mut self, published: TripleReader<Samples<N, S>>) {
+        self.published = Some(published);
+    }
+
+    /// Check `shutdown` at the start of every callback, and shut down as soon as it is set,
+    /// no matter how many messages are still queued up. See `UIThread::shutdown`
+    fn set_shutdown_flag(&mut self, shutdown: Arc<AtomicBool>) {
+        self.shutdown = Some(shutdown);
+    }
+
+    /// Also play samples swapped into `sample_slot`, see `UIThread::swap_samples`
+    fn set_sample_slot(&mut self, sample_slot: Arc<SampleSlot<N, S>>) {
+        self.sample_slot = Some(sample_slot);
+    }
+
+    /// Report the position, playhead and peak once every `callbacks` callbacks. Smaller numbers
+    /// give smoother playheads for more feedback traffic
+    fn set_feedback_interval(&mut self, callbacks: u64) {
+        assert!(callbacks > 0);
+        self.feedback_every = callbacks;
+    }
+
+    /// The rate the host plays our output at, in frames per second. The delay and chorus lines
+    /// hold a fixed amount of time, so a new rate makes new ones (forgetting their settings).
+    /// This allocates, so call it before the realtime thread starts
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        assert!(sample_rate > 0);
+        if sample_rate == self.sample_rate {
+            return;
+        }
+
+        self.sample_rate = sample_rate;
+        if self.max_delay.is_none() {
+            self.delay = Delay::new(MAX_DELAY_SECONDS * sample_rate as usize);
+        }
+        self.chorus = Chorus::new(CHORUS_MAX_MILLIS * sample_rate as usize / 1000);
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Make room for `capacity` timestamped messages waiting for their due time (see
+    /// `RtMessage::due`). Once it's full, the realtime thread stops taking messages until the
+    /// earliest one is due. Allocates, so call it before the engine starts
+    fn set_event_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0);
+        let additional = capacity.saturating_sub(self.events.len());
+        self.events.reserve_exact(additional);
+    }
+
+    /// Handle at most `max` messages per callback. Anything left over waits for the next one
+    fn set_max_messages(&mut self, max: usize) {
+        self.max_messages = max;
+    }
+
+    /// Fade from the old samples to the new ones over `frames` frames whenever new samples start
+    /// playing, so discontinuous waveforms don't click. 0 switches over instantly
+    fn set_crossfade(&mut self, frames: usize) {
+        self.fade_frames = frames;
+
+        // a fade this cuts short is over, so let go of what we were fading out of
+        if self.fade_done >= frames {
+            if let Some(old) = self.fading.take() {
+                self.retire(old);
+            }
+        }
+    }
+
+    /// Change how the samples are played through. The read cursor carries on from where it is
+    fn set_playback_mode(&mut self, mode: PlaybackMode) {
+        // the ping-pong cursor runs over twice the samples, bring it back into range
+        self.read_frame = self.source_frame(0).unwrap_or(N);
+        self.playback_mode = mode;
+    }
+
+    /// Glide the output volume to `volume`, starting at the current frame
+    fn set_volume(&mut self, volume: f32) {
+        self.volume.set_target(volume);
+    }
+
+    /// Fade the output volume from `from` to `to` over `frames` frames, starting at the current
+    /// frame. The volume jumps to `from` first
+    fn ramp_volume(&mut self, from: f32, to: f32, frames: usize) {
+        self.volume.ramp(from, to, frames);
+    }
+
+    /// Put out silence until `resume`, holding on to our place in the samples. Messages are
+    /// still handled while paused
+    fn pause(&mut self) {
+        if self.transport == Transport::Playing {
+            self.transport = Transport::Paused;
+        }
+    }
+
+    /// Carry on playing from wherever we were paused or stopped
+    fn resume(&mut self) {
+        self.transport = Transport::Playing;
+    }
+
+    /// Put out silence until `resume`, which starts the samples over
+    fn stop_playback(&mut self) {
+        self.transport = Transport::Stopped;
+        self.read_frame = 0;
+    }
+
+    fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// How `run_threads` should schedule the thread it runs us on, see `RtScheduling`
+    fn set_scheduling(&mut self, scheduling: RtScheduling) {
+        self.scheduling = scheduling;
+    }
+
+    /// Fade out over `frames` frames when told to shut down, and only then acknowledge it, so
+    /// cutting off a waveform partway through doesn't click. No more messages are handled while
+    /// fading out. 0 shuts down right away
+    fn set_shutdown_fade(&mut self, frames: usize) {
+        self.shutdown_fade = frames;
+    }
+
+    /// Put the dry input out instead of everything the engine (and the processor) would have,
+    /// for comparing the two. Messages are still handled and playback carries on underneath, so
+    /// switching back is seamless
+    fn set_bypass(&mut self, bypass: bool) {
+        self.bypass = bypass;
+    }
+
+    /// Start playing `samples` as another voice, on top of everything else. Meant to be called
+    /// from `RtMessage::apply`, see `VoiceManager::note_on`
+    fn note_on(&mut self, id: VoiceId, samples: Arc<Samples<N, S>>, gain: f32) {
+        if let Some(old) = self.voices.note_on(id, samples, gain) {
+            self.retire(old);
+        }
+    }
+
+    /// Let go of a voice started by `note_on`. It fades out over the envelope's release, see
+    /// `set_envelope`
+    fn note_off(&mut self, id: VoiceId) {
+        if let Some(old) = self.voices.note_off(id) {
+            self.retire(old);
+        }
+    }
+
+    /// The envelope voices started from now on follow, see `Envelope`
+    fn set_envelope(&mut self, adsr: Adsr) {
+        self.voices.set_envelope(adsr);
+    }
+
+    /// Play `samples` in layer `id` at `gain`, replacing whatever was there. Layers are mixed on
+    /// top of the main samples and follow the same playhead, so a pad, a lead and a click track
+    /// stay in time with each other. Ids outside the layers we have are ignored
+    fn set_layer(&mut self, id: LayerId, samples: Arc<Samples<N, S>>, gain: f32) {
+        let layer = match self.layers.get_mut(id) {
+            Some(layer) => layer,
+            None        => {
+                self.log("[realtime] no such layer:", id as f32);
+                self.retire(samples);
+                return;
+            },
+        };
+
+        if let Some(old) = layer.replace(Layer { samples, gain }) {
+            self.retire(old.samples);
+        }
+    }
+
+    /// Change the gain of layer `id`, if it is playing anything
+    fn set_layer_gain(&mut self, id: LayerId, gain: f32) {
+        if let Some(&mut Some(ref mut layer)) = self.layers.get_mut(id) {
+            layer.gain = gain;
+        }
+    }
+
+    /// Empty layer `id`
+    fn remove_layer(&mut self, id: LayerId) {
+        if let Some(old) = self.layers.get_mut(id).and_then(Option::take) {
+            self.retire(old.samples);
+        }
+    }
+
+    /// Play `table` at `frequency` Hz and `gain`, on top of everything else, replacing any
+    /// wavetable which was playing. Unlike samples, a wavetable can play any note, and carries on
+    /// smoothly when the frequency changes. Meant to be called from `RtMessage::apply`: the UI
+    /// thread holds on to the table, so dropping the old one here never frees it
+    fn play_wavetable(&mut self, table: Arc<Wavetable>, frequency: f32, gain: f32) {
+        let phase = self.wavetable.as_ref().map_or(0.0, |voice| voice.phase);
+        self.wavetable = Some(WavetableVoice { table, frequency, gain, phase });
+    }
+
+    /// Change the frequency the wavetable is playing at, if there is one
+    fn set_wavetable_frequency(&mut self, frequency: f32) {
+        if let Some(ref mut voice) = self.wavetable {
+            voice.frequency = frequency;
+        }
+    }
+
+    /// Stop playing the wavetable, see `play_wavetable`
+    fn stop_wavetable(&mut self) {
+        self.wavetable = None;
+    }
+
+    /// Modulate `target` with an LFO at `rate` Hz, `depth` deep (see `LfoTarget`). Both glide
+    /// from where they were. A depth of 0 turns the LFO off
+    fn set_lfo(&mut self, target: LfoTarget, rate: f32, depth: f32) {
+        match target {
+            LfoTarget::Volume    => self.tremolo.set(rate, depth.clamp(0.0, 1.0)),
+            LfoTarget::Frequency => self.vibrato.set(rate, depth),
+        }
+    }
+
+    /// Make room for delays of up to `frames` frames, see `set_delay`, instead of
+    /// `MAX_DELAY_SECONDS`. This allocates, so call it before the realtime thread starts
+    fn set_max_delay(&mut self, frames: usize) {
+        self.delay = Delay::new(frames);
+        self.max_delay = Some(frames);
+    }
+
+    /// Echo the output `frames` frames later, feeding `feedback` of the echo back in and mixing
+    /// `mix` of it into the output. Everything glides from where it was, and a mix of 0 turns the
+    /// delay off
+    fn set_delay(&mut self, frames: usize, feedback: f32, mix: f32) {
+        self.delay.set(frames, feedback, mix);
+    }
+
+    /// Sweep a delayed copy of the output against itself, see `Chorus`. Everything glides from
+    /// where it was, and a mix of 0 turns the chorus off
+    fn set_chorus(&mut self, settings: ChorusSettings) {
+        self.chorus.set(settings);
+    }
+
+    /// Run the output through `reverb`, which was made (and allocated) on another thread. Call
+    /// it before the realtime thread starts. See `adjust_reverb`
+    fn set_reverb(&mut self, reverb: Reverb) {
+        self.reverb = Some(reverb);
+    }
+
+    /// Change the reverb's room size, damping and mix (see `Reverb`), gliding from where they
+    /// were. A mix of 0 takes the reverb out of the output. Does nothing without a reverb
+    fn adjust_reverb(&mut self, room_size: f32, damping: f32, mix: f32) {
+        match self.reverb {
+            Some(ref mut reverb) => reverb.set(room_size, damping, mix),
+            None                 => self.log("[realtime] no reverb to adjust, room size:", room_size),
+        }
+    }
+
+    /// Filter the output with `coefficients`, fading over from the old ones. Passthrough
+    /// coefficients fade the filter out, see `BiquadCoefficients::passthrough`
+    fn set_filter(&mut self, coefficients: BiquadCoefficients) {
+        self.filter.set_coefficients(coefficients);
+    }
+
+    // private. adds the wavetable into frames [from, to) of the output, in every channel
+    fn mix_wavetable(&mut self, output_samples: &mut [S], from: usize, to: usize) {
+        let voice = match self.wavetable {
+            Some(ref mut voice) => voice,
+            None                => return,
+        };
+
+        for frame in output_samples[from * CHANNELS..to * CHANNELS].chunks_mut(CHANNELS) {
+            let frequency = if self.vibrato.is_active() {
+                voice.frequency * self.vibrato.next_ratio(self.sample_rate)
+            } else {
+                voice.frequency
+            };
+
+            let value = voice.table.read(voice.phase, frequency) * voice.gain;
+            for sample in frame.iter_mut() {
+                *sample = S::from_f32(sample.to_f32() + value);
+            }
+
+            voice.phase = (voice.phase + frequency / self.sample_rate as f32).fract();
+        }
+    }
+
+    // private. adds every layer into frames [from, to) of the output
+    fn mix_layers(&self, output_samples: &mut [S], from: usize, to: usize) {
+        for layer in self.layers.iter().flatten() {
+            for frame in from..to {
+                let read = match self.source_frame(frame) {
+                    Some(read) => read,
+                    None       => break,
+                };
+
+                let out = &mut output_samples[frame * CHANNELS..(frame + 1) * CHANNELS];
+                for (out, sample) in out.iter_mut().zip(layer.samples[read].iter()) {
+                    *out = S::from_f32(out.to_f32() + sample.to_f32() * layer.gain);
+                }
+            }
+        }
+    }
+
+    /// Start playing `samples`. Meant to be called from `RtMessage::apply`
+    fn play(&mut self, samples: Arc<Samples<N, S>>) {
+        self.log("[realtime] received new samples. Second sample:", samples[1][0].to_f32());
+        self.source_silent = is_silent(samples.as_flattened());
+
+        // the collector (or the return queue) is also holding the old samples, so this never
+        // frees them
+        let mut old = self.current_samples.replace(samples);
+
+        // if we were playing from the triple buffer the old samples haven't been heard in a while
+        if self.use_published {
+            if let Some(old) = old.take() {
+                self.retire(old);
+            }
+        }
+
+        // fade out of whatever we were playing, silence included. A fade which is still going
+        // gets cut short
+        if self.fade_frames > 0 {
+            old = mem::replace(&mut self.fading, old);
+            self.fade_done = 0;
+        }
+
+        if let Some(old) = old {
+            self.retire(old);
+        }
+
+        // every new set of samples is another shot
+        if self.playback_mode == PlaybackMode::OneShot {
+            self.read_frame = 0;
+        }
+
+        self.use_published = false;
+        self.report(Feedback::BufferApplied);
+    }
+
+    // private. hands samples we're done with back to the UI thread, see `set_returns`.
+    // We may be holding the last reference, so dropping them here could free them in the
+    // callback. If there's nowhere to send them right now they are parked in `retired` and sent
+    // on by a later callback, see `flush_retired`
+    fn retire(&mut self, samples: Arc<Samples<N, S>>) {
+        let samples = match self.hand_off(samples) {
+            Ok(())       => return,
+            Err(samples) => samples,
+        };
+
+        if self.retired.len() < self.retired.capacity() {
+            self.retired.push(samples);
+            return;
+        }
+
+        // last resort, this one may well be freed right here
+        self.log("[realtime] nowhere to put retired samples, dropping them. Parked:", self.retired.len() as f32);
+    }
+
+    // private. passes samples to the UI thread, or failing that the collector. Hands them back
+    // if neither has room
+    fn hand_off(&mut self, samples: Arc<Samples<N, S>>) -> Result<(), Arc<Samples<N, S>>> {
+        let samples = match self.returns {
+            Some(ref mut returns) => match returns.try_push(samples) {
+                Ok(())       => return Ok(()),
+                Err(samples) => samples,
+            },
+
+            // the collector is already tracking everything sent to us
+            None => return Ok(()),
+        };
+
+        // nobody is tracking returned samples, so if the return queue is full they go to the
+        // collector instead
+        match self.collector {
+            Some(ref collector) => collector.try_track(samples),
+            None                => Err(samples),
+        }
+    }
+
+    // private. tries again to send on samples `retire` had to park, oldest first
+    fn flush_retired(&mut self) {
+        let mut sent = 0;
+        while sent < self.retired.len() {
+            let samples = self.retired[sent].clone();
+            // the clone keeps the parked copy from being the last reference if this fails
+            if self.hand_off(samples).is_err() {
+                break;
+            }
+            sent += 1;
+        }
+
+        // everything sent has a reference somewhere else now, so these drops never free
+        self.retired.drain(..sent);
+    }
+
+    /// Where playback is up to, see `Playhead`
+    fn playhead(&self) -> Playhead {
+        Playhead {
+            position: self.position,
+            frame:    self.source_frame(0),
+            mode:     self.playback_mode,
+        }
+    }
+
+    /// Glitches counted so far, see `XrunCounters`
+    fn xruns(&self) -> XrunCounters {
+        self.xruns
+    }
+
+    /// Smoothed CPU load, see `Feedback::Load`
+    fn cpu_load(&self) -> f32 {
+        self.load
+    }
+
+    // private. folds the time this callback took into the load figure. The host has to play
+    // `frames` frames in this long, so that's all the time we have. Returns the unsmoothed load
+    fn measure_load(&mut self, started: Instant, frames: usize) -> f32 {
+        let period = frames as f32 / self.sample_rate as f32;
+        if period <= 0.0 {
+            return 0.0;
+        }
+
+        let load = started.elapsed().as_secs_f32() / period;
+        self.load += (load - self.load) * LOAD_SMOOTHING;
+        load
+    }
+
+    // private. folds a callback's output into the levels, see `Levels`. Returns the loudest sample
+    fn meter(&mut self, output_samples: &[S]) -> f32 {
+        let mut loudest = 0.0f32;
+        for frame in output_samples.chunks(CHANNELS) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = sample.to_f32();
+                self.meter_peak[channel] = self.meter_peak[channel].max(sample.abs());
+                self.meter_squares[channel] += sample * sample;
+                loudest = loudest.max(sample.abs());
+            }
+        }
+
+        self.meter_frames += output_samples.len() / CHANNELS;
+        loudest
+    }
+
+    // private. the levels since the last call, then starts measuring again
+    fn take_levels(&mut self) -> Levels {
+        let mut levels = Levels { peak: self.meter_peak, rms: [0.0; CHANNELS] };
+        if self.meter_frames > 0 {
+            for (rms, squares) in levels.rms.iter_mut().zip(self.meter_squares.iter()) {
+                *rms = (squares / self.meter_frames as f32).sqrt();
+            }
+        }
+
+        self.meter_peak = [0.0; CHANNELS];
+        self.meter_squares = [0.0; CHANNELS];
+        self.meter_frames = 0;
+        levels
+    }
+
+    /// Count ourselves as overloaded after `callbacks` callbacks in a row run over budget
+    fn set_overload_threshold(&mut self, callbacks: u64) {
+        assert!(callbacks > 0);
+        self.overload_after = callbacks;
+    }
+
+    /// What to do about it when we're overloaded, see `OverloadPolicy`
+    fn set_overload_policy(&mut self, policy: OverloadPolicy) {
+        self.overload_policy = policy;
+    }
+
+    // private. keeps track of how many callbacks in a row have run over budget, and reacts once
+    // there have been too many. A single slow callback is only a glitch, a streak of them means
+    // the callback can't keep up
+    fn watch_deadline(&mut self, over_budget: bool) {
+        if !over_budget {
+            self.overruns = 0;
+            return;
+        }
+
+        self.overruns += 1;
+        if !self.overruns.is_multiple_of(self.overload_after) {
+            return;
+        }
+
+        let overruns = self.overruns;
+        self.report(Feedback::Overloaded(overruns));
+
+        if self.overload_policy == OverloadPolicy::ShedVoices {
+            if let Some(old) = self.voices.shed_oldest() {
+                self.retire(old);
+            }
+        }
+    }
+
+    /// Report the current state back to the UI thread, see `Feedback::Snapshot`.
+    /// Never allocates, the snapshot is copied straight into the feedback queue
+    fn snapshot(&mut self) {
+        let snapshot = EngineSnapshot {
+            buffer_id:     self.current_samples.as_ref().map(|samples| &**samples as *const Samples<N, S> as usize),
+            use_published: self.use_published,
+            position:      self.position,
+            callbacks:     self.callbacks,
+            underrun:      self.underrun,
+            waiting:       self.waiting.is_some() || !self.events.is_empty(),
+            xruns:         self.xruns,
+            transport:     self.transport,
+        };
+
+        self.report(Feedback::Snapshot(snapshot));
+    }
+
+    /// Answer `Message::DumpState`, if there's somewhere to put the answer
+    fn set_state_dump(&mut self, state_dump: TripleWriter<StateDump>) {
+        self.state_dump = Some(state_dump);
+    }
+
+    /// Copy our whole state into the triple buffer given to `set_state_dump`, and let the UI
+    /// thread know it's there. Never allocates
+    fn dump_state(&mut self) {
+        let id = |samples: &Arc<Samples<N, S>>| &**samples as *const Samples<N, S> as usize;
+        let dump = StateDump {
+            buffer_id:     self.current_samples.as_ref().map(id),
+            fading_id:     self.fading.as_ref().map(id),
+            use_published: self.use_published,
+            source_silent: self.source_silent,
+            read_frame:    self.read_frame,
+            playback_mode: self.playback_mode,
+            transport:     self.transport,
+            position:      self.position,
+            callbacks:     self.callbacks,
+            next_seq:      self.next_seq,
+            incoming:      self.incoming.occupancy(),
+            waiting:       self.waiting.is_some(),
+            scheduled:     self.events.len(),
+            voices:        self.voices.active(),
+            layers:        self.layers.iter().flatten().count(),
+            volume:        self.volume.value(),
+            volume_target: self.volume.target(),
+            fade_done:     self.fade_done.min(self.fade_frames),
+            fade_frames:   self.fade_frames,
+            underrun:      self.underrun,
+            xruns:         self.xruns,
+            load:          self.load,
+            sample_rate:   self.sample_rate,
+            bypass:        self.bypass,
+        };
+
+        match self.state_dump {
+            Some(ref mut state_dump) => state_dump.write(dump),
+            None                     => return,
+        }
+
+        self.report(Feedback::StateDumped);
+    }
+
+    /// Run `callbacks` callbacks against scratch buffers, then put everything back the way it was,
+    /// so the first real callback doesn't pay for page faults or anything initialised lazily.
+    /// Nothing is reported, nothing queued up is picked up, and the processor (whose state we
+    /// can't put back) isn't run. Run it before the device starts and before any voices start
+    fn warm_up(&mut self, callbacks: usize) {
+        // touch the memory set aside for later
+        for slot in self.events.spare_capacity_mut() {
+            *slot = MaybeUninit::zeroed();
+        }
+        self.voices.warm_up();
+        self.delay.line.clear();
+        self.chorus.line.clear();
+        if let Some(ref mut reverb) = self.reverb {
+            reverb.clear();
+        }
+        for slot in self.retired.spare_capacity_mut() {
+            *slot = MaybeUninit::zeroed();
+        }
+
+        // then go through the motions, without telling anyone or taking anything in
+        let feedback = self.feedback.take();
+        let processor = self.processor.take();
+        let published = self.published.take();
+        let sample_slot = self.sample_slot.take();
+        let events = mem::take(&mut self.events);
+        let max_messages = mem::replace(&mut self.max_messages, 0);
+
+        // remembering everything a callback moves on
+        let volume = self.volume;
+        let transport = self.transport;
+        let read_frame = self.read_frame;
+        let fading = (self.fading.take(), self.fade_done);
+        let lfos = (self.tremolo, self.vibrato, self.chorus.lfo);
+        let filter = self.filter;
+        let delay = (self.delay.time, self.delay.feedback, self.delay.mix);
+        let chorus = (self.chorus.delay, self.chorus.feedback, self.chorus.mix);
+        let reverb = self.reverb.as_ref().map(|reverb| (reverb.room_size, reverb.damping, reverb.mix));
+        let wavetable = self.wavetable.as_ref().map(|voice| voice.phase);
+
+        let input = silence::<N, S>();
+        let mut output = silence::<N, S>();
+        for callback in 0..callbacks {
+            let info = CallbackInfo {
+                frame_count:   N,
+                sample_time:   (callback * N) as u64,
+                wall_deadline: None,
+            };
+
+            let _ = self.realtime_callback(info, input.as_flattened(), output.as_flattened_mut());
+        }
+
+        // and start over
+        self.feedback = feedback;
+        self.processor = processor;
+        self.published = published;
+        self.sample_slot = sample_slot;
+        self.events = events;
+        self.max_messages = max_messages;
+
+        self.volume = volume;
+        self.transport = transport;
+        self.read_frame = read_frame;
+        (self.fading, self.fade_done) = fading;
+        (self.tremolo, self.vibrato, self.chorus.lfo) = lfos;
+        self.filter = filter;
+        (self.delay.time, self.delay.feedback, self.delay.mix) = delay;
+        (self.chorus.delay, self.chorus.feedback, self.chorus.mix) = chorus;
+        if let (Some(ref mut reverb), Some(params)) = (self.reverb.as_mut(), reverb) {
+            (reverb.room_size, reverb.damping, reverb.mix) = params;
+        }
+        if let (Some(ref mut voice), Some(phase)) = (self.wavetable.as_mut(), wavetable) {
+            voice.phase = phase;
+        }
+
+        // whatever went through the effects was never heard
+        self.delay.line.clear();
+        self.chorus.line.clear();
+        if let Some(ref mut reverb) = self.reverb {
+            reverb.clear();
+        }
+
+        self.position = 0;
+        self.callbacks = 0;
+        self.overruns = 0;
+        self.peak = 0.0;
+        self.underrun = false;
+        self.xruns = XrunCounters::default();
+        self.reported_xruns = 0;
+        self.next_time = None;
+        self.load = 0.0;
+        self.take_levels();
+    }
+
+    // private. applies a single message
+    fn handle_message(&mut self, message: M) -> CallbackStatus {
+        if message.is_shutdown() {
+            return CallbackStatus::Shutdown;
+        }
+
+        message.apply(self);
+        CallbackStatus::Continue
+    }
+
+    // private. starts fading out for a shutdown, see `set_shutdown_fade`. Returns true if there's
+    // no fade, and the shutdown should be acknowledged right away
+    fn begin_shutdown(&mut self) -> bool {
+        if self.shutdown_fade == 0 {
+            return true;
+        }
+
+        if self.tail.is_none() {
+            self.tail = Some(self.shutdown_fade);
+        }
+
+        false
+    }
+
+    // private. ramps the output down towards silence, carrying on from wherever the last
+    // callback got to. Anything past the end of the ramp is silence
+    fn fade_out(&mut self, output_samples: &mut [S]) {
+        let mut left = match self.tail {
+            Some(left) => left,
+            None       => return,
+        };
+
+        for frame in output_samples.chunks_mut(CHANNELS) {
+            let gain = (left as f32 / self.shutdown_fade as f32).min(1.0);
+            for sample in frame.iter_mut() {
+                *sample = S::from_f32(sample.to_f32() * gain);
+            }
+
+            left = left.saturating_sub(1);
+        }
+
+        self.tail = Some(left);
+    }
+
+    // private. acknowledges the shutdown. Unlike other feedback this is never dropped, if the
+    // queue is full the oldest event makes way for it
+    fn stop(&mut self) -> CallbackStatus {
+        if let Some(ref mut feedback) = self.feedback {
+            feedback.force_push(Feedback::Stopped);
+        }
+
+        CallbackStatus::Shutdown
+    }
+
+    // private. like `stop`, but tells the UI thread why
+    fn fail(&mut self, error: EngineError) -> CallbackStatus {
+        if let Some(ref mut feedback) = self.feedback {
+            feedback.force_push(Feedback::Failed(error));
+        }
+
+        CallbackStatus::Error(error)
+    }
+
+    // private. the message held back for lack of room in the schedule goes first, then the UI
+    // thread's, then anything submitted by workers
+    fn next_message(&mut self) -> Option<M> {
+        if let Some(message) = self.waiting.take() {
+            return Some(message);
+        }
+
+        if let Some(sequenced) = self.incoming.try_pop() {
+            self.check_sequence(sequenced.seq);
+            return Some(sequenced.message);
+        }
+
+        self.submissions.as_ref().and_then(|submissions| submissions.queue.pop())
+    }
+
+    // private. parks a message until the callback which plays frame `due`. The schedule is kept
+    // sorted latest first, so the next event is always at the end. Hands the message back if the
+    // schedule is full, it never grows past the capacity it was given
+    fn schedule(&mut self, due: u64, message: M) -> Result<(), M> {
+        if self.events.len() == self.events.capacity() {
+            return Err(message);
+        }
+
+        // messages due at the same time go in the order they were sent
+        let index = self.events.partition_point(|&(scheduled, _)| scheduled > due);
+        self.events.insert(index, (due, message));
+        Ok(())
+    }
+
+    // private. the next scheduled message, if it's due before frame `end`
+    fn next_event(&mut self, end: u64) -> Option<(u64, M)> {
+        match self.events.last() {
+            Some(&(due, _)) if due < end => self.events.pop(),
+            _                            => None,
+        }
+    }
+
+    // private. reports any messages which were dropped between the last one we saw and `seq`
+    fn check_sequence(&mut self, seq: u64) {
+        if seq > self.next_seq {
+            let missed = seq - self.next_seq;
+            self.report(Feedback::Gap(missed));
+        }
+
+        self.next_seq = self.next_seq.max(seq + 1);
+    }
+
+    // private. the frame of the samples which plays `offset` frames into this callback, None if
+    // a one shot has run out
+    fn source_frame(&self, offset: usize) -> Option<usize> {
+        let cursor = self.read_frame + offset;
+        match self.playback_mode {
+            PlaybackMode::OneShot  => if cursor < N { Some(cursor) } else { None },
+            PlaybackMode::Loop     => Some(cursor % N),
+            PlaybackMode::PingPong => {
+                // the first and last frames aren't repeated when turning around
+                let period = (2 * N - 2).max(1);
+                let cursor = cursor % period;
+                Some(if cursor < N { cursor } else { period - cursor })
+            },
+        }
+    }
+
+    // private. moves the read cursor on past this callback's frames
+    fn advance(&mut self, frames: usize) {
+        let cursor = self.read_frame + frames;
+        self.read_frame = match self.playback_mode {
+            PlaybackMode::OneShot  => cursor.min(N),
+            PlaybackMode::Loop     => cursor % N,
+            PlaybackMode::PingPong => cursor % (2 * N - 2).max(1),
+        };
+    }
+
+    // private. fills frames [from, to) of the output with the samples we are currently playing,
+    // starting wherever the last callback left off and carrying on according to the playback
+    // mode, then mixes in the voices and applies the volume. The output can be any length
+    fn render(&mut self, output_samples: &mut [S], from: usize, to: usize) {
+        if self.transport != Transport::Playing {
+            output_samples[from * CHANNELS..to * CHANNELS].fill(S::SILENCE);
+            return;
+        }
+
+        let source = if self.use_published {
+            self.published.as_ref().map(|published| published.read())
+        } else {
+            // samples: &Arc<[[S; CHANNELS]; N]>
+            self.current_samples.as_ref().map(|samples| samples.as_ref())
+        };
+
+        match source {
+            Some(source) if !self.source_silent => for frame in from..to {
+                let out = &mut output_samples[frame * CHANNELS..(frame + 1) * CHANNELS];
+                match self.source_frame(frame) {
+                    Some(read) => out.copy_from_slice(&source[read]),
+                    None       => out.copy_from_slice(&[S::SILENCE; CHANNELS]),
+                }
+            },
+
+            // nothing worth copying, so fill the whole range in one go
+            _ => output_samples[from * CHANNELS..to * CHANNELS].fill(S::SILENCE),
+        }
+
+        self.crossfade(output_samples, from, to);
+        self.mix_layers(output_samples, from, to);
+        self.voices.mix(output_samples, from, to);
+        self.mix_wavetable(output_samples, from, to);
+
+        if self.filter.is_active() {
+            self.filter.process(&mut output_samples[from * CHANNELS..to * CHANNELS]);
+        }
+
+        self.chorus.process(&mut output_samples[from * CHANNELS..to * CHANNELS], self.sample_rate);
+        self.delay.process(&mut output_samples[from * CHANNELS..to * CHANNELS]);
+        if let Some(ref mut reverb) = self.reverb {
+            reverb.process(&mut output_samples[from * CHANNELS..to * CHANNELS]);
+        }
+
+        // nothing to do at full volume
+        if self.volume.is_smoothing() || self.volume.value() != 1.0 || self.tremolo.is_active() {
+            for frame in output_samples[from * CHANNELS..to * CHANNELS].chunks_mut(CHANNELS) {
+                let mut gain = self.volume.next();
+                if self.tremolo.is_active() {
+                    gain *= self.tremolo.next_gain(self.sample_rate);
+                }
+
+                for sample in frame.iter_mut() {
+                    *sample = S::from_f32(sample.to_f32() * gain);
+                }
+            }
+        }
+    }
+
+    // private. blends the samples we are fading out of into the start of frames [from, to),
+    // which already hold the new samples. See `set_crossfade`
+    fn crossfade(&mut self, output_samples: &mut [S], from: usize, to: usize) {
+        if self.fade_done >= self.fade_frames {
+            return;
+        }
+
+        let mut frame = from;
+        while frame < to && self.fade_done < self.fade_frames {
+            let t = self.fade_done as f32 / self.fade_frames as f32;
+            let read = self.source_frame(frame);
+            for (c, out) in output_samples[frame * CHANNELS..(frame + 1) * CHANNELS].iter_mut().enumerate() {
+                let old = match (self.fading.as_ref(), read) {
+                    (Some(old), Some(read)) => old[read][c].to_f32(),
+                    _                       => 0.0,
+                };
+                *out = S::from_f32(old * (1.0 - t) + out.to_f32() * t);
+            }
+
+            self.fade_done += 1;
+            frame += 1;
+        }
+
+        // done with the old samples
+        if self.fade_done >= self.fade_frames {
+            if let Some(old) = self.fading.take() {
+                self.retire(old);
+            }
+        }
+    }
+
+    /// realtime callback, called to get the list of samples.
+    /// The host decides how many frames it wants each time: `output_samples` is interleaved like
+    /// `Samples`, but can be any whole number of frames long, as long as it matches
+    /// `info.frame_count`
+    fn realtime_callback(&mut self, info: CallbackInfo, input_samples: &[S], output_samples: &mut [S]) -> CallbackStatus {
+        let started = Instant::now();
+
+        // shutdown jumps the queue
+        let shutdown = self.shutdown.as_ref().is_some_and(|shutdown| shutdown.load(Ordering::Acquire));
+        if shutdown && self.begin_shutdown() {
+            return self.stop();
+        }
+
+        if output_samples.len() != info.frame_count * CHANNELS {
+            return self.fail(EngineError::BadBuffer(output_samples.len()));
+        }
+
+        if !self.retired.is_empty() {
+            self.flush_retired();
+        }
+
+        // apply everything that has piled up since the last callback, so the latest state is heard
+        // right away. The cap bounds how long a burst of messages can hold up the callback.
+        // Messages with a due time are scheduled instead, see below
+        let frames = info.frame_count;
+        let end = self.position + frames as u64;
+        for _ in 0..self.max_messages {
+            // once we're on the way out, nothing else gets a look in
+            if self.tail.is_some() {
+                break;
+            }
+
+            let message = match self.next_message() {
+                // we've received a messaged
+                Some(message) => message,
+
+                // nothing left, just keep sending samples
+                None => break
+            };
+
+            // if the schedule is full, hold on to the message (and everything queued behind it)
+            // until there's room
+            if let Some(due) = message.due().filter(|&due| due > self.position) {
+                if let Err(message) = self.schedule(due, message) {
+                    self.waiting = Some(message);
+                    break;
+                }
+
+                continue;
+            }
+
+            // anything already late takes effect right away
+            if self.handle_message(message) == CallbackStatus::Shutdown && self.begin_shutdown() {
+                return self.stop();
+            }
+        }
+
+        // scheduled messages split the buffer, so they take effect on exactly the right sample.
+        // Everything before that sample is rendered with the old state
+        let mut rendered = 0;
+        while self.tail.is_none() {
+            let (due, message) = match self.next_event(end) {
+                Some(event) => event,
+                None        => break,
+            };
+
+            let offset = due.saturating_sub(self.position) as usize;
+            if offset > rendered {
+                self.render(output_samples, rendered, offset);
+                rendered = offset;
+            }
+
+            if self.handle_message(message) == CallbackStatus::Shutdown && self.begin_shutdown() {
+                return self.stop();
+            }
+        }
+
+        // pick up samples swapped in by the UI thread. Whatever we were playing before is
+        // being held by the collector, so letting go of it here never frees it
+        let swapped = self.sample_slot.as_ref().and_then(|slot| slot.take());
+        if let Some(samples) = swapped {
+            self.play(samples);
+        }
+
+        // pick up the latest published samples, if there are any
+        if let Some(ref mut published) = self.published {
+            if published.update() {
+                self.use_published = true;
+                self.source_silent = is_silent(published.read().as_flattened());
+            }
+        }
+
+        // copy our current samples into the rest of the output buffer
+        self.render(output_samples, rendered, frames);
+
+        // nothing moves while we're paused
+        if self.transport == Transport::Playing {
+            self.advance(frames);
+            self.voices.advance(frames);
+        }
+
+        // voices which have faded out are done with
+        while let Some(old) = self.voices.reap() {
+            self.retire(old);
+        }
+
+        // bypassed, the dry input goes straight out instead. Everything above still ran, so
+        // switching back picks up right where it would have been. Without an input device that
+        // means silence
+        if self.bypass {
+            let dry = input_samples.len().min(output_samples.len());
+            output_samples[..dry].copy_from_slice(&input_samples[..dry]);
+            output_samples[dry..].fill(S::SILENCE);
+        }
+
+        // let the input through, if we're monitoring it
+        if !self.bypass && self.monitor_gain != 0.0 {
+            for (out, input) in output_samples.iter_mut().zip(input_samples.iter()) {
+                *out = S::from_f32(out.to_f32() + input.to_f32() * self.monitor_gain);
+            }
+        }
+
+        // then let the user's DSP have a go at it. If it panics the panic stops here, with
+        // silence instead of whatever it left half written, and the host decides what to do
+        let mut panicked = false;
+        let processor = if self.bypass { None } else { self.processor.as_mut() };
+        if let Some(processor) = processor {
+            // the panic hook keeps quiet about it, see `install_quiet_panic_hook`
+            QUIET_PANICS.with(|quiet| quiet.set(true));
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                processor.process(info, input_samples, output_samples)
+            }));
+            QUIET_PANICS.with(|quiet| quiet.set(false));
+
+            match result {
+                Ok(CallbackStatus::Continue)  => (),
+                Ok(CallbackStatus::KeepQuiet) => (),
+                // fades out first, like being told to by the UI thread
+                Ok(CallbackStatus::Shutdown)  => {
+                    if self.begin_shutdown() {
+                        return self.stop();
+                    }
+                },
+                Ok(CallbackStatus::Error(e))  => return self.fail(e),
+                Err(_) => {
+                    for sample in output_samples.iter_mut() {
+                        *sample = S::SILENCE;
+                    }
+
+                    panicked = true;
+                },
+            }
+        }
+
+        if panicked {
+            self.report(Feedback::Panicked);
+        }
+
+        // on the way out, ramp down to silence so stopping doesn't click. Once we get there,
+        // acknowledge the shutdown
+        if self.tail.is_some() {
+            self.fade_out(output_samples);
+            if self.tail == Some(0) {
+                return self.stop();
+            }
+        }
+
+        // only report an underrun when we first run out of things to play
+        let underrun = self.transport == Transport::Playing
+            && !self.use_published && self.current_samples.is_none() && self.voices.is_empty()
+            && self.layers.iter().all(Option::is_none) && self.wavetable.is_none();
+        if underrun && !self.underrun {
+            self.report(Feedback::Underrun);
+        }
+        self.underrun = underrun;
+
+        // count every glitch though
+        if underrun {
+            self.xruns.silent += 1;
+        }
+
+        if self.next_time.is_some_and(|next| next != info.sample_time) {
+            self.xruns.skipped += 1;
+        }
+        self.next_time = Some(info.sample_time + frames as u64);
+
+        // only look at the clock if the host has given us something to compare it to
+        let late = info.wall_deadline.is_some_and(|deadline| Instant::now() > deadline);
+        if late {
+            self.xruns.late += 1;
+        }
+
+        let load = self.measure_load(started, frames);
+        self.watch_deadline(late || load > 1.0);
+
+        self.position += frames as u64;
+        let loudest = self.meter(output_samples);
+        self.peak = self.peak.max(loudest);
+
+        self.callbacks += 1;
+        if self.callbacks.is_multiple_of(self.feedback_every) {
+            let (position, peak, playhead) = (self.position, self.peak, self.playhead());
+            self.report(Feedback::Position(position));
+            self.report(Feedback::Playhead(playhead));
+            self.report(Feedback::Load(self.load));
+            self.report(Feedback::Peak(peak));
+            self.peak = 0.0;
+
+            let levels = self.take_levels();
+            self.report(Feedback::Levels(levels));
+
+            let xruns = self.xruns;
+            if xruns.total() != self.reported_xruns {
+                self.report(Feedback::Xruns(xruns));
+                self.reported_xruns = xruns.total();
+            }
+        }
+
+        if panicked {
+            return CallbackStatus::Error(EngineError::Panicked);
+        }
+
+        if loudest == 0.0 {
+            return CallbackStatus::KeepQuiet;
+        }
+
+        CallbackStatus::Continue
+    }
+}
+
+thread_local! {
+    // true while the realtime thread is running the user's processor
+    static QUIET_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+// private. puts a panic hook in front of whichever one was there, which says nothing about panics
+// in the user's processor. The default hook prints (and might capture a backtrace) on the
+// realtime thread, and the panic is reported with `Feedback::Panicked` anyway. Panics anywhere
+// else go to the old hook. Only the first call does anything
+fn install_quiet_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !QUIET_PANICS.with(Cell::get) {
+                previous(info);
+            }
+        }));
+    });
+}
+
+impl<M: RtMessage<N, S>, const N: usize, S: Sample> RealtimeCallback<S> for RealtimeThread<M, N, S> {
+    /// See `realtime_callback`
+    fn process(&mut self, info: CallbackInfo, input: &[S], out: &mut [S]) -> CallbackStatus {
+        self.realtime_callback(info, input, out)
+    }
+}
+
+// how long `UIThread::run` waits for the realtime thread to acknowledge a shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// frequency of the sine wave the UI thread makes, unless picked otherwise. One cycle every 64
+// frames at 48kHz
+const DEFAULT_FREQUENCY: f32 = 750.0;
+
+// volume of the samples the interactive mode sends until it is told otherwise
+const DEFAULT_VOLUME: f32 = 0.5;
+
+/// The frequency of a MIDI note number, in Hz. Equal temperament, with note 69 (the A above
+/// middle C) at 440Hz
+fn note_frequency(note: u8) -> f32 {
+    440.0 * 2.0f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+// how often `UIThread::run_commands` checks on the realtime thread while there are no commands
+const COMMAND_POLL: Duration = Duration::from_millis(10);
+
+/// What the UI thread has heard from the realtime thread, published for anything which wants to
+/// show it. See `UIThread::set_telemetry`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Telemetry {
+    /// total number of frames produced so far
+    position: u64,
+    levels:   Levels,
+    /// see `Feedback::Load`
+    load:     f32,
+    xruns:    XrunCounters,
+}
+
+// number of characters in each channel's bar on the meter, see `Telemetry`'s Display
+const METER_WIDTH: usize = 20;
+
+// how often main asks the UI thread to print the meter, with --meter
+const METER_EVERY: Duration = Duration::from_millis(100);
+
+impl fmt::Display for Telemetry {
+    /// One line with a bar (the RMS level) and the peak in dBFS for every channel, then the load
+    /// and glitch count
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (channel, (peak, rms)) in self.levels.peak.iter().zip(self.levels.rms.iter()).enumerate() {
+            let filled = ((rms.min(1.0) * METER_WIDTH as f32).round() as usize).min(METER_WIDTH);
+            let peak_db = 20.0 * peak.log10();
+            write!(f, "{} [{}{}] {:6.1} dB  ", channel, "#".repeat(filled), ".".repeat(METER_WIDTH - filled), peak_db.max(-99.9))?;
+        }
+
+        write!(f, "load {:3.0}%  xruns {}", self.load * 100.0, self.xruns.total())
+    }
+}
+
+/// The shape of the wave the UI thread makes, see `UIThread::set_waveform`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Waveform {
+    #[default]
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    /// The waveform called `name`, as written by `name`
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sine"     => Some(Waveform::Sine),
+            "square"   => Some(Waveform::Square),
+            "saw"      => Some(Waveform::Saw),
+            "triangle" => Some(Waveform::Triangle),
+            "noise"    => Some(Waveform::Noise),
+            _          => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Waveform::Sine     => "sine",
+            Waveform::Square   => "square",
+            Waveform::Saw      => "saw",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise    => "noise",
+        }
+    }
+
+    /// A new oscillator making this waveform
+    fn oscillator(&self) -> WaveOscillator {
+        match *self {
+            Waveform::Sine     => WaveOscillator::Sine(Sine),
+            Waveform::Square   => WaveOscillator::Square(PolyBlepSquare),
+            Waveform::Saw      => WaveOscillator::Saw(PolyBlepSaw),
+            Waveform::Triangle => WaveOscillator::Triangle(Triangle),
+            Waveform::Noise    => WaveOscillator::Noise(Noise::new()),
+        }
+    }
+}
+
+/// A parameter a MIDI controller can be bound to, see `ControlMap`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Parameter {
+    Volume,
+    Frequency,
+    Waveform,
+}
+
+impl Parameter {
+    /// The parameter called `name`, as written by `name`. These are the keys of `SynthParams`
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "volume"    => Some(Parameter::Volume),
+            "frequency" => Some(Parameter::Frequency),
+            "waveform"  => Some(Parameter::Waveform),
+            _           => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Parameter::Volume    => "volume",
+            Parameter::Frequency => "frequency",
+            Parameter::Waveform  => "waveform",
+        }
+    }
+
+    /// The command setting this parameter for a controller at `value`, from 0 to 127. The volume
+    /// goes from silent to full, the frequency follows MIDI notes, and the waveforms take a
+    /// fifth of the range each
+    fn command<const N: usize, S: Sample>(&self, value: u8) -> UiCommand<N, S> {
+        let value = value.min(127);
+        match *self {
+            Parameter::Volume    => UiCommand::SetVolume(value as f32 / 127.0),
+            Parameter::Frequency => UiCommand::SetFrequency(note_frequency(value)),
+            Parameter::Waveform  => UiCommand::SetWaveform(match value as usize * 5 / 128 {
+                0 => Waveform::Sine,
+                1 => Waveform::Square,
+                2 => Waveform::Saw,
+                3 => Waveform::Triangle,
+                _ => Waveform::Noise,
+            }),
+        }
+    }
+}
+
+// number of MIDI controllers
+const CONTROLLERS: usize = 128;
+
+/// Which MIDI controller moves which parameter. Controllers are bound by asking the map to
+/// `learn` a parameter and then moving a knob, see `UiCommand::Learn`
+#[derive(Clone, Debug)]
+struct ControlMap {
+    bindings: [Option<Parameter>; CONTROLLERS],
+    learning: Option<Parameter>,
+}
+
+impl ControlMap {
+    fn new() -> Self {
+        ControlMap {
+            bindings: [None; CONTROLLERS],
+            learning: None,
+        }
+    }
+
+    /// Bind `parameter` to the next controller which moves, in place of whatever was bound to it
+    fn learn(&mut self, parameter: Parameter) {
+        self.learning = Some(parameter);
+    }
+
+    /// Bind `controller` to `parameter`. A parameter is moved by at most one controller, so it
+    /// comes away from any other
+    fn bind(&mut self, controller: u8, parameter: Parameter) {
+        for binding in self.bindings.iter_mut().filter(|binding| **binding == Some(parameter)) {
+            *binding = None;
+        }
+
+        self.bindings[controller as usize % CONTROLLERS] = Some(parameter);
+    }
+
+    /// Every controller which is bound, and what to
+    fn bindings(&self) -> Vec<(u8, Parameter)> {
+        self.bindings.iter().enumerate()
+            .filter_map(|(controller, binding)| binding.map(|parameter| (controller as u8, parameter)))
+            .collect()
+    }
+
+    /// Replace every binding with `bindings`
+    fn set_bindings(&mut self, bindings: &[(u8, Parameter)]) {
+        self.bindings = [None; CONTROLLERS];
+        for &(controller, parameter) in bindings {
+            self.bind(controller, parameter);
+        }
+    }
+
+    /// The command for `controller` moving to `value`, binding it first if we are learning.
+    /// None if the controller isn't bound to anything
+    fn translate<const N: usize, S: Sample>(&mut self, controller: u8, value: u8) -> Option<UiCommand<N, S>> {
+        if let Some(parameter) = self.learning.take() {
+            println!("[ui] controller {} moves the {}", controller, parameter.name());
+            self.bind(controller, parameter);
+        }
+
+        self.bindings[controller as usize % CONTROLLERS].map(|parameter| parameter.command(value))
+    }
+}
+
+/// Something the application wants the UI thread to do, see `UIThread::set_commands`. The UI
+/// thread works out which messages the realtime thread needs to make it happen
+enum UiCommand<const N: usize = FRAMES, S: Sample = f32> {
+    /// play the sine wave at this volume, from 0 to 1
+    SetVolume(f32),
+    /// play the sine wave at this frequency, in Hz
+    SetFrequency(f32),
+    /// change the shape of the wave, see `UIThread::set_waveform`
+    SetWaveform(Waveform),
+    /// write the volume, frequency and waveform to a file, see `UIThread::save_preset`
+    SavePreset(PathBuf),
+    /// play with the volume, frequency and waveform from a file, see `UIThread::load_preset`
+    LoadPreset(PathBuf),
+    /// play these samples instead of the sine wave, until the next change of volume or frequency
+    LoadWave(Arc<Samples<N, S>>),
+    /// MIDI controller (the first number) moved to a value from 0 to 127, see `ControlMap`
+    Controller(u8, u8),
+    /// bind the next controller which moves to this parameter
+    Learn(Parameter),
+    /// go back to the volume and frequency from before the last change, see `HISTORY_CAPACITY`
+    Undo,
+    /// put back a change taken away by `Undo`
+    Redo,
+    /// shut the engine down
+    Shutdown,
+}
+
+impl<const N: usize, S: Sample> UiCommand<N, S> {
+    /// The parameter this command sets outright, if any. Undoing, redoing, loading a preset and
+    /// moving a controller all change parameters too, but which ones depends on the UI thread
+    fn parameter(&self) -> Option<Parameter> {
+        match *self {
+            UiCommand::SetVolume(_)    => Some(Parameter::Volume),
+            UiCommand::SetFrequency(_) => Some(Parameter::Frequency),
+            UiCommand::SetWaveform(_)  => Some(Parameter::Waveform),
+            _                          => None,
+        }
+    }
+
+    /// Parse a command as typed by a person: `vol <gain>`, `freq <hz>`, `note <midi note>`,
+    /// `wave <waveform>`, `save <file>`, `load <file>`, `cc <controller> <value>`,
+    /// `learn <parameter>`, `undo`, `redo` or `quit`. There's no way to type out a `LoadWave`
+    fn parse(text: &str) -> io::Result<Self> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let number = |word: &str| word.parse::<f32>().ok();
+        let command = match words[..] {
+            ["vol", volume]   => number(volume).filter(|volume| (0.0..=1.0).contains(volume)).map(UiCommand::SetVolume),
+            ["freq", hz]      => number(hz).filter(|&hz| hz.is_finite() && hz > 0.0).map(UiCommand::SetFrequency),
+            ["note", note]    => number(note).filter(|note| (0.0..=127.0).contains(note))
+                                             .map(|note| UiCommand::SetFrequency(note_frequency(note as u8))),
+            ["wave", name]    => Waveform::parse(name).map(UiCommand::SetWaveform),
+            ["save", path]    => Some(UiCommand::SavePreset(PathBuf::from(path))),
+            ["load", path]    => Some(UiCommand::LoadPreset(PathBuf::from(path))),
+            ["cc", cc, value] => match (cc.parse::<u8>(), value.parse::<u8>()) {
+                (Ok(cc), Ok(value)) if cc < 128 && value < 128 => Some(UiCommand::Controller(cc, value)),
+                _                                              => None,
+            },
+            ["learn", name]   => Parameter::parse(name).map(UiCommand::Learn),
+            ["undo"]          => Some(UiCommand::Undo),
+            ["redo"]          => Some(UiCommand::Redo),
+            ["quit"]          => Some(UiCommand::Shutdown),
+            []                => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no command")),
+            _                 => None,
+        };
+
+        if let Some(command) = command {
+            return Ok(command);
+        }
+
+        let usage = match words[0] {
+            "vol"   => "vol <gain between 0 and 1>",
+            "freq"  => "freq <hz above 0>",
+            "note"  => "note <midi note from 0 to 127>",
+            "wave"  => "wave <sine, square, saw, triangle or noise>",
+            "save"  => "save <file>",
+            "load"  => "load <file>",
+            "cc"    => "cc <controller from 0 to 127> <value from 0 to 127>",
+            "learn" => "learn <volume, frequency or waveform>",
+            "undo"  => "undo",
+            "redo"  => "redo",
+            "quit"  => "quit",
+            other   => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown command {:?}", other))),
+        };
+
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("usage: {}", usage)))
+    }
+}
+
+/// A handle for sending commands to the UI thread, see `UIThread::connect`. Clone it to give each
+/// controller (a window, a network connection, a script...) its own. The UI thread merges what
+/// they all send, and when several change the same parameter at once the last change wins
+struct UiClient<const N: usize = FRAMES, S: Sample = f32> {
+    commands: mpsc::Sender<UiCommand<N, S>>,
+}
+
+impl<const N: usize, S: Sample> UiClient<N, S> {
+    /// Hand `command` to the UI thread. false if the UI thread has gone away
+    fn send(&self, command: UiCommand<N, S>) -> bool {
+        self.commands.send(command).is_ok()
+    }
+}
+
+impl<const N: usize, S: Sample> Clone for UiClient<N, S> {
+    fn clone(&self) -> Self {
+        UiClient { commands: self.commands.clone() }
+    }
+}
+
+/// Commands for the UI thread to carry out at set times, see `UIThread::set_script`.
+/// Written one per line as the number of seconds since the start, then the command (see
+/// `UiCommand::parse`). Blank lines and anything after a `#` are ignored:
+///
+/// ```text
+/// # fade in, then up an octave
+/// 0.0  vol 0.1
+/// 0.5  vol 0.4
+/// 1.0  freq 1500
+/// 2.0  quit
+/// ```
+struct Script<const N: usize = FRAMES, S: Sample = f32> {
+    /// in the order they are carried out
+    events: Vec<(Duration, UiCommand<N, S>)>,
+}
+
+impl<const N: usize, S: Sample> Script<N, S> {
+    /// Read a script from a file
+    fn load(path: &str) -> io::Result<Self> {
+        let mut text = String::new();
+        std::fs::File::open(path)?.read_to_string(&mut text)?;
+        Script::parse(&text)
+    }
+
+    /// Parse a script, see `Script`. Events don't have to be in order
+    fn parse(text: &str) -> io::Result<Self> {
+        let mut events = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let bad_line = |why: &dyn fmt::Display| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, why))
+            };
+
+            let (at, command) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let at = match at.parse::<f32>() {
+                Ok(at) if at >= 0.0 && at.is_finite() => Duration::from_secs_f32(at),
+                _                                     => return Err(bad_line(&"expected a time in seconds")),
+            };
+
+            let command = UiCommand::parse(command).map_err(|e| bad_line(&e))?;
+            events.push((at, command));
+        }
+
+        // stable, so events at the same time keep their order
+        events.sort_by_key(|&(at, _)| at);
+        Ok(Script { events })
+    }
+}
+
+/// Parameters for the wave the UI thread makes, read from a file. Either a preset (see
+/// `UIThread::save_preset`) or a file which can be edited while the engine runs (see
+/// `UIThread::watch_params`). The file is a flat TOML table. Anything left out stays as it was:
+///
+/// ```text
+/// # a quiet A
+/// volume    = 0.25
+/// frequency = 440.0   # or `note = 69`
+/// waveform  = "saw"
+/// cc7       = "volume"  # MIDI controller 7 moves the volume, see `ControlMap`
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+struct SynthParams {
+    /// from 0 to 1
+    volume:    Option<f32>,
+    /// in Hz
+    frequency: Option<f32>,
+    waveform:  Option<Waveform>,
+    /// which MIDI controller moves which parameter. When there are any, they replace every
+    /// binding the UI thread had
+    controls:  Vec<(u8, Parameter)>,
+}
+
+impl SynthParams {
+    fn load(path: &Path) -> io::Result<Self> {
+        let mut text = String::new();
+        std::fs::File::open(path)?.read_to_string(&mut text)?;
+        SynthParams::parse(&text)
+    }
+
+    /// Write every parameter which is set to `path`, in a form `load` can read back
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        if let Some(volume) = self.volume {
+            text.push_str(&format!("volume    = {}\n", volume));
+        }
+
+        if let Some(frequency) = self.frequency {
+            text.push_str(&format!("frequency = {}\n", frequency));
+        }
+
+        if let Some(waveform) = self.waveform {
+            text.push_str(&format!("waveform  = \"{}\"\n", waveform.name()));
+        }
+
+        for &(controller, parameter) in &self.controls {
+            text.push_str(&format!("cc{:<7} = \"{}\"\n", controller, parameter.name()));
+        }
+
+        std::fs::write(path, text)
+    }
+
+    /// Parse parameters, see `SynthParams`. Only `key = number` lines (and `waveform = "name"`,
+    /// `cc<controller> = "parameter"`) are understood
+    fn parse(text: &str) -> io::Result<Self> {
+        let mut params = SynthParams::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let bad_line = |why: &str| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, why))
+            };
+
+            let (key, value) = line.split_once('=').ok_or_else(|| bad_line("expected `key = value`"))?;
+            let (key, value) = (key.trim(), value.trim());
+            if key == "waveform" {
+                let waveform = Waveform::parse(value.trim_matches('"'));
+                params.waveform = Some(waveform.ok_or_else(|| bad_line("waveform must be sine, square, saw, triangle or noise"))?);
+                continue;
+            }
+
+            if let Some(controller) = key.strip_prefix("cc") {
+                let controller = controller.parse::<u8>().ok().filter(|&controller| controller < 128);
+                let controller = controller.ok_or_else(|| bad_line("controllers go from cc0 to cc127"))?;
+                let parameter = Parameter::parse(value.trim_matches('"'));
+                let parameter = parameter.ok_or_else(|| bad_line("controllers move the volume, frequency or waveform"))?;
+                params.controls.push((controller, parameter));
+                continue;
+            }
+
+            let value = value.parse::<f32>().map_err(|_| bad_line("expected a number"))?;
+            match key {
+                "volume" if (0.0..=1.0).contains(&value)        => params.volume = Some(value),
+                "frequency" if value.is_finite() && value > 0.0 => params.frequency = Some(value),
+                "note" if (0.0..=127.0).contains(&value)        => params.frequency = Some(note_frequency(value as u8)),
+
+                "volume"    => return Err(bad_line("volume must be between 0 and 1")),
+                "frequency" => return Err(bad_line("frequency must be a number above 0")),
+                "note"      => return Err(bad_line("note must be between 0 and 127")),
+                other       => return Err(bad_line(&format!("unknown parameter {:?}", other))),
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+// private. a parameter file being watched, see `UIThread::watch_params`
+struct ParamWatch {
+    path:     PathBuf,
+    // when the file was last changed, as of the last time we looked
+    modified: Option<SystemTime>,
+}
+
+// how often `UIThread::run_watching` looks at the parameter file
+const PARAM_POLL: Duration = Duration::from_millis(200);
+
+// number of changes `UiCommand::Undo` can take back. The oldest are forgotten first
+const HISTORY_CAPACITY: usize = 64;
+
+// private. the parameters `UiCommand::Undo` and `UiCommand::Redo` move between
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ParamState {
+    volume:    f32,
+    frequency: f32,
+    waveform:  Waveform,
+}
+
+/// A struct which runs the UI thread and contains all of the data owned by the UI thread
+struct UIThread<M = Message, const N: usize = FRAMES, S: Sample = f32> {
+    outgoing:    Broadcast<Sequenced<M>>,
+    next_seq:    u64,
+    overflow:    OverflowPolicy,
+    publisher:   Option<TripleWriter<Samples<N, S>>>,
+    collector:   Option<GcHandle<Samples<N, S>>>,
+    gc_reports:  Option<mpsc::Receiver<GcReport>>,
+    last_report: Option<GcReport>,
+    feedback:    Option<RingConsumer<Feedback>>,
+    position:    u64,
+    peak:        f32,
+    sample_pool: Option<SamplePool<N, S>>,
+    shutdown:    Option<Arc<AtomicBool>>,
+    sample_slot: Option<Arc<SampleSlot<N, S>>>,
+    stopped:     bool,
+    coalesced:   Vec<M>,
+    snapshot:    Option<EngineSnapshot>,
+    returns:     Option<RingConsumer<Arc<Samples<N, S>>>>,
+    xruns:       XrunCounters,
+    playhead:    Option<Playhead>,
+    load:        f32,
+    sample_rate: u32,
+    frequency:   f32,
+    tone:        Option<(Waveform, Arc<Wavetable>)>,
+    tone_on:     bool,
+    state_dump:  Option<TripleReader<StateDump>>,
+    dump_ready:  bool,
+    levels:      Levels,
+    interactive: bool,
+    volume:      f32,
+    commands:    Option<mpsc::Receiver<UiCommand<N, S>>>,
+    script:      Option<Script<N, S>>,
+    telemetry:   Option<TripleWriter<Telemetry>>,
+    meter_every: Option<Duration>,
+    last_meter:  Option<Instant>,
+    min_volume:  f32,
+    max_volume:  f32,
+    pacing:      Option<Duration>,
+    last_sent:   Option<Instant>,
+    params:      Option<ParamWatch>,
+    undo:        VecDeque<ParamState>,
+    redo:        Vec<ParamState>,
+    waveform:    Waveform,
+    oscillator:  WaveOscillator,
+    controls:    ControlMap,
+    client:      Option<UiClient<N, S>>,
+}
+
+impl<M: RtMessage<N, S>, const N: usize, S: Sample> UIThread<M, N, S> {
+    fn new(outgoing: RingProducer<Sequenced<M>>) -> Self {
+        UIThread::with_overflow_policy(outgoing, OverflowPolicy::Block)
+    }
+
+    /// Configure a UIThread sending to `outgoing` (a queue, or a `Broadcast`), see
+    /// `UIThreadBuilder`
+    fn builder<O: Into<Broadcast<Sequenced<M>>>>(outgoing: O) -> UIThreadBuilder<M, N, S> {
+        UIThreadBuilder::new(outgoing)
+    }
+
+    /// Construct a UIThread which handles a full queue according to `overflow`
+    fn with_overflow_policy(outgoing: RingProducer<Sequenced<M>>, overflow: OverflowPolicy) -> Self {
+        UIThread::with_broadcast(Broadcast::from(outgoing), overflow)
+    }
+
+    /// Construct a UIThread which sends every message to each of `outgoing`'s consumers, so
+    /// it can drive several realtime threads at once. `overflow` applies to each queue separately
+    fn with_broadcast(outgoing: Broadcast<Sequenced<M>>, overflow: OverflowPolicy) -> Self {
+        // the log reads the second frame of every buffer sent
+        const { assert!(N >= 2, "buffers need at least two frames") };
+
+        UIThread {
+            outgoing,
+            next_seq:    0,
+            overflow,
+            publisher:   None,
+            collector:   None,
+            gc_reports:  None,
+            last_report: None,
+            feedback:    None,
+            position:    0,
+            peak:        0.0,
+            sample_pool: None,
+            shutdown:    None,
+            sample_slot: None,
+            stopped:     false,
+            coalesced:   Vec::new(),
+            snapshot:    None,
+            returns:     None,
+            xruns:       XrunCounters::default(),
+            playhead:    None,
+            load:        0.0,
+            sample_rate: SAMPLE_RATE,
+            frequency:   DEFAULT_FREQUENCY,
+            tone:        None,
+            tone_on:     false,
+            state_dump:  None,
+            dump_ready:  false,
+            levels:      Levels::default(),
+            interactive: false,
+            volume:      DEFAULT_VOLUME,
+            commands:    None,
+            script:      None,
+            telemetry:   None,
+            meter_every: None,
+            last_meter:  None,
+            min_volume:  0.0,
+            max_volume:  1.0,
+            pacing:      None,
+            last_sent:   None,
+            params:      None,
+            undo:        VecDeque::with_capacity(HISTORY_CAPACITY),
+            redo:        Vec::with_capacity(HISTORY_CAPACITY),
+            waveform:    Waveform::Sine,
+            oscillator:  Waveform::Sine.oscillator(),
+            controls:    ControlMap::new(),
+            client:      None,
+        }
+    }
+
+    /// Track every set of samples sent from now on with this collector, see `GcScope`.
+    /// If the collector's reports are given too, the UI thread keeps an eye on them
+    fn set_collector(&mut self, collector: GcHandle<Samples<N, S>>, reports: Option<mpsc::Receiver<GcReport>>) {
+        self.collector = Some(collector);
+        self.gc_reports = reports;
+    }
+
+    /// Listen to events reported by the realtime thread, see `RealtimeThread::set_feedback`
+    fn set_feedback(&mut self, feedback: RingConsumer<Feedback>) {
+        self.feedback = Some(feedback);
+    }
+
+    /// Handle everything the realtime thread has reported since the last call
+    fn check_feedback(&mut self) {
+        let feedback = match self.feedback {
+            Some(ref mut feedback) => feedback,
+            None                   => return,
+        };
+
+        while let Some(event) = feedback.try_pop() {
+            match event {
+                Feedback::BufferApplied => println!("[ui] realtime thread applied new samples"),
+                Feedback::Underrun      => println!("[ui] realtime thread has nothing to play!"),
+                Feedback::Position(p)   => self.position = p,
+                Feedback::Peak(p)       => self.peak = p,
+                Feedback::Gap(n)        => println!("[ui] {} messages never reached the realtime thread", n),
+                Feedback::Stopped       => self.stopped = true,
+                Feedback::Failed(e)     => {
+                    println!("[ui] realtime thread failed: {}", e);
+                    self.stopped = true;
+                },
+                Feedback::Snapshot(s)   => self.snapshot = Some(s),
+                Feedback::Xruns(x)      => self.xruns = x,
+                Feedback::Playhead(p)   => self.playhead = Some(p),
+                Feedback::Panicked      => println!("[ui] realtime processor panicked!"),
+                Feedback::Load(l)       => self.load = l,
+                Feedback::StateDumped   => self.dump_ready = true,
+                Feedback::Levels(l)     => self.levels = l,
+                Feedback::Overloaded(n) => println!("[ui] realtime thread has been over budget for {} callbacks", n),
+            }
+        }
+
+        let telemetry = self.telemetry();
+        if let Some(ref mut publisher) = self.telemetry {
+            publisher.write(telemetry);
+        }
+
+        if let Some(every) = self.meter_every {
+            if self.last_meter.is_none_or(|last| last.elapsed() >= every) {
+                println!("[ui] {}", telemetry);
+                self.last_meter = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Everything the realtime thread has reported, as of the last `check_feedback`
+    fn telemetry(&self) -> Telemetry {
+        Telemetry {
+            position: self.position,
+            levels:   self.levels,
+            load:     self.load,
+            xruns:    self.xruns,
+        }
+    }
+
+    /// Print a meter line (see `Telemetry`) from `check_feedback`, at most once every `every`.
+    /// Lines only come out as often as `check_feedback` runs
+    fn set_meter(&mut self, every: Duration) {
+        self.meter_every = Some(every);
+    }
+
+    /// Publish what the realtime thread reports through `telemetry`, every time `check_feedback`
+    /// runs. Lets another thread (a GUI, say) show levels and stats without touching the feedback
+    /// queue
+    fn set_telemetry(&mut self, telemetry: TripleWriter<Telemetry>) {
+        self.telemetry = Some(telemetry);
+    }
+
+    /// Look at any reports the collector has sent, complaining if garbage is piling up faster than
+    /// it is being collected
+    fn check_collector(&mut self) {
+        let reports = match self.gc_reports {
+            Some(ref reports) => reports,
+            None              => return,
+        };
+
+        while let Ok(report) = reports.try_recv() {
+            if let Some(last) = self.last_report {
+                if report.pool_size > last.pool_size && report.freed == 0 {
+                    println!("[ui] garbage is piling up! {:?}", report);
+                }
+            }
+
+            self.last_report = Some(report);
+        }
+    }
+
+    /// Publish samples through a triple buffer instead of sending a message for every update.
+    /// See `RealtimeThread::set_published`
+    fn set_publisher(&mut self, publisher: TripleWriter<Samples<N, S>>) {
+        self.publisher = Some(publisher);
+    }
+
+    /// Hand samples to the realtime thread through the triple buffer. Nothing is allocated, and
+    /// if the realtime thread hasn't picked up the previous samples yet, they are just replaced
+    fn publish(&mut self, samples: &Samples<N, S>) {
+        match self.publisher {
+            Some(ref mut publisher) => publisher.write(*samples),
+            None                    => panic!("no publisher set, see UIThread::set_publisher"),
+        }
+    }
+
+    /// Reuse samples the realtime thread hands back through `returns` instead of allocating new
+    /// ones. Samples sent this way aren't tracked, see `RealtimeThread::set_returns`
+    fn set_returns(&mut self, returns: RingConsumer<Arc<Samples<N, S>>>) {
+        self.returns = Some(returns);
+    }
+
+    // private. a returned buffer nobody else is holding on to, if there is one
+    fn recycled_buffer(&mut self) -> Option<Arc<Samples<N, S>>> {
+        let returns = self.returns.as_mut()?;
+
+        // anything still shared (say, sitting in a sample pool) is just let go of
+        while let Some(mut samples) = returns.try_pop() {
+            if Arc::get_mut(&mut samples).is_some() {
+                return Some(samples);
+            }
+        }
+
+        None
+    }
+
+    /// Build samples in preallocated buffers from `sample_pool` instead of allocating new ones
+    fn set_sample_pool(&mut self, sample_pool: SamplePool<N, S>) {
+        self.sample_pool = Some(sample_pool);
+    }
+
+    /// Shut down through `shutdown` instead of queueing up a message, see
+    /// `RealtimeThread::set_shutdown_flag`
+    fn set_shutdown_flag(&mut self, shutdown: Arc<AtomicBool>) {
+        self.shutdown = Some(shutdown);
+    }
+
+    /// Swap samples straight into `sample_slot` instead of queueing up messages, see
+    /// `UIThread::swap_samples`
+    fn set_sample_slot(&mut self, sample_slot: Arc<SampleSlot<N, S>>) {
+        self.sample_slot = Some(sample_slot);
+    }
+
+    /// Replace whatever the realtime thread is playing with `samples`, skipping the queue.
+    /// The samples are tracked first, so the realtime thread can let go of them whenever it likes
+    fn swap_samples(&mut self, samples: Arc<Samples<N, S>>) {
+        if let Some(ref collector) = self.collector {
+            GcScope::track_samples(collector, &samples);
+        }
+
+        match self.sample_slot {
+            // samples which were never picked up are still tracked, so just let go of them
+            Some(ref slot) => drop(slot.put(samples)),
+            None           => panic!("no sample slot set, see UIThread::set_sample_slot"),
+        }
+    }
+
+    /// Tell the realtime thread to shut down. With a shutdown flag, the realtime thread notices on
+    /// its very next callback, even if the queue is full. Otherwise, `RtMessage::shutdown` is sent
+    fn shutdown(&mut self) -> Result<(), SendError<M>> {
+        match self.shutdown {
+            Some(ref shutdown) => shutdown.store(true, Ordering::Release),
+            None               => return self.send(M::shutdown()),
+        }
+
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the realtime thread to acknowledge a shutdown.
+    /// Returns false if it didn't, which means its callback is probably stuck. This relies on
+    /// feedback, see `UIThread::set_feedback`, and always returns false without it
+    fn wait_for_stop(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.check_feedback();
+            if self.stopped || self.feedback.is_none() {
+                return self.stopped;
+            }
+
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// The most recent snapshot the realtime thread has sent back, see `Message::Snapshot`.
+    /// Call `check_feedback` first to pick up new ones
+    fn last_snapshot(&self) -> Option<EngineSnapshot> {
+        self.snapshot
+    }
+
+    /// How full the queue to the realtime thread is, see `Broadcast::occupancy`
+    fn queue_occupancy(&self) -> QueueOccupancy {
+        self.outgoing.occupancy()
+    }
+
+    /// The latest playhead the realtime thread has reported, for drawing or for scheduling
+    /// messages relative to what has actually been heard. Call `check_feedback` first
+    fn playhead(&self) -> Option<Playhead> {
+        self.playhead
+    }
+
+    /// Where the realtime thread puts its state in response to `Message::DumpState`
+    fn set_state_dump(&mut self, state_dump: TripleReader<StateDump>) {
+        self.state_dump = Some(state_dump);
+    }
+
+    /// The state the realtime thread dumped most recently, if it has dumped anything since the
+    /// last call. Call `check_feedback` first
+    fn dumped_state(&mut self) -> Option<StateDump> {
+        if !mem::replace(&mut self.dump_ready, false) {
+            return None;
+        }
+
+        let state_dump = self.state_dump.as_mut()?;
+        state_dump.update();
+        Some(*state_dump.read())
+    }
+
+    /// The realtime thread's smoothed CPU load, see `Feedback::Load`. Call `check_feedback` first
+    fn cpu_load(&self) -> f32 {
+        self.load
+    }
+
+    /// Glitches the realtime thread has reported so far. Call `check_feedback` first to pick up
+    /// new ones
+    fn xruns(&self) -> XrunCounters {
+        self.xruns
+    }
+
+    /// The output levels the realtime thread reported most recently, see `Levels`. Call
+    /// `check_feedback` first
+    fn levels(&self) -> Levels {
+        self.levels
+    }
+
+    /// true once the realtime thread has acknowledged a shutdown
+    fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Send a message to the realtime thread, tracking anything it carries once it is sent.
+    /// Keyed messages (see `RtMessage::key`) never wait for room in the queue. If there isn't any,
+    /// they are held back and coalesced, so they may arrive after messages sent later.
+    /// Otherwise, if the queue is full the overflow policy decides what happens, see `try_send`
+    fn send(&mut self, message: M) -> Result<(), SendError<M>> {
+        let key = match message.key() {
+            Some(key) => key,
+            None      => return self.try_send(message),
+        };
+
+        // a held back message is never handed back, so it can be tracked straight away
+        if let Some(ref collector) = self.collector {
+            GcScope::track_message(collector, &message);
+        }
+
+        self.coalesce(key, message);
+        Ok(())
+    }
+
+    /// Send a batch of messages to the realtime thread, tracking anything they carry once they
+    /// are sent. The realtime thread sees as many of them at once as fit in the queue, instead of
+    /// picking them up one at a time. This waits for room like `OverflowPolicy::Block`, whatever
+    /// the overflow policy is, and keyed messages are never coalesced.
+    /// If the engine is stopping (or gone) before the whole batch is sent, the first message which
+    /// didn't make it is handed back, and the rest are dropped
+    fn send_all<I: IntoIterator<Item = M>>(&mut self, messages: I) -> Result<(), SendError<M>> {
+        let mut batch = Vec::new();
+        let mut held = Vec::new();
+        for message in messages {
+            held.push(GcScope::hold(&message));
+            batch.push(Sequenced { seq: self.next_seq + batch.len() as u64, message });
+        }
+
+        // once the engine has been told to stop, nothing is going to make room
+        let count = batch.len();
+        let shutdown = &self.shutdown;
+        let stopping = || shutdown.as_ref().is_some_and(|shutdown| shutdown.load(Ordering::Acquire));
+        let (sent, result) = match self.outgoing.push_all_unless(batch, stopping) {
+            Ok(())        => (count, Ok(())),
+            Err(mut rest) => (count - rest.len(), match rest.drain(..).next() {
+                Some(first) => Err(SendError::Disconnected(first.message)),
+                None        => Ok(()),
+            }),
+        };
+
+        // only what was sent is tracked, the rest was never seen by the realtime thread
+        self.next_seq += sent as u64;
+        if let Some(ref collector) = self.collector {
+            for held in held.into_iter().take(sent) {
+                GcScope::track_held(collector, held);
+            }
+        }
+        result
+    }
+
+    // private. replaces any held back message with the same key, then tries to send them all
+    fn coalesce(&mut self, key: usize, message: M) {
+        match self.coalesced.iter().position(|held| held.key() == Some(key)) {
+            Some(idx) => self.coalesced[idx] = message,
+            None      => self.coalesced.push(message),
+        }
+
+        self.flush_coalesced();
+    }
+
+    /// Send as many held back keyed messages as there is room for, without waiting.
+    /// Returns the number still held back. Call this regularly so they don't go stale.
+    /// When broadcasting, a consumer with room may get the same message twice, which is harmless
+    /// since only the latest value of a parameter matters
+    fn flush_coalesced(&mut self) -> usize {
+        while !self.coalesced.is_empty() {
+            let message = self.coalesced.remove(0);
+            if let Err(message) = self.try_push_now(message) {
+                self.coalesced.insert(0, message);
+                break;
+            }
+        }
+
+        self.coalesced.len()
+    }
+
+    // private. sends without tracking anything or waiting, whatever the overflow policy says
+    fn try_push_now(&mut self, message: M) -> Result<(), M> {
+        match self.outgoing.try_push(Sequenced { seq: self.next_seq, message }) {
+            Ok(()) => {
+                self.next_seq += 1;
+                Ok(())
+            },
+            Err(s) => Err(s.message),
+        }
+    }
+
+    /// Send a message to the realtime thread, tracking anything it carries once it is sent.
+    /// If the queue is full, the overflow policy decides what happens, and anything which had to
+    /// be thrown away is reported back. Unlike `send`, keyed messages are never held back.
+    /// A message handed back wasn't sent, so nothing in it has been tracked yet
+    fn try_send(&mut self, message: M) -> Result<(), SendError<M>> {
+        let held = GcScope::hold(&message);
+        let result = self.try_push_message(message);
+
+        // with `DropOldest` the new message made it in, the one handed back was sent earlier
+        if let Ok(()) | Err(SendError::DroppedOldest(_)) = result {
+            if let Some(ref collector) = self.collector {
+                GcScope::track_held(collector, held);
+            }
+        }
+
+        result
+    }
+
+    // private. sends without tracking anything, the ring buffer doesn't allocate
+    fn try_push_message(&mut self, message: M) -> Result<(), SendError<M>> {
+        if self.outgoing.is_disconnected() {
+            return Err(SendError::Disconnected(message));
+        }
+
+        let sequenced = Sequenced { seq: self.next_seq, message };
+        let result = match self.overflow {
+            // once the engine has been told to stop, nothing is going to make room
+            OverflowPolicy::Block => {
+                let shutdown = &self.shutdown;
+                let stopping = || shutdown.as_ref().is_some_and(|shutdown| shutdown.load(Ordering::Acquire));
+                self.outgoing.push_unless(sequenced, stopping).map_err(|s| SendError::Disconnected(s.message))
+            },
+
+            OverflowPolicy::DropOldest => match self.outgoing.force_push(sequenced) {
+                Some(evicted) => Err(SendError::DroppedOldest(evicted.message)),
+                None          => Ok(()),
+            },
+
+            OverflowPolicy::DropNewest => {
+                self.outgoing.try_push(sequenced).map_err(|s| SendError::DroppedNewest(s.message))
+            },
+
+            OverflowPolicy::Error => {
+                self.outgoing.try_push(sequenced).map_err(|s| SendError::Full(s.message))
+            },
+        };
+
+        // a message handed back to the caller might be sent again, so it keeps its number.
+        // Anything that was sent or thrown away uses one up, so drops show up as gaps
+        match result {
+            Err(SendError::Full(_)) | Err(SendError::Disconnected(_)) => (),
+            _ => self.next_seq += 1,
+        }
+
+        result
+    }
+
+    /// The rate the realtime thread's host runs at, in frames per second. `compute_samples`
+    /// needs it to get frequencies right
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        assert!(sample_rate > 0);
+        self.sample_rate = sample_rate;
+    }
+
+    /// The frequency of the wave `send_samples` plays, in Hz
+    fn set_frequency(&mut self, frequency: f32) {
+        assert!(frequency.is_finite() && frequency > 0.0);
+        self.frequency = frequency;
+    }
+
+    /// Keep every volume `compute_samples` is asked for between `min` and `max`, both between 0
+    /// and 1. Anything outside is clamped
+    fn set_volume_range(&mut self, min: f32, max: f32) {
+        assert!(0.0 <= min && min <= max && max <= 1.0);
+        self.min_volume = min;
+        self.max_volume = max;
+    }
+
+    /// Send new samples at most once every `pacing`. `send_samples` waits until it's time
+    fn set_pacing(&mut self, pacing: Duration) {
+        self.pacing = Some(pacing);
+    }
+
+    /// The shape of the wave `compute_samples` makes
+    fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    /// Write the current volume, frequency, waveform and controller bindings to `path`, see
+    /// `SynthParams`
+    fn save_preset(&self, path: &Path) -> io::Result<()> {
+        let params = SynthParams {
+            volume:    Some(self.volume),
+            frequency: Some(self.frequency),
+            waveform:  Some(self.waveform),
+            controls:  self.controls.bindings(),
+        };
+
+        params.save(path)
+    }
+
+    /// Take the volume, frequency, waveform and controller bindings from a file written by
+    /// `save_preset`. The parameters take effect with the next samples sent
+    /// (`UiCommand::LoadPreset` sends them straight away), and loading can be undone like any
+    /// other change
+    fn load_preset(&mut self, path: &Path) -> io::Result<()> {
+        let params = SynthParams::load(path)?;
+        let current = self.params_state();
+        self.remember(current);
+        self.redo.clear();
+        self.apply_params(&params);
+        Ok(())
+    }
+
+    // private. takes on every parameter which is set
+    fn apply_params(&mut self, params: &SynthParams) {
+        if let Some(volume) = params.volume {
+            self.volume = volume;
+        }
+
+        if let Some(frequency) = params.frequency {
+            self.set_frequency(frequency);
+        }
+
+        if let Some(waveform) = params.waveform {
+            self.waveform = waveform;
+        }
+
+        if !params.controls.is_empty() {
+            self.controls.set_bindings(&params.controls);
+        }
+    }
+
+    /// Which MIDI controller moves which parameter, see `UiCommand::Controller`
+    fn controls(&mut self) -> &mut ControlMap {
+        &mut self.controls
+    }
+
+    // private. the parameters undo and redo move between
+    fn params_state(&self) -> ParamState {
+        ParamState { volume: self.volume, frequency: self.frequency, waveform: self.waveform }
+    }
+
+    /// Play MIDI note `note`, see `note_frequency`
+    fn note(&mut self, note: u8) {
+        self.set_frequency(note_frequency(note));
+    }
+
+    /// Play the wave at the given volume. Pitched waves are played from a wavetable, which the
+    /// realtime thread steps through at exactly the frequency asked for, carrying on smoothly from
+    /// wherever it was (see `tone_message`). Anything else is computed as samples and sent to the
+    /// realtime thread. If there is a sample pool, sending samples doesn't allocate
+    fn send_samples(&mut self, volume: f32) -> Result<(), SendError<M>> where M: From<Arc<Samples<N, S>>> {
+        if let (Some(pacing), Some(last)) = (self.pacing, self.last_sent) {
+            let since = last.elapsed();
+            if since < pacing {
+                thread::sleep(pacing - since);
+            }
+        }
+        self.last_sent = Some(Instant::now());
+
+        if let Some(message) = self.tone_message(volume) {
+            if let Some(switch) = self.switch_source(true) {
+                self.send(switch)?;
+            }
+
+            // the table was tracked when it was built, see `tone_message`
+            println!("[ui] playing the wavetable at {} Hz", self.frequency);
+            return self.try_push_message(message);
+        }
+
+        if let Some(switch) = self.switch_source(false) {
+            self.send(switch)?;
+        }
+
+        let computed = self.compute_samples(volume);
+        println!("[ui] sending new samples. Second sample: {}", computed[1][0].to_f32());
+
+        if self.sample_pool.is_none() && self.returns.is_some() {
+            let mut samples = self.recycled_buffer().unwrap_or_else(|| Arc::new(silence()));
+            *Arc::get_mut(&mut samples).unwrap() = computed;
+
+            // the realtime thread hands it back when it's done, so there's no need to track it
+            return self.try_push_message(M::from(samples));
+        }
+
+        if self.sample_pool.is_none() {
+            return self.send(M::from(Arc::new(computed)));
+        }
+
+        // wait for the realtime thread to let go of a buffer
+        let samples = loop {
+            let pool = self.sample_pool.as_mut().unwrap();
+            match pool.fill(|buffer| *buffer = computed) {
+                Some(samples) => break samples,
+                None          => thread::yield_now(),
+            }
+        };
+
+        // the pool keeps the buffer alive, so there's no need to track it
+        self.try_push_message(M::from(samples))
+    }
+
+    /// computes the samples for a wave (see `set_waveform`) at the frequency set by `set_frequency`
+    /// the volume parameter sets the audible volume of sound produced.
+    /// The samples loop, so they have to hold a whole number of cycles: the frequency is rounded
+    /// to the nearest one which fits, a multiple of `sample_rate / N`. `send_samples` only sends
+    /// these for noise, or if the message type can't play wavetables
+    fn compute_samples(&mut self, volume: f32) -> Samples<N, S> {
+        assert!(volume >= 0.0);
+        assert!(volume <= 1.0);
+        let volume = volume.max(self.min_volume).min(self.max_volume);
+
+        // we need to populate N frames with a whole number of cycles of the wave, the same in
+        // every channel
+        let cycles = (self.frequency * N as f32 / self.sample_rate as f32).round().max(1.0);
+
+        // the oscillator is kept between calls so noise carries on where it left off
+        if self.oscillator.waveform() != self.waveform {
+            self.oscillator = self.waveform.oscillator();
+        }
+
+        let mut samples = silence();
+        self.oscillator.fill(&mut samples, 0.0, cycles, volume);
+        samples
+    }
+
+    // private. the message which plays the wave at the given volume from a wavetable, see
+    // `RtMessage::play_wavetable`. None for noise, which has no pitch, or if the message type
+    // can't play wavetables. Building a table is slow, so the last one is kept and reused. It is
+    // tracked once, when it's built, so the messages carrying it don't need tracking
+    fn tone_message(&mut self, volume: f32) -> Option<M> {
+        assert!(volume >= 0.0);
+        assert!(volume <= 1.0);
+        let volume = volume.max(self.min_volume).min(self.max_volume);
+
+        let stale = match self.tone {
+            Some((waveform, ref table)) => waveform != self.waveform || table.sample_rate != self.sample_rate,
+            None                        => true,
+        };
+
+        if stale {
+            let table = Arc::new(Wavetable::for_waveform(self.waveform, self.sample_rate)?);
+            if let Some(ref collector) = self.collector {
+                GcScope::track_held(collector, Held { samples: None, wavetable: Some(table.clone()) });
+            }
+            self.tone = Some((self.waveform, table));
+        }
+
+        let table = self.tone.as_ref().map(|(_, table)| table.clone())?;
+        M::play_wavetable(table, self.frequency, volume)
+    }
+
+    // private. the message which silences whatever played the wave before, when switching
+    // between a wavetable (`tone` true) and samples, so the two don't play on top of each other.
+    // Not tracked yet
+    fn switch_source(&mut self, tone: bool) -> Option<M> where M: From<Arc<Samples<N, S>>> {
+        if tone == self.tone_on {
+            return None;
+        }
+
+        self.tone_on = tone;
+        if tone {
+            Some(M::from(Arc::new(silence())))
+        } else {
+            M::stop_wavetable()
+        }
+    }
+
+    /// Take commands from stdin in `run`, instead of running through the demo. See
+    /// `run_interactive`
+    fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// Take commands from the application in `run`, instead of running through the demo. `run`
+    /// carries on until it receives `UiCommand::Shutdown` or every sender has gone away
+    fn set_commands(&mut self, commands: mpsc::Receiver<UiCommand<N, S>>) {
+        self.commands = Some(commands);
+        self.client = None;
+    }
+
+    /// Take commands from any number of clients in `run`, see `UiClient`. Every client this
+    /// returns (and every clone of one) feeds the same queue. `run` carries on until one of them
+    /// sends `UiCommand::Shutdown` or they have all gone away. The first call replaces anything
+    /// given to `set_commands`
+    fn connect(&mut self) -> UiClient<N, S> {
+        if self.client.is_none() {
+            let (commands, command_rx) = mpsc::channel();
+            self.set_commands(command_rx);
+            self.client = Some(UiClient { commands });
+        }
+
+        self.client.clone().unwrap()
+    }
+
+    /// Keep the sine wave's parameters in line with the file at `path`, see `SynthParams`.
+    /// Whenever the file changes, new samples are computed and sent. If there's nothing else for
+    /// `run` to do, it watches the file until the file is deleted. Otherwise the file is checked
+    /// whenever `run` is waiting on commands
+    fn watch_params<P: Into<PathBuf>>(&mut self, path: P) {
+        self.params = Some(ParamWatch { path: path.into(), modified: None });
+    }
+
+    /// Carry out `script` in `run`, instead of running through the demo. The engine shuts down
+    /// once the script is done
+    fn set_script(&mut self, script: Script<N, S>) {
+        self.script = Some(script);
+    }
+
+    /// All of the UI thread code
+    fn run(&mut self) where M: From<Arc<Samples<N, S>>> {
+        let carry_on = if self.interactive {
+            self.run_interactive()
+        } else if self.script.is_some() {
+            self.run_script()
+        } else if self.commands.is_some() {
+            self.run_commands()
+        } else if self.params.is_some() {
+            self.run_watching()
+        } else {
+            self.run_demo()
+        };
+
+        if !carry_on {
+            return;
+        }
+
+        // tell the other thread to shutdown, and make sure it listened
+        if let Err(e) = self.shutdown() {
+            println!("[ui] couldn't send shutdown: {}", e);
+            return;
+        }
+
+        if !self.wait_for_stop(SHUTDOWN_TIMEOUT) {
+            println!("[ui] realtime thread never acknowledged the shutdown!");
+        }
+    }
+
+    // private. sends a handful of samples at increasing volumes. false if the realtime thread went
+    // away before we were done
+    fn run_demo(&mut self) -> bool where M: From<Arc<Samples<N, S>>> {
+        // create 10 "ui events"
+        for i in 0..5 {
+            if !self.step(i as f32 / 10.0) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // private. reads commands from stdin, one per line, until `quit` or the end of the input:
+    //   vol <gain>   play at this volume, from 0 to 1
+    //   freq <hz>    play a sine wave at this frequency
+    //   note <note>  play a sine wave at this MIDI note
+    //   status       print what the realtime thread has reported
+    //   wave <name>  play a sine, square, saw or triangle wave, or noise
+    //   save <file>  save the volume, frequency and waveform as a preset
+    //   load <file>  load a preset
+    //   cc <n> <v>   MIDI controller n moved to v, see `ControlMap`
+    //   learn <name> bind the next controller which moves to the volume, frequency or waveform
+    //   undo, redo   go back to (or forward from) earlier settings
+    //   quit         shut down
+    // false if the realtime thread went away first
+    fn run_interactive(&mut self) -> bool where M: From<Arc<Samples<N, S>>> {
+        println!("[ui] commands: vol <gain>, freq <hz>, note <midi note>, wave <waveform>, save <file>, load <file>, cc <controller> <value>, learn <parameter>, undo, redo, status, quit");
+
+        // start with something to listen to
+        let volume = self.volume;
+        if !self.step(volume) {
+            return false;
+        }
+
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e)   => {
+                    println!("[ui] couldn't read a command: {}", e);
+                    break;
+                },
+            };
+
+            match line.trim() {
+                "" => continue,
+
+                "status" => {
+                    self.check_feedback();
+                    println!("[ui] at frame {}: {}", self.position, self.telemetry());
+                    continue;
+                },
+
+                _ => (),
+            }
+
+            let command = match UiCommand::parse(&line) {
+                Ok(command) => command,
+                Err(e)      => {
+                    println!("[ui] {}", e);
+                    continue;
+                },
+            };
+
+            match command {
+                UiCommand::Shutdown => break,
+                command             => if !self.apply_command(command) {
+                    return false;
+                },
+            }
+        }
+
+        true
+    }
+
+    // private. carries out each command in the script given to `set_script` at its time, keeping
+    // up with the realtime thread while it waits. false if it went away first
+    fn run_script(&mut self) -> bool where M: From<Arc<Samples<N, S>>> {
+        let script = match self.script.take() {
+            Some(script) => script,
+            None         => return true,
+        };
+
+        let started = Instant::now();
+        for (at, command) in script.events {
+            let due = started + at;
+            loop {
+                let now = Instant::now();
+                if now >= due {
+                    break;
+                }
+
+                thread::sleep((due - now).min(COMMAND_POLL));
+                self.check_collector();
+                self.check_feedback();
+                if self.stopped {
+                    return false;
+                }
+            }
+
+            match command {
+                UiCommand::Shutdown => return true,
+                command             => if !self.apply_command(command) {
+                    return false;
+                },
+            }
+        }
+
+        true
+    }
+
+    // private. watches the parameter file given to `watch_params` until it is deleted. false if
+    // the realtime thread went away first
+    fn run_watching(&mut self) -> bool where M: From<Arc<Samples<N, S>>> {
+        loop {
+            match self.check_params() {
+                Some(false) => return false,
+                None        => return true,
+                Some(true)  => (),
+            }
+
+            self.check_collector();
+            self.check_feedback();
+            if self.stopped {
+                return false;
+            }
+
+            thread::sleep(PARAM_POLL);
+        }
+    }
+
+    // private. reloads the parameter file if it has changed since we last looked, and sends
+    // samples to match. None if there's no file (any more), otherwise false if the realtime thread
+    // has gone away. A file which doesn't parse is reported and skipped until it changes again
+    fn check_params(&mut self) -> Option<bool> where M: From<Arc<Samples<N, S>>> {
+        let watch = self.params.as_mut()?;
+        let modified = match std::fs::metadata(&watch.path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e)       => {
+                println!("[ui] stopped watching {}: {}", watch.path.display(), e);
+                self.params = None;
+                return None;
+            },
+        };
+
+        if watch.modified == Some(modified) {
+            return Some(true);
+        }
+        watch.modified = Some(modified);
+
+        let params = match SynthParams::load(&watch.path) {
+            Ok(params) => params,
+            Err(e)     => {
+                println!("[ui] couldn't load {}: {}", watch.path.display(), e);
+                return Some(true);
+            },
+        };
+
+        println!("[ui] loaded {}", watch.path.display());
+        self.apply_params(&params);
+
+        let volume = self.volume;
+        Some(self.step(volume))
+    }
+
+    // private. carries out commands from the queue given to `set_commands` as they arrive,
+    // keeping up with the realtime thread in between. Everything already waiting is carried out
+    // together, see `apply_commands`. false if the realtime thread went away first
+    fn run_commands(&mut self) -> bool where M: From<Arc<Samples<N, S>>> {
+        // we hang on to a client so `connect` can hand out more, but it would keep the queue
+        // open after every real client had gone
+        self.client = None;
+        loop {
+            let received = match self.commands {
+                Some(ref commands) => commands.recv_timeout(COMMAND_POLL).map(|command| {
+                    let mut batch = vec![command];
+                    batch.extend(commands.try_iter());
+                    batch
+                }),
+                None => return true,
+            };
+
+            match received {
+                Ok(batch) => match self.apply_commands(batch) {
+                    Some(true)  => (),
+                    Some(false) => return false,
+                    None        => return true,
+                },
+
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if self.check_params() == Some(false) {
+                        return false;
+                    }
+
+                    self.check_collector();
+                    self.check_feedback();
+                    if self.stopped {
+                        return false;
+                    }
+                },
+
+                // nobody is left to send commands, so we're done
+                Err(mpsc::RecvTimeoutError::Disconnected) => return true,
+            }
+        }
+    }
+
+    // private. carries out commands which arrived together, possibly from several clients. In a
+    // run of commands which set parameters outright, only the last change to each parameter is
+    // made, and samples are sent once for the whole run. None once a command asks to shut down,
+    // otherwise false once there's nobody left to talk to
+    fn apply_commands(&mut self, commands: Vec<UiCommand<N, S>>) -> Option<bool> where M: From<Arc<Samples<N, S>>> {
+        let parameters: Vec<Option<Parameter>> = commands.iter().map(UiCommand::parameter).collect();
+        let mut changed = false;
+        for (i, command) in commands.into_iter().enumerate() {
+            if let Some(parameter) = parameters[i] {
+                let later = parameters[i + 1..].iter().take_while(|later| later.is_some());
+                if !later.clone().any(|&later| later == Some(parameter)) {
+                    changed |= self.change_params(&command);
+                }
+                continue;
+            }
+
+            // the run is over, so the realtime thread needs to hear about it before anything else
+            if changed && !self.step(self.volume) {
+                return Some(false);
+            }
+            changed = false;
+
+            if let UiCommand::Shutdown = command {
+                return None;
+            }
+
+            if !self.apply_command(command) {
+                return Some(false);
+            }
+        }
+
+        if changed {
+            return Some(self.step(self.volume));
+        }
+
+        Some(true)
+    }
+
+    // private. turns a command into messages for the realtime thread. Shutting down is left to
+    // `run`, so `UiCommand::Shutdown` does nothing here. false once there's nobody left to talk to
+    fn apply_command(&mut self, command: UiCommand<N, S>) -> bool where M: From<Arc<Samples<N, S>>> {
+        let sent = match command {
+            UiCommand::LoadWave(samples) => {
+                // the wave replaces the wavetable too, see `send_samples`
+                match self.switch_source(false) {
+                    Some(switch) => self.send(switch).and_then(|()| self.send(M::from(samples))),
+                    None         => self.send(M::from(samples)),
+                }
+            },
+
+            UiCommand::Shutdown => return true,
+
+            UiCommand::SavePreset(path) => {
+                if let Err(e) = self.save_preset(&path) {
+                    println!("[ui] couldn't save {}: {}", path.display(), e);
+                }
+                return true;
+            },
+
+            UiCommand::LoadPreset(path) => {
+                if let Err(e) = self.load_preset(&path) {
+                    println!("[ui] couldn't load {}: {}", path.display(), e);
+                    return true;
+                }
+
+                let volume = self.volume;
+                self.send_samples(volume)
+            },
+
+            command => {
+                if !self.change_params(&command) {
+                    return true;
+                }
+
+                let volume = self.volume;
+                self.send_samples(volume)
+            },
+        };
+
+        self.settle(sent)
+    }
+
+    // private. updates the parameters for a command, keeping the history `Undo` and `Redo` move
+    // through. false if nothing changed, because there was nothing to undo or redo (or the
+    // command doesn't change parameters)
+    fn change_params(&mut self, command: &UiCommand<N, S>) -> bool {
+        let current = self.params_state();
+        let restored = match *command {
+            UiCommand::SetVolume(volume) => {
+                self.remember(current);
+                self.redo.clear();
+                ParamState { volume, ..current }
+            },
+
+            UiCommand::SetFrequency(frequency) => {
+                self.remember(current);
+                self.redo.clear();
+                ParamState { frequency, ..current }
+            },
+
+            UiCommand::SetWaveform(waveform) => {
+                self.remember(current);
+                self.redo.clear();
+                ParamState { waveform, ..current }
+            },
+
+            UiCommand::Undo => match self.undo.pop_back() {
+                Some(previous) => {
+                    self.redo.push(current);
+                    previous
+                },
+                None => {
+                    println!("[ui] nothing to undo");
+                    return false;
+                },
+            },
+
+            UiCommand::Redo => match self.redo.pop() {
+                Some(next) => {
+                    self.remember(current);
+                    next
+                },
+                None => {
+                    println!("[ui] nothing to redo");
+                    return false;
+                },
+            },
+
+            UiCommand::Controller(controller, value) => match self.controls.translate(controller, value) {
+                Some(command) => return self.change_params(&command),
+                None          => return false,
+            },
+
+            UiCommand::Learn(parameter) => {
+                println!("[ui] move a controller to bind it to the {}", parameter.name());
+                self.controls.learn(parameter);
+                return false;
+            },
+
+            UiCommand::LoadWave(_) | UiCommand::SavePreset(_) | UiCommand::LoadPreset(_) | UiCommand::Shutdown => return false,
+        };
+
+        self.volume = restored.volume;
+        self.set_frequency(restored.frequency);
+        self.waveform = restored.waveform;
+        true
+    }
+
+    // private. adds to the undo history, forgetting the oldest change if it is full
+    fn remember(&mut self, state: ParamState) {
+        if self.undo.len() == HISTORY_CAPACITY {
+            self.undo.pop_front();
+        }
+
+        self.undo.push_back(state);
+    }
+
+    // private. the messages which carry out `command`, in order, tracked as `send` would but not
+    // sent. Never waits, unlike `apply_command`. Nothing for `UiCommand::Shutdown`, which is left
+    // to `run`, for saving presets, and for undoing or redoing when there's nothing to undo or redo
+    fn command_message(&mut self, command: UiCommand<N, S>) -> Vec<M> where M: From<Arc<Samples<N, S>>> {
+        let samples = match command {
+            UiCommand::LoadWave(samples) => Some(samples),
+            UiCommand::Shutdown          => return Vec::new(),
+
+            // files are small, so this doesn't hold the executor up for long
+            UiCommand::SavePreset(path) => {
+                if let Err(e) = self.save_preset(&path) {
+                    println!("[ui] couldn't save {}: {}", path.display(), e);
+                }
+                return Vec::new();
+            },
+
+            UiCommand::LoadPreset(path) => {
+                if let Err(e) = self.load_preset(&path) {
+                    println!("[ui] couldn't load {}: {}", path.display(), e);
+                    return Vec::new();
+                }
+
+                None
+            },
+
+            command => {
+                if !self.change_params(&command) {
+                    return Vec::new();
+                }
+
+                None
+            },
+        };
+
+        // a pitched wave plays from a wavetable, see `send_samples`
+        let volume = self.volume;
+        let tone = match samples {
+            Some(_) => None,
+            None    => self.tone_message(volume),
+        };
+
+        let mut messages: Vec<M> = self.switch_source(tone.is_some()).into_iter().collect();
+        if tone.is_none() {
+            let samples = samples.unwrap_or_else(|| Arc::new(self.compute_samples(volume)));
+            messages.push(M::from(samples));
+        }
+
+        if let Some(ref collector) = self.collector {
+            for message in &messages {
+                GcScope::track_message(collector, message);
+            }
+        }
+
+        // the table was tracked when it was built
+        messages.extend(tone);
+        messages
+    }
+
+    // private. sends samples at `volume`, see `settle`
+    fn step(&mut self, volume: f32) -> bool where M: From<Arc<Samples<N, S>>> {
+        let sent = self.send_samples(volume);
+        self.settle(sent)
+    }
+
+    // private. reports on how a send went, then catches up on everything the realtime thread has
+    // been up to. false once there's nobody left to talk to
+    fn settle(&mut self, sent: Result<(), SendError<M>>) -> bool {
+        // if the other thread has gone away, there's nothing left to do
+        match sent {
+            Ok(()) => (),
+            Err(e @ SendError::Disconnected(_)) => {
+                println!("[ui] {}, giving up", e);
+                return false;
+            },
+            Err(e) => println!("[ui] {}", e),
+        }
+
+        self.flush_coalesced();
+        self.check_collector();
+        self.check_feedback();
+
+        // the realtime thread has already stopped on its own, nothing is listening
+        !self.stopped
+    }
+}
+
+/// Sets up a `UIThread`, for when the defaults `UIThread::new` picks don't fit. Everything not
+/// set here can still be set on the UIThread afterwards
+struct UIThreadBuilder<M = Message, const N: usize = FRAMES, S: Sample = f32> {
+    outgoing:    Broadcast<Sequenced<M>>,
+    overflow:    OverflowPolicy,
+    volume:      (f32, f32),
+    frequency:   f32,
+    sample_rate: u32,
+    pacing:      Option<Duration>,
+    feedback:    Option<RingConsumer<Feedback>>,
+    returns:     Option<RingConsumer<Arc<Samples<N, S>>>>,
+    commands:    Option<mpsc::Receiver<UiCommand<N, S>>>,
+    telemetry:   Option<TripleWriter<Telemetry>>,
+}
+
+impl<M: RtMessage<N, S>, const N: usize, S: Sample> UIThreadBuilder<M, N, S> {
+    fn new<O: Into<Broadcast<Sequenced<M>>>>(outgoing: O) -> Self {
+        UIThreadBuilder {
+            outgoing:    outgoing.into(),
+            overflow:    OverflowPolicy::Block,
+            volume:      (0.0, 1.0),
+            frequency:   DEFAULT_FREQUENCY,
+            sample_rate: SAMPLE_RATE,
+            pacing:      None,
+            feedback:    None,
+            returns:     None,
+            commands:    None,
+            telemetry:   None,
+        }
+    }
+
+    /// What to do when the queue is full, see `OverflowPolicy`. Blocks by default
+    fn overflow_policy(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// See `UIThread::set_volume_range`
+    fn volume_range(mut self, min: f32, max: f32) -> Self {
+        assert!(0.0 <= min && min <= max && max <= 1.0);
+        self.volume = (min, max);
+        self
+    }
+
+    /// See `UIThread::set_frequency`
+    fn frequency(mut self, frequency: f32) -> Self {
+        assert!(frequency.is_finite() && frequency > 0.0);
+        self.frequency = frequency;
+        self
+    }
+
+    /// See `UIThread::set_sample_rate`. `run_threads_with` sets this too
+    fn sample_rate(mut self, sample_rate: u32) -> Self {
+        assert!(sample_rate > 0);
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// See `UIThread::set_pacing`
+    fn pacing(mut self, pacing: Duration) -> Self {
+        self.pacing = Some(pacing);
+        self
+    }
+
+    /// See `UIThread::set_feedback`. `run_threads_with` sets this too
+    fn feedback(mut self, feedback: RingConsumer<Feedback>) -> Self {
+        self.feedback = Some(feedback);
+        self
+    }
+
+    /// See `UIThread::set_returns`
+    fn returns(mut self, returns: RingConsumer<Arc<Samples<N, S>>>) -> Self {
+        self.returns = Some(returns);
+        self
+    }
+
+    /// See `UIThread::set_commands`
+    fn commands(mut self, commands: mpsc::Receiver<UiCommand<N, S>>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// See `UIThread::set_telemetry`
+    fn telemetry(mut self, telemetry: TripleWriter<Telemetry>) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    fn build(self) -> UIThread<M, N, S> {
+        let mut ui = UIThread::with_broadcast(self.outgoing, self.overflow);
+        ui.set_volume_range(self.volume.0, self.volume.1);
+        ui.set_frequency(self.frequency);
+        ui.set_sample_rate(self.sample_rate);
+        ui.pacing = self.pacing;
+        ui.feedback = self.feedback;
+        ui.returns = self.returns;
+        ui.commands = self.commands;
+        ui.telemetry = self.telemetry;
+        ui
+    }
+}
+
+// how long a future waiting on the realtime thread sleeps before looking again. The realtime
+// thread empties the queue once a callback, which is about this often
+const RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Wakes tasks up a little later, from a thread of its own. The realtime thread can't wake
+/// anyone when it makes room in the queue, so futures waiting on it ask to be woken after
+/// `RETRY_DELAY` instead of spinning. The thread goes away with the timer
+struct WakeTimer {
+    wakers: mpsc::Sender<(Instant, Waker)>,
+}
+
+impl WakeTimer {
+    fn new() -> Self {
+        let (wakers, requests) = mpsc::channel::<(Instant, Waker)>();
+        thread::spawn(move || {
+            let mut waiting: Vec<(Instant, Waker)> = Vec::new();
+            loop {
+                let now = Instant::now();
+                waiting.retain(|&(at, ref waker)| {
+                    if at <= now {
+                        waker.wake_by_ref();
+                    }
+                    at > now
+                });
+
+                let request = match waiting.iter().map(|&(at, _)| at).min() {
+                    Some(next) => requests.recv_timeout(next.saturating_duration_since(now)),
+                    None       => requests.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+
+                match request {
+                    Ok(request)                               => waiting.push(request),
+                    Err(mpsc::RecvTimeoutError::Timeout)      => (),
+                    // nobody is left to wait on anything, let everyone have one last look
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        for (_, waker) in waiting {
+                            waker.wake();
+                        }
+                        return;
+                    },
+                }
+            }
+        });
+
+        WakeTimer { wakers }
+    }
+
+    /// Wake `waker` once `delay` has passed
+    fn wake_after(&self, waker: &Waker, delay: Duration) {
+        // the thread only goes away when we do
+        let _ = self.wakers.send((Instant::now() + delay, waker.clone()));
+    }
+}
+
+/// Values which turn up over time, for async code to pick up. The same shape as the `Stream` in
+/// the futures crate, so wrapping one of those takes a few lines. `AsyncUiHandle::run` takes its
+/// commands from one, see `stream_channel`
+trait Stream {
+    type Item;
+
+    /// The next value, or None once there won't be any more. If there's nothing yet, the waker in
+    /// `cx` is woken when there is
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>;
+}
+
+/// A channel whose receiving end is a `Stream`. Anything sent wakes whichever task is waiting
+/// on the stream, and the stream ends once every sender has gone
+fn stream_channel<T>() -> (StreamSender<T>, ChannelStream<T>) {
+    let (sender, receiver) = mpsc::channel();
+    let waker = Arc::new(Mutex::new(None));
+    let sender = StreamSender { sender: ManuallyDrop::new(sender), waker: waker.clone() };
+    (sender, ChannelStream { receiver, waker })
+}
+
+/// The sending half of a `stream_channel`. Clone it to send from several places
+struct StreamSender<T> {
+    sender: ManuallyDrop<mpsc::Sender<T>>,
+    waker:  Arc<Mutex<Option<Waker>>>,
+}
+
+impl<T> StreamSender<T> {
+    /// Send `t` to the stream. Hands it back if the stream has gone away
+    fn send(&self, t: T) -> Result<(), mpsc::SendError<T>> {
+        self.sender.send(t)?;
+        self.wake();
+        Ok(())
+    }
+
+    // private. wakes whoever is waiting on the stream
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Clone for StreamSender<T> {
+    fn clone(&self) -> Self {
+        StreamSender { sender: self.sender.clone(), waker: self.waker.clone() }
+    }
+}
+
+impl<T> Drop for StreamSender<T> {
+    fn drop(&mut self) {
+        // let go of the sender first, so if this was the last one the stream sees it has ended
+        unsafe { ManuallyDrop::drop(&mut self.sender) };
+        self.wake();
+    }
+}
+
+/// The receiving half of a `stream_channel`
+struct ChannelStream<T> {
+    receiver: mpsc::Receiver<T>,
+    waker:    Arc<Mutex<Option<Waker>>>,
+}
+
+impl<T> Stream for ChannelStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        // leave the waker first, so something sent after we look still wakes us
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        match self.receiver.try_recv() {
+            Ok(t)                                 => Poll::Ready(Some(t)),
+            Err(mpsc::TryRecvError::Empty)        => Poll::Pending,
+            Err(mpsc::TryRecvError::Disconnected) => Poll::Ready(None),
+        }
+    }
+}
+
+/// Drives a `UIThread` from async code (a tokio based GUI, a network server, ...).
+/// Sending never blocks the executor. If the queue is full, the send sleeps for `RETRY_DELAY`
+/// (see `WakeTimer`) and tries again
+struct AsyncUiHandle<M = Message, const N: usize = FRAMES, S: Sample = f32> {
+    ui:    UIThread<M, N, S>,
+    timer: WakeTimer,
+}
+
+impl<M: RtMessage<N, S>, const N: usize, S: Sample> AsyncUiHandle<M, N, S> {
+    fn new(ui: UIThread<M, N, S>) -> Self {
+        AsyncUiHandle { ui, timer: WakeTimer::new() }
+    }
+
+    /// Send a message to the realtime thread, tracking anything it carries first.
+    /// The returned future finishes once the message is in the queue, or hands the message back if
+    /// the realtime thread has gone away
+    fn send<'a>(&'a mut self, message: M) -> SendFuture<'a, M, N, S> {
+        if let Some(ref collector) = self.ui.collector {
+            GcScope::track_message(collector, &message);
+        }
+
+        SendFuture { ui: &mut self.ui, timer: &self.timer, message: Some(message) }
+    }
+
+    /// The async version of `UIThread::run`. Carries out commands from `commands` as they arrive,
+    /// until `UiCommand::Shutdown` or the end of the stream, then shuts the engine down. The
+    /// future finishes with true once the realtime thread has stopped, or false if it went away
+    /// without saying so or never acknowledged the shutdown. Nothing in here blocks the executor
+    fn run<'a, C>(&'a mut self, commands: C) -> RunFuture<'a, C, M, N, S>
+        where C: Stream<Item = UiCommand<N, S>> + Unpin, M: From<Arc<Samples<N, S>>>
+    {
+        RunFuture { ui: &mut self.ui, timer: &self.timer, commands, pending: VecDeque::new(), deadline: None }
+    }
+
+    /// The wrapped UIThread, for everything which doesn't need to wait
+    fn ui(&mut self) -> &mut UIThread<M, N, S> {
+        &mut self.ui
+    }
+
+    fn into_inner(self) -> UIThread<M, N, S> {
+        self.ui
+    }
+}
+
+/// Returned by `AsyncUiHandle::send`
+struct SendFuture<'a, M: 'a, const N: usize, S: Sample> {
+    ui:      &'a mut UIThread<M, N, S>,
+    timer:   &'a WakeTimer,
+    message: Option<M>,
+}
+
+// the message is never pinned, it just gets moved into the queue
+impl<'a, M, const N: usize, S: Sample> Unpin for SendFuture<'a, M, N, S> {}
+
+impl<'a, M: RtMessage<N, S>, const N: usize, S: Sample> Future for SendFuture<'a, M, N, S> {
+    type Output = Result<(), SendError<M>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let message = this.message.take().expect("SendFuture polled after it finished");
+
+        if this.ui.outgoing.is_disconnected() {
+            return Poll::Ready(Err(SendError::Disconnected(message)));
+        }
+
+        match this.ui.try_push_now(message) {
+            Ok(()) => Poll::Ready(Ok(())),
+
+            // the realtime thread makes room on its own, without telling anyone. Let the executor
+            // run something else, and look again in a little while
+            Err(message) => {
+                this.message = Some(message);
+                this.timer.wake_after(cx.waker(), RETRY_DELAY);
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/// Returned by `AsyncUiHandle::run`
+struct RunFuture<'a, C, M: 'a, const N: usize, S: Sample> {
+    ui:       &'a mut UIThread<M, N, S>,
+    timer:    &'a WakeTimer,
+    commands: C,
+    // messages waiting for room in the queue, oldest first
+    pending:  VecDeque<M>,
+    // set once we're shutting down, see `SHUTDOWN_TIMEOUT`
+    deadline: Option<Instant>,
+}
+
+impl<'a, C: Unpin, M, const N: usize, S: Sample> Unpin for RunFuture<'a, C, M, N, S> {}
+
+impl<'a, C, M, const N: usize, S: Sample> Future for RunFuture<'a, C, M, N, S>
+    where C: Stream<Item = UiCommand<N, S>> + Unpin, M: RtMessage<N, S> + From<Arc<Samples<N, S>>>
+{
+    type Output = bool;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<bool> {
+        let this = &mut *self;
+        this.ui.check_collector();
+        this.ui.check_feedback();
+
+        loop {
+            // get whatever we were sending on its way first, like `SendFuture`
+            while let Some(message) = this.pending.pop_front() {
+                if this.ui.outgoing.is_disconnected() {
+                    println!("[ui] realtime thread has gone away, giving up");
+                    return Poll::Ready(false);
+                }
+
+                if let Err(message) = this.ui.try_push_now(message) {
+                    this.pending.push_front(message);
+                    this.timer.wake_after(cx.waker(), RETRY_DELAY);
+                    return Poll::Pending;
+                }
+            }
+
+            // nothing tells us when feedback arrives, so look again every so often until it does
+            if let Some(deadline) = this.deadline {
+                if this.ui.stopped || this.ui.feedback.is_none() {
+                    return Poll::Ready(this.ui.stopped);
+                }
+
+                if Instant::now() >= deadline {
+                    println!("[ui] realtime thread never acknowledged the shutdown!");
+                    return Poll::Ready(false);
+                }
+
+                this.timer.wake_after(cx.waker(), RETRY_DELAY);
+                return Poll::Pending;
+            }
+
+            // the realtime thread has already stopped on its own, nothing is listening
+            if this.ui.stopped {
+                return Poll::Ready(true);
+            }
+
+            // the stream wakes us up when it has another command
+            match Pin::new(&mut this.commands).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+
+                Poll::Ready(Some(UiCommand::Shutdown)) | Poll::Ready(None) => {
+                    if let Some(ref shutdown) = this.ui.shutdown {
+                        shutdown.store(true, Ordering::Release);
+                    } else {
+                        this.pending.push_back(M::shutdown());
+                    }
+
+                    this.deadline = Some(Instant::now() + SHUTDOWN_TIMEOUT);
+                },
+
+                Poll::Ready(Some(command)) => this.pending.extend(this.ui.command_message(command)),
+            }
+        }
+    }
+}
+
+// beginning of control panel implementation
+// how often the control panel redraws, so the meters keep moving
+#[cfg(feature = "egui")]
+const PANEL_REFRESH: Duration = Duration::from_millis(33);
+
+// the range of the frequency knob, roughly what anyone can hear
+const PANEL_MIN_FREQUENCY: f32 = 20.0;
+const PANEL_MAX_FREQUENCY: f32 = 20_000.0;
+
+/// A window with a volume slider, a frequency control and level meters, built on egui. Build with
+/// `--features egui` and run with `--gui` to use it.
+/// The panel never touches the engine itself. It sends `UiCommand`s to the UI thread, and reads
+/// what the UI thread has heard from the realtime thread through its telemetry. Only the window
+/// needs egui, so everything it drives builds (and can be checked) without the feature
+struct ControlPanel {
+    commands:  UiClient,
+    telemetry: TripleReader<Telemetry>,
+    volume:    f32,
+    frequency: f32,
+}
+
+impl ControlPanel {
+    /// Set `ui` up to take commands from (and publish telemetry for) a new panel
+    fn attach(ui: &mut UIThread) -> Self {
+        let commands = ui.connect();
+
+        let (telemetry_tx, telemetry) = triple_buffer(Telemetry::default());
+        ui.set_telemetry(telemetry_tx);
+
+        ControlPanel::new(commands, telemetry)
+    }
+
+    /// A panel sending to `commands` and showing whatever is published to `telemetry`
+    fn new(commands: UiClient, telemetry: TripleReader<Telemetry>) -> Self {
+        ControlPanel {
+            commands,
+            telemetry,
+            volume:    DEFAULT_VOLUME,
+            frequency: DEFAULT_FREQUENCY,
+        }
+    }
+
+    /// Move the volume slider. The UI thread only hears about it if the volume actually changed
+    fn set_volume(&mut self, volume: f32) {
+        let volume = volume.clamp(0.0, 1.0);
+        if volume != self.volume {
+            self.volume = volume;
+            self.commands.send(UiCommand::SetVolume(volume));
+        }
+    }
+
+    /// Turn the frequency knob, see `set_volume`
+    fn set_frequency(&mut self, frequency: f32) {
+        let frequency = frequency.clamp(PANEL_MIN_FREQUENCY, PANEL_MAX_FREQUENCY);
+        if frequency != self.frequency {
+            self.frequency = frequency;
+            self.commands.send(UiCommand::SetFrequency(frequency));
+        }
+    }
+
+    /// The latest telemetry the UI thread has published, for the meters
+    fn telemetry(&mut self) -> Telemetry {
+        self.telemetry.update();
+        *self.telemetry.read()
+    }
+
+    /// Run the engine (see `run_threads_with`) until the window is closed. The window needs the
+    /// main thread on some platforms, so the engine runs on one of its own
+    #[cfg(feature = "egui")]
+    fn run(self, rt: RealtimeThread, ui: UIThread) -> Result<RunSummary, EngineError> {
+        let engine = thread::spawn(move || run_threads_with(rt, ui, SAMPLE_RATE));
+
+        let commands = self.commands.clone();
+        commands.send(UiCommand::SetVolume(self.volume));
+        if let Err(e) = eframe::run_native("synth", eframe::NativeOptions::default(), Box::new(|_cc| Ok(Box::new(self)))) {
+            println!("[gui] {}", e);
+        }
+
+        // the window has gone, so we're done
+        commands.send(UiCommand::Shutdown);
+        engine.join().unwrap()
+    }
+}
+
+#[cfg(feature = "egui")]
+impl eframe::App for ControlPanel {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let telemetry = self.telemetry();
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let mut volume = self.volume;
+            if ui.add(egui::Slider::new(&mut volume, 0.0..=1.0).text("volume")).changed() {
+                self.set_volume(volume);
+            }
+
+            let mut frequency = self.frequency;
+            let knob = egui::Slider::new(&mut frequency, PANEL_MIN_FREQUENCY..=PANEL_MAX_FREQUENCY)
+                .logarithmic(true)
+                .suffix(" Hz")
+                .text("frequency");
+            if ui.add(knob).changed() {
+                self.set_frequency(frequency);
+            }
+
+            ui.separator();
+            for (channel, (peak, rms)) in telemetry.levels.peak.iter().zip(telemetry.levels.rms.iter()).enumerate() {
+                let text = format!("channel {}: rms {:.2}, peak {:.2}", channel, rms, peak);
+                ui.add(egui::ProgressBar::new(rms.min(1.0)).text(text));
+            }
+
+            ui.label(format!("load {:.0}%, {} xruns", telemetry.load * 100.0, telemetry.xruns.total()));
+        });
+
+        ctx.request_repaint_after(PANEL_REFRESH);
+    }
+}
+// end of control panel implementation
+
+// beginning of remote control implementation
+/// A message which can be sent over the network, see `UIThread::serve_remote`
+trait WireMessage: Sized {
+    /// Append the encoded message to `out`
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Read one message, for an engine running at `sample_rate`. Returns None if the stream ends
+    /// cleanly before the next message starts
+    fn decode<R: Read>(r: &mut R, sample_rate: u32) -> io::Result<Option<Self>>;
+}
+
+// message tags on the wire. Everything after the tag is little endian. Samples always travel as
+// f32, whatever format either end runs in
+const WIRE_NEW_SAMPLES: u8    = 0; // followed by N * CHANNELS f32s, interleaved
+const WIRE_NEW_SAMPLES_AT: u8 = 1; // followed by a u64 position, then the samples
+const WIRE_SHUTDOWN: u8       = 2;
+const WIRE_SNAPSHOT: u8       = 3;
+const WIRE_PLAYBACK_MODE: u8  = 4; // followed by one byte, see `encode_playback_mode`
+const WIRE_NOTE_ON: u8        = 5; // followed by a u32 voice, an f32 gain, then the samples
+const WIRE_NOTE_OFF: u8       = 6; // followed by a u32 voice
+const WIRE_SET_VOLUME: u8     = 7; // followed by an f32
+const WIRE_PAUSE: u8          = 8;
+const WIRE_RESUME: u8         = 9;
+const WIRE_STOP: u8           = 10;
+const WIRE_DUMP_STATE: u8     = 11;
+const WIRE_BYPASS: u8         = 12; // followed by one byte, 1 to bypass and 0 not to
+const WIRE_SET_LAYER: u8      = 13; // followed by a u32 layer, an f32 gain, then the samples
+const WIRE_LAYER_GAIN: u8     = 14; // followed by a u32 layer and an f32 gain
+const WIRE_REMOVE_LAYER: u8   = 15; // followed by a u32 layer
+const WIRE_RAMP_VOLUME: u8    = 16; // followed by two f32 gains and a u64 number of frames
+const WIRE_PLAY_WAVETABLE: u8 = 17; // followed by an f32 frequency and gain, then the wavetable
+const WIRE_WAVETABLE_FREQ: u8 = 18; // followed by an f32
+const WIRE_STOP_WAVETABLE: u8 = 19;
+const WIRE_SET_ENVELOPE: u8   = 20; // followed by u64 attack and decay frames, an f32 sustain level, then u64 release frames
+const WIRE_SET_LFO: u8        = 21; // followed by one byte (0 for volume, 1 for frequency), then an f32 rate and depth
+const WIRE_SET_FILTER: u8     = 22; // followed by the f32 coefficients b0, b1, b2, a1 and a2
+const WIRE_SET_DELAY: u8      = 23; // followed by a u64 number of frames, then an f32 feedback and mix
+const WIRE_SET_REVERB: u8     = 24; // followed by an f32 room size, damping and mix
+const WIRE_SET_CHORUS: u8     = 25; // followed by an f32 delay, depth, rate, feedback and mix
+
+// the largest number of harmonics a wavetable sent over the network can have, so a bad message
+// can't keep us reading forever. Enough for a table built at 192 kHz
+const WIRE_MAX_HARMONICS: usize = 1 << 13;
+
+// a wavetable is sent as its harmonics, and rebuilt when it arrives. A u32 sample rate, a u32
+// count, then that many f32 amplitudes. The sample rate is the sender's, which needn't be ours
+fn encode_wavetable(table: &Wavetable, out: &mut Vec<u8>) {
+    out.extend_from_slice(&table.sample_rate.to_le_bytes());
+    out.extend_from_slice(&(table.harmonics.len() as u32).to_le_bytes());
+    for amplitude in &table.harmonics {
+        out.extend_from_slice(&amplitude.to_bits().to_le_bytes());
+    }
+}
+
+// the table is rebuilt for `sample_rate`, the rate we play at, and anything above what its
+// lowest octave has room for is read and thrown away
+fn decode_wavetable<R: Read>(r: &mut R, sample_rate: u32) -> io::Result<Arc<Wavetable>> {
+    let mut header = [0u8; 8];
+    r.read_exact(&mut header)?;
+    let count = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if count == 0 || count > WIRE_MAX_HARMONICS {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad number of harmonics {}", count)));
+    }
+
+    let playable = ((sample_rate as f32 / 2.0 / WAVETABLE_LOWEST) as usize).max(1);
+    let mut harmonics = Vec::with_capacity(count.min(playable));
+    for h in 0..count {
+        let mut amplitude = [0u8; 4];
+        r.read_exact(&mut amplitude)?;
+        if h < playable {
+            harmonics.push(f32::from_bits(u32::from_le_bytes(amplitude)));
+        }
+    }
+
+    Ok(Arc::new(Wavetable::from_harmonics(harmonics, sample_rate)))
+}
+
+fn encode_samples<const N: usize, S: Sample>(samples: &Samples<N, S>, out: &mut Vec<u8>) {
+    for sample in samples.as_flattened() {
+        out.extend_from_slice(&sample.to_f32().to_bits().to_le_bytes());
+    }
+}
+
+fn decode_samples<R: Read, const N: usize, S: Sample>(r: &mut R) -> io::Result<Arc<Samples<N, S>>> {
+    let mut samples = silence();
+    for frame in samples.iter_mut() {
+        let mut bytes = [0u8; CHANNELS * 4];
+        r.read_exact(&mut bytes)?;
+
+        for (sample, chunk) in frame.iter_mut().zip(bytes.chunks(4)) {
+            let value = f32::from_bits(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+            *sample = S::from_f32(value);
+        }
+    }
+
+    Ok(Arc::new(samples))
+}
+
+fn encode_playback_mode(mode: PlaybackMode) -> u8 {
+    match mode {
+        PlaybackMode::OneShot  => 0,
+        PlaybackMode::Loop     => 1,
+        PlaybackMode::PingPong => 2,
+    }
+}
+
+fn decode_playback_mode(mode: u8) -> io::Result<PlaybackMode> {
+    match mode {
+        0 => Ok(PlaybackMode::OneShot),
+        1 => Ok(PlaybackMode::Loop),
+        2 => Ok(PlaybackMode::PingPong),
+        other => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("unknown playback mode {}", other))),
+    }
+}
+
+impl<const N: usize, S: Sample> WireMessage for Message<N, S> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Message::NewSamples(ref samples) => {
+                out.push(WIRE_NEW_SAMPLES);
+                encode_samples(samples, out);
+            },
+
+            Message::NewSamplesAt(ref samples, at) => {
+                out.push(WIRE_NEW_SAMPLES_AT);
+                out.extend_from_slice(&at.to_le_bytes());
+                encode_samples(samples, out);
+            },
+
+            Message::SetPlaybackMode(mode) => {
+                out.push(WIRE_PLAYBACK_MODE);
+                out.push(encode_playback_mode(mode));
+            },
+
+            Message::NoteOn(id, ref samples, gain) => {
+                out.push(WIRE_NOTE_ON);
+                out.extend_from_slice(&id.to_le_bytes());
+                out.extend_from_slice(&gain.to_bits().to_le_bytes());
+                encode_samples(samples, out);
+            },
+
+            Message::NoteOff(id) => {
+                out.push(WIRE_NOTE_OFF);
+                out.extend_from_slice(&id.to_le_bytes());
+            },
+
+            Message::SetEnvelope(adsr) => {
+                out.push(WIRE_SET_ENVELOPE);
+                out.extend_from_slice(&(adsr.attack as u64).to_le_bytes());
+                out.extend_from_slice(&(adsr.decay as u64).to_le_bytes());
+                out.extend_from_slice(&adsr.sustain.to_bits().to_le_bytes());
+                out.extend_from_slice(&(adsr.release as u64).to_le_bytes());
+            },
+
+            Message::SetLfo(target, rate, depth) => {
+                out.push(WIRE_SET_LFO);
+                out.push(match target { LfoTarget::Volume => 0, LfoTarget::Frequency => 1 });
+                out.extend_from_slice(&rate.to_bits().to_le_bytes());
+                out.extend_from_slice(&depth.to_bits().to_le_bytes());
+            },
+
+            Message::SetFilter(c) => {
+                out.push(WIRE_SET_FILTER);
+                for coefficient in &[c.b0, c.b1, c.b2, c.a1, c.a2] {
+                    out.extend_from_slice(&coefficient.to_bits().to_le_bytes());
+                }
+            },
+
+            Message::SetDelay(frames, feedback, mix) => {
+                out.push(WIRE_SET_DELAY);
+                out.extend_from_slice(&(frames as u64).to_le_bytes());
+                out.extend_from_slice(&feedback.to_bits().to_le_bytes());
+                out.extend_from_slice(&mix.to_bits().to_le_bytes());
+            },
+
+            Message::SetReverb(room_size, damping, mix) => {
+                out.push(WIRE_SET_REVERB);
+                for value in &[room_size, damping, mix] {
+                    out.extend_from_slice(&value.to_bits().to_le_bytes());
+                }
+            },
+
+            Message::SetChorus(c) => {
+                out.push(WIRE_SET_CHORUS);
+                for value in &[c.delay, c.depth, c.rate, c.feedback, c.mix] {
+                    out.extend_from_slice(&value.to_bits().to_le_bytes());
+                }
+            },
+
+            Message::SetVolume(volume) => {
+                out.push(WIRE_SET_VOLUME);
+                out.extend_from_slice(&volume.to_bits().to_le_bytes());
+            },
+
+            Message::Bypass(bypass) => {
+                out.push(WIRE_BYPASS);
+                out.push(bypass as u8);
+            },
+
+            Message::RampVolume(from, to, frames) => {
+                out.push(WIRE_RAMP_VOLUME);
+                out.extend_from_slice(&from.to_bits().to_le_bytes());
+                out.extend_from_slice(&to.to_bits().to_le_bytes());
+                out.extend_from_slice(&(frames as u64).to_le_bytes());
+            },
+
+            Message::SetLayer(id, ref samples, gain) => {
+                out.push(WIRE_SET_LAYER);
+                out.extend_from_slice(&(id as u32).to_le_bytes());
+                out.extend_from_slice(&gain.to_bits().to_le_bytes());
+                encode_samples(samples, out);
+            },
+
+            Message::SetLayerGain(id, gain) => {
+                out.push(WIRE_LAYER_GAIN);
+                out.extend_from_slice(&(id as u32).to_le_bytes());
+                out.extend_from_slice(&gain.to_bits().to_le_bytes());
+            },
+
+            Message::RemoveLayer(id) => {
+                out.push(WIRE_REMOVE_LAYER);
+                out.extend_from_slice(&(id as u32).to_le_bytes());
+            },
+
+            Message::PlayWavetable(ref table, frequency, gain) => {
+                out.push(WIRE_PLAY_WAVETABLE);
+                out.extend_from_slice(&frequency.to_bits().to_le_bytes());
+                out.extend_from_slice(&gain.to_bits().to_le_bytes());
+                encode_wavetable(table, out);
+            },
+
+            Message::WavetableFrequency(frequency) => {
+                out.push(WIRE_WAVETABLE_FREQ);
+                out.extend_from_slice(&frequency.to_bits().to_le_bytes());
+            },
+
+            Message::StopWavetable => out.push(WIRE_STOP_WAVETABLE),
+            Message::Pause         => out.push(WIRE_PAUSE),
+            Message::Resume        => out.push(WIRE_RESUME),
+            Message::Stop          => out.push(WIRE_STOP),
+            Message::DumpState     => out.push(WIRE_DUMP_STATE),
+            Message::Snapshot      => out.push(WIRE_SNAPSHOT),
+            Message::Shutdown      => out.push(WIRE_SHUTDOWN),
+        }
+    }
+
+    fn decode<R: Read>(r: &mut R, sample_rate: u32) -> io::Result<Option<Self>> {
+        let mut tag = [0u8; 1];
+        if r.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+
+        match tag[0] {
+            WIRE_NEW_SAMPLES => Ok(Some(Message::NewSamples(decode_samples(r)?))),
+
+            WIRE_NEW_SAMPLES_AT => {
+                let mut at = [0u8; 8];
+                r.read_exact(&mut at)?;
+                let at = u64::from_le_bytes(at);
+                Ok(Some(Message::NewSamplesAt(decode_samples(r)?, at)))
+            },
+
+            WIRE_PLAYBACK_MODE => {
+                let mut mode = [0u8; 1];
+                r.read_exact(&mut mode)?;
+                Ok(Some(Message::SetPlaybackMode(decode_playback_mode(mode[0])?)))
+            },
+
+            WIRE_NOTE_ON => {
+                let mut header = [0u8; 8];
+                r.read_exact(&mut header)?;
+                let id = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+                let gain = f32::from_bits(u32::from_le_bytes([header[4], header[5], header[6], header[7]]));
+                Ok(Some(Message::NoteOn(id, decode_samples(r)?, gain)))
+            },
+
+            WIRE_NOTE_OFF => {
+                let mut id = [0u8; 4];
+                r.read_exact(&mut id)?;
+                Ok(Some(Message::NoteOff(u32::from_le_bytes(id))))
+            },
+
+            WIRE_SET_ENVELOPE => {
+                let mut body = [0u8; 28];
+                r.read_exact(&mut body)?;
+                let frames = |bytes: &[u8]| {
+                    let mut frames = [0u8; 8];
+                    frames.copy_from_slice(bytes);
+                    u64::from_le_bytes(frames) as usize
+                };
+                let sustain = f32::from_bits(u32::from_le_bytes([body[16], body[17], body[18], body[19]]));
+                Ok(Some(Message::SetEnvelope(Adsr {
+                    attack:  frames(&body[0..8]),
+                    decay:   frames(&body[8..16]),
+                    sustain,
+                    release: frames(&body[20..28]),
+                })))
+            },
+
+            WIRE_SET_LFO => {
+                let mut body = [0u8; 9];
+                r.read_exact(&mut body)?;
+                let target = match body[0] {
+                    0     => LfoTarget::Volume,
+                    1     => LfoTarget::Frequency,
+                    other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown lfo target {}", other))),
+                };
+                let rate = f32::from_bits(u32::from_le_bytes([body[1], body[2], body[3], body[4]]));
+                let depth = f32::from_bits(u32::from_le_bytes([body[5], body[6], body[7], body[8]]));
+                Ok(Some(Message::SetLfo(target, rate, depth)))
+            },
+
+            WIRE_SET_FILTER => {
+                let mut c = [0.0f32; 5];
+                for coefficient in c.iter_mut() {
+                    let mut bytes = [0u8; 4];
+                    r.read_exact(&mut bytes)?;
+                    *coefficient = f32::from_bits(u32::from_le_bytes(bytes));
+                }
+                Ok(Some(Message::SetFilter(BiquadCoefficients { b0: c[0], b1: c[1], b2: c[2], a1: c[3], a2: c[4] })))
+            },
+
+            WIRE_SET_DELAY => {
+                let mut body = [0u8; 16];
+                r.read_exact(&mut body)?;
+                let mut frames = [0u8; 8];
+                frames.copy_from_slice(&body[..8]);
+                let feedback = f32::from_bits(u32::from_le_bytes([body[8], body[9], body[10], body[11]]));
+                let mix = f32::from_bits(u32::from_le_bytes([body[12], body[13], body[14], body[15]]));
+                Ok(Some(Message::SetDelay(u64::from_le_bytes(frames) as usize, feedback, mix)))
+            },
+
+            WIRE_SET_REVERB => {
+                let mut values = [0.0f32; 3];
+                for value in values.iter_mut() {
+                    let mut bytes = [0u8; 4];
+                    r.read_exact(&mut bytes)?;
+                    *value = f32::from_bits(u32::from_le_bytes(bytes));
+                }
+                Ok(Some(Message::SetReverb(values[0], values[1], values[2])))
+            },
+
+            WIRE_SET_CHORUS => {
+                let mut values = [0.0f32; 5];
+                for value in values.iter_mut() {
+                    let mut bytes = [0u8; 4];
+                    r.read_exact(&mut bytes)?;
+                    *value = f32::from_bits(u32::from_le_bytes(bytes));
+                }
+                Ok(Some(Message::SetChorus(ChorusSettings {
+                    delay: values[0], depth: values[1], rate: values[2], feedback: values[3], mix: values[4],
+                })))
+            },
+
+            WIRE_SET_VOLUME => {
+                let mut volume = [0u8; 4];
+                r.read_exact(&mut volume)?;
+                Ok(Some(Message::SetVolume(f32::from_bits(u32::from_le_bytes(volume)))))
+            },
+
+            WIRE_BYPASS => {
+                let mut bypass = [0u8; 1];
+                r.read_exact(&mut bypass)?;
+                Ok(Some(Message::Bypass(bypass[0] != 0)))
+            },
+
+            WIRE_RAMP_VOLUME => {
+                let mut body = [0u8; 16];
+                r.read_exact(&mut body)?;
+                let from = f32::from_bits(u32::from_le_bytes([body[0], body[1], body[2], body[3]]));
+                let to = f32::from_bits(u32::from_le_bytes([body[4], body[5], body[6], body[7]]));
+                let mut frames = [0u8; 8];
+                frames.copy_from_slice(&body[8..]);
+                Ok(Some(Message::RampVolume(from, to, u64::from_le_bytes(frames) as usize)))
+            },
+
+            WIRE_SET_LAYER => {
+                let mut header = [0u8; 8];
+                r.read_exact(&mut header)?;
+                let id = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as LayerId;
+                let gain = f32::from_bits(u32::from_le_bytes([header[4], header[5], header[6], header[7]]));
+                Ok(Some(Message::SetLayer(id, decode_samples(r)?, gain)))
+            },
+
+            WIRE_LAYER_GAIN => {
+                let mut body = [0u8; 8];
+                r.read_exact(&mut body)?;
+                let id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]) as LayerId;
+                let gain = f32::from_bits(u32::from_le_bytes([body[4], body[5], body[6], body[7]]));
+                Ok(Some(Message::SetLayerGain(id, gain)))
+            },
+
+            WIRE_REMOVE_LAYER => {
+                let mut id = [0u8; 4];
+                r.read_exact(&mut id)?;
+                Ok(Some(Message::RemoveLayer(u32::from_le_bytes(id) as LayerId)))
+            },
+
+            WIRE_PLAY_WAVETABLE => {
+                let mut header = [0u8; 8];
+                r.read_exact(&mut header)?;
+                let frequency = f32::from_bits(u32::from_le_bytes([header[0], header[1], header[2], header[3]]));
+                let gain = f32::from_bits(u32::from_le_bytes([header[4], header[5], header[6], header[7]]));
+                Ok(Some(Message::PlayWavetable(decode_wavetable(r, sample_rate)?, frequency, gain)))
+            },
+
+            WIRE_WAVETABLE_FREQ => {
+                let mut frequency = [0u8; 4];
+                r.read_exact(&mut frequency)?;
+                Ok(Some(Message::WavetableFrequency(f32::from_bits(u32::from_le_bytes(frequency)))))
+            },
+
+            WIRE_STOP_WAVETABLE => Ok(Some(Message::StopWavetable)),
+            WIRE_PAUSE          => Ok(Some(Message::Pause)),
+            WIRE_RESUME         => Ok(Some(Message::Resume)),
+            WIRE_STOP           => Ok(Some(Message::Stop)),
+            WIRE_DUMP_STATE     => Ok(Some(Message::DumpState)),
+            WIRE_SNAPSHOT       => Ok(Some(Message::Snapshot)),
+            WIRE_SHUTDOWN       => Ok(Some(Message::Shutdown)),
+
+            other => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("unknown message tag {}", other))),
+        }
+    }
+}
+
+/// Drives a remote engine, see `UIThread::serve_remote`
+struct RemoteClient {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl RemoteClient {
+    fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(RemoteClient { stream, buffer: Vec::new() })
+    }
+
+    /// Send a message to the remote engine
+    fn send<M: WireMessage>(&mut self, message: &M) -> io::Result<()> {
+        self.buffer.clear();
+        message.encode(&mut self.buffer);
+        self.stream.write_all(&self.buffer)
+    }
+}
+
+impl<const N: usize, S: Sample> UIThread<Message<N, S>, N, S> {
+    /// Fade the realtime thread's output volume from `from` to `to` over `duration`. This is a
+    /// single message, the realtime thread's smoother does the rest
+    fn ramp_volume(&mut self, from: f32, to: f32, duration: Duration) -> Result<(), SendError<Message<N, S>>> {
+        let frames = (duration.as_secs_f64() * self.sample_rate as f64).round() as usize;
+        self.send(Message::RampVolume(from, to, frames))
+    }
+
+    /// Add a tremolo (`LfoTarget::Volume`) or vibrato (`LfoTarget::Frequency`), see
+    /// `RealtimeThread::set_lfo`
+    fn set_lfo(&mut self, target: LfoTarget, rate: f32, depth: f32) -> Result<(), SendError<Message<N, S>>> {
+        self.send(Message::SetLfo(target, rate, depth))
+    }
+
+    /// Filter the output, see `BiquadCoefficients::new`. The coefficients are worked out here and
+    /// the realtime thread fades over to them
+    fn set_filter(&mut self, kind: FilterKind, frequency: f32, q: f32) -> Result<(), SendError<Message<N, S>>> {
+        let coefficients = BiquadCoefficients::new(kind, frequency, q, self.sample_rate);
+        self.send(Message::SetFilter(coefficients))
+    }
+
+    /// Echo the output `time` later, see `RealtimeThread::set_delay`
+    fn set_delay(&mut self, time: Duration, feedback: f32, mix: f32) -> Result<(), SendError<Message<N, S>>> {
+        let frames = (time.as_secs_f64() * self.sample_rate as f64).round() as usize;
+        self.send(Message::SetDelay(frames, feedback, mix))
+    }
+
+    /// Change the chorus, see `ChorusSettings::chorus` and `ChorusSettings::flanger` for a start
+    fn set_chorus(&mut self, settings: ChorusSettings) -> Result<(), SendError<Message<N, S>>> {
+        self.send(Message::SetChorus(settings))
+    }
+
+    /// Change the reverb, see `RealtimeThread::adjust_reverb`
+    fn set_reverb(&mut self, room_size: f32, damping: f32, mix: f32) -> Result<(), SendError<Message<N, S>>> {
+        self.send(Message::SetReverb(room_size, damping, mix))
+    }
+
+    /// Fade the filter out, see `set_filter`
+    fn clear_filter(&mut self) -> Result<(), SendError<Message<N, S>>> {
+        self.send(Message::SetFilter(BiquadCoefficients::passthrough()))
+    }
+
+    /// Shape the voices started from now on with an envelope, see `Adsr::from_times`
+    fn set_envelope(&mut self, attack: Duration, decay: Duration, sustain: f32, release: Duration) -> Result<(), SendError<Message<N, S>>> {
+        let adsr = Adsr::from_times(attack, decay, sustain, release, self.sample_rate);
+        self.send(Message::SetEnvelope(adsr))
+    }
+}
+
+impl<M: RtMessage<N, S> + WireMessage, const N: usize, S: Sample> UIThread<M, N, S> {
+    /// Take commands from the network instead of generating them here.
+    /// Connections on `listener` are handled one at a time, and every message read from them is
+    /// sent on to the realtime thread. Returns once a shutdown message has been forwarded
+    fn serve_remote(&mut self, listener: &TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let mut stream = io::BufReader::new(stream?);
+
+            // a bad connection shouldn't take the engine down, just drop it and wait for the next
+            loop {
+                let message = match M::decode(&mut stream, self.sample_rate) {
+                    Ok(Some(message)) => message,
+                    Ok(None)          => break,
+                    Err(e)            => {
+                        println!("[ui] dropping remote connection: {}", e);
+                        break;
+                    },
+                };
+
+                let shutdown = message.is_shutdown();
+                match self.send(message) {
+                    Ok(()) => (),
+                    Err(e @ SendError::Disconnected(_)) => {
+                        return Err(io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()));
+                    },
+                    Err(e) => println!("[ui] {}", e),
+                }
+
+                if shutdown {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+// end of remote control implementation
+
+// beginning of OSC server implementation
+
+// largest OSC packet `OscServer` reads. Anything longer is cut short and won't decode
+const OSC_PACKET_SIZE: usize = 1536;
+
+/// An argument of an OSC message. Only the types controllers send for knobs, faders and buttons
+/// are understood
+#[derive(Clone, Debug, PartialEq)]
+enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+impl OscArg {
+    // private. the argument as a number, if it is a finite one
+    fn to_f32(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(value)   => Some(value as f32),
+            OscArg::Float(value) => Some(value).filter(|value| value.is_finite()),
+            OscArg::Str(_)       => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match *self {
+            OscArg::Str(ref value) => Some(value),
+            _                      => None,
+        }
+    }
+}
+
+/// A message from an OSC controller, see `OscServer`
+#[derive(Clone, Debug, PartialEq)]
+struct OscMessage {
+    address: String,
+    args:    Vec<OscArg>,
+}
+
+impl OscMessage {
+    /// Decode every message in an OSC packet. Bundles are taken apart, and their time tags are
+    /// ignored: everything is carried out as soon as it arrives
+    fn decode(packet: &[u8]) -> io::Result<Vec<OscMessage>> {
+        let mut messages = Vec::new();
+        OscMessage::decode_into(packet, &mut messages)?;
+        Ok(messages)
+    }
+
+    // private. decodes a packet, which is either a bundle or a single message
+    fn decode_into(packet: &[u8], messages: &mut Vec<OscMessage>) -> io::Result<()> {
+        let mut r = packet;
+        if packet.starts_with(b"#bundle\0") {
+            // skip "#bundle" and the time tag, then read each size-prefixed element
+            r = &r[16.min(r.len())..];
+            while !r.is_empty() {
+                let size = osc_int(&mut r)? as usize;
+                if size > r.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bundle element runs off the end"));
+                }
+
+                OscMessage::decode_into(&r[..size], messages)?;
+                r = &r[size..];
+            }
+            return Ok(());
+        }
+
+        let address = osc_string(&mut r)?;
+        if !address.starts_with('/') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad address {:?}", address)));
+        }
+
+        // old senders leave the type tags out, meaning no arguments
+        let tags = if r.is_empty() { String::from(",") } else { osc_string(&mut r)? };
+        let mut args = Vec::new();
+        for tag in tags.chars().skip(1) {
+            let arg = match tag {
+                'i'   => OscArg::Int(osc_int(&mut r)?),
+                'f'   => OscArg::Float(f32::from_bits(osc_int(&mut r)? as u32)),
+                's'   => OscArg::Str(osc_string(&mut r)?),
+                // booleans carry no data, TouchOSC buttons send these
+                'T'   => OscArg::Int(1),
+                'F'   => OscArg::Int(0),
+                other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported argument type {:?}", other))),
+            };
+            args.push(arg);
+        }
+
+        messages.push(OscMessage { address, args });
+        Ok(())
+    }
+}
+
+// private. reads a big endian 32 bit integer
+fn osc_int(r: &mut &[u8]) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(i32::from_be_bytes(bytes))
+}
+
+// private. reads a nul terminated string, padded out to a multiple of 4 bytes
+fn osc_string(r: &mut &[u8]) -> io::Result<String> {
+    let end = r.iter().position(|&b| b == 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unterminated string"))?;
+    let text = String::from_utf8(r[..end].to_vec())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "string isn't utf-8"))?;
+
+    let padded = (end + 4) & !3;
+    *r = &r[padded.min(r.len())..];
+    Ok(text)
+}
+
+impl<const N: usize, S: Sample> UiCommand<N, S> {
+    /// The command for an OSC message, see `OscServer` for the addresses
+    fn from_osc(message: &OscMessage) -> io::Result<Self> {
+        let number = message.args.first().and_then(OscArg::to_f32);
+        let name = message.args.first().and_then(OscArg::as_str);
+        let command = match (&message.address[..], number, name) {
+            ("/engine/volume", Some(volume), _)            => Some(UiCommand::SetVolume(volume.clamp(0.0, 1.0))),
+            ("/engine/frequency", Some(hz), _) if hz.is_finite() && hz > 0.0 => Some(UiCommand::SetFrequency(hz)),
+            ("/engine/note", Some(note), _)                => Some(UiCommand::SetFrequency(note_frequency(note.clamp(0.0, 127.0) as u8))),
+            ("/engine/waveform", _, Some(name))            => Waveform::parse(name).map(UiCommand::SetWaveform),
+            ("/engine/learn", _, Some(name))               => Parameter::parse(name).map(UiCommand::Learn),
+            ("/engine/preset/save", _, Some(path))         => Some(UiCommand::SavePreset(PathBuf::from(path))),
+            ("/engine/preset/load", _, Some(path))         => Some(UiCommand::LoadPreset(PathBuf::from(path))),
+            ("/engine/undo", _, _)                         => Some(UiCommand::Undo),
+            ("/engine/redo", _, _)                         => Some(UiCommand::Redo),
+            ("/engine/quit", _, _)                         => Some(UiCommand::Shutdown),
+
+            ("/engine/cc", Some(controller), _) => message.args.get(1).and_then(OscArg::to_f32).map(|value| {
+                UiCommand::Controller(controller.clamp(0.0, 127.0) as u8, value.clamp(0.0, 127.0) as u8)
+            }),
+
+            _ => None,
+        };
+
+        command.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("can't handle {} {:?}", message.address, message.args))
+        })
+    }
+}
+
+/// Drives the engine from OSC controllers (TouchOSC, SuperCollider, Max...) over UDP. Every
+/// message becomes a `UiCommand`, sent through a `UiClient`:
+///
+/// ```text
+/// /engine/volume      f   from 0 to 1
+/// /engine/frequency   f   in Hz
+/// /engine/note        i   a MIDI note
+/// /engine/waveform    s   sine, square, saw, triangle or noise
+/// /engine/cc          ii  a MIDI controller and its value, see `ControlMap`
+/// /engine/learn       s   volume, frequency or waveform
+/// /engine/preset/save s   a file
+/// /engine/preset/load s   a file
+/// /engine/undo
+/// /engine/redo
+/// /engine/quit
+/// ```
+///
+/// Numbers can be ints or floats. Nothing is sent back
+struct OscServer<const N: usize = FRAMES, S: Sample = f32> {
+    socket: UdpSocket,
+    client: UiClient<N, S>,
+}
+
+impl<const N: usize, S: Sample> OscServer<N, S> {
+    /// Listen for OSC packets on `addr` (`0.0.0.0:9000`, say), passing commands to `client`
+    fn bind(addr: &str, client: UiClient<N, S>) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        Ok(OscServer { socket, client })
+    }
+
+    /// Handle packets until a `/engine/quit` has been passed on or the UI thread has gone away.
+    /// Packets which don't make sense are reported and dropped
+    fn run(&self) -> io::Result<()> {
+        let mut packet = [0; OSC_PACKET_SIZE];
+        loop {
+            let (size, from) = self.socket.recv_from(&mut packet)?;
+            let messages = match OscMessage::decode(&packet[..size]) {
+                Ok(messages) => messages,
+                Err(e)       => {
+                    println!("[osc] bad packet from {}: {}", from, e);
+                    continue;
+                },
+            };
+
+            for message in &messages {
+                let command = match UiCommand::from_osc(message) {
+                    Ok(command) => command,
+                    Err(e)      => {
+                        println!("[osc] {}", e);
+                        continue;
+                    },
+                };
+
+                let shutdown = matches!(command, UiCommand::Shutdown);
+                if !self.client.send(command) || shutdown {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// end of OSC server implementation
+
+// number of messages the UI thread can queue up for the realtime thread
+const QUEUE_CAPACITY: usize = 4;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // run with --interactive to play with the engine from the terminal
+    let (tx, rx) = ring_buffer(QUEUE_CAPACITY);
+    let mut ui = UIThread::new(tx);
+    ui.set_interactive(args.iter().any(|arg| arg == "--interactive"));
+
+    // --meter prints the output levels and stats 10 times a second
+    if args.iter().any(|arg| arg == "--meter") {
+        ui.set_meter(METER_EVERY);
+    }
+
+    // --watch <file> keeps the sound in line with a parameter file, see `SynthParams`
+    if let Some(path) = args.iter().position(|arg| arg == "--watch").and_then(|i| args.get(i + 1)) {
+        ui.watch_params(path);
+    }
+
+    // --preset <file> starts with the settings in a preset, see `UIThread::save_preset`
+    if let Some(path) = args.iter().position(|arg| arg == "--preset").and_then(|i| args.get(i + 1)) {
+        if let Err(e) = ui.load_preset(Path::new(path)) {
+            println!("couldn't load {}: {}", path, e);
+            return;
+        }
+    }
+
+    // or with --script <file> to play through a script, see `Script`
+    if let Some(path) = args.iter().position(|arg| arg == "--script").and_then(|i| args.get(i + 1)) {
+        match Script::load(path) {
+            Ok(script) => ui.set_script(script),
+            Err(e)     => {
+                println!("couldn't load {}: {}", path, e);
+                return;
+            },
+        }
+    }
+
+    // --osc <addr> takes commands from OSC controllers as well, see `OscServer`
+    if let Some(addr) = args.iter().position(|arg| arg == "--osc").and_then(|i| args.get(i + 1)) {
+        let server = match OscServer::bind(addr, ui.connect()) {
+            Ok(server) => server,
+            Err(e)     => {
+                println!("couldn't listen on {}: {}", addr, e);
+                return;
+            },
+        };
+
+        // nothing waits for this thread, it goes when the process does
+        thread::spawn(move || if let Err(e) = server.run() {
+            println!("[osc] {}", e);
+        });
+    }
+
+    // or, built with --features egui, with --gui to get a window, see `ControlPanel`
+    #[cfg(feature = "egui")]
+    let panel = if args.iter().any(|arg| arg == "--gui") { Some(ControlPanel::attach(&mut ui)) } else { None };
+
+    // run_threads_with takes care of starting and stopping the collector
+    let rt = RealtimeThread::<Message, FRAMES, f32>::new(rx);
+    #[cfg(feature = "egui")]
+    let result = match panel {
+        Some(panel) => panel.run(rt, ui),
+        None        => run_threads_with(rt, ui, SAMPLE_RATE),
+    };
+    #[cfg(not(feature = "egui"))]
+    let result = run_threads_with(rt, ui, SAMPLE_RATE);
+
+    match result {
+        Ok(summary) => if !summary.clean_shutdown {
+            println!("realtime thread didn't shut down cleanly! {:?}", summary);
+        },
+        Err(e) => println!("engine failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // private. the same checks against whichever queue is underneath
+    fn queue_keeps_order_and_evicts_oldest<Q: Queue<u32>>() {
+        let (mut tx, mut rx) = queue_with::<u32, Q>(2);
+        assert_eq!(tx.try_push(0), Ok(()));
+        assert_eq!(tx.try_push(1), Ok(()));
+        assert_eq!(tx.try_push(2), Err(2));
+        assert_eq!(tx.force_push(2), Some(0));
+
+        let mut batch = vec![3, 4];
+        assert_eq!(tx.try_push_all(&mut batch), 0);
+        assert_eq!(tx.occupancy().depth, 2);
+        assert_eq!(tx.occupancy().high_water, 2);
+
+        assert_eq!(rx.try_pop(), Some(1));
+        assert_eq!(rx.try_pop(), Some(2));
+        assert_eq!(rx.try_pop(), None);
+        assert_eq!(tx.try_push_all(&mut batch), 2);
+        assert_eq!(rx.try_pop(), Some(3));
+        assert_eq!(rx.try_pop(), Some(4));
+
+        drop(rx);
+        assert!(tx.is_disconnected());
+    }
+
+    #[test]
+    fn queue_backends_behave_the_same() {
+        queue_keeps_order_and_evicts_oldest::<RingBuffer<u32>>();
+        queue_keeps_order_and_evicts_oldest::<MpscQueue<u32>>();
+    }
+
+    #[test]
+    fn control_panel_sends_only_real_changes() {
+        let (commands, received) = mpsc::channel();
+        let (mut telemetry, reader) = triple_buffer(Telemetry::default());
+        let mut panel = ControlPanel::new(UiClient { commands }, reader);
+
+        panel.set_volume(2.0);
+        panel.set_volume(1.0);
+        panel.set_frequency(5.0);
+        let sent: Vec<UiCommand> = received.try_iter().collect();
+        assert_eq!(sent.len(), 2);
+        assert!(matches!(sent[0], UiCommand::SetVolume(v) if v == 1.0));
+        assert!(matches!(sent[1], UiCommand::SetFrequency(f) if f == PANEL_MIN_FREQUENCY));
+
+        telemetry.write(Telemetry { load: 0.5, ..Telemetry::default() });
+        assert_eq!(panel.telemetry().load, 0.5);
+    }
+
+    #[test]
+    fn gc_hooks_may_track_more_values() {
+        let gc = Arc::new(Mutex::new(GC::<u32>::with_interval(Duration::from_millis(1))));
+        let (done_tx, done) = mpsc::channel();
+
+        // the hook can only reach the collector through the lock, which the collector must not hold
+        let hook_gc = Arc::downgrade(&gc);
+        gc.lock().unwrap().track_with(Arc::new(1), move |value| {
+            hook_gc.upgrade().unwrap().lock().unwrap().track(Arc::new(value + 1));
+            done_tx.send(value).unwrap();
+        });
+
+        assert_eq!(done.recv_timeout(Duration::from_secs(5)), Ok(1));
+        gc.lock().unwrap().flush();
+        assert_eq!(gc.lock().unwrap().stats().tracked, 2);
+    }
+
+    #[test]
+    fn gc_hooks_wait_for_weak_references() {
+        let mut gc = GC::<u32>::new();
+        let (done_tx, done) = mpsc::channel();
+        let value = Arc::new(7);
+        let weak = Arc::downgrade(&value);
+
+        gc.track_with(value, move |value| done_tx.send(value).unwrap());
+        gc.flush();
+        assert!(done.try_recv().is_err());
+
+        // upgrading here used to race with the collector unwrapping the Arc
+        assert_eq!(weak.upgrade().map(|v| *v), Some(7));
+        drop(weak);
+        gc.flush();
+        assert_eq!(done.try_recv(), Ok(7));
+    }
+
+    // private. counts how many of its kind have been dropped
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn gc_frees_garbage_on_the_way_down() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut gc = GC::with_interval(Duration::from_secs(3600));
+        gc.pause();
+
+        let kept = Arc::new(DropCounter(dropped.clone()));
+        gc.track(kept.clone());
+        gc.track(Arc::new(DropCounter(dropped.clone())));
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+
+        // the thread is stopped and joined, then whatever is garbage goes with it
+        drop(gc);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(Arc::strong_count(&kept), 1);
+    }
+
+    #[test]
+    fn send_all_publishes_the_batch_at_once() {
+        let (tx, rx) = ring_buffer(8);
+        let mut ui: UIThread = UIThread::new(tx);
+
+        // the realtime side can only ever see none of the batch or all of it
+        let watcher = thread::spawn(move || {
+            let mut depth = 0;
+            while depth == 0 {
+                depth = rx.occupancy().depth;
+            }
+            (depth, rx)
+        });
+
+        ui.send_all(vec![Message::SetVolume(0.1), Message::SetVolume(0.2), Message::SetVolume(0.3)]).unwrap();
+        let (depth, mut rx) = watcher.join().unwrap();
+        assert_eq!(depth, 3);
+
+        for (seq, volume) in [0.1, 0.2, 0.3].iter().enumerate() {
+            let sequenced = rx.try_pop().unwrap();
+            assert_eq!(sequenced.seq, seq as u64);
+            assert!(matches!(sequenced.message, Message::SetVolume(v) if v == *volume));
+        }
+        assert!(rx.try_pop().is_none());
+    }
+
+    #[test]
+    fn send_all_gives_up_once_the_engine_is_stopping() {
+        let (tx, _rx) = ring_buffer(2);
+        let mut ui: UIThread = UIThread::new(tx);
+        let shutdown = Arc::new(AtomicBool::new(true));
+        ui.set_shutdown_flag(shutdown);
+
+        // nobody is popping, so only the first two fit
+        let batch = (0..4).map(|i| Message::SetVolume(i as f32)).collect::<Vec<_>>();
+        match ui.send_all(batch) {
+            Err(SendError::Disconnected(Message::SetVolume(v))) => assert_eq!(v, 2.0),
+            _ => panic!("expected the first message which didn't fit back"),
+        }
+    }
+
+    // private. a block where every sample is `value`
+    fn constant(value: f32) -> Arc<Samples> {
+        Arc::new([[value; CHANNELS]; FRAMES])
+    }
+
+    #[test]
+    fn voice_manager_steals_the_oldest_and_retriggers_in_place() {
+        let mut voices = VoiceManager::<FRAMES, f32>::new(2);
+        let (first, second) = (constant(0.1), constant(0.2));
+        assert!(voices.note_on(1, first.clone(), 1.0).is_none());
+        assert!(voices.note_on(2, second.clone(), 1.0).is_none());
+
+        // the same voice again starts it over, it doesn't take another
+        let replaced = voices.note_on(2, constant(0.3), 1.0).unwrap();
+        assert!(Arc::ptr_eq(&replaced, &second));
+        assert_eq!(voices.active(), 2);
+
+        let stolen = voices.note_on(3, constant(0.4), 1.0).unwrap();
+        assert!(Arc::ptr_eq(&stolen, &first));
+        assert_eq!(voices.active(), 2);
+    }
+
+    // private. timing for a callback of `frames` frames starting at `sample_time`
+    fn info(frames: usize, sample_time: u64) -> CallbackInfo {
+        CallbackInfo { frame_count: frames, sample_time, wall_deadline: None }
+    }
+
+    // private. plays full scale, and wants the engine to stop
+    struct Quitter;
+
+    impl RealtimeCallback for Quitter {
+        fn process(&mut self, _info: CallbackInfo, _input: &[f32], out: &mut [f32]) -> CallbackStatus {
+            out.fill(1.0);
+            CallbackStatus::Shutdown
+        }
+    }
+
+    #[test]
+    fn processor_shutdown_fades_out_first() {
+        let (_tx, rx) = ring_buffer(4);
+        let mut rt = RealtimeThread::<Message, FRAMES, f32>::new(rx);
+        rt.set_processor(Box::new(Quitter));
+        rt.set_shutdown_fade(FRAMES * 2);
+
+        let mut output = vec![0.0; FRAMES * CHANNELS];
+        assert!(rt.realtime_callback(info(FRAMES, 0), &[], &mut output) == CallbackStatus::Continue);
+        assert_eq!(output[0], 1.0);
+        assert!(output[CHANNELS * (FRAMES - 1)] < output[0]);
+        assert!(output[CHANNELS * (FRAMES - 1)] > 0.0);
+
+        assert!(rt.realtime_callback(info(FRAMES, FRAMES as u64), &[], &mut output) == CallbackStatus::Shutdown);
+        assert!(output[CHANNELS * (FRAMES - 1)] < 0.01);
+    }
+
+    #[test]
+    fn delay_reaches_all_the_way_back() {
+        let mut line = DelayLine::new(5);
+        line.push([1.0; CHANNELS]);
+        for _ in 0..line.max_delay() - 1 {
+            line.push([0.0; CHANNELS]);
+        }
+
+        // anything further back than the line can reach gets the furthest it can
+        assert_eq!(line.read(line.max_delay() as f32), [1.0; CHANNELS]);
+        assert_eq!(line.read(100.0), [1.0; CHANNELS]);
+        assert_eq!(line.read(line.max_delay() as f32 - 0.5), [0.5; CHANNELS]);
+
+        // and `Delay` asks for exactly that much
+        let mut delay = Delay::new(4);
+        delay.set(100, 0.0, 1.0);
+        delay.time.set_immediate(delay.time.target());
+        delay.mix.set_immediate(1.0);
+
+        let mut frames = vec![0.0; 6 * CHANNELS];
+        frames[..CHANNELS].fill(1.0);
+        delay.process(&mut frames);
+        assert_eq!(frames[4 * CHANNELS], 1.0);
+        assert_eq!(frames.iter().filter(|&&sample| sample != 0.0).count(), CHANNELS);
+    }
+
+    // private. waits up to a few seconds for `done`, for things which happen on another thread
+    fn eventually<F: Fn() -> bool>(done: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    #[test]
+    fn gc_rescans_at_its_interval() {
+        let mut quick = GC::with_interval(Duration::from_millis(1));
+        let mut slow = GC::with_interval(Duration::from_secs(3600));
+        let (a, b) = (Arc::new(1), Arc::new(2));
+        quick.track(a.clone());
+        slow.track(b.clone());
+        quick.flush();
+        slow.flush();
+
+        // nobody tells the collector when these become garbage, it has to look again
+        drop((a, b));
+        assert!(eventually(|| quick.stats().collected == 1));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(slow.stats().collected, 0);
+    }
+
+    #[test]
+    fn gc_stats_follow_the_pool() {
+        let mut gc = GC::new();
+        let kept: Vec<Arc<u32>> = (0..3).map(Arc::new).collect();
+        for arc in &kept {
+            gc.track(arc.clone());
+        }
+        gc.flush();
+        assert_eq!(gc.stats(), GcStats { tracked: 3, collected: 0, pool_size: 3, high_water: 3 });
+
+        drop(kept);
+        gc.flush();
+        assert_eq!(gc.stats(), GcStats { tracked: 3, collected: 3, pool_size: 0, high_water: 3 });
+    }
+
+    #[test]
+    fn one_collector_takes_any_type() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut gc = AnyGC::new();
+        gc.track_any(Arc::new(DropCounter(dropped.clone())));
+        gc.track_any(Arc::new(String::from("not a counter")));
+        let samples: Arc<Samples> = constant(0.5);
+        gc.track_any(samples.clone());
+
+        gc.flush();
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(gc.stats().collected, 2);
+
+        drop(samples);
+        gc.flush();
+        assert_eq!(gc.stats().collected, 3);
+    }
+
+    #[test]
+    fn budgeted_scans_pick_up_where_they_stopped() {
+        let counters = GcCounters::new();
+        let mut garbage = Vec::new();
+        let mut pool: ArcPool = (0..4).map(|i| Entry::new(Box::new(Arc::new(i)))).collect();
+        let kept = Arc::new(4);
+        pool.insert(0, Entry::new(Box::new(kept.clone())));
+
+        // no time at all still gets one item looked at per scan
+        let mut cursor = 0;
+        cleanup(&mut pool, &counters, &mut cursor, Some(Duration::ZERO), &mut garbage);
+        assert_eq!((pool.len(), cursor), (5, 1));
+        cleanup(&mut pool, &counters, &mut cursor, Some(Duration::ZERO), &mut garbage);
+        assert_eq!((pool.len(), cursor), (4, 1));
+
+        cleanup(&mut pool, &counters, &mut cursor, None, &mut garbage);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(garbage.len(), 4);
+        assert_eq!(counters.collected.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn a_full_pool_hands_arcs_back() {
+        let mut gc = GC::new();
+        gc.set_capacity(2);
+        let kept: Vec<Arc<u32>> = (0..3).map(Arc::new).collect();
+        assert!(gc.try_track(kept[0].clone()).is_ok());
+        assert!(gc.try_track(kept[1].clone()).is_ok());
+        match gc.try_track(kept[2].clone()) {
+            Err(TrackError::PoolFull(arc)) => assert!(Arc::ptr_eq(&arc, &kept[2])),
+            Ok(())                         => panic!("the pool should be full"),
+        }
+
+        // once the collector catches up there's room again
+        drop(kept);
+        gc.flush();
+        assert!(gc.try_track(Arc::new(3)).is_ok());
+    }
+
+    #[test]
+    fn tracking_wakes_the_collector() {
+        let mut gc = GC::with_interval(Duration::from_secs(3600));
+        gc.flush();
+
+        // the collector is parked for the whole interval unless something wakes it
+        gc.track(Arc::new(1));
+        assert!(eventually(|| gc.stats().collected == 1));
+    }
+
+    #[test]
+    fn flush_drains_even_while_paused() {
+        let mut gc = GC::with_interval(Duration::from_secs(3600));
+        gc.pause();
+        for i in 0..10 {
+            gc.track(Arc::new(i));
+        }
+
+        gc.flush();
+        assert_eq!(gc.stats().collected, 10);
+        assert_eq!(gc.stats().pool_size, 0);
+    }
+
+    #[test]
+    fn nothing_is_freed_while_paused() {
+        let mut gc = GC::with_interval(Duration::from_millis(1));
+
+        // once a pass is over, the next one sees the pause
+        gc.flush();
+        gc.pause();
+        gc.track(Arc::new(1));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(gc.stats().collected, 0);
+
+        gc.resume();
+        assert!(eventually(|| gc.stats().collected == 1));
+    }
+
+    #[test]
+    fn gc_collects_everything_tracked_through_a_full_intake() {
+        let gc = GC::<usize>::with_interval(Duration::from_millis(1));
+        let handle = gc.handle();
+
+        // with the collector paused, nothing drains the intake, so it has to fill up
+        gc.pause();
+        let mut count = 0;
+        let rejected = loop {
+            match handle.try_track(Arc::new(count)) {
+                Ok(())   => count += 1,
+                Err(arc) => break arc,
+            }
+        };
+        assert!(count >= INTAKE_CAPACITY);
+
+        // then keep retrying, the way `GcScope::track_samples` does
+        gc.resume();
+        let mut arc = rejected;
+        for _ in 0..INTAKE_CAPACITY * 3 {
+            while let Err(ret) = handle.try_track(arc) {
+                arc = ret;
+                thread::yield_now();
+            }
+            count += 1;
+            arc = Arc::new(count);
+        }
+        drop(arc);
+
+        gc.flush();
+        let stats = gc.stats();
+        assert_eq!(stats.tracked, count);
+        assert_eq!(stats.collected, stats.tracked);
+        assert_eq!(stats.pool_size, 0);
+    }
+
+    // private. says which thread dropped it
+    struct DroppedOn(mpsc::Sender<thread::ThreadId>);
+
+    impl Drop for DroppedOn {
+        fn drop(&mut self) {
+            let _ = self.0.send(thread::current().id());
+        }
+    }
+
+    #[test]
+    fn deferred_boxes_and_vecs_are_freed_by_the_collector() {
+        let gc = GC::<u32>::new();
+        let handle = gc.handle();
+        let (tx, dropped) = mpsc::channel();
+
+        assert!(handle.defer(Box::new(DroppedOn(tx.clone()))).is_ok());
+        assert!(handle.defer_vec(vec![DroppedOn(tx.clone()), DroppedOn(tx)]).is_ok());
+        gc.flush();
+
+        let here = thread::current().id();
+        let threads: Vec<thread::ThreadId> = dropped.try_iter().collect();
+        assert_eq!(threads.len(), 3);
+        assert!(threads.iter().all(|&id| id != here));
+        assert_eq!(gc.stats().collected, 2);
+    }
+
+    #[test]
+    fn long_lived_items_move_to_the_cold_generation() {
+        let counters = GcCounters::new();
+        let mut garbage = Vec::new();
+        let kept = Arc::new(0);
+        let mut hot: ArcPool = vec![Entry::new(Box::new(kept.clone()))];
+        let mut cold = ArcPool::new();
+
+        for _ in 0..PROMOTE_AFTER {
+            promote(&mut hot, &mut cold);
+            assert_eq!(cold.len(), 0);
+            cleanup(&mut hot, &counters, &mut 0, None, &mut garbage);
+        }
+
+        // a newcomer stays put, the survivor moves
+        hot.push(Entry::new(Box::new(kept.clone())));
+        promote(&mut hot, &mut cold);
+        assert_eq!((hot.len(), cold.len()), (1, 1));
+        assert_eq!(cold[0].survived, PROMOTE_AFTER);
+    }
+
+    #[test]
+    fn weak_tracking_never_keeps_a_value_alive() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut gc = GC::with_interval(Duration::from_secs(3600));
+        let value = Arc::new(DropCounter(dropped.clone()));
+        gc.track_weak(&value);
+        gc.flush();
+        assert_eq!(Arc::strong_count(&value), 1);
+        assert_eq!(gc.stats().pool_size, 1);
+
+        // the last owner drops the value itself, the collector only tidies up after
+        drop(value);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+        gc.flush();
+        assert_eq!(gc.stats().pool_size, 0);
+    }
+
+    #[test]
+    fn the_collector_thread_is_named_and_niced() {
+        let config = GcConfig { name: "test-collector".to_string(), nice: Some(5), ..GcConfig::default() };
+        let mut gc = GC::<u32>::with_config(config);
+        let (tx, seen) = mpsc::channel();
+
+        // hooks run on the collector thread, so they can look at it
+        gc.track_with(Arc::new(0), move |_| {
+            #[cfg(target_os = "linux")]
+            let nice = {
+                extern "C" {
+                    fn getpriority(which: i32, who: u32) -> i32;
+                }
+                Some(unsafe { getpriority(0, 0) })
+            };
+            #[cfg(not(target_os = "linux"))]
+            let nice = None;
+
+            tx.send((thread::current().name().map(String::from), nice)).unwrap();
+        });
+
+        let (name, nice) = seen.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name.as_deref(), Some("test-collector"));
+        if let Some(nice) = nice {
+            assert!(nice >= 5);
+        }
+    }
+
+    #[test]
+    fn the_scope_tracks_what_is_sent_and_frees_it_on_the_way_out() {
+        let (tx, mut rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        let scope = GcScope::<FRAMES, f32>::new();
+        ui.set_collector(scope.handle(), None);
+
+        let samples = constant(0.5);
+        let weak = Arc::downgrade(&samples);
+        ui.send(Message::NewSamples(samples)).unwrap();
+        scope.collector.flush();
+        assert_eq!(scope.collector.stats().tracked, 1);
+
+        // the realtime thread lets go, so only the collector holds on to it
+        drop(rx.try_pop());
+        drop(ui);
+        drop(scope);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn rt_arcs_are_freed_by_the_collector() {
+        let gc = GC::new();
+        let (tx, dropped) = mpsc::channel();
+        let first = gc.rt_arc(DroppedOn(tx.clone()));
+        let second = first.clone();
+        assert_eq!(RtArc::strong_count(&first), 2);
+
+        drop(first);
+        drop(second);
+        gc.flush();
+        assert_ne!(dropped.try_recv().unwrap(), thread::current().id());
+        assert_eq!(gc.stats().collected, 1);
+
+        // once the collector is gone, the last owner frees it
+        let orphan = gc.rt_arc(DroppedOn(tx));
+        drop(gc);
+        drop(orphan);
+        assert_eq!(dropped.try_recv().unwrap(), thread::current().id());
+    }
+
+    #[test]
+    fn the_recycler_gets_buffers_back_reset() {
+        let mut gc = GC::new();
+        let recycler = Recycler::new(|buffer: &mut Vec<f32>| buffer.iter_mut().for_each(|s| *s = 0.0));
+
+        let mut buffer = recycler.get(|| vec![0.0; 4]);
+        Arc::get_mut(&mut buffer).unwrap()[0] = 1.0;
+        let address = buffer.as_ptr();
+        gc.track_recycled(buffer, &recycler);
+        gc.flush();
+
+        // the same allocation comes back, wiped
+        let again = recycler.get(|| panic!("should have been recycled"));
+        assert_eq!(again.as_ptr(), address);
+        assert_eq!(*again, vec![0.0; 4]);
+
+        // a handle can do the same
+        let handle = gc.handle();
+        assert!(handle.try_track_recycled(again, &recycler).is_ok());
+        gc.flush();
+        assert_eq!(recycler.get(Vec::new).as_ptr(), address);
+    }
+
+    #[test]
+    fn collector_reports_reach_the_ui_thread() {
+        let mut gc = GC::<Samples>::new();
+        let reports = gc.reports();
+        assert!(reports.is_some());
+        assert!(gc.reports().is_none());
+
+        let (tx, _rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        ui.set_collector(gc.handle(), reports);
+
+        gc.track(constant(0.0));
+        gc.flush();
+        ui.check_collector();
+        let last = ui.last_report.unwrap();
+        assert_eq!(last.pool_size, 0);
+        assert_eq!(gc.stats().collected, 1);
+    }
+
+    #[test]
+    fn sharded_collectors_add_up() {
+        let mut gc = ShardedGC::new(4);
+        let handle = gc.handle();
+        let kept: Vec<Arc<usize>> = (0..64).map(Arc::new).collect();
+        for arc in &kept[..32] {
+            gc.track(arc.clone());
+        }
+        for arc in &kept[32..] {
+            handle.try_track(arc.clone()).unwrap();
+        }
+
+        gc.flush();
+        let stats = gc.stats();
+        assert_eq!((stats.tracked, stats.collected, stats.pool_size), (64, 0, 64));
+
+        // the work is spread out, no shard ends up with everything
+        assert!(gc.shards.iter().all(|shard| shard.stats().tracked < 64));
+
+        drop(kept);
+        gc.flush();
+        assert_eq!(gc.stats().collected, 64);
+    }
+
+    #[test]
+    fn ring_keeps_order_through_wrap_around() {
+        let (mut tx, mut rx) = ring_buffer(4);
+        for i in 0..4 {
+            assert_eq!(tx.try_push(i), Ok(()));
+        }
+        assert_eq!(tx.try_push(4), Err(4));
+
+        // take a couple off and put more on, so the indices wrap
+        assert_eq!(rx.try_pop(), Some(0));
+        assert_eq!(rx.try_pop(), Some(1));
+        assert_eq!(tx.try_push(4), Ok(()));
+        assert_eq!(tx.try_push(5), Ok(()));
+
+        for i in 2..6 {
+            assert_eq!(rx.try_pop(), Some(i));
+        }
+        assert_eq!(rx.try_pop(), None);
+    }
+
+    #[test]
+    fn ring_notices_either_end_going_away() {
+        let (mut tx, rx) = ring_buffer::<u32>(2);
+        assert!(!tx.is_disconnected());
+        drop(rx);
+        assert!(tx.is_disconnected());
+        assert_eq!(tx.try_push(1), Ok(()));
+        assert_eq!(tx.try_push(2), Ok(()));
+
+        // full, and nobody left to make room
+        assert_eq!(tx.push(3), Err(3));
+
+        let (tx, rx) = ring_buffer::<u32>(2);
+        drop(tx);
+        assert!(rx.is_disconnected());
+    }
+
+    #[test]
+    fn triple_buffer_reader_sees_only_the_latest() {
+        let (mut writer, mut reader) = triple_buffer(0);
+        assert!(!reader.update());
+        assert_eq!(*reader.read(), 0);
+
+        // anything published in between is simply overwritten
+        writer.write(1);
+        writer.write(2);
+        *writer.buffer() = 3;
+        writer.publish();
+        assert!(reader.update());
+        assert_eq!(*reader.read(), 3);
+
+        // nothing new, so the reader keeps what it had
+        assert!(!reader.update());
+        assert_eq!(*reader.read(), 3);
+
+        writer.write(4);
+        assert!(reader.update());
+        assert_eq!(*reader.read(), 4);
+    }
+
+    // private. a realtime thread, with the sending end of its queue
+    fn realtime() -> (RingProducer<Sequenced<Message>>, RealtimeThread) {
+        let (tx, rx) = ring_buffer(16);
+        (tx, RealtimeThread::new(rx))
+    }
+
+    // private. queue `messages` up for the realtime thread, numbered from `first`
+    fn queue(tx: &mut RingProducer<Sequenced<Message>>, first: u64, messages: Vec<Message>) {
+        for (i, message) in messages.into_iter().enumerate() {
+            assert!(tx.try_push(Sequenced { seq: first + i as u64, message }).is_ok());
+        }
+    }
+
+    // private. runs one callback of FRAMES frames, the one after `callbacks` others
+    fn callback(rt: &mut RealtimeThread, callbacks: u64) -> (CallbackStatus, Vec<f32>) {
+        let mut output = vec![0.0; FRAMES * CHANNELS];
+        let status = rt.realtime_callback(info(FRAMES, callbacks * FRAMES as u64), &[], &mut output);
+        (status, output)
+    }
+
+    // private. everything the realtime thread has reported so far
+    fn reported(feedback: &mut RingConsumer<Feedback>) -> Vec<Feedback> {
+        let mut events = Vec::new();
+        while let Some(event) = feedback.try_pop() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn the_realtime_thread_reports_back() {
+        let (mut tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+
+        callback(&mut rt, 0);
+        assert!(reported(&mut feedback).contains(&Feedback::Underrun));
+
+        queue(&mut tx, 0, vec![Message::NewSamples(constant(0.5))]);
+        callback(&mut rt, 1);
+        let events = reported(&mut feedback);
+        assert!(events.contains(&Feedback::BufferApplied));
+        assert!(!events.contains(&Feedback::Underrun));
+    }
+
+    #[test]
+    fn the_sample_pool_reuses_buffers_once_they_come_back() {
+        let gc = GC::new();
+        let mut pool = SamplePool::<FRAMES, f32>::new(2, gc.handle());
+
+        let first = pool.fill(|samples| samples[0] = [1.0; CHANNELS]).unwrap();
+        let second = pool.fill(|samples| samples[0] = [2.0; CHANNELS]).unwrap();
+        assert!(pool.fill(|_| ()).is_none());
+
+        // the same allocation comes back, refilled, once the realtime thread lets go
+        let address = Arc::as_ptr(&first);
+        drop(first);
+        let third = pool.fill(|samples| samples[0] = [3.0; CHANNELS]).unwrap();
+        assert_eq!(Arc::as_ptr(&third), address);
+        assert_eq!(third[0], [3.0; CHANNELS]);
+
+        // buffers still in use when the pool goes away are left to the collector
+        drop(pool);
+        gc.flush();
+        assert_eq!(gc.stats().tracked, 2);
+        drop((second, third));
+        gc.flush();
+        assert_eq!(gc.stats().collected, 2);
+    }
+
+    #[test]
+    fn each_callback_drains_the_queue_up_to_the_cap() {
+        let (mut tx, mut rt) = realtime();
+        queue(&mut tx, 0, (0..5).map(|i| Message::SetVolume(i as f32 / 5.0)).collect());
+
+        rt.set_max_messages(3);
+        callback(&mut rt, 0);
+        assert_eq!(tx.occupancy().depth, 2);
+
+        rt.set_max_messages(MAX_MESSAGES_PER_CALLBACK);
+        callback(&mut rt, 1);
+        assert_eq!(tx.occupancy().depth, 0);
+    }
+
+    // private. the volume a SetVolume message carries
+    fn volume_of(message: &Message) -> f32 {
+        match *message {
+            Message::SetVolume(volume) => volume,
+            _                          => panic!("expected a volume"),
+        }
+    }
+
+    // private. what the realtime thread would see, sequence numbers and volumes
+    fn drain_volumes(rx: &mut RingConsumer<Sequenced<Message>>) -> Vec<(u64, f32)> {
+        let mut seen = Vec::new();
+        while let Some(sequenced) = rx.try_pop() {
+            seen.push((sequenced.seq, volume_of(&sequenced.message)));
+        }
+        seen
+    }
+
+    #[test]
+    fn overflow_policies_decide_what_a_full_queue_does() {
+        let full = |policy| {
+            let (tx, rx) = ring_buffer(2);
+            let mut ui: UIThread = UIThread::with_overflow_policy(tx, policy);
+            ui.try_send(Message::SetVolume(0.0)).unwrap();
+            ui.try_send(Message::SetVolume(0.1)).unwrap();
+            (ui, rx)
+        };
+
+        let (mut ui, mut rx) = full(OverflowPolicy::DropOldest);
+        match ui.try_send(Message::SetVolume(0.2)) {
+            Err(SendError::DroppedOldest(message)) => assert_eq!(volume_of(&message), 0.0),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(drain_volumes(&mut rx), vec![(1, 0.1), (2, 0.2)]);
+
+        // a dropped message still uses up a number, so the realtime thread sees the gap
+        let (mut ui, mut rx) = full(OverflowPolicy::DropNewest);
+        match ui.try_send(Message::SetVolume(0.2)) {
+            Err(SendError::DroppedNewest(message)) => assert_eq!(volume_of(&message), 0.2),
+            other => panic!("{:?}", other),
+        }
+        drain_volumes(&mut rx);
+        ui.try_send(Message::SetVolume(0.3)).unwrap();
+        assert_eq!(drain_volumes(&mut rx), vec![(3, 0.3)]);
+
+        // one handed back may be sent again, so it keeps its number
+        let (mut ui, mut rx) = full(OverflowPolicy::Error);
+        match ui.try_send(Message::SetVolume(0.2)) {
+            Err(SendError::Full(message)) => assert_eq!(volume_of(&message), 0.2),
+            other => panic!("{:?}", other),
+        }
+        drain_volumes(&mut rx);
+        ui.try_send(Message::SetVolume(0.3)).unwrap();
+        assert_eq!(drain_volumes(&mut rx), vec![(2, 0.3)]);
+
+        // blocking gives up once nobody is going to make room
+        let (mut ui, rx) = full(OverflowPolicy::Block);
+        drop(rx);
+        assert!(matches!(ui.try_send(Message::SetVolume(0.2)), Err(SendError::Disconnected(_))));
+    }
+
+    #[test]
+    fn shutdown_jumps_the_queue() {
+        let (tx, rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        let mut rt: RealtimeThread = RealtimeThread::new(rx);
+        let flag = Arc::new(AtomicBool::new(false));
+        ui.set_shutdown_flag(flag.clone());
+        rt.set_shutdown_flag(flag);
+        rt.set_shutdown_fade(0);
+
+        // a full queue can't hold the shutdown up
+        for i in 0..4 {
+            ui.try_send(Message::SetVolume(i as f32)).unwrap();
+        }
+        ui.shutdown().unwrap();
+        assert!(callback(&mut rt, 0).0 == CallbackStatus::Shutdown);
+        assert_eq!(ui.outgoing.occupancy().depth, 4);
+    }
+
+    // private. a message type of the application's own
+    #[derive(Clone)]
+    enum Custom {
+        Play(Arc<Samples>),
+        Quit,
+    }
+
+    impl RtMessage for Custom {
+        fn shutdown() -> Self {
+            Custom::Quit
+        }
+
+        fn is_shutdown(&self) -> bool {
+            matches!(*self, Custom::Quit)
+        }
+
+        fn samples(&self) -> Option<&Arc<Samples>> {
+            match *self {
+                Custom::Play(ref samples) => Some(samples),
+                Custom::Quit              => None,
+            }
+        }
+
+        fn apply(self, rt: &mut RealtimeThread<Custom>) {
+            if let Custom::Play(samples) = self {
+                rt.play(samples);
+            }
+        }
+    }
+
+    #[test]
+    fn applications_can_bring_their_own_messages() {
+        let (tx, rx) = ring_buffer(4);
+        let mut ui = UIThread::<Custom>::new(tx);
+        let mut rt = RealtimeThread::<Custom>::new(rx);
+        rt.set_shutdown_fade(0);
+        let gc = GC::new();
+        ui.set_collector(gc.handle(), None);
+
+        ui.send(Custom::Play(constant(0.5))).unwrap();
+        let mut output = vec![0.0; FRAMES * CHANNELS];
+        assert!(rt.realtime_callback(info(FRAMES, 0), &[], &mut output) == CallbackStatus::Continue);
+        assert!(output.iter().any(|&sample| sample != 0.0));
+
+        // what the message carried is tracked, like the engine's own messages
+        gc.flush();
+        assert_eq!(gc.stats().tracked, 1);
+
+        ui.send(Custom::shutdown()).unwrap();
+        assert!(rt.realtime_callback(info(FRAMES, FRAMES as u64), &[], &mut output) == CallbackStatus::Shutdown);
+    }
+
+    #[test]
+    fn swapped_samples_skip_the_queue() {
+        let (tx, mut rt) = realtime();
+        let mut ui: UIThread = UIThread::new(tx);
+        let slot = Arc::new(SampleSlot::new());
+        ui.set_sample_slot(slot.clone());
+        rt.set_sample_slot(slot.clone());
+
+        // only the latest samples are ever picked up, the ones before are just let go of
+        let replaced = constant(0.25);
+        let weak = Arc::downgrade(&replaced);
+        ui.swap_samples(replaced);
+        ui.swap_samples(constant(0.5));
+        assert!(weak.upgrade().is_none());
+
+        let (_, output) = callback(&mut rt, 0);
+        assert!(output.iter().any(|&sample| sample != 0.0));
+        assert!(slot.take().is_none());
+        assert_eq!(ui.outgoing.occupancy().depth, 0);
+    }
+
+    #[test]
+    fn timestamped_samples_start_on_their_frame() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        queue(&mut tx, 0, vec![Message::NewSamplesAt(constant(0.5), 10), Message::NewSamplesAt(constant(0.25), FRAMES as u64 + 3)]);
+
+        let (_, output) = callback(&mut rt, 0);
+        assert!(output[..10 * CHANNELS].iter().all(|&sample| sample == 0.0));
+        assert!(output[10 * CHANNELS..].iter().all(|&sample| sample == 0.5));
+
+        // one due in a later callback waits for it
+        let (_, output) = callback(&mut rt, 1);
+        assert!(output[..3 * CHANNELS].iter().all(|&sample| sample == 0.5));
+        assert!(output[3 * CHANNELS..].iter().all(|&sample| sample == 0.25));
+    }
+
+    #[test]
+    fn broadcast_feeds_every_consumer_still_around() {
+        let mut hub = Broadcast::new();
+        assert_eq!(hub.push(0), Err(0));
+
+        let mut first = hub.subscribe(4);
+        let mut second = hub.subscribe(2);
+        let gone = hub.subscribe(2);
+        drop(gone);
+        assert_eq!(hub.consumers(), 2);
+
+        assert_eq!(hub.try_push(1), Ok(()));
+        assert_eq!((first.try_pop(), second.try_pop()), (Some(1), Some(1)));
+
+        // a consumer with room still gets the item when another is full
+        assert_eq!(hub.try_push(2), Ok(()));
+        assert_eq!(hub.try_push(3), Ok(()));
+        assert_eq!(hub.try_push(4), Err(4));
+        assert_eq!(hub.occupancy().depth, 3);
+        let drain = |rx: &mut RingConsumer<i32>| (0..4).filter_map(|_| rx.try_pop()).collect::<Vec<_>>();
+        assert_eq!(drain(&mut first), vec![2, 3, 4]);
+        assert_eq!(drain(&mut second), vec![2, 3]);
+
+        drop((first, second));
+        assert!(hub.is_disconnected());
+    }
+
+    #[test]
+    fn gaps_in_the_sequence_are_reported() {
+        let (mut tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+
+        queue(&mut tx, 0, vec![Message::SetVolume(0.5), Message::SetVolume(0.6)]);
+        queue(&mut tx, 5, vec![Message::SetVolume(0.7)]);
+        callback(&mut rt, 0);
+        let gaps: Vec<Feedback> = reported(&mut feedback).into_iter().filter(|event| matches!(*event, Feedback::Gap(_))).collect();
+        assert_eq!(gaps, vec![Feedback::Gap(3)]);
+    }
+
+    #[test]
+    fn the_ui_thread_waits_for_shutdown_to_be_acknowledged() {
+        let (tx, rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        let mut rt: RealtimeThread = RealtimeThread::new(rx);
+        let (feedback_tx, feedback_rx) = ring_buffer(FEEDBACK_CAPACITY);
+        rt.set_feedback(feedback_tx);
+        ui.set_feedback(feedback_rx);
+        rt.set_shutdown_fade(0);
+
+        ui.shutdown().unwrap();
+        assert!(!ui.wait_for_stop(Duration::from_millis(10)));
+
+        assert!(callback(&mut rt, 0).0 == CallbackStatus::Shutdown);
+        assert!(ui.wait_for_stop(Duration::from_secs(1)));
+    }
+
+    // private. a parameter change which only the latest of matters
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Knob(usize, f32);
+
+    impl RtMessage for Knob {
+        fn shutdown() -> Self {
+            Knob(usize::MAX, 0.0)
+        }
+
+        fn is_shutdown(&self) -> bool {
+            self.0 == usize::MAX
+        }
+
+        fn key(&self) -> Option<usize> {
+            Some(self.0)
+        }
+
+        fn apply(self, _rt: &mut RealtimeThread<Knob>) {}
+    }
+
+    #[test]
+    fn keyed_messages_coalesce_while_the_queue_is_full() {
+        let (tx, mut rx) = ring_buffer(2);
+        let mut ui = UIThread::<Knob>::new(tx);
+        for value in [0.1, 0.2, 0.3, 0.4] {
+            ui.send(Knob(0, value)).unwrap();
+        }
+        ui.send(Knob(1, 1.0)).unwrap();
+        assert_eq!(ui.flush_coalesced(), 2);
+
+        // only the latest value of each knob is left waiting
+        assert_eq!((rx.try_pop().unwrap().message, rx.try_pop().unwrap().message), (Knob(0, 0.1), Knob(0, 0.2)));
+        assert_eq!(ui.flush_coalesced(), 0);
+        assert_eq!((rx.try_pop().unwrap().message, rx.try_pop().unwrap().message), (Knob(0, 0.4), Knob(1, 1.0)));
+    }
+
+    // private. wakes the thread which is blocked on a future
+    struct Unparker(thread::Thread);
+
+    impl std::task::Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // private. the simplest possible executor, polls `future` on this thread until it finishes
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(Unparker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending       => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn async_sends_wait_for_room_without_blocking() {
+        let (tx, mut rx) = ring_buffer(2);
+        let mut handle = AsyncUiHandle::new(UIThread::<Message>::new(tx));
+        block_on(handle.send(Message::SetVolume(0.1))).unwrap();
+        block_on(handle.send(Message::SetVolume(0.2))).unwrap();
+
+        // a full queue leaves the future pending, rather than holding up the executor
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut handle.send(Message::SetVolume(0.3))).poll(&mut cx).is_pending());
+
+        // and the timer wakes it to look again once the realtime thread has made room
+        let consumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            let first = rx.try_pop().unwrap();
+            (first, rx)
+        });
+        block_on(handle.send(Message::SetVolume(0.4))).unwrap();
+        let (first, mut rx) = consumer.join().unwrap();
+        assert_eq!(volume_of(&first.message), 0.1);
+        assert_eq!(drain_volumes(&mut rx), vec![(1, 0.2), (2, 0.4)]);
+    }
+
+    #[test]
+    fn rt_log_drops_instead_of_blocking() {
+        let (mut logger, log_thread) = rt_log(2);
+        let dropped = logger.dropped.clone();
+
+        // far more than the logging thread can print between polls, none of these may wait
+        for i in 0..10_000 {
+            logger.log("[test] record", i as f32);
+        }
+        assert!(dropped.load(Ordering::Relaxed) > 0);
+
+        // the thread finishes once the logger is gone
+        drop(logger);
+        log_thread.join();
+    }
+
+    #[test]
+    fn run_threads_works_with_any_queue_capacity() {
+        // a rendezvous-sized queue and a roomy one both get through the demo and shut down cleanly
+        for &capacity in &[1, 64] {
+            let summary = run_threads::<Message, FRAMES, f32>(capacity, 48_000).unwrap();
+            assert!(summary.clean_shutdown);
+            assert!(summary.position > 0);
+        }
+    }
+
+    #[test]
+    fn a_vanished_realtime_thread_is_reported_not_a_panic() {
+        let (tx, rx) = ring_buffer(2);
+        let mut ui: UIThread = UIThread::new(tx);
+        drop(rx);
+
+        // the message comes back, so the caller can decide what to do with it
+        match ui.try_send(Message::SetVolume(0.5)) {
+            Err(SendError::Disconnected(message)) => assert_eq!(volume_of(&message), 0.5),
+            other => panic!("{:?}", other),
+        }
+
+        // and the demo gives up quietly
+        assert!(!ui.step(0.5));
+    }
+
+    // private. the message, encoded, decoded, then encoded again
+    fn round_trip(message: &Message) -> (Vec<u8>, Vec<u8>) {
+        let mut bytes = Vec::new();
+        message.encode(&mut bytes);
+
+        let decoded = <Message>::decode(&mut &bytes[..], SAMPLE_RATE).unwrap().unwrap();
+        let mut again = Vec::new();
+        decoded.encode(&mut again);
+        (bytes, again)
+    }
+
+    #[test]
+    fn wire_messages_round_trip() {
+        let mut samples: Samples = silence();
+        for (i, frame) in samples.iter_mut().enumerate() {
+            *frame = [i as f32 / FRAMES as f32; CHANNELS];
+        }
+        let samples = Arc::new(samples);
+
+        let messages = vec![
+            Message::NewSamples(samples.clone()),
+            Message::NewSamplesAt(samples, 4800),
+            Message::SetVolume(0.25),
+            Message::SetDelay(480, 0.5, 0.3),
+            Message::SetReverb(0.7, 0.2, 0.4),
+            Message::SetChorus(ChorusSettings::flanger(SAMPLE_RATE)),
+            Message::WavetableFrequency(220.0),
+            Message::StopWavetable,
+            Message::Shutdown,
+        ];
+
+        for message in &messages {
+            let (bytes, again) = round_trip(message);
+            assert_eq!(bytes, again);
+        }
+
+        // a stream which ends between messages is fine, one which ends in the middle of one isn't
+        assert!(Message::<FRAMES, f32>::decode(&mut &[][..], SAMPLE_RATE).unwrap().is_none());
+        let mut bytes = Vec::new();
+        Message::<FRAMES, f32>::SetVolume(0.25).encode(&mut bytes);
+        assert!(Message::<FRAMES, f32>::decode(&mut &bytes[..2], SAMPLE_RATE).is_err());
+    }
+
+    #[test]
+    fn remote_commands_reach_the_realtime_thread() {
+        let (tx, mut rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let client = thread::spawn(move || {
+            let mut client = RemoteClient::connect(&addr).unwrap();
+            client.send(&Message::<FRAMES, f32>::SetVolume(0.3)).unwrap();
+            client.send(&Message::<FRAMES, f32>::Shutdown).unwrap();
+        });
+
+        // returns once the shutdown has been passed along
+        ui.serve_remote(&listener).unwrap();
+        client.join().unwrap();
+
+        assert_eq!(volume_of(&rx.try_pop().unwrap().message), 0.3);
+        assert!(rx.try_pop().unwrap().message.is_shutdown());
+        assert!(rx.try_pop().is_none());
+    }
+
+    #[test]
+    fn snapshots_describe_the_realtime_thread() {
+        let (mut tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+
+        let samples = constant(0.5);
+        let buffer_id = &*samples as *const Samples as usize;
+        queue(&mut tx, 0, vec![Message::NewSamples(samples)]);
+        callback(&mut rt, 0);
+        queue(&mut tx, 1, vec![Message::Snapshot]);
+        callback(&mut rt, 1);
+
+        let snapshot = reported(&mut feedback).into_iter().find_map(|event| match event {
+            Feedback::Snapshot(snapshot) => Some(snapshot),
+            _                            => None,
+        }).unwrap();
+        assert_eq!(snapshot.buffer_id, Some(buffer_id));
+        assert_eq!(snapshot.position, FRAMES as u64);
+        assert_eq!(snapshot.callbacks, 1);
+        assert!(!snapshot.underrun);
+        assert!(!snapshot.waiting);
+    }
+
+    #[test]
+    fn submitters_each_keep_their_own_order() {
+        const PRODUCERS: usize = 4;
+        const EACH: usize = 100;
+        let (submitter, submissions) = submission_queue::<Message, FRAMES, f32>(8, None);
+
+        // a small queue, so the producers have to wait on the consumer and on each other
+        let producers: Vec<_> = (0..PRODUCERS).map(|p| {
+            let submitter = submitter.clone();
+            thread::spawn(move || {
+                for i in 0..EACH {
+                    assert!(submitter.submit(Message::SetVolume((p * 1000 + i) as f32)).is_ok());
+                }
+            })
+        }).collect();
+
+        let mut next = [0; PRODUCERS];
+        let mut received = 0;
+        while received < PRODUCERS * EACH {
+            match submissions.queue.pop() {
+                Some(message) => {
+                    let value = volume_of(&message) as usize;
+                    let (p, i) = (value / 1000, value % 1000);
+                    assert_eq!(i, next[p]);
+                    next[p] += 1;
+                    received += 1;
+                },
+                None => thread::yield_now(),
+            }
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        // with the realtime thread's end gone, nobody is going to make room
+        drop(submissions);
+        while submitter.try_submit(Message::SetVolume(0.0)).is_ok() {}
+        assert!(submitter.submit(Message::SetVolume(0.0)).is_err());
+    }
+
+    #[test]
+    fn replaced_samples_come_back_for_reuse() {
+        let (mut tx, mut rt) = realtime();
+        let (returns_tx, mut returns) = ring_buffer(4);
+        rt.set_returns(returns_tx, None);
+        rt.set_crossfade(0);
+
+        let first = constant(0.1);
+        queue(&mut tx, 0, vec![Message::NewSamples(first.clone())]);
+        callback(&mut rt, 0);
+        assert!(returns.try_pop().is_none());
+
+        // once something else is playing, the old samples go back rather than being dropped here
+        queue(&mut tx, 1, vec![Message::NewSamples(constant(0.2))]);
+        callback(&mut rt, 1);
+        let returned = returns.try_pop().unwrap();
+        assert!(Arc::ptr_eq(&returned, &first));
+        assert!(returns.try_pop().is_none());
+    }
+
+    #[test]
+    fn the_ui_can_watch_the_queue_fill_up() {
+        let (tx, mut rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        assert_eq!(ui.queue_occupancy(), QueueOccupancy { depth: 0, capacity: 4, high_water: 0 });
+
+        for i in 0..3 {
+            ui.try_send(Message::SetVolume(i as f32 / 10.0)).unwrap();
+        }
+        assert_eq!(ui.queue_occupancy(), QueueOccupancy { depth: 3, capacity: 4, high_water: 3 });
+
+        // draining the queue doesn't forget how deep it got
+        drain_volumes(&mut rx);
+        assert_eq!(ui.queue_occupancy(), QueueOccupancy { depth: 0, capacity: 4, high_water: 3 });
+    }
+
+    // private. a processor which halves whatever the engine played
+    struct Halve;
+
+    impl RealtimeCallback for Halve {
+        fn process(&mut self, _info: CallbackInfo, _input: &[f32], out: &mut [f32]) -> CallbackStatus {
+            for sample in out.iter_mut() {
+                *sample *= 0.5;
+            }
+            CallbackStatus::Continue
+        }
+    }
+
+    #[test]
+    fn processors_run_on_what_the_engine_played() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_processor(Box::new(Halve));
+        rt.set_crossfade(0);
+
+        // messages are still handled as usual, the processor only sees the output
+        queue(&mut tx, 0, vec![Message::NewSamples(constant(0.8))]);
+        let (status, output) = callback(&mut rt, 0);
+        assert!(status == CallbackStatus::Continue);
+        assert!(output.iter().all(|&sample| sample == 0.4));
+    }
+
+    #[test]
+    fn each_channel_plays_its_own_samples() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+
+        let mut frames = [[0.0; CHANNELS]; FRAMES];
+        for frame in frames.iter_mut() {
+            for (c, sample) in frame.iter_mut().enumerate() {
+                *sample = (c + 1) as f32 / 10.0;
+            }
+        }
+        queue(&mut tx, 0, vec![Message::NewSamples(Arc::new(frames))]);
+
+        // interleaved, one sample per channel per frame
+        let (_, output) = callback(&mut rt, 0);
+        for frame in output.chunks(CHANNELS) {
+            for (c, &sample) in frame.iter().enumerate() {
+                assert_eq!(sample, (c + 1) as f32 / 10.0);
+            }
+        }
+    }
+
+    #[test]
+    fn monitoring_mixes_the_input_in() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        queue(&mut tx, 0, vec![Message::NewSamples(constant(0.1))]);
+
+        let input = vec![0.4; FRAMES * CHANNELS];
+        let mut output = vec![0.0; FRAMES * CHANNELS];
+
+        // off by default
+        rt.realtime_callback(info(FRAMES, 0), &input, &mut output);
+        assert!(output.iter().all(|&sample| sample == 0.1));
+
+        rt.set_monitor_gain(0.5);
+        rt.realtime_callback(info(FRAMES, FRAMES as u64), &input, &mut output);
+        assert!(output.iter().all(|&sample| (sample - 0.3).abs() < 1e-6));
+    }
+
+    // private. samples whose value is their frame number, so the output shows where it read from
+    fn ramp() -> Arc<Samples> {
+        let mut samples: Samples = silence();
+        for (i, frame) in samples.iter_mut().enumerate() {
+            *frame = [i as f32; CHANNELS];
+        }
+        Arc::new(samples)
+    }
+
+    // private. the frames the realtime thread read from, one callback of `frames` frames
+    fn frames_read(rt: &mut RealtimeThread, frames: usize, sample_time: u64) -> Vec<usize> {
+        let mut output = vec![0.0; frames * CHANNELS];
+        rt.realtime_callback(info(frames, sample_time), &[], &mut output);
+        output.chunks(CHANNELS).map(|frame| frame[0] as usize).collect()
+    }
+
+    #[test]
+    fn the_host_picks_the_callback_size() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        queue(&mut tx, 0, vec![Message::NewSamples(ramp())]);
+
+        // each callback carries on from where the last one stopped, whatever its size
+        assert_eq!(frames_read(&mut rt, 10, 0), (0..10).collect::<Vec<_>>());
+        let long = frames_read(&mut rt, FRAMES + 20, 10);
+        assert_eq!(long, (10..FRAMES + 30).map(|i| i % FRAMES).collect::<Vec<_>>());
+        assert_eq!(frames_read(&mut rt, 1, FRAMES as u64 + 30), vec![30]);
+    }
+
+    #[test]
+    fn the_period_is_picked_at_compile_time() {
+        const LONG: usize = 256;
+        let (mut tx, rx) = ring_buffer(4);
+        let mut rt = RealtimeThread::<Message<LONG>, LONG, f32>::new(rx);
+        let mut ui = UIThread::<Message<LONG>, LONG, f32>::new(ring_buffer(4).0);
+        rt.set_crossfade(0);
+
+        // the UI thread makes a whole period's worth, and the realtime thread plays all of it
+        let samples = Arc::new(ui.compute_samples(0.5));
+        assert_eq!(samples.len(), LONG);
+        assert!(tx.try_push(Sequenced { seq: 0, message: Message::NewSamples(samples.clone()) }).is_ok());
+
+        let mut output = vec![0.0; LONG * CHANNELS];
+        rt.realtime_callback(info(LONG, 0), &[], &mut output);
+        assert_eq!(output, samples.as_flattened());
+    }
+
+    #[test]
+    fn sample_formats_convert_at_the_edges() {
+        // integer formats clip, and full scale maps onto full scale
+        let floats = [0.0f32, 0.5, -1.0, 2.0];
+        let mut ints = [0i16; 4];
+        convert_samples(&floats, &mut ints);
+        assert_eq!(ints, [0, i16::MAX / 2, -i16::MAX, i16::MAX]);
+
+        // only as much as fits in both
+        let mut wide = [9.0f64; 2];
+        convert_samples(&ints, &mut wide);
+        assert_eq!(wide[0], 0.0);
+        assert!((wide[1] - 0.5).abs() < 1e-4);
+
+        // and the engine runs the same in any of them
+        let (mut tx, rx) = ring_buffer(4);
+        let mut rt = RealtimeThread::<Message<FRAMES, i16>, FRAMES, i16>::new(rx);
+        rt.set_crossfade(0);
+        let samples: Samples<FRAMES, i16> = convert_block(&constant(0.5));
+        assert!(tx.try_push(Sequenced { seq: 0, message: Message::NewSamples(Arc::new(samples)) }).is_ok());
+
+        let mut output = vec![0i16; FRAMES * CHANNELS];
+        rt.realtime_callback(info(FRAMES, 0), &[], &mut output);
+        assert!(output.iter().all(|&sample| sample == i16::MAX / 2));
+    }
+
+    // private. a processor which gives up straight away
+    struct Broken;
+
+    impl RealtimeCallback for Broken {
+        fn process(&mut self, _info: CallbackInfo, _input: &[f32], _out: &mut [f32]) -> CallbackStatus {
+            CallbackStatus::Error(EngineError::Processor("broken"))
+        }
+    }
+
+    #[test]
+    fn callback_errors_stop_the_engine() {
+        // a buffer which doesn't match the frame count is reported, and nothing is written to it
+        let (_tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        let mut output = vec![0.0; 3];
+        assert!(rt.realtime_callback(info(FRAMES, 0), &[], &mut output) == CallbackStatus::Error(EngineError::BadBuffer(3)));
+        assert!(reported(&mut feedback).contains(&Feedback::Failed(EngineError::BadBuffer(3))));
+
+        // and run_threads hands the error back, rather than carrying on
+        let (tx, rx) = ring_buffer(4);
+        let mut rt = RealtimeThread::<Message, FRAMES, f32>::new(rx);
+        rt.set_processor(Box::new(Broken));
+        let result = run_threads_with(rt, UIThread::new(tx), SAMPLE_RATE);
+        assert!(matches!(result, Err(EngineError::Processor("broken"))));
+    }
+
+    // private. a processor which passes on the timing of every callback it sees
+    struct Timing(mpsc::Sender<(usize, u64)>);
+
+    impl RealtimeCallback for Timing {
+        fn process(&mut self, info: CallbackInfo, _input: &[f32], _out: &mut [f32]) -> CallbackStatus {
+            self.0.send((info.frame_count, info.sample_time)).unwrap();
+            CallbackStatus::Continue
+        }
+    }
+
+    #[test]
+    fn processors_see_the_callback_timing() {
+        let (tx, timings) = mpsc::channel();
+        let (_tx, mut rt) = realtime();
+        rt.set_processor(Box::new(Timing(tx)));
+
+        callback(&mut rt, 0);
+        callback(&mut rt, 1);
+        let mut output = vec![0.0; 10 * CHANNELS];
+        rt.realtime_callback(info(10, 2 * FRAMES as u64), &[], &mut output);
+
+        assert_eq!(timings.try_iter().collect::<Vec<_>>(), vec![(FRAMES, 0), (FRAMES, FRAMES as u64), (10, 2 * FRAMES as u64)]);
+    }
+
+    #[test]
+    fn glitches_are_counted_and_reported() {
+        let (mut tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        rt.set_feedback_interval(1);
+
+        // nothing to play yet
+        callback(&mut rt, 0);
+        assert_eq!(rt.xruns(), XrunCounters { late: 0, silent: 1, skipped: 0 });
+
+        // a deadline which has already gone by
+        queue(&mut tx, 0, vec![Message::NewSamples(constant(0.5))]);
+        let mut output = vec![0.0; FRAMES * CHANNELS];
+        let mut late = info(FRAMES, FRAMES as u64);
+        late.wall_deadline = Some(Instant::now() - Duration::from_millis(1));
+        rt.realtime_callback(late, &[], &mut output);
+        assert_eq!(rt.xruns(), XrunCounters { late: 1, silent: 1, skipped: 0 });
+
+        // the host jumped ahead
+        callback(&mut rt, 5);
+        let xruns = XrunCounters { late: 1, silent: 1, skipped: 1 };
+        assert_eq!(rt.xruns(), xruns);
+        assert!(reported(&mut feedback).contains(&Feedback::Xruns(xruns)));
+    }
+
+    #[test]
+    fn new_samples_fade_in_over_the_old_ones() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        queue(&mut tx, 0, vec![Message::NewSamples(constant(0.0))]);
+        callback(&mut rt, 0);
+
+        // half a callback of fade, then the new samples on their own
+        rt.set_crossfade(FRAMES / 2);
+        queue(&mut tx, 1, vec![Message::NewSamples(constant(1.0))]);
+        let (_, output) = callback(&mut rt, 1);
+        let left: Vec<f32> = output.chunks(CHANNELS).map(|frame| frame[0]).collect();
+
+        assert!(left[0] < 0.1);
+        assert!(left.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(left[FRAMES / 4] > 0.0 && left[FRAMES / 4] < 1.0);
+        assert!(left[FRAMES / 2..].iter().all(|&sample| sample == 1.0));
+    }
+
+    #[test]
+    fn playback_modes_move_the_cursor() {
+        let forwards: Vec<usize> = (0..FRAMES).collect();
+
+        // once through, then silence
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        rt.set_playback_mode(PlaybackMode::OneShot);
+        queue(&mut tx, 0, vec![Message::NewSamples(ramp())]);
+        assert_eq!(frames_read(&mut rt, FRAMES, 0), forwards);
+        let (_, output) = callback(&mut rt, 1);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+
+        // back down without repeating the last frame, then up again without repeating the first
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        rt.set_playback_mode(PlaybackMode::PingPong);
+        queue(&mut tx, 0, vec![Message::NewSamples(ramp())]);
+        assert_eq!(frames_read(&mut rt, FRAMES, 0), forwards);
+        let mut backwards: Vec<usize> = (0..FRAMES - 1).rev().collect();
+        backwards.push(1);
+        assert_eq!(frames_read(&mut rt, FRAMES, FRAMES as u64), backwards);
+
+        // the default goes back to the start
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        queue(&mut tx, 0, vec![Message::NewSamples(ramp())]);
+        assert_eq!(frames_read(&mut rt, FRAMES, 0), forwards);
+        assert_eq!(frames_read(&mut rt, FRAMES, FRAMES as u64), forwards);
+    }
+
+    #[test]
+    fn the_playhead_is_published_every_few_callbacks() {
+        let (mut tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        rt.set_feedback_interval(2);
+        queue(&mut tx, 0, vec![Message::NewSamples(ramp())]);
+
+        for i in 0..3 {
+            callback(&mut rt, i);
+        }
+        let mut output = vec![0.0; 10 * CHANNELS];
+        rt.realtime_callback(info(10, 3 * FRAMES as u64), &[], &mut output);
+
+        let playheads: Vec<Playhead> = reported(&mut feedback).into_iter().filter_map(|event| match event {
+            Feedback::Playhead(playhead) => Some(playhead),
+            _                            => None,
+        }).collect();
+        assert_eq!(playheads, vec![
+            Playhead { position: 2 * FRAMES as u64, frame: Some(0), mode: PlaybackMode::Loop },
+            Playhead { position: 3 * FRAMES as u64 + 10, frame: Some(10), mode: PlaybackMode::Loop },
+        ]);
+    }
+
+    // private. a processor which always panics
+    struct Panicky;
+
+    impl RealtimeCallback for Panicky {
+        fn process(&mut self, _info: CallbackInfo, _input: &[f32], _out: &mut [f32]) -> CallbackStatus {
+            panic!("bad dsp");
+        }
+    }
+
+    #[test]
+    fn processor_panics_are_contained() {
+        let (mut tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        rt.set_processor(Box::new(Panicky));
+        queue(&mut tx, 0, vec![Message::NewSamples(constant(0.5))]);
+
+        // silence rather than whatever was half written, and the panic stops here
+        let (status, output) = callback(&mut rt, 0);
+        assert!(status == CallbackStatus::Error(EngineError::Panicked));
+        assert!(output.iter().all(|&sample| sample == 0.0));
+        assert!(reported(&mut feedback).contains(&Feedback::Panicked));
+
+        // run_threads puts up with a few before giving up
+        let (tx, rx) = ring_buffer(4);
+        let mut rt = RealtimeThread::<Message, FRAMES, f32>::new(rx);
+        rt.set_processor(Box::new(Panicky));
+        let result = run_threads_with(rt, UIThread::new(tx), SAMPLE_RATE);
+        assert!(matches!(result, Err(EngineError::Panicked)));
+    }
+
+    // private. a processor which takes far longer than a period
+    struct Slow;
+
+    impl RealtimeCallback for Slow {
+        fn process(&mut self, _info: CallbackInfo, _input: &[f32], _out: &mut [f32]) -> CallbackStatus {
+            thread::sleep(Duration::from_millis(5));
+            CallbackStatus::Continue
+        }
+    }
+
+    #[test]
+    fn load_is_smoothed_over_callbacks() {
+        let (_tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        rt.set_feedback_interval(1);
+        rt.set_sample_rate(SAMPLE_RATE);
+        rt.set_processor(Box::new(Slow));
+
+        for i in 0..2 {
+            callback(&mut rt, i);
+        }
+        let loads: Vec<f32> = reported(&mut feedback).into_iter().filter_map(|event| match event {
+            Feedback::Load(load) => Some(load),
+            _                    => None,
+        }).collect();
+
+        // every callback is well over its period, but the figure only climbs towards that
+        let period = FRAMES as f32 / SAMPLE_RATE as f32;
+        assert_eq!(loads.len(), 2);
+        assert!(0.0 < loads[0] && loads[0] < loads[1]);
+        assert!(loads[1] < 0.005 / period);
+    }
+
+    #[test]
+    fn denormals_are_flushed_to_zero() {
+        let tiny = f32::MIN_POSITIVE / 4.0;
+        let mut samples = [tiny, -tiny, 0.5, -f32::MIN_POSITIVE, 0.0];
+        flush_denormals(&mut samples);
+        assert_eq!(samples, [0.0, 0.0, 0.5, -f32::MIN_POSITIVE, 0.0]);
+
+        // the guard gets the CPU to do the same, but only while it's around
+        if cfg!(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")) {
+            let guard = DenormalGuard::new();
+            assert_eq!(std::hint::black_box(f32::MIN_POSITIVE) / 4.0, 0.0);
+            drop(guard);
+            assert_eq!(std::hint::black_box(f32::MIN_POSITIVE) / 4.0, tiny);
+        }
+    }
+
+    #[test]
+    fn frequencies_follow_the_sample_rate() {
+        // the first frame the sine reaches its peak at, a quarter of the way through a cycle
+        let first_peak = |sample_rate| {
+            let mut ui: UIThread = UIThread::new(ring_buffer(2).0);
+            ui.set_sample_rate(sample_rate);
+            ui.set_frequency(1500.0);
+            let samples = ui.compute_samples(1.0);
+            samples.iter().position(|frame| frame[0] > 0.999).unwrap()
+        };
+
+        // 32 frames a cycle at 48k, twice that at 96k
+        assert_eq!(first_peak(48_000), 8);
+        assert_eq!(first_peak(96_000), 16);
+    }
+
+    #[test]
+    fn smoothed_params_glide_to_their_targets() {
+        // a straight line, landing exactly on the target
+        let mut linear = SmoothedParam::new(0.1, Ramp::Linear(4));
+        linear.set_target(0.5);
+        let steps: Vec<f32> = (0..5).map(|_| linear.next()).collect();
+        for (step, expected) in steps.iter().zip(&[0.2, 0.3, 0.4, 0.5, 0.5]) {
+            assert!((step - expected).abs() < 1e-6);
+        }
+        assert_eq!(linear.value(), 0.5);
+        assert!(!linear.is_smoothing());
+
+        // slows down as it gets close, but gets there in the end
+        let mut exponential = SmoothedParam::new(0.0, Ramp::Exponential(8.0));
+        exponential.set_target(1.0);
+        let first = exponential.next();
+        let second = exponential.next() - first;
+        assert!(0.0 < second && second < first);
+        for _ in 0..1000 {
+            exponential.next();
+        }
+        assert_eq!(exponential.value(), 1.0);
+
+        // an explicit ramp takes exactly as long as asked, and a jump doesn't take any time
+        exponential.ramp(1.0, 0.0, 2);
+        exponential.next();
+        assert_eq!(exponential.next(), 0.0);
+        exponential.set_immediate(0.7);
+        assert_eq!(exponential.next(), 0.7);
+    }
+
+    #[test]
+    fn scheduled_messages_are_applied_in_time_order() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+
+        // sent out of order, played in order, each on its own frame
+        queue(&mut tx, 0, vec![
+            Message::NewSamplesAt(constant(0.25), 20),
+            Message::NewSamplesAt(constant(0.5), 10),
+            Message::NewSamplesAt(constant(0.75), 40),
+        ]);
+
+        let (_, output) = callback(&mut rt, 0);
+        let left: Vec<f32> = output.chunks(CHANNELS).map(|frame| frame[0]).collect();
+        assert!(left[..10].iter().all(|&sample| sample == 0.0));
+        assert!(left[10..20].iter().all(|&sample| sample == 0.5));
+        assert!(left[20..40].iter().all(|&sample| sample == 0.25));
+        assert!(left[40..].iter().all(|&sample| sample == 0.75));
+    }
+
+    #[test]
+    fn pause_holds_the_cursor_and_stop_rewinds_it() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        queue(&mut tx, 0, vec![Message::NewSamples(ramp())]);
+        assert_eq!(frames_read(&mut rt, 10, 0), (0..10).collect::<Vec<_>>());
+
+        // silence while paused, then on from where it was
+        queue(&mut tx, 1, vec![Message::Pause]);
+        let (_, output) = callback(&mut rt, 1);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+        assert_eq!(rt.transport(), Transport::Paused);
+        queue(&mut tx, 2, vec![Message::Resume]);
+        assert_eq!(frames_read(&mut rt, 10, 2 * FRAMES as u64), (10..20).collect::<Vec<_>>());
+
+        // stopping starts the samples over once playing again
+        queue(&mut tx, 3, vec![Message::Stop]);
+        let (_, output) = callback(&mut rt, 3);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+        assert_eq!(rt.transport(), Transport::Stopped);
+        queue(&mut tx, 4, vec![Message::Resume]);
+        assert_eq!(frames_read(&mut rt, 10, 4 * FRAMES as u64), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn silent_callbacks_clear_the_output_and_say_so() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+
+        // whatever the host left in the buffer doesn't leak out
+        let mut output = vec![0.9; FRAMES * CHANNELS];
+        assert!(rt.realtime_callback(info(FRAMES, 0), &[], &mut output) == CallbackStatus::KeepQuiet);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+
+        // samples which are all zeros are just as quiet
+        queue(&mut tx, 0, vec![Message::NewSamples(constant(0.0))]);
+        assert!(callback(&mut rt, 1).0 == CallbackStatus::KeepQuiet);
+        queue(&mut tx, 1, vec![Message::NewSamples(constant(0.5))]);
+        assert!(callback(&mut rt, 2).0 == CallbackStatus::Continue);
+    }
+
+    #[test]
+    fn dumped_state_reaches_the_ui_thread() {
+        let (tx, rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        let mut rt: RealtimeThread = RealtimeThread::new(rx);
+        let (feedback_tx, feedback_rx) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        ui.set_feedback(feedback_rx);
+        let (writer, reader) = triple_buffer(StateDump::default());
+        rt.set_state_dump(writer);
+        ui.set_state_dump(reader);
+
+        let samples = constant(0.5);
+        let buffer_id = &*samples as *const Samples as usize;
+        ui.try_send(Message::NewSamples(samples)).unwrap();
+        callback(&mut rt, 0);
+        ui.check_feedback();
+        assert!(ui.dumped_state().is_none());
+
+        ui.try_send(Message::DumpState).unwrap();
+        callback(&mut rt, 1);
+        ui.check_feedback();
+        let dump = ui.dumped_state().unwrap();
+        assert_eq!(dump.buffer_id, Some(buffer_id));
+        assert_eq!(dump.position, FRAMES as u64);
+        assert_eq!(dump.next_seq, 2);
+        assert_eq!(dump.transport, Transport::Playing);
+
+        // each dump is only handed out once
+        assert!(ui.dumped_state().is_none());
+    }
+
+    #[test]
+    fn warming_up_leaves_no_trace() {
+        let (mut tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        rt.set_feedback_interval(1);
+        rt.set_crossfade(0);
+        queue(&mut tx, 0, vec![Message::NewSamples(ramp())]);
+
+        // nothing queued is picked up, and nothing is reported
+        rt.warm_up(4);
+        assert!(reported(&mut feedback).is_empty());
+        assert_eq!(rt.xruns(), XrunCounters::default());
+
+        // so the first real callback starts from the very beginning
+        assert_eq!(frames_read(&mut rt, FRAMES, 0), (0..FRAMES).collect::<Vec<_>>());
+        assert!(reported(&mut feedback).contains(&Feedback::Position(FRAMES as u64)));
+    }
+
+    #[test]
+    fn bypass_passes_the_input_through() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        rt.set_processor(Box::new(Halve));
+        queue(&mut tx, 0, vec![Message::NewSamples(ramp()), Message::Bypass(true)]);
+
+        // the dry input, without the samples or the processor
+        let input = vec![0.3; FRAMES * CHANNELS];
+        let mut output = vec![0.0; FRAMES * CHANNELS];
+        rt.realtime_callback(info(FRAMES, 0), &input, &mut output);
+        assert_eq!(output, input);
+
+        // and silence with no input
+        let (_, output) = callback(&mut rt, 1);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+
+        // messages are still handled, and switching back picks up where playing would have been
+        queue(&mut tx, 2, vec![Message::Bypass(false)]);
+        let (_, output) = callback(&mut rt, 2);
+        let read: Vec<f32> = output.chunks(CHANNELS).map(|frame| frame[0]).collect();
+        assert_eq!(read, (0..FRAMES).map(|i| i as f32 * 0.5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn scheduling_falls_back_when_it_cant_be_applied() {
+        // on a thread of its own, so nothing sticks to the test runner
+        let carried_on = thread::spawn(|| {
+            assert!(set_realtime_priority(1000).is_err());
+            assert!(pin_to_core(1 << 20).is_err());
+
+            // neither of which stops the loop from running
+            RtScheduling { priority: Some(1000), core: Some(1 << 20) }.apply();
+            true
+        }).join().unwrap();
+        assert!(carried_on);
+    }
+
+    #[test]
+    fn a_streak_of_late_callbacks_is_reported() {
+        let (_tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        rt.set_overload_threshold(2);
+
+        let mut output = vec![0.0; FRAMES * CHANNELS];
+        let mut run = |rt: &mut RealtimeThread, i: u64, late: bool| {
+            let mut info = info(FRAMES, i * FRAMES as u64);
+            if late {
+                info.wall_deadline = Some(Instant::now() - Duration::from_millis(1));
+            }
+            rt.realtime_callback(info, &[], &mut output);
+        };
+
+        let overloads = |feedback: &mut RingConsumer<Feedback>| -> Vec<u64> {
+            reported(feedback).into_iter().filter_map(|event| match event {
+                Feedback::Overloaded(n) => Some(n),
+                _                       => None,
+            }).collect()
+        };
+
+        // one on its own is only a glitch
+        run(&mut rt, 0, true);
+        run(&mut rt, 1, false);
+        run(&mut rt, 2, true);
+        assert!(overloads(&mut feedback).is_empty());
+
+        // every time the streak reaches another multiple of the threshold
+        for i in 3..6 {
+            run(&mut rt, i, true);
+        }
+        assert_eq!(overloads(&mut feedback), vec![2, 4]);
+    }
+
+    #[test]
+    fn replaced_samples_are_never_freed_in_the_callback() {
+        let (mut tx, mut rt) = realtime();
+        let (returns_tx, mut returns) = ring_buffer(2);
+        rt.set_returns(returns_tx, None);
+        rt.set_crossfade(0);
+
+        // the realtime thread holds the only reference to each of these
+        let played: Vec<Weak<Samples>> = (0..4).map(|i| {
+            let samples = constant(i as f32 / 10.0);
+            let weak = Arc::downgrade(&samples);
+            queue(&mut tx, i, vec![Message::NewSamples(samples)]);
+            callback(&mut rt, i);
+            weak
+        }).collect();
+
+        // two went back, and with nowhere else to go the third is parked rather than dropped
+        assert!(played.iter().all(|weak| weak.upgrade().is_some()));
+        assert!(Arc::ptr_eq(&returns.try_pop().unwrap(), &played[0].upgrade().unwrap()));
+        assert!(Arc::ptr_eq(&returns.try_pop().unwrap(), &played[1].upgrade().unwrap()));
+
+        // and sent on once there's room
+        callback(&mut rt, 4);
+        assert!(Arc::ptr_eq(&returns.try_pop().unwrap(), &played[2].upgrade().unwrap()));
+    }
+
+    #[test]
+    fn layers_mix_on_top_of_the_main_samples() {
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        let level = |rt: &mut RealtimeThread, i: u64| {
+            let (_, output) = callback(rt, i);
+            assert!(output.windows(2).all(|pair| pair[0] == pair[1]));
+            output[0]
+        };
+
+        queue(&mut tx, 0, vec![
+            Message::NewSamples(constant(0.1)),
+            Message::SetLayer(0, constant(0.2), 0.5),
+            Message::SetLayer(1, constant(0.4), 1.0),
+            // there's no such layer, so this is ignored
+            Message::SetLayer(MAX_LAYERS, constant(1.0), 1.0),
+        ]);
+        assert!((level(&mut rt, 0) - 0.6).abs() < 1e-6);
+
+        queue(&mut tx, 4, vec![Message::SetLayerGain(1, 0.5)]);
+        assert!((level(&mut rt, 1) - 0.4).abs() < 1e-6);
+
+        queue(&mut tx, 5, vec![Message::RemoveLayer(0)]);
+        assert!((level(&mut rt, 2) - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn levels_are_measured_per_channel_between_reports() {
+        let (mut tx, mut rt) = realtime();
+        let (feedback_tx, mut feedback) = ring_buffer(64);
+        rt.set_feedback(feedback_tx);
+        rt.set_feedback_interval(2);
+        rt.set_crossfade(0);
+
+        // a square wave on the left, half a buffer of silence then a constant on the right
+        let mut samples: Samples = silence();
+        for (i, frame) in samples.iter_mut().enumerate() {
+            frame[0] = if i % 2 == 0 { 0.5 } else { -0.5 };
+            frame[1] = if i < FRAMES / 2 { 0.0 } else { 0.8 };
+        }
+        queue(&mut tx, 0, vec![Message::NewSamples(Arc::new(samples))]);
+        callback(&mut rt, 0);
+        callback(&mut rt, 1);
+
+        let levels: Vec<Levels> = reported(&mut feedback).into_iter().filter_map(|event| match event {
+            Feedback::Levels(levels) => Some(levels),
+            _                        => None,
+        }).collect();
+        assert_eq!(levels.len(), 1);
+        assert_eq!(levels[0].peak, [0.5, 0.8]);
+        assert!((levels[0].rms[0] - 0.5).abs() < 1e-5);
+        assert!((levels[0].rms[1] - 0.32f32.sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn typed_commands_are_parsed() {
+        let parse = |text| UiCommand::<FRAMES, f32>::parse(text);
+        assert!(matches!(parse("vol 0.3"), Ok(UiCommand::SetVolume(volume)) if volume == 0.3));
+        assert!(matches!(parse("  freq   440 "), Ok(UiCommand::SetFrequency(hz)) if hz == 440.0));
+        assert!(matches!(parse("quit"), Ok(UiCommand::Shutdown)));
+
+        // anything out of range gets the usage, anything else is unknown
+        let error = |text| parse(text).err().unwrap().to_string();
+        assert_eq!(error("vol 2"), "usage: vol <gain between 0 and 1>");
+        assert_eq!(error("freq"), "usage: freq <hz above 0>");
+        assert_eq!(error("louder"), "unknown command \"louder\"");
+        assert_eq!(error(""), "no command");
+    }
+
+    #[test]
+    fn application_commands_become_messages() {
+        let (tx, mut rx) = ring_buffer(16);
+        let mut ui: UIThread = UIThread::new(tx);
+        let (commands, command_rx) = mpsc::channel();
+        ui.set_commands(command_rx);
+
+        let wave = constant(0.5);
+        commands.send(UiCommand::LoadWave(wave.clone())).unwrap();
+        commands.send(UiCommand::Shutdown).unwrap();
+
+        // stops at the shutdown, leaving the engine for `run` to shut down
+        assert!(ui.run_commands());
+        let mut sent = Vec::new();
+        while let Some(sequenced) = rx.try_pop() {
+            sent.push(sequenced.message);
+        }
+        assert!(sent.iter().any(|message| matches!(*message, Message::NewSamples(ref samples) if Arc::ptr_eq(samples, &wave))));
+        assert!(!sent.iter().any(Message::is_shutdown));
+
+        // and with every client gone there's nothing left to wait for
+        drop(commands);
+        assert!(ui.run_commands());
+    }
+
+    #[test]
+    fn scripts_run_in_time_order_until_they_quit() {
+        let script = Script::<FRAMES, f32>::parse("\
+            # comments and blank lines are skipped\n\
+            \n\
+            0.05 vol 0.4\n\
+            0.0  vol 0.1   # out of order is fine\n\
+            0.08 quit\n\
+            0.09 vol 0.9\n").unwrap();
+        let times: Vec<Duration> = script.events.iter().map(|&(at, _)| at).collect();
+        assert_eq!(times, [0.0, 0.05, 0.08, 0.09].map(Duration::from_secs_f32));
+
+        // mistakes are pointed out by line
+        let error = Script::<FRAMES, f32>::parse("0.0 vol 0.1\nsoon vol 0.2").err().unwrap();
+        assert_eq!(error.to_string(), "line 2: expected a time in seconds");
+        let error = Script::<FRAMES, f32>::parse("0.0 vol 2").err().unwrap();
+        assert_eq!(error.to_string(), "line 1: usage: vol <gain between 0 and 1>");
+
+        // each command waits for its time, and nothing after the quit happens
+        let (tx, mut rx) = ring_buffer(16);
+        let mut ui: UIThread = UIThread::new(tx);
+        ui.set_script(script);
+        let started = Instant::now();
+        assert!(ui.run_script());
+        assert!(started.elapsed() >= Duration::from_millis(80));
+        assert_eq!(ui.volume, 0.4);
+        assert!(rx.try_pop().is_some());
+    }
+
+    #[test]
+    fn volume_ramps_are_a_single_message() {
+        let (tx, mut rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        ui.set_sample_rate(SAMPLE_RATE);
+        ui.ramp_volume(0.0, 1.0, Duration::from_secs_f64(FRAMES as f64 / SAMPLE_RATE as f64)).unwrap();
+
+        let ramp = rx.try_pop().unwrap();
+        assert!(matches!(ramp.message, Message::RampVolume(from, to, frames) if from == 0.0 && to == 1.0 && frames == FRAMES));
+        assert!(rx.try_pop().is_none());
+
+        // the realtime thread's smoother does the rest, a frame at a time
+        let (mut tx, mut rt) = realtime();
+        rt.set_crossfade(0);
+        queue(&mut tx, 0, vec![Message::NewSamples(constant(1.0)), ramp.message]);
+        let (_, output) = callback(&mut rt, 0);
+        for (i, frame) in output.chunks(CHANNELS).enumerate() {
+            assert!((frame[0] - (i + 1) as f32 / FRAMES as f32).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn notes_pick_the_frequency_and_buffers_loop_cleanly() {
+        assert_eq!(note_frequency(69), 440.0);
+        assert_eq!(note_frequency(81), 880.0);
+        assert!((note_frequency(60) - 261.626).abs() < 1e-3);
+
+        // a note which doesn't fit the buffer is rounded to one which does, so looping the
+        // buffer carries on the wave without a jump
+        let mut ui: UIThread = UIThread::new(ring_buffer(2).0);
+        ui.set_sample_rate(SAMPLE_RATE);
+        ui.note(60);
+        assert_eq!(ui.frequency, note_frequency(60));
+        let samples = ui.compute_samples(1.0);
+        let left: Vec<f32> = samples.iter().map(|frame| frame[0]).collect();
+        let biggest_step = left.windows(2).map(|pair| (pair[1] - pair[0]).abs()).fold(0.0, f32::max);
+        assert!((left[0] - left[FRAMES - 1]).abs() <= biggest_step);
+        assert_eq!(left[0], 0.0);
+    }
+
+    #[test]
+    fn async_run_carries_out_a_stream_of_commands() {
+        let (tx, rx) = ring_buffer(16);
+        let mut handle = AsyncUiHandle::new(UIThread::<Message>::new(tx));
+        let mut rt: RealtimeThread = RealtimeThread::new(rx);
+        let (feedback_tx, feedback_rx) = ring_buffer(FEEDBACK_CAPACITY);
+        rt.set_feedback(feedback_tx);
+        handle.ui().set_feedback(feedback_rx);
+        rt.set_shutdown_fade(0);
+
+        // the realtime thread plays until it's told to stop, then says what it was playing
+        let realtime = thread::spawn(move || {
+            let mut callbacks = 0;
+            while callback(&mut rt, callbacks).0 != CallbackStatus::Shutdown {
+                callbacks += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            rt.wavetable.map(|voice| voice.gain)
+        });
+
+        let (commands, stream) = stream_channel();
+        commands.send(UiCommand::SetVolume(0.5)).unwrap();
+        commands.send(UiCommand::Shutdown).unwrap();
+
+        // finishes once the shutdown has been acknowledged
+        assert!(block_on(handle.run(stream)));
+        assert!(handle.ui().stopped());
+        assert_eq!(realtime.join().unwrap(), Some(0.5));
+    }
+
+    #[test]
+    fn telemetry_follows_the_feedback() {
+        let (tx, rx) = ring_buffer(4);
+        let mut ui: UIThread = UIThread::new(tx);
+        let mut rt: RealtimeThread = RealtimeThread::new(rx);
+        let (feedback_tx, feedback_rx) = ring_buffer(FEEDBACK_CAPACITY);
+        rt.set_feedback(feedback_tx);
+        rt.set_feedback_interval(1);
+        rt.set_crossfade(0);
+        ui.set_feedback(feedback_rx);
+        let (writer, mut reader) = triple_buffer(Telemetry::default());
+        ui.set_telemetry(writer);
+
+        ui.try_send(Message::NewSamples(constant(0.5))).unwrap();
+        callback(&mut rt, 0);
+        ui.check_feedback();
+
+        let telemetry = ui.telemetry();
+        assert_eq!(telemetry.position, FRAMES as u64);
+        assert_eq!(telemetry.levels.peak, [0.5; CHANNELS]);
+        assert!(reader.update());
+        assert_eq!(*reader.read(), telemetry);
+
+        // a bar for the RMS level and the peak in dB for each channel, then the stats
+        let line = Telemetry {
+            levels: Levels { peak: [1.0, 0.0], rms: [0.5, 0.0] },
+            load:   0.25,
+            xruns:  XrunCounters { late: 1, silent: 2, skipped: 0 },
+            ..Telemetry::default()
+        }.to_string();
+        assert_eq!(line, "0 [##########..........]    0.0 dB  1 [....................]  -99.9 dB  load  25%  xruns 3");
+    }
+
+    #[test]
+    fn the_builder_configures_the_ui_thread() {
+        let (tx, _rx) = ring_buffer(2);
+        let mut ui: UIThread = UIThreadBuilder::new(tx)
+            .overflow_policy(OverflowPolicy::Error)
+            .volume_range(0.2, 0.6)
+            .frequency(1500.0)
+            .sample_rate(96_000)
+            .pacing(Duration::from_millis(20))
+            .build();
+        assert_eq!(ui.frequency, 1500.0);
+        assert_eq!(ui.sample_rate, 96_000);
+        assert_eq!(ui.pacing, Some(Duration::from_millis(20)));
+
+        // volumes are kept in range
+        let loudest = |samples: Samples| samples.iter().map(|frame| frame[0]).fold(0.0, f32::max);
+        assert!((loudest(ui.compute_samples(1.0)) - 0.6).abs() < 1e-3);
+        assert!((loudest(ui.compute_samples(0.0)) - 0.2).abs() < 1e-3);
+
+        // a full queue hands messages back
+        ui.try_send(Message::SetVolume(0.1)).unwrap();
+        ui.try_send(Message::SetVolume(0.2)).unwrap();
+        assert!(matches!(ui.try_send(Message::SetVolume(0.3)), Err(SendError::Full(_))));
+    }
+
+    // private. a file in the temp directory which is deleted on drop
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            TempFile(env::temp_dir().join(format!("sound-test-{}-{}", std::process::id(), name)))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn parameter_files_are_watched() {
+        let params = SynthParams::parse("# a quiet A\nvolume = 0.25\n\nfrequency = 300  # overridden\nnote = 69\n").unwrap();
+        assert_eq!(params.volume, Some(0.25));
+        assert_eq!(params.frequency, Some(440.0));
+        let error = |text| SynthParams::parse(text).err().unwrap().to_string();
+        assert_eq!(error("volume = 0.5\nvolume = 1.5"), "line 2: volume must be between 0 and 1");
+        assert_eq!(error("volume 0.5"), "line 1: expected `key = value`");
+        assert_eq!(error("tempo = 120"), "line 1: unknown parameter \"tempo\"");
+
+        // every change to the file is sent on, and deleting it stops the watch
+        let file = TempFile::new("watched.toml");
+        std::fs::write(&file.0, "volume = 0.25\nfrequency = 1500\n").unwrap();
+        let (tx, mut rx) = ring_buffer(16);
+        let mut ui: UIThread = UIThread::new(tx);
+        ui.watch_params(file.0.clone());
+
+        assert_eq!(ui.check_params(), Some(true));
+        assert_eq!((ui.volume, ui.frequency), (0.25, 1500.0));
+        assert!(rx.try_pop().is_some());
+
+        while rx.try_pop().is_some() {}
+        assert_eq!(ui.check_params(), Some(true));
+        assert!(rx.try_pop().is_none());
+
+        std::fs::remove_file(&file.0).unwrap();
+        assert_eq!(ui.check_params(), None);
+    }
+
+    #[test]
+    fn undo_and_redo_walk_the_history() {
+        let (tx, mut rx) = ring_buffer(256);
+        let mut ui: UIThread = UIThread::new(tx);
+        let start = (ui.volume, ui.frequency);
+        let run = |ui: &mut UIThread, command| {
+            assert!(ui.apply_command(command));
+            (ui.volume, ui.frequency)
+        };
+
+        assert_eq!(run(&mut ui, UiCommand::SetVolume(0.2)), (0.2, start.1));
+        assert_eq!(run(&mut ui, UiCommand::SetFrequency(500.0)), (0.2, 500.0));
+        assert_eq!(run(&mut ui, UiCommand::Undo), (0.2, start.1));
+        assert_eq!(run(&mut ui, UiCommand::Undo), start);
+        assert_eq!(run(&mut ui, UiCommand::Undo), start);
+        assert_eq!(run(&mut ui, UiCommand::Redo), (0.2, start.1));
+
+        // a new change forgets anything which could have been redone
+        assert_eq!(run(&mut ui, UiCommand::SetVolume(0.7)), (0.7, start.1));
+        assert_eq!(run(&mut ui, UiCommand::Redo), (0.7, start.1));
+
+        // each step is sent on to the realtime thread
+        assert!(rx.try_pop().is_some());
+
+        // and only so much is remembered
+        for i in 0..HISTORY_CAPACITY + 10 {
+            run(&mut ui, UiCommand::SetFrequency(100.0 + i as f32));
+        }
+        for _ in 0..HISTORY_CAPACITY + 10 {
+            run(&mut ui, UiCommand::Undo);
+        }
+        assert_eq!(ui.frequency, 109.0);
+    }
+
+    #[test]
+    fn presets_round_trip_through_a_file() {
+        let file = TempFile::new("preset.toml");
+        let mut ui: UIThread = UIThread::new(ring_buffer(2).0);
+        ui.volume = 0.3;
+        ui.set_frequency(660.0);
+        ui.set_waveform(Waveform::Saw);
+        ui.save_preset(&file.0).unwrap();
+
+        let (tx, mut rx) = ring_buffer(16);
+        let mut other: UIThread = UIThread::new(tx);
+        let before = (other.volume, other.frequency, other.waveform);
+        assert!(other.apply_command(UiCommand::LoadPreset(file.0.clone())));
+        assert_eq!((other.volume, other.frequency, other.waveform), (0.3, 660.0, Waveform::Saw));
+        assert!(rx.try_pop().is_some());
+
+        // loading is a change like any other
+        assert!(other.apply_command(UiCommand::Undo));
+        assert_eq!((other.volume, other.frequency, other.waveform), before);
+
+        // a file which isn't there leaves everything alone
+        let missing = TempFile::new("missing.toml");
+        assert!(other.load_preset(&missing.0).is_err());
+        assert_eq!((other.volume, other.frequency, other.waveform), before);
+    }
+
+    #[test]
+    fn controllers_are_learned_and_saved_with_presets() {
+        let mut map = ControlMap::new();
+        assert!(map.translate::<FRAMES, f32>(7, 64).is_none());
+
+        // the next controller to move is bound, and moves the parameter from then on
+        map.learn(Parameter::Volume);
+        assert!(matches!(map.translate::<FRAMES, f32>(7, 127), Some(UiCommand::SetVolume(volume)) if volume == 1.0));
+        assert!(matches!(map.translate::<FRAMES, f32>(7, 0), Some(UiCommand::SetVolume(volume)) if volume == 0.0));
+        assert!(map.translate::<FRAMES, f32>(74, 69).is_none());
+
+        // a parameter only follows one controller
+        map.learn(Parameter::Volume);
+        map.translate::<FRAMES, f32>(74, 0);
+        map.bind(1, Parameter::Frequency);
+        assert_eq!(map.bindings(), vec![(1, Parameter::Frequency), (74, Parameter::Volume)]);
+
+        // through the UI thread, and into a preset
+        let file = TempFile::new("controls.toml");
+        let (tx, _rx) = ring_buffer(64);
+        let mut ui: UIThread = UIThread::new(tx);
+        assert!(ui.apply_command(UiCommand::Learn(Parameter::Frequency)));
+        assert!(ui.apply_command(UiCommand::Controller(21, 69)));
+        assert_eq!(ui.frequency, 440.0);
+        ui.save_preset(&file.0).unwrap();
+        assert_eq!(SynthParams::load(&file.0).unwrap().controls, vec![(21, Parameter::Frequency)]);
+    }
+
+    #[test]
+    fn the_last_client_to_change_a_parameter_wins() {
+        let (tx, _rx) = ring_buffer(64);
+        let mut ui: UIThread = UIThread::new(tx);
+        let start = (ui.volume, ui.frequency);
+        let gui = ui.connect();
+        let network = gui.clone();
+        let script = ui.connect();
+
+        assert!(gui.send(UiCommand::SetVolume(0.2)));
+        assert!(network.send(UiCommand::SetVolume(0.6)));
+        assert!(script.send(UiCommand::SetFrequency(500.0)));
+
+        // runs until every client has gone away
+        drop((gui, network, script));
+        assert!(ui.run_commands());
+        assert_eq!((ui.volume, ui.frequency), (0.6, 500.0));
+
+        // the volume nobody heard never made it into the history either
+        assert!(ui.apply_command(UiCommand::Undo));
+        assert_eq!((ui.volume, ui.frequency), (0.6, start.1));
+        assert!(ui.apply_command(UiCommand::Undo));
+        assert_eq!((ui.volume, ui.frequency), start);
+    }
+
+    // private. an OSC bundle holding `elements`, each already encoded
+    fn osc_bundle(elements: &[&[u8]]) -> Vec<u8> {
+        let mut packet = b"#bundle\0".to_vec();
+        packet.extend_from_slice(&[0; 8]);
+        for element in elements {
+            packet.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            packet.extend_from_slice(element);
+        }
+        packet
+    }
+
+    #[test]
+    fn osc_bundles_come_apart() {
+        let volume = b"/engine/volume\0\0,f\0\0\x3f\x00\x00\x00";
+        let packet = osc_bundle(&[b"/engine/quit\0\0\0\0", volume]);
+
+        let messages = OscMessage::decode(&packet).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].address, "/engine/quit");
+        assert_eq!(messages[1].args, vec![OscArg::Float(0.5)]);
+    }
+
+    #[test]
+    fn malformed_osc_bundles_are_errors() {
+        // an element which claims to be longer than what's left
+        let mut packet = osc_bundle(&[b"/engine/quit\0\0\0\0"]);
+        packet[16..20].copy_from_slice(&64i32.to_be_bytes());
+        assert!(OscMessage::decode(&packet).is_err());
+
+        // a negative size
+        packet[16..20].copy_from_slice(&(-4i32).to_be_bytes());
+        assert!(OscMessage::decode(&packet).is_err());
+
+        // a size cut short
+        let mut packet = osc_bundle(&[]);
+        packet.extend_from_slice(&[0, 0]);
+        assert!(OscMessage::decode(&packet).is_err());
+
+        // an element which isn't a message
+        let packet = osc_bundle(&[b"engine\0\0"]);
+        assert!(OscMessage::decode(&packet).is_err());
+    }
+
+    #[test]
+    fn osc_packets_drive_the_ui_thread() {
+        let (tx, _rx) = ring_buffer(64);
+        let mut ui: UIThread = UIThread::new(tx);
+        let server = OscServer::bind("127.0.0.1:0", ui.connect()).unwrap();
+        let addr = server.socket.local_addr().unwrap();
+
+        // sent before the server starts, UDP holds on to them until it reads them
+        let controller = UdpSocket::bind("127.0.0.1:0").unwrap();
+        controller.send_to(b"nonsense", addr).unwrap();
+        controller.send_to(b"/engine/note\0\0\0\0,i\0\0\0\0\0\x45", addr).unwrap();
+        controller.send_to(b"/engine/volume\0\0,f\0\0\x3f\x00\x00\x00", addr).unwrap();
+        controller.send_to(b"/engine/quit\0\0\0\0", addr).unwrap();
+
+        // the bad packet is dropped, and the server stops after passing the quit on
+        server.run().unwrap();
+        drop(server);
+        assert!(ui.run_commands());
+        assert_eq!((ui.volume, ui.frequency), (0.5, 440.0));
+    }
+
+    #[test]
+    fn each_waveform_has_its_own_shape() {
+        // naive waves, so the jumps aren't smoothed
+        assert_eq!((Square.sample(0.1, 0.01), Square.sample(0.6, 0.01)), (1.0, -1.0));
+        assert_eq!((Saw.sample(0.0, 0.01), Saw.sample(0.25, 0.01)), (0.0, 0.5));
+        assert_eq!((Triangle.sample(0.0, 0.01), Triangle.sample(0.25, 0.01)), (0.0, 1.0));
+        assert_eq!(Triangle.sample(0.75, 0.01), -1.0);
+
+        let mut noise = Noise::new();
+        let samples: Vec<f32> = (0..64).map(|_| noise.sample(0.0, 0.01)).collect();
+        assert!(samples.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+        assert!(samples.windows(2).any(|pair| pair[0] != pair[1]));
+
+        // every waveform picks its own oscillator
+        for waveform in [Waveform::Sine, Waveform::Square, Waveform::Saw, Waveform::Triangle, Waveform::Noise] {
+            assert_eq!(waveform.oscillator().waveform(), waveform);
+        }
+    }
+
+    // private. the biggest jump between neighbouring frames of a cycle of `osc`, played at 1/`step`
+    // frames a cycle
+    fn biggest_jump(osc: &mut impl Oscillator, step: f32) -> f32 {
+        let frames = (1.0 / step) as usize;
+        let samples: Vec<f32> = (0..=frames).map(|i| osc.sample((i as f32 * step + 0.3 * step).fract(), step)).collect();
+        samples.windows(2).map(|pair| (pair[1] - pair[0]).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn polyblep_smooths_the_jumps() {
+        let step = 1.0 / 64.0;
+        assert_eq!(biggest_jump(&mut Square, step), 2.0);
+        assert!(biggest_jump(&mut Saw, step) > 1.9);
+        assert!(biggest_jump(&mut PolyBlepSquare, step) < 1.5);
+        assert!(biggest_jump(&mut PolyBlepSaw, step) < 1.5);
+
+        // away from the jumps the waves are left alone
+        assert_eq!(PolyBlepSquare.sample(0.25, step), 1.0);
+        assert_eq!(PolyBlepSaw.sample(0.25, step), Saw.sample(0.25, step));
+    }
+
+    #[test]
+    fn wavetables_are_rebuilt_at_our_sample_rate() {
+        let table = Arc::new(Wavetable::for_waveform(Waveform::Saw, 2 * SAMPLE_RATE).unwrap());
+        let mut bytes = Vec::new();
+        Message::<FRAMES, f32>::PlayWavetable(table, 440.0, 0.5).encode(&mut bytes);
+
+        match Message::<FRAMES, f32>::decode(&mut &bytes[..], SAMPLE_RATE).unwrap() {
+            Some(Message::PlayWavetable(table, frequency, gain)) => {
+                assert_eq!(table.sample_rate, SAMPLE_RATE);
+                assert_eq!(table.harmonics.len(), (SAMPLE_RATE as f32 / 2.0 / WAVETABLE_LOWEST) as usize);
+                assert_eq!((frequency, gain), (440.0, 0.5));
+            },
+            _ => panic!("expected a wavetable"),
+        }
+    }
+
+    #[test]
+    fn high_notes_play_from_tables_with_fewer_harmonics() {
+        let table = Wavetable::for_waveform(Waveform::Saw, SAMPLE_RATE).unwrap();
+
+        // low down the saw ramps straight up to its peak
+        let (quarter, eighth) = (table.read(0.25, 40.0), table.read(0.125, 40.0));
+        assert!((eighth - quarter / 2.0).abs() < 0.02);
+
+        // the top octave only has room for the fundamental, which is a sine
+        let (quarter, eighth) = (table.read(0.25, 15000.0), table.read(0.125, 15000.0));
+        assert!((eighth - quarter * f32::consts::FRAC_1_SQRT_2).abs() < 0.01);
+        assert!(table.read(0.5, 15000.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn envelopes_go_through_each_stage() {
+        let adsr = Adsr { attack: 4, decay: 2, sustain: 0.5, release: 4 };
+        let mut envelope = Envelope::new(adsr);
+
+        let levels: Vec<f32> = (0..8).map(|_| envelope.next()).collect();
+        assert_eq!(levels, vec![0.25, 0.5, 0.75, 1.0, 0.75, 0.5, 0.5, 0.5]);
+        assert!(!envelope.is_released());
+
+        // released half way through, the release still takes its whole time
+        envelope.release();
+        assert!(envelope.is_released());
+        let levels: Vec<f32> = (0..5).map(|_| envelope.next()).collect();
+        assert_eq!(levels, vec![0.375, 0.25, 0.125, 0.0, 0.0]);
+        assert!(envelope.is_done());
+
+        // the default is on straight away and cut off straight away
+        let mut envelope = Envelope::new(Adsr::default());
+        assert_eq!(envelope.next(), 1.0);
+        envelope.release();
+        assert!(envelope.is_done());
+        assert_eq!(envelope.next(), 0.0);
+    }
+
+    #[test]
+    fn envelope_times_are_in_frames_at_our_sample_rate() {
+        let adsr = Adsr::from_times(Duration::from_millis(10), Duration::from_millis(20), 1.5,
+                                    Duration::from_secs(1), SAMPLE_RATE);
+        assert_eq!(adsr, Adsr { attack: 480, decay: 960, sustain: 1.0, release: 48_000 });
+    }
+
+    #[test]
+    fn lfos_swing_between_their_limits() {
+        let mut lfo = Lfo::new();
+        assert!(!lfo.is_active());
+        assert_eq!(lfo.next_gain(SAMPLE_RATE), 1.0);
+
+        // a cycle every 100 frames, once the rate and depth have glided up
+        let rate = SAMPLE_RATE as f32 / 100.0;
+        lfo.set(rate, 1.0);
+        assert!(lfo.is_active());
+        for _ in 0..LFO_RAMP_FRAMES {
+            lfo.next(SAMPLE_RATE);
+        }
+
+        let gains: Vec<f32> = (0..100).map(|_| lfo.next_gain(SAMPLE_RATE)).collect();
+        let (lowest, highest) = gains.iter().fold((1.0f32, 0.0f32), |(lo, hi), &g| (lo.min(g), hi.max(g)));
+        assert!((0.0..0.01).contains(&lowest));
+        assert!((0.99..=1.0).contains(&highest));
+
+        // an octave either way
+        lfo.set(rate, 12.0);
+        for _ in 0..LFO_RAMP_FRAMES {
+            lfo.next(SAMPLE_RATE);
+        }
+        let ratios: Vec<f32> = (0..100).map(|_| lfo.next_ratio(SAMPLE_RATE)).collect();
+        assert!(ratios.iter().all(|ratio| (0.5..=2.0).contains(ratio)));
+        assert!(ratios.iter().any(|&ratio| ratio > 1.99) && ratios.iter().any(|&ratio| ratio < 0.51));
+    }
+
+    // private. the loudest a second's sine at `frequency` comes out of `process` once it has
+    // settled, the sine going in at full scale
+    fn gain_at(frequency: f32, mut process: impl FnMut(&mut [f32])) -> f32 {
+        let mut samples: Vec<f32> = (0..SAMPLE_RATE as usize).flat_map(|i| {
+            let value = (2.0 * f32::consts::PI * frequency * i as f32 / SAMPLE_RATE as f32).sin();
+            [value; CHANNELS]
+        }).collect();
+
+        process(&mut samples);
+        samples[samples.len() / 2..].iter().fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn biquads_pass_some_frequencies_and_not_others() {
+        let lowpass = BiquadCoefficients::new(FilterKind::Lowpass, 1000.0, 0.707, SAMPLE_RATE);
+        let highpass = BiquadCoefficients::new(FilterKind::Highpass, 1000.0, 0.707, SAMPLE_RATE);
+        let through = |coefficients, frequency| gain_at(frequency, |samples| {
+            let mut biquad = Biquad::new();
+            biquad.set_coefficients(coefficients);
+            biquad.process(samples);
+        });
+
+        assert!(through(lowpass, 100.0) > 0.95);
+        assert!(through(lowpass, 10_000.0) < 0.05);
+        assert!(through(highpass, 100.0) < 0.05);
+        assert!(through(highpass, 10_000.0) > 0.95);
+
+        // a 6 dB shelf doubles everything under it
+        let shelf = BiquadCoefficients::new(FilterKind::LowShelf(6.0), 1000.0, 0.707, SAMPLE_RATE);
+        assert!((through(shelf, 50.0) - 2.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn biquads_fade_to_new_coefficients() {
+        let mut biquad = Biquad::new();
+        assert!(!biquad.is_active());
+
+        let lowpass = BiquadCoefficients::new(FilterKind::Lowpass, 1000.0, 0.707, SAMPLE_RATE);
+        biquad.set_coefficients(lowpass);
+        assert!(biquad.is_active());
+        assert_eq!(biquad.coefficients(), BiquadCoefficients::passthrough());
+
+        // half way there after half the fade
+        let mut frames = vec![0.0f32; BIQUAD_RAMP_FRAMES / 2 * CHANNELS];
+        biquad.process(&mut frames);
+        assert!((biquad.coefficients().b0 - (1.0 + lowpass.b0) / 2.0).abs() < 1e-6);
+
+        biquad.process(&mut frames);
+        assert!((biquad.coefficients().b0 - lowpass.b0).abs() < 1e-6);
+
+        // fading back out leaves it with nothing to do
+        biquad.set_coefficients(BiquadCoefficients::passthrough());
+        let mut frames = vec![0.0f32; BIQUAD_RAMP_FRAMES * CHANNELS];
+        biquad.process(&mut frames);
+        assert!(!biquad.is_active());
+    }
+
+    // private. a modulator stuck at one value
+    struct Fixed(f32);
+
+    impl Modulator for Fixed {
+        fn next_value(&mut self, _sample_rate: u32) -> f32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn svfs_pass_some_frequencies_and_not_others() {
+        let through = |mode, frequency| gain_at(frequency, |samples| {
+            let mut svf = Svf::new(mode, 0.707);
+            for frame in samples.chunks_mut(CHANNELS) {
+                svf.process_frame(frame, 1000.0, SAMPLE_RATE);
+            }
+        });
+
+        assert!(through(SvfMode::Lowpass, 100.0) > 0.95);
+        assert!(through(SvfMode::Lowpass, 10_000.0) < 0.05);
+        assert!(through(SvfMode::Highpass, 100.0) < 0.05);
+        assert!(through(SvfMode::Highpass, 10_000.0) > 0.95);
+        assert!(through(SvfMode::Notch, 1000.0) < 0.05);
+    }
+
+    #[test]
+    fn modulators_move_the_svf_cutoff() {
+        // two octaves up from 250 Hz is the same filter as 1 kHz
+        let modulated = gain_at(2000.0, |samples| {
+            let mut node = SvfNode::new(Svf::new(SvfMode::Lowpass, 0.707), 250.0, 2.0, Fixed(1.0), SAMPLE_RATE);
+            node.process(info(samples.len() / CHANNELS, 0), &[], samples);
+        });
+        let fixed = gain_at(2000.0, |samples| {
+            let mut svf = Svf::new(SvfMode::Lowpass, 0.707);
+            for frame in samples.chunks_mut(CHANNELS) {
+                svf.process_frame(frame, 1000.0, SAMPLE_RATE);
+            }
+        });
+        assert!((modulated - fixed).abs() < 1e-3);
+
+        // envelopes are released when the sound stops, and start again when it comes back
+        let mut envelope = Envelope::new(Adsr::default());
+        envelope.gate(false);
+        assert!(envelope.is_done());
+        envelope.gate(true);
+        assert_eq!(envelope.next_value(SAMPLE_RATE), 1.0);
+    }
+
+    #[test]
+    fn delays_echo_and_feed_back() {
+        let mut delay = Delay::new(10);
+        delay.set(4, 0.5, 0.5);
+        delay.time.set_immediate(4.0);
+        delay.feedback.set_immediate(0.5);
+        delay.mix.set_immediate(0.5);
+
+        let mut frames = vec![0.0; 13 * CHANNELS];
+        frames[..CHANNELS].fill(1.0);
+        delay.process(&mut frames);
+
+        // half dry, then each echo half the one before
+        let left: Vec<f32> = frames.iter().step_by(CHANNELS).copied().collect();
+        assert_eq!(left, vec![0.5, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.25, 0.0, 0.0, 0.0, 0.125]);
+
+        // too much feedback would ring on forever
+        delay.set(4, 2.0, 0.5);
+        assert_eq!(delay.feedback.target(), 0.99);
+    }
+
+    // private. how loud `reverb` rings on a second after a click, and how long before it starts
+    fn tail(reverb: &mut Reverb) -> (f32, usize) {
+        let mut frames = vec![0.0f32; 2 * SAMPLE_RATE as usize * CHANNELS];
+        frames[..CHANNELS].fill(1.0);
+        reverb.process(&mut frames);
+
+        // the click itself is dry, leave it out
+        let start = frames[CHANNELS..].iter().position(|sample| *sample != 0.0).unwrap() / CHANNELS + 1;
+        let late = frames[SAMPLE_RATE as usize * CHANNELS..].iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        (late, start)
+    }
+
+    #[test]
+    fn reverbs_ring_on_for_as_long_as_the_room_is_big() {
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        reverb.set(0.2, 0.5, 1.0);
+        reverb.room_size.set_immediate(0.2);
+        reverb.mix.set_immediate(1.0);
+        let (small, start) = tail(&mut reverb);
+
+        // nothing comes back until the shortest comb has gone round once
+        assert!(start >= (REVERB_COMBS[0] as f32 * SAMPLE_RATE as f32 / REVERB_TUNING_RATE) as usize);
+
+        reverb.clear();
+        reverb.set(1.0, 0.5, 1.0);
+        reverb.room_size.set_immediate(1.0);
+        let (big, _) = tail(&mut reverb);
+        assert!(big > 10.0 * small);
+
+        // with no mix, only the dry signal comes out
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        let mut frames = vec![0.25f32; 4800 * CHANNELS];
+        reverb.process(&mut frames);
+        assert!(frames.iter().all(|sample| *sample == 0.25));
+    }
+
+    #[test]
+    fn choruses_sweep_the_channels_opposite_ways() {
+        let mut chorus = Chorus::new(100);
+        chorus.set(ChorusSettings { delay: 10.0, depth: 4.0, rate: SAMPLE_RATE as f32 / 100.0, feedback: 0.0, mix: 1.0 });
+        chorus.delay.set_immediate(10.0);
+        chorus.mix.set_immediate(1.0);
+        chorus.lfo.rate.set_immediate(SAMPLE_RATE as f32 / 100.0);
+        chorus.lfo.depth.set_immediate(4.0);
+
+        // a ramp, so each output says how far back it was read from
+        let mut frames: Vec<f32> = (0..200).flat_map(|i| [i as f32; CHANNELS]).collect();
+        chorus.process(&mut frames, SAMPLE_RATE);
+
+        let mut widest = 0.0f32;
+        for (i, frame) in frames.chunks(CHANNELS).enumerate().skip(20) {
+            assert!((frame[0] + frame[1] - 2.0 * (i as f32 - 10.0)).abs() < 1e-3);
+            widest = widest.max((frame[0] - frame[1]).abs());
+        }
+        assert!(widest > 7.9 && widest <= 8.0);
+    }
+
+    #[test]
+    fn chorus_sweeps_stay_inside_the_line() {
+        let mut chorus = Chorus::new(20);
+        chorus.set(ChorusSettings { delay: 100.0, depth: 100.0, rate: 1.0, feedback: 2.0, mix: 1.0 });
+        assert_eq!(chorus.lfo.depth.target(), 9.5);
+        assert_eq!(chorus.delay.target(), 10.5);
+        assert_eq!(chorus.feedback.target(), 0.95);
+    }
 }